@@ -0,0 +1,38 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    #[cfg(feature = "grpc")]
+    grpc();
+}
+
+// generate the gRPC service, with the vendored protoc unless PROTOC names
+// another
+#[cfg(feature = "grpc")]
+fn grpc() {
+    println!("cargo:rerun-if-changed=proto/bigiron_virt.proto");
+    println!("cargo:rerun-if-env-changed=PROTOC");
+
+    if std::env::var_os("PROTOC").is_none() {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc");
+        std::env::set_var("PROTOC", protoc);
+    }
+    tonic_prost_build::compile_protos("proto/bigiron_virt.proto")
+        .expect("error compiling proto/bigiron_virt.proto");
+}
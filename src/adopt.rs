@@ -0,0 +1,336 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Reading a machine spec back out of the XML of a domain made outside
+//! bigiron-virt, for `bigiron-virt adopt`.
+//!
+//! Only what the machine model can say is taken: cpus, memory, the disks
+//! and the nics with their MACs. The first disk becomes the instance disk
+//! and must be a qcow2 file, the others become file or block storage and
+//! must be raw, since that's how storage disks are attached.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+
+use crate::api::models::{Block, DiskBus, DiskDriver, File, Machine, Nic, Size, StorageKind};
+use crate::error::Error;
+
+/// What adopting takes from a domain's XML
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct DomainInfo {
+    pub name: String,
+    pub uuid: Option<String>,
+    pub cpu: u32,
+    pub memory: u64,
+    pub disks: Vec<DiskInfo>,
+    pub nics: Vec<Nic>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct DiskInfo {
+    pub path: PathBuf,
+    pub block: bool,
+    // the driver type, e.g. qcow2
+    pub format: Option<String>,
+    pub bus: Option<String>,
+}
+
+impl DomainInfo {
+    /// The instance disk, the first one
+    pub fn root(&self) -> Result<&DiskInfo, Error> {
+        let root = self
+            .disks
+            .first()
+            .ok_or_else(|| format!("domain {} has no disks", self.name))?;
+        if root.block || root.format.as_deref() != Some("qcow2") {
+            return Err(format!(
+                "first disk {:?} of {} isn't a qcow2 file",
+                root.path, self.name
+            )
+            .into());
+        }
+        Ok(root)
+    }
+
+    /// The machine the domain is, its instance disk recorded as the image
+    /// at `url` with sha256 `hash`
+    pub fn machine(&self, url: &str, hash: &str) -> Result<Machine, Error> {
+        self.root()?;
+
+        let mut builder = Machine::builder()
+            .name(&self.name)
+            .cpu(self.cpu)
+            .memory(&Size(self.memory).to_size_string())
+            .image(url, hash);
+        if let Some(ref uuid) = self.uuid {
+            builder = builder.uuid(uuid);
+        }
+
+        let mut buses = Vec::new();
+        for disk in &self.disks[1..] {
+            if disk.format.as_deref().is_some_and(|f| f != "raw") {
+                return Err(format!("disk {:?} of {} isn't raw", disk.path, self.name).into());
+            }
+            let driver = DiskDriver::default();
+            let path = disk.path.clone();
+            builder = builder.storage(match disk.block {
+                true => StorageKind::Block(Block { path, driver }),
+                false => StorageKind::File(File { path, driver }),
+            });
+            buses.push(disk.bus.as_deref());
+        }
+        // storage disks share a bus, anything but scsi ends up on virtio
+        if !buses.is_empty() && buses.iter().all(|b| *b == Some("scsi")) {
+            builder = builder.storage_bus(DiskBus::Scsi);
+        }
+
+        for nic in &self.nics {
+            builder = builder.nic(nic.clone());
+        }
+
+        builder.build()
+    }
+}
+
+/// Parse domain XML `xml`, failing on disks and nics adopting can't take
+pub(crate) fn parse(xml: &str) -> Result<DomainInfo, Error> {
+    let mut reader = Reader::from_str(xml);
+    let mut path: Vec<String> = Vec::new();
+    let mut info = DomainInfo::default();
+    let mut memory_unit = String::from("KiB");
+    let mut disk: Option<DiskInfo> = None;
+    let mut nic: Option<Nic> = None;
+
+    loop {
+        let (e, empty) = match reader.read_event()? {
+            Event::Start(e) => (e, false),
+            Event::Empty(e) => (e, true),
+            Event::Text(t) => {
+                let text = t.unescape()?.trim().to_string();
+                match path.join("/").as_str() {
+                    "domain/name" => info.name = text,
+                    "domain/uuid" => info.uuid = Some(text),
+                    "domain/memory" => info.memory = bytes(&text, &memory_unit)?,
+                    "domain/vcpu" => info.cpu = text.parse()?,
+                    _ => {}
+                }
+                continue;
+            }
+            Event::End(_) => {
+                match path.pop().as_deref() {
+                    Some("disk") => info.disks.extend(disk.take()),
+                    Some("interface") => info.nics.extend(nic.take()),
+                    _ => {}
+                }
+                continue;
+            }
+            Event::Eof => break,
+            _ => continue,
+        };
+
+        let element = String::from_utf8_lossy(e.name().as_ref()).into_owned();
+        let attrs = attributes(&e)?;
+        let attr = |name: &str| attrs.get(name).cloned();
+
+        match (path.join("/").as_str(), element.as_str()) {
+            ("domain", "memory") => memory_unit = attr("unit").unwrap_or(memory_unit),
+            // cdroms and floppies are left out
+            ("domain/devices", "disk") if attr("device").as_deref().unwrap_or("disk") == "disk" => {
+                let kind = attr("type").unwrap_or_default();
+                if kind != "file" && kind != "block" {
+                    return Err(format!("{} disks can't be adopted", kind).into());
+                }
+                disk = Some(DiskInfo {
+                    block: kind == "block",
+                    ..Default::default()
+                });
+            }
+            ("domain/devices", "interface") => {
+                let kind = attr("type").unwrap_or_default();
+                nic = Some(match kind.as_str() {
+                    "bridge" => Nic::bridge(""),
+                    "network" => Nic::network(""),
+                    "direct" => Nic::macvtap(""),
+                    _ => return Err(format!("{} interfaces can't be adopted", kind).into()),
+                });
+            }
+            ("domain/devices/disk", "source") => {
+                if let Some(ref mut d) = disk {
+                    d.path = attr("file").or(attr("dev")).unwrap_or_default().into();
+                }
+            }
+            ("domain/devices/disk", "driver") => {
+                if let Some(ref mut d) = disk {
+                    d.format = attr("type");
+                }
+            }
+            ("domain/devices/disk", "target") => {
+                if let Some(ref mut d) = disk {
+                    d.bus = attr("bus");
+                }
+            }
+            ("domain/devices/interface", "source") => {
+                if let Some(ref mut n) = nic {
+                    let parent = attr("bridge").or(attr("network")).or(attr("dev"));
+                    n.parent = parent.unwrap_or_default();
+                }
+            }
+            ("domain/devices/interface", "mac") => {
+                if let Some(ref mut n) = nic {
+                    n.mac = attr("address");
+                }
+            }
+            _ => {}
+        }
+
+        if !empty {
+            path.push(element);
+        }
+    }
+
+    if info.cpu == 0 || info.memory == 0 {
+        return Err(format!("domain {} has no vcpu or memory", info.name).into());
+    }
+    Ok(info)
+}
+
+fn attributes(e: &BytesStart) -> Result<HashMap<String, String>, Error> {
+    let mut attrs = HashMap::new();
+    for a in e.attributes() {
+        let a = a?;
+        let key = String::from_utf8_lossy(a.key.as_ref()).into_owned();
+        attrs.insert(key, a.unescape_value()?.into_owned());
+    }
+    Ok(attrs)
+}
+
+// a libvirt memory amount in bytes, `unit` as in its XML
+fn bytes(amount: &str, unit: &str) -> Result<u64, Error> {
+    let scale: u64 = match unit {
+        "b" | "bytes" => 1,
+        "k" | "KiB" => 1 << 10,
+        "M" | "MiB" => 1 << 20,
+        "G" | "GiB" => 1 << 30,
+        "T" | "TiB" => 1 << 40,
+        "KB" => 1000,
+        "MB" => 1000 * 1000,
+        "GB" => 1000 * 1000 * 1000,
+        _ => return Err(format!("unknown memory unit {}", unit).into()),
+    };
+    Ok(amount.parse::<u64>()? * scale)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // as virt-install defines them, shortened
+    const DOMAIN: &str = r#"<domain type='kvm' id='3'>
+  <name>legacy01</name>
+  <uuid>5b0cbd1e-28c1-4a76-9d0e-3fdc3f4d3b3a</uuid>
+  <memory unit='KiB'>4194304</memory>
+  <currentMemory unit='KiB'>4194304</currentMemory>
+  <vcpu placement='static'>2</vcpu>
+  <os>
+    <type arch='x86_64' machine='pc-q35-6.2'>hvm</type>
+  </os>
+  <devices>
+    <emulator>/usr/bin/qemu-system-x86_64</emulator>
+    <disk type='file' device='disk'>
+      <driver name='qemu' type='qcow2'/>
+      <source file='/var/lib/libvirt/images/legacy01.qcow2' index='2'/>
+      <target dev='vda' bus='virtio'/>
+    </disk>
+    <disk type='block' device='disk'>
+      <driver name='qemu' type='raw' cache='none'/>
+      <source dev='/dev/vg0/legacy-data'/>
+      <target dev='vdb' bus='virtio'/>
+    </disk>
+    <disk type='file' device='cdrom'>
+      <driver name='qemu' type='raw'/>
+      <target dev='sda' bus='sata'/>
+      <readonly/>
+    </disk>
+    <interface type='bridge'>
+      <mac address='52:54:00:6b:3c:58'/>
+      <source bridge='br0'/>
+      <model type='virtio'/>
+    </interface>
+    <interface type='network'>
+      <mac address='52:54:00:11:22:33'/>
+      <source network='default' portid='1b6a' bridge='virbr0'/>
+      <model type='virtio'/>
+    </interface>
+  </devices>
+</domain>"#;
+
+    #[test]
+    fn domain_to_machine() {
+        let info = parse(DOMAIN).unwrap();
+        assert_eq!(info.name, "legacy01");
+        assert_eq!(info.cpu, 2);
+        assert_eq!(info.memory, 4 << 30);
+        assert_eq!(info.disks.len(), 2);
+        assert_eq!(info.nics.len(), 2);
+
+        let url = "file:///var/lib/libvirt/images/legacy01.qcow2";
+        let hash = "754129c5052756ee47a0c395e518bd3413f444dff69b98f8a8fa42f2fa3acc2d";
+        let m = info.machine(url, hash).unwrap();
+        m.validate().unwrap();
+        assert_eq!(
+            m.metadata.uuid.as_deref(),
+            Some("5b0cbd1e-28c1-4a76-9d0e-3fdc3f4d3b3a")
+        );
+        assert_eq!(m.spec.memory, "4Gi".parse().unwrap());
+        assert_eq!(m.spec.image.url, url);
+        assert_eq!(
+            m.spec.storage.unwrap(),
+            [StorageKind::Block(Block {
+                path: PathBuf::from("/dev/vg0/legacy-data"),
+                driver: DiskDriver::default(),
+            })]
+        );
+
+        let nics = m.spec.nics.unwrap();
+        assert_eq!(nics[0], Nic::bridge("br0").with_mac("52:54:00:6b:3c:58"));
+        // the network, not the bridge libvirt made for it
+        assert_eq!(
+            nics[1],
+            Nic::network("default").with_mac("52:54:00:11:22:33")
+        );
+    }
+
+    #[test]
+    fn not_adoptable() {
+        let raw_root = DOMAIN.replacen("type='qcow2'", "type='raw'", 1);
+        let err = parse(&raw_root).unwrap().root().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "first disk \"/var/lib/libvirt/images/legacy01.qcow2\" of legacy01 isn't a qcow2 file"
+        );
+
+        let user_net = DOMAIN.replacen("<interface type='bridge'>", "<interface type='user'>", 1);
+        let err = parse(&user_net).unwrap_err();
+        assert_eq!(err.to_string(), "user interfaces can't be adopted");
+
+        assert_eq!(bytes("2", "GiB").unwrap(), 2 << 30);
+        assert!(bytes("2", "parsecs").is_err());
+    }
+}
@@ -15,15 +15,52 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
 use serde_yaml;
+use tracing::warn;
 
 pub mod models;
-use models::{Machine, Resource};
+use models::{AddressPool, HostBridge, Machine, MachineClass, Resource, Selector};
 
-use crate::error::Error;
-use crate::hostmanager::{HostManager, MachineStatus};
+use crate::backup::BackupInfo;
+use crate::capacity::PlanReport;
+use crate::config::{HostConfig, SpecDefaults};
+use crate::doctor::DoctorReport;
+use crate::error::{self, Error};
+use crate::events::LifecycleEvent;
+use crate::guest_agent::ExecResult;
+use crate::hostmanager::HostManager;
+pub use crate::hostmanager::{MachineInfo, MachineStatus};
+use crate::image::repo::ImageInfo;
+pub use crate::ipam::PoolUsage;
+use crate::metrics::{self, Metrics};
+pub use crate::orphans::Orphan;
+use crate::reconcile::{Actions, ReconcileOptions, StateDiff};
+use crate::remote::{Client, TlsServer};
+use crate::scheduler::{Inventory, Scheduler};
+use crate::secrets::{self, SecretInfo, Usage};
+use crate::selftest::{SelftestOptions, SelftestReport};
+pub use crate::stats::MachineStats;
+pub use crate::update::UpdateReport;
+pub use crate::vmstore::Timestamps;
 
 pub fn resources_from_yaml(yaml: &str) -> Result<Vec<Resource>, Error> {
+    resources_with_classes(yaml, &[])
+}
+
+/// The resources in `yaml`, with the classes machines name in `spec.class`
+/// looked up in `yaml` first and then in `classes`. The classes are merged
+/// in and not returned themselves.
+pub fn resources_with_classes(
+    yaml: &str,
+    classes: &[MachineClass],
+) -> Result<Vec<Resource>, Error> {
+    let mut known = classes_from_yaml(yaml)?;
+    known.extend_from_slice(classes);
+
     let mut rs = Vec::new();
 
     for res in yaml.split("---\n") {
@@ -31,32 +68,331 @@ pub fn resources_from_yaml(yaml: &str) -> Result<Vec<Resource>, Error> {
             continue;
         }
 
-        let r = serde_yaml::from_str(&res)?;
+        let mut doc: serde_yaml::Value = serde_yaml::from_str(res)?;
+        let kind = doc.get("kind").and_then(|k| k.as_str());
+        if matches!(kind, Some("MachineClass" | "AddressPool" | "Bridge")) {
+            continue;
+        }
+
+        let class = doc
+            .get("spec")
+            .and_then(|s| s.get("class"))
+            .and_then(|c| c.as_str())
+            .map(String::from);
+        let r = match (class, doc.get_mut("spec")) {
+            (Some(name), Some(serde_yaml::Value::Mapping(spec))) => {
+                known
+                    .iter()
+                    .find(|c| c.metadata.name == name)
+                    .ok_or_else(|| error::invalid(format!("unknown machine class {}", name)))?
+                    .apply(spec);
+                serde_yaml::from_value(doc)?
+            }
+            // parsed again from the text, so errors keep their line numbers
+            _ => serde_yaml::from_str(res)?,
+        };
         rs.push(r);
     }
 
     Ok(rs)
 }
 
+/// The machine classes declared in `yaml`
+pub fn classes_from_yaml(yaml: &str) -> Result<Vec<MachineClass>, Error> {
+    let mut classes: Vec<MachineClass> = Vec::new();
+
+    for res in yaml.split("---\n") {
+        if res.is_empty() {
+            continue;
+        }
+
+        let doc: serde_yaml::Value = serde_yaml::from_str(res)?;
+        if doc.get("kind").and_then(|k| k.as_str()) != Some("MachineClass") {
+            continue;
+        }
+
+        let class: MachineClass = serde_yaml::from_value(doc)?;
+        if classes
+            .iter()
+            .any(|c| c.metadata.name == class.metadata.name)
+        {
+            return Err(format!("machine class {} is declared twice", class.metadata.name).into());
+        }
+        classes.push(class);
+    }
+
+    Ok(classes)
+}
+
+/// The address pools declared in `yaml`
+pub fn pools_from_yaml(yaml: &str) -> Result<Vec<AddressPool>, Error> {
+    let mut pools = Vec::new();
+
+    for res in yaml.split("---\n") {
+        if res.is_empty() {
+            continue;
+        }
+
+        let doc: serde_yaml::Value = serde_yaml::from_str(res)?;
+        if doc.get("kind").and_then(|k| k.as_str()) == Some("AddressPool") {
+            pools.push(serde_yaml::from_value(doc)?);
+        }
+    }
+
+    Ok(pools)
+}
+
+/// The host bridges declared in `yaml`
+pub fn bridges_from_yaml(yaml: &str) -> Result<Vec<HostBridge>, Error> {
+    let mut bridges = Vec::new();
+
+    for res in yaml.split("---\n") {
+        if res.is_empty() {
+            continue;
+        }
+
+        let doc: serde_yaml::Value = serde_yaml::from_str(res)?;
+        if doc.get("kind").and_then(|k| k.as_str()) == Some("Bridge") {
+            bridges.push(serde_yaml::from_value(doc)?);
+        }
+    }
+
+    Ok(bridges)
+}
+
+/// Create the machines in `yaml`, with `userdataFile` and cdrom paths
+/// relative to the current directory
 pub fn create_from_yaml(yaml: &str) -> Result<(), Error> {
-    let resources = resources_from_yaml(yaml).unwrap();
+    create_resources(yaml, Path::new(".")).map(|_| ())
+}
+
+/// Create the machines in model file `path`, with `userdataFile` and cdrom
+/// paths relative to the model file, returning their names. Each is created after
+/// the machines its `dependsOn` names.
+pub fn create_from_file(path: &Path) -> Result<Vec<String>, Error> {
+    let yaml = std::fs::read_to_string(path)
+        .map_err(|e| format!("error reading model file {:?}: {}", path, e))?;
+    create_resources(&yaml, &model_dir(path))
+}
+
+fn create_resources(yaml: &str, base_dir: &Path) -> Result<Vec<String>, Error> {
+    let resources = resources_from_yaml(yaml)?;
 
     let mut hm = HostManager::new()?;
+    let defaults = HostConfig::load()?.defaults;
+
+    // before the machines, which take addresses from them and join them
+    for pool in pools_from_yaml(yaml)? {
+        hm.save_pool(&pool)?;
+    }
+    for bridge in bridges_from_yaml(yaml)? {
+        crate::bridge::ensure(&bridge)?;
+    }
 
+    let mut machines = Vec::new();
     for res in resources {
         match res {
             Resource::Machine(mut m) => {
-                hm.create_machine(&mut m)?;
+                m.apply_defaults(&defaults);
+                m.validate()?;
+                m.resolve_files(base_dir)?;
+                machines.push(m);
             }
         }
     }
 
+    let mut names = Vec::new();
+    for mut m in models::dependency_order(machines)? {
+        hm.wait_dependencies(&m, Instant::now() + DEPENDENCY_TIMEOUT)?;
+        hm.create_machine(&mut m)?;
+        names.push(m.metadata.name);
+    }
+
+    Ok(names)
+}
+
+// how long a create waits for the machines it depends on to be ready
+const DEPENDENCY_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Wait for cloud-init to be done in each of machines `ids`, all within
+/// `timeout`, calling `on_ready` with each name as it is
+pub fn wait_ready<F: FnMut(&str)>(
+    ids: &[String],
+    timeout: Duration,
+    mut on_ready: F,
+) -> Result<(), Error> {
+    let mut hm = HostManager::new()?;
+    let deadline = Instant::now() + timeout;
+
+    for id in ids {
+        hm.wait_ready(id, deadline)?;
+        on_ready(id);
+    }
+
+    Ok(())
+}
+
+/// Create the machines in `yaml` like a model sent from another host,
+/// calling `on_phase` with the machine name and phase, e.g. "building:
+/// creating disk", each time a create reaches another phase
+pub fn create_with_progress<F>(yaml: &str, mut on_phase: F) -> Result<(), Error>
+where
+    F: FnMut(&str, &str) + 'static,
+{
+    let mut hm = HostManager::new()?;
+    hm.on_phase(move |name, phase| on_phase(name, &phase.to_string()));
+    create_sent(&mut hm, yaml)
+}
+
+// create the machines in a model from another host, which can't refer to
+// files on this one
+fn create_sent(hm: &mut HostManager, yaml: &str) -> Result<(), Error> {
+    let (bridges, machines) = sent_resources(yaml, &HostConfig::load()?.defaults)?;
+    for pool in pools_from_yaml(yaml)? {
+        hm.save_pool(&pool)?;
+    }
+    for bridge in bridges {
+        crate::bridge::ensure(&bridge)?;
+    }
+    for mut m in models::dependency_order(machines)? {
+        // with nothing to wait for this only checks dependencies exist
+        hm.wait_dependencies(&m, Instant::now())?;
+        hm.create_machine(&mut m)?;
+    }
     Ok(())
 }
 
-pub fn list_machines() -> Result<Vec<MachineStatus>, Error> {
+// the bridges and machines of a model from another host, with `defaults`
+// filled in, refusing anything that would reach into this host
+fn sent_resources(
+    yaml: &str,
+    defaults: &SpecDefaults,
+) -> Result<(Vec<HostBridge>, Vec<Machine>), Error> {
+    let bridges = bridges_from_yaml(yaml)?;
+    if let Some(bridge) = bridges.iter().find(|b| b.spec.uplink.is_some()) {
+        return Err(error::invalid(format!(
+            "bridge {} can't be sent with an uplink, which would take over a host interface",
+            bridge.metadata.name
+        )));
+    }
+    let mut machines = Vec::new();
+    for res in resources_from_yaml(yaml)? {
+        let Resource::Machine(mut m) = res;
+        m.apply_defaults(defaults);
+        m.validate()?;
+        if let Some(field) = host_reference(&m) {
+            return Err(error::invalid(format!(
+                "machine {}: {} refers to this host and can't be sent",
+                m.metadata.name, field
+            )));
+        }
+        // waiting would hold up the server for every other request
+        if m.spec.depends_on.iter().flatten().any(|d| d.ready()) {
+            return Err(error::invalid(format!(
+                "machine {}: dependencies with ready: true can't be sent, create them first",
+                m.metadata.name
+            )));
+        }
+        machines.push(m);
+    }
+    Ok((bridges, machines))
+}
+
+// the first field of `m` naming a file, device, command, secret or raw
+// domain XML on this host, which a model sent from another host mustn't use
+fn host_reference(m: &Machine) -> Option<&'static str> {
+    use models::StorageKind;
+
+    let spec = &m.spec;
+    // files would be read from this host, the sender resolves them
+    if spec.userdata_file.is_some() {
+        return Some("userdataFile");
+    }
+    let mut storage = spec.storage.iter().flatten();
+    if storage.any(|s| matches!(s, StorageKind::File(_) | StorageKind::Block(_))) {
+        return Some("file or block storage");
+    }
+    // every provider reads with this host's access, env: and vault: too
+    if spec.secrets.as_ref().is_some_and(|s| !s.is_empty()) {
+        return Some("a secret");
+    }
+    let encryption = spec.image.encryption.as_ref();
+    if encryption.map_or(false, |e| e.passphrase_file.is_some()) {
+        return Some("passphraseFile");
+    }
+    if spec.cdroms.iter().flatten().any(|c| c.path.is_some()) {
+        return Some("cdrom path");
+    }
+    if spec.direct_boot.is_some() {
+        return Some("directBoot");
+    }
+    if spec.extra_domain_xml.is_some() {
+        return Some("extraDomainXml");
+    }
+    if spec.domain_xml_override.is_some() {
+        return Some("domainXmlOverride");
+    }
+    None
+}
+
+fn model_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => ".".into(),
+    }
+}
+
+/// Create the machines declared in `opts.dir` that don't exist and, if
+/// pruning, destroy the ones not declared, every `opts.interval` or once
+pub fn reconcile(opts: &ReconcileOptions) -> Result<Actions, Error> {
+    crate::reconcile::run(opts)
+}
+
+/// Report whether and where the machines in `yaml` would fit on this host,
+/// without creating anything
+pub fn simulate_plan(yaml: &str) -> Result<PlanReport, Error> {
+    let defaults = HostConfig::load()?.defaults;
+    let machines: Vec<Machine> = resources_from_yaml(yaml)?
+        .into_iter()
+        .map(|res| match res {
+            Resource::Machine(mut m) => {
+                m.apply_defaults(&defaults);
+                m
+            }
+        })
+        .collect();
+
     let hm = HostManager::new()?;
-    Ok(hm.list_machines()?)
+    hm.simulate(&machines)
+}
+
+/// What creating the machines in model files `paths` would create and
+/// find declared differently on this host, and destroy if `prune` as
+/// `reconcile --prune` would
+pub fn plan_changes(paths: &[PathBuf], prune: bool) -> Result<StateDiff, Error> {
+    let mut declared = Vec::new();
+    for path in paths {
+        declared.extend(machines_from_file(path)?);
+    }
+
+    let hm = HostManager::new()?;
+    crate::reconcile::state_diff(&declared, &hm.machines()?, prune)
+}
+
+/// Create, boot and destroy a throwaway machine to check the host works
+pub fn selftest(opts: &SelftestOptions) -> Result<SelftestReport, Error> {
+    crate::selftest::run(opts)
+}
+
+/// Check the host has what machines need, without creating anything
+pub fn doctor(bridges: &[String]) -> Result<DoctorReport, Error> {
+    let config = HostConfig::load()?;
+    Ok(crate::doctor::run(&config, bridges))
+}
+
+pub fn list_machines(selector: Option<&Selector>) -> Result<Vec<MachineStatus>, Error> {
+    let hm = HostManager::new()?;
+    Ok(hm.list_machines(selector)?)
 }
 
 pub fn destroy_machine(id: &str) -> Result<(), Error> {
@@ -64,6 +400,524 @@ pub fn destroy_machine(id: &str) -> Result<(), Error> {
     Ok(hm.destroy_machine(id)?)
 }
 
+/// Outcome of destroying one machine in a bulk destroy
+pub type DestroyResult = (String, Result<(), Error>);
+
+/// Destroy each of `ids`, carrying on past failures. Running machines get
+/// `timeout`, or the host's destroy timeout, to shut down before they are
+/// powered off.
+pub fn destroy_machines(
+    ids: &[String],
+    timeout: Option<Duration>,
+) -> Result<Vec<DestroyResult>, Error> {
+    let mut hm = HostManager::new()?;
+    if let Some(timeout) = timeout {
+        hm.set_destroy_timeout(timeout);
+    }
+
+    Ok(ids
+        .iter()
+        .map(|id| (id.clone(), hm.destroy_machine(id)))
+        .collect())
+}
+
+/// Ids of the machines on this host past their `metadata.ttl`
+pub fn expired_machines() -> Result<Vec<String>, Error> {
+    let hm = HostManager::new()?;
+    hm.expired(std::time::SystemTime::now())
+}
+
+/// Domains, instances and images left over on this host, see `Orphan`
+pub fn find_orphans() -> Result<Vec<Orphan>, Error> {
+    let hm = HostManager::new()?;
+    hm.orphans(std::time::SystemTime::now())
+}
+
+/// Outcome of fixing one orphan
+pub type FixResult = (Orphan, Result<(), Error>);
+
+/// Fix each of `orphans`, carrying on past failures
+pub fn fix_orphans(orphans: &[Orphan]) -> Result<Vec<FixResult>, Error> {
+    let mut hm = HostManager::new()?;
+
+    Ok(orphans
+        .iter()
+        .map(|o| (o.clone(), hm.fix_orphan(o)))
+        .collect())
+}
+
+/// Ids of every machine on this host
+pub fn all_machine_ids() -> Result<Vec<String>, Error> {
+    let hm = HostManager::new()?;
+    Ok(hm.list_machines(None)?.into_iter().map(|m| m.id).collect())
+}
+
+/// Ids of the machines whose labels match `selector`
+pub fn select_machines(selector: &Selector) -> Result<Vec<String>, Error> {
+    let hm = HostManager::new()?;
+    hm.select_machines(selector)
+}
+
+/// Names of the machines declared in `yaml`
+pub fn machine_ids_from_yaml(yaml: &str) -> Result<Vec<String>, Error> {
+    Ok(resources_from_yaml(yaml)?
+        .into_iter()
+        .map(|res| match res {
+            Resource::Machine(m) => m.metadata.name,
+        })
+        .collect())
+}
+
+/// Stored spec, state and guest addresses of machine `id`
+pub fn show_machine(id: &str) -> Result<MachineInfo, Error> {
+    let hm = HostManager::new()?;
+    hm.machine_info(id)
+}
+
+/// Run `argv` in machine `id` through the guest agent
+pub fn guest_exec(id: &str, argv: &[String], timeout: Duration) -> Result<ExecResult, Error> {
+    let hm = HostManager::new()?;
+    hm.guest_exec(id, argv, timeout)
+}
+
+/// Addresses the guest agent reports for machine `id`
+pub fn guest_ip_addrs(id: &str) -> Result<Vec<IpAddr>, Error> {
+    let hm = HostManager::new()?;
+    hm.guest_ip_addrs(id)
+}
+
+/// An address of machine `id` answering on TCP `port`, waiting up to
+/// `timeout` for one, e.g. for ssh once it has booted
+pub fn reachable_address(id: &str, port: u16, timeout: Duration) -> Result<SocketAddr, Error> {
+    let hm = HostManager::new()?;
+    hm.reachable_address(id, port, timeout)
+}
+
+/// Where ssh keeps the host keys of machine `id`
+pub fn known_hosts_path(id: &str) -> Result<PathBuf, Error> {
+    let hm = HostManager::new()?;
+    hm.known_hosts_path(id)
+}
+
+/// CPU, memory, disk and network usage of running machine `id`, measured
+/// over `interval`
+pub fn machine_stats(id: &str, interval: Duration) -> Result<MachineStats, Error> {
+    let hm = HostManager::new()?;
+    hm.machine_stats(id, interval)
+}
+
+/// Cleanly shut down machine `id`
+pub fn stop_machine(id: &str, timeout: Duration) -> Result<(), Error> {
+    let mut hm = HostManager::new()?;
+    hm.stop_machine(id, timeout)
+}
+
+/// Rebuild the config drive of machine `id` and swap it in, with the
+/// userdata, secrets and nic addressing of the machine in model file
+/// `file` if given
+pub fn update_config_drive(id: &str, file: Option<&Path>) -> Result<(), Error> {
+    let update = match file {
+        Some(file) => Some(machine_from_file(file)?),
+        None => None,
+    };
+
+    let mut hm = HostManager::new()?;
+    hm.update_config_drive(id, update.as_ref())
+}
+
+/// Change machine `id` to the spec in model file `file`, live as far as
+/// it can be and, if `restart`, restarting it within `timeout` for the
+/// rest
+pub fn update_machine(
+    id: &str,
+    file: &Path,
+    restart: bool,
+    timeout: Duration,
+) -> Result<UpdateReport, Error> {
+    let update = machine_from_file(file)?;
+
+    let mut hm = HostManager::new()?;
+    hm.update_machine(id, &update, restart, timeout)
+}
+
+// the one machine in model file `file`, ready to create
+fn machine_from_file(file: &Path) -> Result<Machine, Error> {
+    let yaml = std::fs::read_to_string(file)
+        .map_err(|e| format!("error reading model file {:?}: {}", file, e))?;
+    let mut resources = resources_from_yaml(&yaml)?;
+    if resources.len() != 1 {
+        return Err("expected exactly one machine in the spec".into());
+    }
+
+    let Resource::Machine(mut m) = resources.remove(0);
+    m.apply_defaults(&HostConfig::load()?.defaults);
+    m.validate()?;
+    m.resolve_files(&model_dir(file))?;
+    Ok(m)
+}
+
+/// Files on the config drive of machine `id` as `(name, contents)`
+pub fn config_drive_files(id: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let hm = HostManager::new()?;
+    hm.config_drive_files(id)
+}
+
+/// Pause running machine `id`
+pub fn pause_machine(id: &str) -> Result<(), Error> {
+    let mut hm = HostManager::new()?;
+    hm.pause_machine(id)
+}
+
+pub fn resume_machine(id: &str) -> Result<(), Error> {
+    let mut hm = HostManager::new()?;
+    hm.resume_machine(id)
+}
+
+/// Stop machine `id` keeping its memory state, in the instance directory
+/// or in `file` if given, until `restore_saved_machine`
+pub fn save_machine(id: &str, file: Option<&Path>) -> Result<(), Error> {
+    let mut hm = HostManager::new()?;
+    hm.save_machine(id, file)
+}
+
+/// Start machine `id` again where `save_machine` left it
+pub fn restore_saved_machine(id: &str) -> Result<(), Error> {
+    let mut hm = HostManager::new()?;
+    hm.restore_saved_machine(id)
+}
+
+/// Write machine `id` with its disk and config drive to a tarball at `output`
+pub fn export_machine(id: &str, output: &Path) -> Result<(), Error> {
+    let hm = HostManager::new()?;
+    hm.export_machine(id, output)
+}
+
+/// Recreate a machine from an exported archive, returning its name
+pub fn import_machine(archive: &Path) -> Result<String, Error> {
+    let mut hm = HostManager::new()?;
+    hm.import_machine(archive)
+}
+
+/// Manage existing libvirt domain `name` as a machine, returning the spec
+/// read from it
+pub fn adopt_machine(name: &str) -> Result<Machine, Error> {
+    let mut hm = HostManager::new()?;
+    hm.adopt_machine(name)
+}
+
+/// Back up machine `id` into the configured backup directory
+pub fn backup_machine(id: &str, quiesce: bool) -> Result<BackupInfo, Error> {
+    let mut hm = HostManager::new()?;
+    hm.backup_machine(id, quiesce)
+}
+
+pub fn list_backups(id: Option<&str>) -> Result<Vec<BackupInfo>, Error> {
+    let hm = HostManager::new()?;
+    hm.list_backups(id)
+}
+
+/// Replace machine `id` with the state saved in backup `name`
+pub fn restore_machine(id: &str, name: &str) -> Result<(), Error> {
+    let mut hm = HostManager::new()?;
+    hm.restore_machine(id, name)
+}
+
+pub fn list_images() -> Result<Vec<ImageInfo>, Error> {
+    let hm = HostManager::new()?;
+    hm.list_images()
+}
+
+/// Import the image at `url` into the repo without creating a machine,
+/// the same as creating one would; it's not downloaded again if present
+pub fn pull_image(
+    url: &str,
+    hash: &str,
+    iso: bool,
+    name: Option<&str>,
+) -> Result<ImageInfo, Error> {
+    let url = url::Url::parse(url)?;
+
+    let mut hm = HostManager::new()?;
+    hm.pull_image(&url, hash, iso, name)
+}
+
+/// Build a disk image from OCI image `reference`, e.g.
+/// quay.io/fedora/fedora:40, with `free` space (e.g. "1G") to spare and
+/// add it to the repo
+pub fn import_oci_image(
+    reference: &str,
+    free: &str,
+    name: Option<&str>,
+) -> Result<ImageInfo, Error> {
+    let free = models::to_size(free)?;
+
+    let mut hm = HostManager::new()?;
+    hm.import_oci_image(reference, free, name)
+}
+
+/// Give repo image `image` (a hash, name or alias) a friendly name for
+/// `spec.image.name`, plus `aliases` and OS info
+pub fn name_image(
+    image: &str,
+    name: &str,
+    aliases: &[String],
+    os: Option<&str>,
+) -> Result<ImageInfo, Error> {
+    let mut hm = HostManager::new()?;
+    hm.name_image(image, name, aliases, os)
+}
+
+/// Set the libvirt secret for `usage` to `value`, base64 decoding it first
+/// if `base64`, defining the secret if needed. Returns its UUID for specs
+/// to refer to.
+pub fn set_secret(
+    usage: &Usage,
+    value: &[u8],
+    base64: bool,
+    private: bool,
+    description: Option<&str>,
+) -> Result<String, Error> {
+    let value = match base64 {
+        true => crate::guest_agent::decode_base64(&String::from_utf8_lossy(value))?,
+        false => value.to_vec(),
+    };
+    if value.is_empty() {
+        return Err(format!("no value given for secret {}", usage).into());
+    }
+
+    secrets::set(usage, &value, private, description)
+}
+
+pub fn list_secrets() -> Result<Vec<SecretInfo>, Error> {
+    secrets::list()
+}
+
+/// Undefine a libvirt secret, by UUID or usage
+pub fn remove_secret(id: &str) -> Result<(), Error> {
+    secrets::remove(id)
+}
+
+/// List address pools with how many of their addresses are handed out
+pub fn list_pools() -> Result<Vec<PoolUsage>, Error> {
+    HostManager::new()?.list_pools()
+}
+
+/// Remove an address pool none of whose addresses are in use
+pub fn remove_pool(name: &str) -> Result<(), Error> {
+    HostManager::new()?.remove_pool(name)
+}
+
+/// Grow a machine's disk, `size` is a size string such as "40Gi"
+pub fn resize_disk(id: &str, target: &str, size: &str) -> Result<(), Error> {
+    let size = models::to_size(size)?;
+
+    let mut hm = HostManager::new()?;
+    hm.resize_disk(id, target, size)
+}
+
+/// Stream lifecycle events of managed machines, or only of `ids` if given,
+/// until `on_event` returns false
+pub fn watch<F>(ids: &[String], mut on_event: F) -> Result<(), Error>
+where
+    F: FnMut(LifecycleEvent) -> bool,
+{
+    let hm = HostManager::new()?;
+    hm.watch(ids, &mut on_event)
+}
+
+/// Pass the serial console log of machine `id` to `on_data`, and with
+/// `follow` what is logged next, until `on_data` returns false
+pub fn console_log<F>(id: &str, follow: bool, mut on_data: F) -> Result<(), Error>
+where
+    F: FnMut(&[u8]) -> bool,
+{
+    let hm = HostManager::new()?;
+    hm.console_log(id, follow, &mut on_data)
+}
+
+/// Serve Prometheus metrics for this host on `listen`, e.g.
+/// "127.0.0.1:9180", and the machine endpoints `schedule` and `--host` use
+/// if `machine_api`, until the process is stopped. With `tls` only clients
+/// with a certificate the host config's `tls` section allows are answered,
+/// and guests' phone_home posts only on `ready_listen` if given.
+/// Machines' health checks and restart policies are run alongside.
+pub fn serve(
+    listen: &str,
+    machine_api: bool,
+    tls: bool,
+    ready_listen: Option<&str>,
+) -> Result<(), Error> {
+    let tls = match tls {
+        true => {
+            let config = HostConfig::load()?;
+            let tls = config
+                .tls
+                .ok_or("--tls needs a tls section in the host config")?;
+            Some(TlsServer::new(&tls)?)
+        }
+        false => None,
+    };
+
+    std::thread::spawn(supervise);
+    std::thread::spawn(record_stops);
+    if let Some(ready_listen) = ready_listen {
+        let ready_listen = ready_listen.to_string();
+        std::thread::spawn(move || {
+            let served = HostManager::new()
+                .and_then(|hm| metrics::serve_ready(&ready_listen, &mut Served(hm)));
+            if let Err(e) = served {
+                warn!("serving phone_home on {} failed: {}", ready_listen, e);
+            }
+        });
+    }
+
+    let mut served = Served(HostManager::new()?);
+    metrics::serve(listen, machine_api, tls.as_ref(), &mut served)
+}
+
+// how often `serve` looks for health checks and restarts that are due
+const SUPERVISE_TICK: Duration = Duration::from_secs(5);
+
+// run machines' health checks and restarts as they come due, for as long
+// as `serve` runs
+fn supervise() {
+    loop {
+        if let Err(e) = HostManager::new().and_then(|mut hm| hm.check_health()) {
+            warn!("health checks failed: {}", e);
+        }
+        if let Err(e) = HostManager::new().and_then(|mut hm| hm.restart_stopped()) {
+            warn!("restarting stopped machines failed: {}", e);
+        }
+        std::thread::sleep(SUPERVISE_TICK);
+    }
+}
+
+// mark machines that stop for their restartPolicy, watching again after
+// losing the connection to the hypervisor
+fn record_stops() {
+    loop {
+        let watched = HostManager::new().and_then(|watcher| {
+            let mut hm = HostManager::new()?;
+            watcher.watch(&[], &mut |event| {
+                if let Err(e) = hm.record_stop(&event) {
+                    warn!("{} {}: {}", event.machine, event.event, e);
+                }
+                true
+            })
+        });
+        if let Err(e) = watched {
+            warn!("watching for stopped machines failed: {}", e);
+        }
+        std::thread::sleep(SUPERVISE_TICK);
+    }
+}
+
+/// Serve the HostManager gRPC service of `proto/bigiron_virt.proto` on
+/// `listen` until the process is stopped
+#[cfg(feature = "grpc")]
+pub fn serve_grpc(listen: &str) -> Result<(), Error> {
+    crate::grpc::serve(listen)
+}
+
+/// Connect to the host serving `serve --api` at `endpoint`, e.g.
+/// https://kvm01:8700, with the certificates in this host's config
+pub fn connect(endpoint: &str) -> Result<Client, Error> {
+    let config = HostConfig::load()?;
+    Client::new(endpoint, config.tls.as_ref())
+}
+
+struct Served(HostManager);
+
+impl metrics::Handler for Served {
+    fn metrics(&mut self) -> Result<Metrics, Error> {
+        self.0.metrics()
+    }
+
+    fn machines(&mut self) -> Result<Vec<Machine>, Error> {
+        self.0.machines()
+    }
+
+    fn list(&mut self) -> Result<Vec<MachineStatus>, Error> {
+        self.0.list_machines(None)
+    }
+
+    fn show(&mut self, id: &str) -> Result<MachineInfo, Error> {
+        self.0.machine_info(id)
+    }
+
+    fn destroy(&mut self, id: &str) -> Result<(), Error> {
+        self.0.destroy_machine(id)
+    }
+
+    fn create(&mut self, yaml: &str) -> Result<(), Error> {
+        create_sent(&mut self.0, yaml)
+    }
+
+    fn ready(&mut self, id: &str, instance_id: &str) -> Result<(), Error> {
+        self.0.mark_ready(id, instance_id)
+    }
+}
+
+/// The machines in model file `path` with this host's defaults filled in,
+/// checked and with `userdataFile` resolved, ready to send to another host
+/// in the order of their `dependsOn`
+pub fn machines_from_file(path: &Path) -> Result<Vec<Machine>, Error> {
+    let yaml = std::fs::read_to_string(path)
+        .map_err(|e| format!("error reading model file {:?}: {}", path, e))?;
+
+    let defaults = HostConfig::load()?.defaults;
+    let mut machines = Vec::new();
+    for res in resources_from_yaml(&yaml)? {
+        let Resource::Machine(mut m) = res;
+        m.apply_defaults(&defaults);
+        m.validate()?;
+        m.resolve_files(&model_dir(path))?;
+        machines.push(m);
+    }
+
+    models::dependency_order(machines)
+}
+
+/// Place the machines in model files `paths` on the hosts in `inventory`
+/// by their scheduling rules and create them there, unless `dry_run`,
+/// returning the host each machine was put on
+pub fn schedule(
+    inventory: &Path,
+    paths: &[PathBuf],
+    dry_run: bool,
+) -> Result<Vec<(String, String)>, Error> {
+    let inventory = Inventory::load(inventory)?;
+    let tls = HostConfig::load()?.tls;
+
+    let mut machines = Vec::new();
+    for path in paths {
+        machines.extend(machines_from_file(path)?);
+    }
+
+    let mut scheduler = Scheduler::connect(&inventory, tls.as_ref())?;
+    let mut placed = Vec::new();
+    for m in machines {
+        let host = scheduler.place(&m)?.clone();
+        if !dry_run {
+            Client::new(&host.endpoint, tls.as_ref())
+                .and_then(|c| c.create(&m))
+                .map_err(|e| {
+                    format!("error creating {} on {}: {}", m.metadata.name, host.name, e)
+                })?;
+        }
+        placed.push((m.metadata.name, host.name));
+    }
+
+    Ok(placed)
+}
+
+/// Hot plug a file or block device into a running machine, returning the
+/// disk target it was given
+pub fn attach_disk(id: &str, path: &Path) -> Result<String, Error> {
+    let mut hm = HostManager::new()?;
+    hm.attach_disk(id, path)
+}
+
 #[cfg(test)]
 mod test {
 
@@ -97,6 +951,8 @@ mod test {
 
         assert!(rs.len() == 2);
 
+        assert_eq!(machine_ids_from_yaml(&inp).unwrap(), vec!["vm1", "vm2"]);
+
         for r in rs {
             match r {
                 models::Resource::Machine(m) => {
@@ -110,4 +966,178 @@ mod test {
             }
         }
     }
+
+    #[test]
+    fn machine_classes() {
+        let class = "kind: MachineClass
+metadata:
+  name: small-ubuntu
+spec:
+  cpu: 2
+  memory: 2Gi
+  image:
+    url: file:///jammy.qcow2
+    hash: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa
+    resize: 20Gi
+  nics:
+    - kind: Bridge
+      parent: br0
+      address:
+        kind: IPv6SLAAC
+";
+        let machines = "kind: Machine
+metadata:
+  name: vm1
+spec:
+  class: small-ubuntu
+  memory: 4Gi
+  image:
+    resize: 40Gi
+---
+kind: Machine
+metadata:
+  name: vm2
+spec:
+  class: small-ubuntu
+  nics: []
+";
+
+        let rs = resources_from_yaml(&format!("{}---\n{}", class, machines)).unwrap();
+        let ms: Vec<Machine> = rs
+            .into_iter()
+            .map(|r| match r {
+                Resource::Machine(m) => m,
+            })
+            .collect();
+        assert_eq!(ms.len(), 2);
+
+        // maps merged, everything else replaced
+        assert_eq!(ms[0].spec.cpu, 2);
+        assert_eq!(ms[0].spec.memory.to_string(), "4 GiB");
+        assert_eq!(ms[0].spec.image.url, "file:///jammy.qcow2");
+        assert_eq!(ms[0].spec.image.resize.unwrap().to_string(), "40 GiB");
+        assert_eq!(ms[0].spec.nics.as_ref().unwrap().len(), 1);
+        assert_eq!(ms[0].spec.class.as_deref(), Some("small-ubuntu"));
+        assert_eq!(ms[1].spec.nics, Some(Vec::new()));
+        assert!(ms.iter().all(|m| m.validate().is_ok()));
+
+        // from elsewhere, e.g. another model file
+        let classes = classes_from_yaml(class).unwrap();
+        assert_eq!(resources_with_classes(machines, &classes).unwrap().len(), 2);
+
+        let err = resources_from_yaml(machines).unwrap_err();
+        assert_eq!(err.to_string(), "unknown machine class small-ubuntu");
+        let err = create_resources(machines, Path::new(".")).unwrap_err();
+        assert_eq!(err.to_string(), "unknown machine class small-ubuntu");
+        let err = classes_from_yaml(&format!("{}---\n{}", class, class)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "machine class small-ubuntu is declared twice"
+        );
+    }
+
+    #[test]
+    fn address_pools() {
+        let yaml = "kind: AddressPool
+metadata:
+  name: lab
+spec:
+  cidr: 192.168.50.0/24
+  gateway: 192.168.50.1
+  range:
+    start: 192.168.50.100
+    end: 192.168.50.199
+  reservations:
+    - 192.168.50.150
+---
+kind: Machine
+metadata:
+  name: vm1
+spec:
+  cpu: 1
+  memory: 1Gi
+  image:
+    url: file:///jammy.qcow2
+    hash: abc1234
+  nics:
+    - kind: Bridge
+      parent: br0
+      address:
+        kind: Pool
+        pool: lab
+";
+
+        let pools = pools_from_yaml(yaml).unwrap();
+        assert_eq!(pools.len(), 1);
+        assert_eq!(pools[0].metadata.name, "lab");
+        assert_eq!(pools[0].spec.range.as_ref().unwrap().end, "192.168.50.199");
+        assert_eq!(pools[0].spec.reservations, ["192.168.50.150"]);
+
+        assert_eq!(machine_ids_from_yaml(yaml).unwrap(), ["vm1"]);
+    }
+
+    #[test]
+    fn sent_models() {
+        let yaml = "kind: Machine
+metadata:
+  name: vm1
+spec:
+  cpu: 1
+  memory: 1Gi
+  image:
+    url: file:///jammy.qcow2
+    hash: aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa
+";
+        let defaults = SpecDefaults::default();
+        let (_, machines) = sent_resources(yaml, &defaults).unwrap();
+        assert_eq!(machines.len(), 1);
+
+        let secret = format!("{}  secrets:\n    token: env:VAULT_TOKEN\n", yaml);
+        let err = sent_resources(&secret, &defaults).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "machine vm1: a secret refers to this host and can't be sent"
+        );
+
+        let bridge = "kind: Bridge\nmetadata:\n  name: br0\nspec:\n  uplink: eth1\n";
+        assert!(sent_resources(&format!("{}---\n{}", bridge, yaml), &defaults).is_err());
+    }
+
+    #[test]
+    fn host_bridges() {
+        let yaml = "kind: Bridge
+metadata:
+  name: br0
+spec:
+  uplink: eth1
+  address: 192.168.50.1/24
+---
+kind: Bridge
+metadata:
+  name: br1
+---
+kind: Machine
+metadata:
+  name: vm1
+spec:
+  cpu: 1
+  memory: 1Gi
+  image:
+    url: file:///jammy.qcow2
+    hash: abc1234
+  nics:
+    - kind: Bridge
+      parent: br0
+      address:
+        kind: IPv6SLAAC
+";
+
+        let bridges = bridges_from_yaml(yaml).unwrap();
+        assert_eq!(bridges.len(), 2);
+        assert_eq!(bridges[0].spec.uplink.as_deref(), Some("eth1"));
+        assert_eq!(bridges[0].spec.address.as_deref(), Some("192.168.50.1/24"));
+        assert_eq!(bridges[1].spec, Default::default());
+
+        assert_eq!(machine_ids_from_yaml(yaml).unwrap(), ["vm1"]);
+    }
 }
@@ -15,12 +15,17 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
-use std::path::PathBuf;
+use std::collections::HashMap as Map;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 use serde_yaml;
+use url::Url;
 
-use crate::error::Error;
+use crate::cloudconfig::CloudConfig;
+use crate::config::SpecDefaults;
+use crate::error::{self, Error};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(tag = "kind")]
@@ -28,6 +33,90 @@ pub enum Resource {
     Machine(Machine),
 }
 
+/// Reusable spec fields, declared as `kind: MachineClass`, that a machine
+/// starts from by naming the class in `spec.class`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct MachineClass {
+    pub metadata: Metadata,
+    // a partial spec, only checked once merged into a machine's
+    pub spec: serde_yaml::Mapping,
+}
+
+impl MachineClass {
+    /// Fill in what machine spec `spec` leaves out from the class. Maps
+    /// are merged key by key, anything else the spec gives, lists
+    /// included, replaces the class's whole.
+    pub fn apply(&self, spec: &mut serde_yaml::Mapping) {
+        fill(spec, &self.spec);
+    }
+}
+
+/// IPv4 addresses handed out to nics with `address: {kind: Pool, pool:
+/// <name>}`, declared as `kind: AddressPool`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AddressPool {
+    pub metadata: Metadata,
+    pub spec: AddressPoolSpec,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AddressPoolSpec {
+    // the subnet, e.g. 192.168.50.0/24
+    pub cidr: String,
+    pub gateway: String,
+
+    // first and last address handed out, else any in the subnet
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub range: Option<AddressRange>,
+
+    // addresses never handed out, e.g. of hosts outside bigiron-virt
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reservations: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub nameservers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct AddressRange {
+    pub start: String,
+    pub end: String,
+}
+
+/// A Linux bridge on the host for `Bridge` nics to join, declared as
+/// `kind: Bridge` and set up before the machines in the same model
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HostBridge {
+    pub metadata: Metadata,
+    #[serde(default)]
+    pub spec: HostBridgeSpec,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct HostBridgeSpec {
+    // interface enslaved to the bridge, e.g. eth0
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uplink: Option<String>,
+
+    // address of the host on the bridge, e.g. 192.168.50.1/24
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+}
+
+fn fill(own: &mut serde_yaml::Mapping, base: &serde_yaml::Mapping) {
+    use serde_yaml::Value;
+
+    for (key, value) in base {
+        match (own.get_mut(key), value) {
+            (None | Some(Value::Null), _) => {
+                own.insert(key.clone(), value.clone());
+            }
+            (Some(Value::Mapping(own)), Value::Mapping(base)) => fill(own, base),
+            _ => {}
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Machine {
     pub metadata: Metadata,
@@ -38,6 +127,52 @@ pub struct Machine {
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Metadata {
     pub name: String,
+    pub labels: Option<Map<String, String>>,
+
+    // assigned on create if not given, stays with the machine through
+    // export and backup
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+
+    // how long after creation `gc` destroys the machine
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<Ttl>,
+}
+
+/// Label requirements such as `env=test,team=infra`, all of which must hold
+#[derive(Debug, Clone, PartialEq)]
+pub struct Selector {
+    requirements: Vec<(String, String)>,
+}
+
+impl Selector {
+    pub fn matches(&self, metadata: &Metadata) -> bool {
+        self.requirements
+            .iter()
+            .all(|(k, v)| metadata.labels.as_ref().and_then(|l| l.get(k)) == Some(v))
+    }
+}
+
+impl std::str::FromStr for Selector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut requirements = Vec::new();
+
+        for req in s.split(',') {
+            let (k, v) = req
+                .split_once('=')
+                .ok_or_else(|| format!("invalid selector '{}', expected key=value", req))?;
+
+            if k.trim().is_empty() {
+                return Err(format!("invalid selector '{}', empty key", req).into());
+            }
+
+            requirements.push((k.trim().to_string(), v.trim().to_string()));
+        }
+
+        Ok(Self { requirements })
+    }
 }
 
 impl Machine {
@@ -45,78 +180,2618 @@ impl Machine {
         let buf = serde_yaml::to_string(self)?;
         return Ok(buf);
     }
+
+    /// Start building a machine in code rather than from a model file
+    pub fn builder() -> MachineBuilder {
+        MachineBuilder::default()
+    }
+
+    /// Replace `spec.userdataFile` with the contents of the files and make
+    /// cdrom paths absolute, relative paths being taken from `base_dir`, so
+    /// the stored spec doesn't depend on where it was read from
+    pub fn resolve_files(&mut self, base_dir: &Path) -> Result<(), Error> {
+        if let Some(file) = self.spec.userdata_file.take() {
+            self.spec.userdata = Some(crate::userdata::load(file.paths(), base_dir)?);
+        }
+        for cdrom in self.spec.cdroms.iter_mut().flatten() {
+            if let Some(path) = cdrom.path.as_mut().filter(|p| p.is_relative()) {
+                *path = base_dir.join(&*path);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in what the spec leaves out from a host's `defaults`
+    pub fn apply_defaults(&mut self, defaults: &SpecDefaults) {
+        let spec = &mut self.spec;
+
+        if spec.cpu == 0 {
+            spec.cpu = defaults.cpu.unwrap_or_default();
+        }
+        if spec.memory.bytes() == 0 {
+            spec.memory = defaults.memory.unwrap_or_default();
+        }
+
+        if let (true, Some(default)) = (spec.image.is_unset(), &defaults.image) {
+            let mut image = default.clone();
+            image.resize = spec.image.resize.or(image.resize);
+            image.mode = spec.image.mode.or(image.mode);
+            image.encryption = spec.image.encryption.clone().or(image.encryption);
+            if spec.image.driver != DiskDriver::default() {
+                image.driver = spec.image.driver.clone();
+            }
+            spec.image = image;
+        }
+
+        if spec.nics.is_none() {
+            spec.nics = defaults.nics.clone();
+        }
+
+        let has_userdata =
+            spec.userdata.is_some() || spec.userdata_file.is_some() || spec.cloud_config.is_some();
+        if !has_userdata {
+            spec.userdata = defaults.userdata.clone();
+        }
+    }
+
+    /// Check the spec for mistakes that would otherwise only show up part
+    /// way through creating the machine, reporting all of them at once
+    pub fn validate(&self) -> Result<(), Error> {
+        let problems = self.problems();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(error::invalid(format!(
+                "invalid machine: {}",
+                problems.join("; ")
+            )))
+        }
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let spec = &self.spec;
+
+        let name = &self.metadata.name;
+        if name.is_empty() {
+            problems.push(String::from("name is required"));
+        } else if name.contains('/') || name == "." || name == ".." {
+            problems.push(format!("invalid name '{}'", name));
+        }
+
+        if let Some(ref uuid) = self.metadata.uuid {
+            let smbios_uuid = spec.smbios.as_ref().and_then(|s| s.uuid.as_ref());
+            match uuid::Uuid::parse_str(uuid) {
+                Err(_) => problems.push(format!("uuid '{}' is not a UUID", uuid)),
+                Ok(u) if smbios_uuid.is_some_and(|s| uuid::Uuid::parse_str(s) != Ok(u)) => {
+                    problems.push(String::from("uuid and smbios uuid must be the same"))
+                }
+                Ok(_) => {}
+            }
+        }
+
+        if spec.cpu == 0 {
+            problems.push(String::from("cpu must be at least 1"));
+        }
+
+        if spec.memory.bytes() == 0 {
+            problems.push(String::from("memory must be more than 0"));
+        }
+
+        let image = &spec.image;
+        // a name alone is resolved from the image repo
+        if image.is_unset() {
+            problems.push(String::from("image is required"));
+        } else if image.name.is_none() || !image.url.is_empty() || !image.hash.is_empty() {
+            if Url::parse(&image.url).is_err() {
+                problems.push(format!("invalid image url '{}'", image.url));
+            }
+
+            let hash = &image.hash;
+            if hash.len() != 64 || !hash.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+                problems.push(format!("image hash '{}' is not a sha256 hex digest", hash));
+            }
+        }
+        if image.name.as_deref() == Some("") {
+            problems.push(String::from("image name can't be empty"));
+        }
+        if let Some(ref encryption) = image.encryption {
+            match (&encryption.secret, &encryption.passphrase_file) {
+                (Some(_), Some(_)) => problems.push(String::from(
+                    "image encryption takes either secret or passphraseFile, not both",
+                )),
+                (None, None) => problems.push(String::from(
+                    "image encryption needs a secret or passphraseFile",
+                )),
+                _ => {}
+            }
+        }
+
+        if let Some(ref file) = spec.userdata_file {
+            if spec.userdata.is_some() {
+                problems.push(String::from(
+                    "userdata and userdataFile can't be used together",
+                ));
+            }
+            if file.paths().is_empty() {
+                problems.push(String::from("userdataFile needs at least one file"));
+            }
+        }
+
+        if let Some(ref cc) = spec.cloud_config {
+            problems.extend(cc.problems());
+            if spec
+                .userdata
+                .as_ref()
+                .is_some_and(|u| !u.starts_with("#cloud-config"))
+            {
+                problems.push(String::from(
+                    "cloudConfig can only be combined with #cloud-config userdata",
+                ));
+            }
+        }
+
+        let mut nic_names = Vec::new();
+        for nic in spec.nics.iter().flatten() {
+            if !["Bridge", "Macvtap", "Network"].contains(&nic.kind.as_str()) {
+                problems.push(format!("unknown nic kind '{}'", nic.kind));
+            }
+
+            if nic.trust_guest_rx_filters == Some(true) && nic.kind != "Macvtap" {
+                problems.push(String::from(
+                    "trustGuestRxFilters is only supported on Macvtap nics",
+                ));
+            }
+
+            if let AddressKind::Pool(ref p) = nic.address {
+                if p.pool.is_empty() {
+                    problems.push(String::from("nic address pool needs a name"));
+                }
+            }
+
+            if let Some(ref mac) = nic.mac {
+                if mac.parse::<crate::mac::Mac>().is_err() {
+                    problems.push(format!("invalid nic mac '{}'", mac));
+                }
+            }
+
+            if nic.isolated == Some(true) && nic.kind != "Bridge" {
+                problems.push(String::from("isolated is only supported on Bridge nics"));
+            }
+
+            if let Some(ref bw) = nic.bandwidth {
+                let limits = [("inbound", &bw.inbound), ("outbound", &bw.outbound)];
+                for (direction, limit) in limits {
+                    let Some(limit) = limit else { continue };
+
+                    if limit.average == 0 {
+                        problems.push(format!(
+                            "nic {} bandwidth needs a non-zero average",
+                            direction
+                        ));
+                    } else if limit.peak.is_some_and(|p| p < limit.average) {
+                        problems.push(format!(
+                            "nic {} bandwidth peak is below its average",
+                            direction
+                        ));
+                    }
+                }
+            }
+
+            if nic.filterref.as_ref().is_some_and(|f| f.filter.is_empty()) {
+                problems.push(String::from("nic filterref requires a filter name"));
+            }
+            // libvirt silently ignores filters on direct interfaces
+            if nic.filterref.is_some() && nic.kind == "Macvtap" {
+                problems.push(String::from("filterref isn't supported on Macvtap nics"));
+            }
+
+            if let Some(ref name) = nic.name {
+                // what the kernel accepts as an interface name
+                let valid = !name.is_empty()
+                    && name.len() < 16
+                    && name != "."
+                    && name != ".."
+                    && !name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace());
+                if !valid {
+                    problems.push(format!("invalid nic name '{}'", name));
+                } else if nic_names.contains(&name) {
+                    problems.push(format!("duplicate nic name '{}'", name));
+                }
+                nic_names.push(name);
+            }
+        }
+
+        let disks = spec.storage.as_ref().map_or(0, |s| s.len());
+        if let Some(last) = disks.checked_sub(1) {
+            if let Err(e) = spec.storage_bus.unwrap_or_default().target(last) {
+                problems.push(e.to_string());
+            }
+        }
+
+        if let Some(ref cpu) = spec.cpu_model {
+            match (cpu.mode, &cpu.model) {
+                (CpuMode::Custom, None) => {
+                    problems.push(String::from("cpuModel mode custom requires a model"))
+                }
+                (CpuMode::HostPassthrough | CpuMode::HostModel, Some(_)) => {
+                    problems.push(String::from("cpuModel model is only used in custom mode"))
+                }
+                _ => {}
+            }
+
+            for feature in cpu.add.iter().filter(|f| cpu.remove.contains(f)) {
+                problems.push(format!(
+                    "cpu feature '{}' is both added and removed",
+                    feature
+                ));
+            }
+
+            if cpu.add.iter().chain(&cpu.remove).any(|f| f.is_empty()) {
+                problems.push(String::from("cpu feature names can't be empty"));
+            }
+        }
+
+        if let Some(ref cells) = spec.numa {
+            let cpus: u32 = cells.iter().map(|c| c.cpus).sum();
+            if cells.iter().any(|c| c.cpus == 0) {
+                problems.push(String::from("numa cells need at least 1 cpu"));
+            } else if cpus != spec.cpu {
+                problems.push(format!(
+                    "numa cells have {} cpus in total, spec has {}",
+                    cpus, spec.cpu
+                ));
+            }
+
+            let memory = Size(cells.iter().map(|c| c.memory.bytes()).sum());
+            if memory != spec.memory {
+                problems.push(format!(
+                    "numa cells have {} memory in total, spec has {}",
+                    memory, spec.memory
+                ));
+            }
+        }
+
+        if let Some(ref placement) = spec.placement {
+            problems.extend(placement.problems(spec.cpu));
+        }
+
+        if let Some(ref boot) = spec.direct_boot {
+            problems.extend(boot.problems());
+        }
+
+        if let Some(ref check) = spec.health_check {
+            problems.extend(check.problems());
+        }
+
+        for dep in spec.depends_on.iter().flatten() {
+            if dep.name().is_empty() {
+                problems.push(String::from("dependsOn names must not be empty"));
+            } else if dep.name() == name {
+                problems.push(format!("'{}' can't depend on itself", name));
+            }
+        }
+
+        if spec.nested_virt == Some(true) {
+            let removed = spec.cpu_model.iter().flat_map(|c| &c.remove);
+            for feature in removed.filter(|f| *f == "vmx" || *f == "svm") {
+                problems.push(format!(
+                    "nestedVirt needs cpu feature '{}', which cpuModel removes",
+                    feature
+                ));
+            }
+        }
+
+        if let Some(ref seclabel) = spec.seclabel {
+            let kind = seclabel.kind.unwrap_or_default();
+            match kind {
+                SecLabelKind::Static if seclabel.label.is_none() => {
+                    problems.push(String::from("seclabel type static requires a label"))
+                }
+                SecLabelKind::Dynamic if seclabel.label.is_some() => {
+                    problems.push(String::from("seclabel label is only used with type static"))
+                }
+                SecLabelKind::Dynamic if seclabel.relabel == Some(false) => {
+                    problems.push(String::from("seclabel type dynamic always relabels"))
+                }
+                SecLabelKind::None if seclabel.label.is_some() || seclabel.relabel.is_some() => {
+                    problems.push(String::from("seclabel type none takes no label or relabel"))
+                }
+                _ => {}
+            }
+        }
+
+        for storage in spec.storage.iter().flatten() {
+            if let StorageKind::Ephemeral(ref scratch) = storage {
+                if scratch.size.bytes() == 0 {
+                    problems.push(String::from("ephemeral disk size must be more than 0"));
+                }
+            }
+        }
+
+        let drivers = std::iter::once(&spec.image.driver)
+            .chain(spec.storage.iter().flatten().map(StorageKind::driver));
+        for seclabel in drivers.clone().filter_map(|d| d.seclabel.as_ref()) {
+            if seclabel.kind.is_some() {
+                problems.push(String::from("disk seclabel can't have a type"));
+            }
+            if seclabel.label.is_some() && seclabel.relabel == Some(false) {
+                problems.push(String::from(
+                    "disk seclabel with a label must allow relabel",
+                ));
+            }
+        }
+        for iotune in drivers.filter_map(|d| d.iotune.as_ref()) {
+            if iotune.total_bytes_sec.is_some()
+                && (iotune.read_bytes_sec.is_some() || iotune.write_bytes_sec.is_some())
+            {
+                problems.push(String::from(
+                    "iotune totalBytesSec can't be combined with readBytesSec or writeBytesSec",
+                ));
+            }
+
+            if iotune.total_iops_sec.is_some()
+                && (iotune.read_iops_sec.is_some() || iotune.write_iops_sec.is_some())
+            {
+                problems.push(String::from(
+                    "iotune totalIopsSec can't be combined with readIopsSec or writeIopsSec",
+                ));
+            }
+        }
+
+        if let Some(uuid) = spec.smbios.as_ref().and_then(|s| s.uuid.as_ref()) {
+            if uuid::Uuid::parse_str(uuid).is_err() {
+                problems.push(format!("smbios uuid '{}' is not a UUID", uuid));
+            }
+        }
+
+        for cdrom in spec.cdroms.iter().flatten() {
+            if cdrom.url.is_some() == cdrom.path.is_some() {
+                problems.push(String::from(
+                    "cdrom requires exactly one of 'url' or 'path'",
+                ));
+            }
+        }
+
+        for usb in spec.usb_devices.iter().flatten() {
+            problems.extend(usb.problems());
+        }
+        for mdev in spec.mdev.iter().flatten() {
+            problems.extend(mdev.problems());
+        }
+        if let Some(ref scheduling) = spec.scheduling {
+            problems.extend(scheduling.problems());
+        }
+
+        if let Some(ref forwards) = spec.port_forwards {
+            if !spec.nics.iter().flatten().any(|n| n.kind == "Network") {
+                problems.push(String::from("portForwards need a Network nic"));
+            }
+
+            let mut seen = Vec::new();
+            for fwd in forwards {
+                if fwd.host_port == 0 || fwd.guest_port == 0 {
+                    problems.push(String::from("port forward ports can't be 0"));
+                }
+                let key = (fwd.host_port, fwd.protocol.unwrap_or_default());
+                if seen.contains(&key) {
+                    problems.push(format!("host port {}/{} is forwarded twice", key.0, key.1));
+                }
+                seen.push(key);
+            }
+        }
+
+        if spec.usb_controller == Some(UsbController::None)
+            && spec.usb_devices.as_ref().is_some_and(|d| !d.is_empty())
+        {
+            problems.push(String::from(
+                "usbDevices need a usbController other than none",
+            ));
+        }
+
+        // domains are x86_64 pc machines, which Secure Execution can't run
+        if spec.confidential == Some(Confidential::S390PV) {
+            problems.push(String::from(
+                "confidential S390PV needs an s390x domain, machines here are x86_64",
+            ));
+        }
+
+        if let Some(ref xml) = spec.extra_domain_xml {
+            problems.extend(crate::domain_xml::extra_problems(xml));
+        }
+        if let Some(ref xml) = spec.domain_xml_override {
+            problems.extend(crate::domain_xml::override_problems(xml));
+        }
+
+        problems
+    }
+}
+
+/// Fluent construction of a `Machine` for programs embedding the crate,
+/// e.g. `Machine::builder().name("vm1").cpu(2).memory("4Gi")`. Nothing is
+/// checked until `build()`, which validates like a model file would be.
+#[derive(Debug, Clone, Default)]
+pub struct MachineBuilder {
+    name: String,
+    labels: Map<String, String>,
+    uuid: Option<String>,
+    ttl: Option<Ttl>,
+    cpu: u32,
+    memory: Size,
+    image: Option<Image>,
+    storage: Vec<StorageKind>,
+    storage_bus: Option<DiskBus>,
+    nics: Vec<Nic>,
+    userdata: Option<String>,
+    userdata_files: Vec<PathBuf>,
+    cloud_config: Option<CloudConfig>,
+    secrets: Map<String, String>,
+    confidential: Option<Confidential>,
+    cpu_model: Option<CpuModel>,
+    numa: Vec<NumaCell>,
+    placement: Option<PlacementHints>,
+    nested_virt: Option<bool>,
+    direct_boot: Option<DirectBoot>,
+    config_drive_format: Option<ConfigDriveFormat>,
+    health_check: Option<HealthCheck>,
+    restart_policy: Option<RestartPolicy>,
+    depends_on: Option<Vec<Dependency>>,
+    mac_policy: Option<MacPolicy>,
+    smbios: Option<Smbios>,
+    seclabel: Option<SecLabel>,
+    input: Option<InputDevice>,
+    video: Option<VideoModel>,
+    sound: Option<SoundModel>,
+    usb_devices: Vec<UsbDevice>,
+    usb_controller: Option<UsbController>,
+    mdevs: Vec<Mdev>,
+    scheduling: Option<Scheduling>,
+    port_forwards: Vec<PortForward>,
+    boot_order: Option<Vec<BootDevice>>,
+    cdroms: Vec<Cdrom>,
+    iothreads: Option<u32>,
+    wait_for_storage: Option<u64>,
+    extra_domain_xml: Option<String>,
+    domain_xml_override: Option<String>,
+    // unparsable sizes, reported by build()
+    problems: Vec<String>,
+}
+
+impl MachineBuilder {
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn label(mut self, key: &str, value: &str) -> Self {
+        self.labels.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Have `gc` destroy the machine `ttl` (e.g. "4h") after it's created
+    pub fn ttl(mut self, ttl: &str) -> Self {
+        match ttl.parse() {
+            Ok(ttl) => self.ttl = Some(ttl),
+            Err(e) => self.problems.push(e.to_string()),
+        }
+        self
+    }
+
+    /// Use `uuid` rather than a generated one
+    pub fn uuid(mut self, uuid: &str) -> Self {
+        self.uuid = Some(uuid.to_string());
+        self
+    }
+
+    pub fn cpu(mut self, cpu: u32) -> Self {
+        self.cpu = cpu;
+        self
+    }
+
+    /// Memory as a size string, e.g. "8Gi"
+    pub fn memory(mut self, memory: &str) -> Self {
+        match memory.parse() {
+            Ok(size) => self.memory = size,
+            Err(e) => self.problems.push(format!("invalid memory size: {}", e)),
+        }
+        self
+    }
+
+    /// Base image and its sha256, replacing any image set before
+    pub fn image(mut self, url: &str, hash: &str) -> Self {
+        self.image = Some(Image {
+            url: url.to_string(),
+            hash: hash.to_string(),
+            name: None,
+            resize: None,
+            mode: None,
+            encryption: None,
+            driver: DiskDriver::default(),
+        });
+        self
+    }
+
+    /// Grow the instance disk to `size`, e.g. "40Gi". Call after `image`.
+    pub fn resize(mut self, size: &str) -> Self {
+        match (size.parse(), self.image.as_mut()) {
+            (Ok(size), Some(image)) => image.resize = Some(size),
+            (Err(e), _) => self.problems.push(format!("invalid image resize: {}", e)),
+            _ => {}
+        }
+        self
+    }
+
+    /// Copy the image in full rather than backing the instance disk with
+    /// it. Call after `image`.
+    pub fn flatten_image(mut self) -> Self {
+        if let Some(image) = self.image.as_mut() {
+            image.mode = Some(ImageMode::Flatten);
+        }
+        self
+    }
+
+    /// Encrypt the instance disk with LUKS. Call after `image`.
+    pub fn encrypt_image(mut self, encryption: Encryption) -> Self {
+        if let Some(image) = self.image.as_mut() {
+            image.encryption = Some(encryption);
+        }
+        self
+    }
+
+    pub fn storage(mut self, storage: StorageKind) -> Self {
+        self.storage.push(storage);
+        self
+    }
+
+    pub fn storage_bus(mut self, bus: DiskBus) -> Self {
+        self.storage_bus = Some(bus);
+        self
+    }
+
+    pub fn nic(mut self, nic: Nic) -> Self {
+        self.nics.push(nic);
+        self
+    }
+
+    pub fn userdata(mut self, userdata: &str) -> Self {
+        self.userdata = Some(userdata.to_string());
+        self
+    }
+
+    /// Take userdata from a file instead, relative to where the machine is
+    /// created from. Given more than once the cloud-configs are merged
+    pub fn userdata_file<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.userdata_files.push(path.into());
+        self
+    }
+
+    pub fn cloud_config(mut self, cloud_config: CloudConfig) -> Self {
+        self.cloud_config = Some(cloud_config);
+        self
+    }
+
+    /// Secret reference injected into userdata as `${secret:NAME}`
+    pub fn secret(mut self, name: &str, reference: &str) -> Self {
+        self.secrets.insert(name.to_string(), reference.to_string());
+        self
+    }
+
+    pub fn confidential(mut self, confidential: Confidential) -> Self {
+        self.confidential = Some(confidential);
+        self
+    }
+
+    pub fn cpu_model(mut self, cpu_model: CpuModel) -> Self {
+        self.cpu_model = Some(cpu_model);
+        self
+    }
+
+    /// Add a guest NUMA cell, cells together must add up to the machine's
+    /// cpus and memory
+    pub fn numa_cell(mut self, cpus: u32, memory: &str, host_node: Option<u32>) -> Self {
+        match memory.parse() {
+            Ok(memory) => self.numa.push(NumaCell {
+                cpus,
+                memory,
+                host_node,
+            }),
+            Err(e) => self
+                .problems
+                .push(format!("invalid numa cell memory: {}", e)),
+        }
+        self
+    }
+
+    pub fn placement(mut self, placement: PlacementHints) -> Self {
+        self.placement = Some(placement);
+        self
+    }
+
+    pub fn nested_virt(mut self) -> Self {
+        self.nested_virt = Some(true);
+        self
+    }
+
+    pub fn direct_boot(mut self, direct_boot: DirectBoot) -> Self {
+        self.direct_boot = Some(direct_boot);
+        self
+    }
+
+    pub fn config_drive_format(mut self, format: ConfigDriveFormat) -> Self {
+        self.config_drive_format = Some(format);
+        self
+    }
+
+    pub fn health_check(mut self, check: HealthCheck) -> Self {
+        self.health_check = Some(check);
+        self
+    }
+
+    pub fn restart_policy(mut self, policy: RestartPolicy) -> Self {
+        self.restart_policy = Some(policy);
+        self
+    }
+
+    pub fn depends_on(mut self, dependency: Dependency) -> Self {
+        self.depends_on
+            .get_or_insert_with(Vec::new)
+            .push(dependency);
+        self
+    }
+
+    pub fn mac_policy(mut self, policy: MacPolicy) -> Self {
+        self.mac_policy = Some(policy);
+        self
+    }
+
+    pub fn smbios(mut self, smbios: Smbios) -> Self {
+        self.smbios = Some(smbios);
+        self
+    }
+
+    pub fn seclabel(mut self, seclabel: SecLabel) -> Self {
+        self.seclabel = Some(seclabel);
+        self
+    }
+
+    pub fn input(mut self, input: InputDevice) -> Self {
+        self.input = Some(input);
+        self
+    }
+
+    pub fn video(mut self, video: VideoModel) -> Self {
+        self.video = Some(video);
+        self
+    }
+
+    pub fn sound(mut self, sound: SoundModel) -> Self {
+        self.sound = Some(sound);
+        self
+    }
+
+    pub fn usb_device(mut self, device: UsbDevice) -> Self {
+        self.usb_devices.push(device);
+        self
+    }
+
+    pub fn usb_controller(mut self, controller: UsbController) -> Self {
+        self.usb_controller = Some(controller);
+        self
+    }
+
+    pub fn mdev(mut self, mdev: Mdev) -> Self {
+        self.mdevs.push(mdev);
+        self
+    }
+
+    pub fn scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.scheduling = Some(scheduling);
+        self
+    }
+
+    /// Forward TCP `host_port` on the host to `guest_port` on the
+    /// machine's Network nic
+    pub fn port_forward(mut self, host_port: u16, guest_port: u16) -> Self {
+        self.port_forwards.push(PortForward {
+            host_port,
+            guest_port,
+            protocol: None,
+        });
+        self
+    }
+
+    pub fn boot_order(mut self, devices: &[BootDevice]) -> Self {
+        self.boot_order = Some(devices.to_vec());
+        self
+    }
+
+    pub fn cdrom(mut self, cdrom: Cdrom) -> Self {
+        self.cdroms.push(cdrom);
+        self
+    }
+
+    pub fn iothreads(mut self, count: u32) -> Self {
+        self.iothreads = Some(count);
+        self
+    }
+
+    pub fn wait_for_storage(mut self, secs: u64) -> Self {
+        self.wait_for_storage = Some(secs);
+        self
+    }
+
+    /// Add raw libvirt device XML, e.g. `<watchdog model='i6300esb'/>`,
+    /// at the end of the domain's devices
+    pub fn extra_domain_xml(mut self, xml: &str) -> Self {
+        self.extra_domain_xml = Some(xml.to_string());
+        self
+    }
+
+    /// Replace generated children of the domain XML with those of `<domain>`
+    /// element `xml`
+    pub fn domain_xml_override(mut self, xml: &str) -> Self {
+        self.domain_xml_override = Some(xml.to_string());
+        self
+    }
+
+    pub fn build(self) -> Result<Machine, Error> {
+        let image = self.image.ok_or("invalid machine: image is required")?;
+
+        let machine = Machine {
+            metadata: Metadata {
+                name: self.name,
+                labels: non_empty_map(self.labels),
+                uuid: self.uuid,
+                ttl: self.ttl,
+            },
+            status: None,
+            spec: Spec {
+                cpu: self.cpu,
+                memory: self.memory,
+                image,
+                storage: non_empty(self.storage),
+                nics: non_empty(self.nics),
+                userdata: self.userdata,
+                userdata_file: UserdataFile::from_paths(self.userdata_files),
+                cloud_config: self.cloud_config,
+                secrets: non_empty_map(self.secrets),
+                confidential: self.confidential,
+                boot_order: self.boot_order,
+                cdroms: non_empty(self.cdroms),
+                iothreads: self.iothreads,
+                wait_for_storage: self.wait_for_storage,
+                storage_bus: self.storage_bus,
+                cpu_model: self.cpu_model,
+                numa: non_empty(self.numa),
+                placement: self.placement,
+                nested_virt: self.nested_virt,
+                direct_boot: self.direct_boot,
+                config_drive_format: self.config_drive_format,
+                health_check: self.health_check,
+                restart_policy: self.restart_policy,
+                depends_on: self.depends_on,
+                mac_policy: self.mac_policy,
+                smbios: self.smbios,
+                seclabel: self.seclabel,
+                input: self.input,
+                video: self.video,
+                sound: self.sound,
+                usb_devices: non_empty(self.usb_devices),
+                usb_controller: self.usb_controller,
+                mdev: non_empty(self.mdevs),
+                scheduling: self.scheduling,
+                port_forwards: non_empty(self.port_forwards),
+                class: None,
+                extra_domain_xml: self.extra_domain_xml,
+                domain_xml_override: self.domain_xml_override,
+            },
+        };
+
+        let mut problems = self.problems;
+        problems.extend(machine.problems());
+        if !problems.is_empty() {
+            return Err(error::invalid(format!(
+                "invalid machine: {}",
+                problems.join("; ")
+            )));
+        }
+
+        Ok(machine)
+    }
+}
+
+// empty collections are left out, as they would be from a model file
+fn non_empty<T>(v: Vec<T>) -> Option<Vec<T>> {
+    if v.is_empty() {
+        None
+    } else {
+        Some(v)
+    }
+}
+
+fn non_empty_map(m: Map<String, String>) -> Option<Map<String, String>> {
+    if m.is_empty() {
+        None
+    } else {
+        Some(m)
+    }
 }
 
-pub type SizeString = String;
+/// `machines` reordered so each comes after those of them its `dependsOn`
+/// names, keeping the order they were given in otherwise
+pub fn dependency_order(mut machines: Vec<Machine>) -> Result<Vec<Machine>, Error> {
+    let mut ordered: Vec<Machine> = Vec::with_capacity(machines.len());
+
+    while !machines.is_empty() {
+        let waiting = |dep: &Dependency| {
+            machines.iter().any(|m| m.metadata.name == dep.name())
+                && !ordered.iter().any(|m| m.metadata.name == dep.name())
+        };
+        let next = machines
+            .iter()
+            .position(|m| !m.spec.depends_on.iter().flatten().any(waiting));
+
+        match next {
+            Some(i) => ordered.push(machines.remove(i)),
+            None => {
+                let names: Vec<&str> = machines.iter().map(|m| m.metadata.name.as_str()).collect();
+                return Err(error::invalid(format!(
+                    "dependsOn has a cycle among {}",
+                    names.join(", ")
+                )));
+            }
+        }
+    }
+
+    Ok(ordered)
+}
 
+/// Parse a size string like "512Mi" into bytes, see `Size`
 pub fn to_size(s: &str) -> Result<u64, Error> {
-    let mut last = &s[s.len() - 1..];
-    let nlast = &s[s.len() - 2..s.len() - 1];
-    let mut co: u64 = 1000;
-    let mut num = &s[..s.len() - 1];
+    Ok(s.parse::<Size>()?.bytes())
+}
 
-    if last == "i" {
-        // binary byte mode
-        co = 1024;
-        last = nlast;
-        num = &s[..s.len() - 2];
+const BINARY_UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+// scale a byte count to the largest binary unit it fills, e.g. 1.5 GiB
+fn human_bytes(bytes: f64) -> String {
+    let mut value = bytes;
+    let mut unit = 0;
+
+    while value >= 1024.0 && unit < BINARY_UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
     }
 
-    let exp = match last {
-        "T" | "t" => 3,
-        "G" | "g" => 3,
-        "M" | "m" => 2,
-        "K" | "k" => 1,
-        _ => 0,
-    };
+    if unit == 0 {
+        return format!("{} {}", value.round(), BINARY_UNITS[0]);
+    }
 
-    let scalar = num.parse::<u64>()?;
-    Ok(scalar * co.pow(exp))
+    let num = format!("{:.1}", value);
+    format!("{} {}", num.trim_end_matches(".0"), BINARY_UNITS[unit])
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct Spec {
-    pub cpu: u32,
-    pub memory: SizeString,
-    pub image: Image,
-    pub storage: Option<Vec<StorageKind>>,
-    pub nics: Option<Vec<Nic>>,
-    pub userdata: Option<String>,
+/// A byte count, displayed in human readable binary units (`1.5 GiB`).
+///
+/// Parses from size strings: a number, optionally with a decimal part,
+/// followed by a decimal (`K`, `M`, `G`, `T`, `P`) or binary (`Ki`, `Mi`,
+/// ...) suffix, optionally ending in `B`, e.g. `1.5Gi`, `512MiB`, `100G`
+/// or plain bytes (`1024`). In model files it's a size string or a number
+/// of bytes, and it serializes to the shortest exact size string.
+///
+/// The alternate form (`{:#}`) renders that exact size string.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Size(pub u64);
+
+impl Size {
+    pub fn bytes(&self) -> u64 {
+        self.0
+    }
+
+    /// Exact size string for this size, using whichever suffix that
+    /// divides it evenly gives the smallest number, e.g. "100G" or "1536Mi"
+    pub fn to_size_string(&self) -> String {
+        let suffixes = [
+            ("P", 1000u64.pow(5)),
+            ("Pi", 1024u64.pow(5)),
+            ("T", 1000u64.pow(4)),
+            ("Ti", 1024u64.pow(4)),
+            ("G", 1000u64.pow(3)),
+            ("Gi", 1024u64.pow(3)),
+            ("M", 1000u64.pow(2)),
+            ("Mi", 1024u64.pow(2)),
+            ("K", 1000),
+            ("Ki", 1024),
+        ];
+
+        suffixes
+            .iter()
+            .filter(|(_, unit)| self.0 >= *unit && self.0 % unit == 0)
+            .min_by_key(|(_, unit)| self.0 / unit)
+            .map(|(suffix, unit)| format!("{}{}", self.0 / unit, suffix))
+            .unwrap_or_else(|| format!("{}B", self.0))
+    }
+}
+
+impl std::fmt::Display for Size {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if f.alternate() {
+            return write!(f, "{}", self.to_size_string());
+        }
+
+        write!(f, "{}", human_bytes(self.0 as f64))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseSizeError {
+    pub input: String,
+    pub reason: &'static str,
+}
+
+impl std::fmt::Display for ParseSizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "invalid size '{}': {}", self.input, self.reason)
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct Image {
-    pub url: String,
-    pub hash: String,
-    pub resize: Option<SizeString>,
+impl std::error::Error for ParseSizeError {}
+
+impl std::str::FromStr for Size {
+    type Err = ParseSizeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason| ParseSizeError {
+            input: s.to_string(),
+            reason,
+        };
+
+        let trimmed = s.trim();
+        let split = trimmed
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(trimmed.len());
+        let (num, suffix) = trimmed.split_at(split);
+
+        let multiplier = match suffix.trim_start() {
+            "" | "B" => 1,
+            suffix => {
+                let (unit, rest) = suffix.split_at(suffix.chars().next().map_or(0, char::len_utf8));
+                let exp = match unit.to_ascii_uppercase().as_str() {
+                    "K" => 1,
+                    "M" => 2,
+                    "G" => 3,
+                    "T" => 4,
+                    "P" => 5,
+                    _ => return Err(err("unknown suffix")),
+                };
+                let base: u64 = match rest {
+                    "" | "B" => 1000,
+                    "i" | "iB" => 1024,
+                    _ => return Err(err("unknown suffix")),
+                };
+                base.pow(exp)
+            }
+        };
+
+        let (whole, frac) = num.split_once('.').unwrap_or((num, ""));
+        if whole.is_empty() || frac.contains('.') {
+            return Err(err("expected a number"));
+        }
+        if frac.len() > 18 {
+            return Err(err("too many decimal places"));
+        }
+
+        let whole: u64 = whole.parse().map_err(|_| err("too large"))?;
+        let bytes = whole.checked_mul(multiplier).ok_or(err("too large"))?;
+
+        if frac.is_empty() {
+            return Ok(Size(bytes));
+        }
+
+        let scale = 10u128.pow(frac.len() as u32);
+        let frac = frac.parse::<u128>().map_err(|_| err("expected a number"))? * multiplier as u128;
+        if frac % scale != 0 {
+            return Err(err("not a whole number of bytes"));
+        }
+
+        bytes
+            .checked_add((frac / scale) as u64)
+            .map(Size)
+            .ok_or(err("too large"))
+    }
+}
+
+impl Serialize for Size {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_size_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SizeVisitor;
+
+        impl serde::de::Visitor<'_> for SizeVisitor {
+            type Value = Size;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(f, "a size string like \"512Mi\" or a number of bytes")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Size, E> {
+                v.parse().map_err(E::custom)
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Size, E> {
+                Ok(Size(v))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Size, E> {
+                u64::try_from(v)
+                    .map(Size)
+                    .map_err(|_| E::custom("size can't be negative"))
+            }
+        }
+
+        deserializer.deserialize_any(SizeVisitor)
+    }
+}
+
+/// How long a machine lives, written as whole numbers of days, hours,
+/// minutes and seconds, e.g. `4h`, `90m` or `1d12h`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ttl(pub Duration);
+
+const TTL_UNITS: [(char, u64); 4] = [('d', 86400), ('h', 3600), ('m', 60), ('s', 1)];
+
+impl std::fmt::Display for Ttl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut secs = self.0.as_secs();
+        for (unit, len) in TTL_UNITS {
+            if secs >= len {
+                write!(f, "{}{}", secs / len, unit)?;
+                secs %= len;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for Ttl {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = |reason: &str| format!("invalid ttl '{}': {}", s, reason);
+
+        let mut secs: u64 = 0;
+        let mut rest = s.trim();
+        while !rest.is_empty() {
+            let split = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let (num, tail) = rest.split_at(split);
+            let mut chars = tail.chars();
+            let unit = chars
+                .next()
+                .ok_or_else(|| err("missing unit, expected d, h, m or s"))?;
+
+            let num: u64 = num.parse().map_err(|_| err("expected a number"))?;
+            let len = TTL_UNITS
+                .iter()
+                .find(|(u, _)| *u == unit)
+                .map(|(_, len)| *len)
+                .ok_or_else(|| err("unknown unit, expected d, h, m or s"))?;
+            secs = num
+                .checked_mul(len)
+                .and_then(|n| secs.checked_add(n))
+                .ok_or_else(|| err("too long"))?;
+            rest = chars.as_str();
+        }
+
+        if secs == 0 {
+            return Err(err("must be longer than 0s").into());
+        }
+        Ok(Ttl(Duration::from_secs(secs)))
+    }
+}
+
+impl Serialize for Ttl {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Ttl {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// `userdataFile`, either one file or a list of cloud-config snippets
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum UserdataFile {
+    One(PathBuf),
+    Many(Vec<PathBuf>),
+}
+
+impl UserdataFile {
+    pub fn paths(&self) -> &[PathBuf] {
+        match self {
+            UserdataFile::One(path) => std::slice::from_ref(path),
+            UserdataFile::Many(paths) => paths,
+        }
+    }
+
+    fn from_paths(mut paths: Vec<PathBuf>) -> Option<Self> {
+        match paths.len() {
+            0 => None,
+            1 => paths.pop().map(UserdataFile::One),
+            _ => Some(UserdataFile::Many(paths)),
+        }
+    }
+}
+
+/// A throughput in bytes per second, displayed as `12.5 MiB/s`
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Rate(pub f64);
+
+impl std::fmt::Display for Rate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/s", human_bytes(self.0))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Spec {
+    // cpu, memory and image may come from the host's defaults instead
+    #[serde(default)]
+    pub cpu: u32,
+    #[serde(default)]
+    pub memory: Size,
+    #[serde(default)]
+    pub image: Image,
+    pub storage: Option<Vec<StorageKind>>,
+    pub nics: Option<Vec<Nic>>,
+    pub userdata: Option<String>,
+
+    // user-data file or cloud-config snippets to merge, relative to the
+    // model file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub userdata_file: Option<UserdataFile>,
+
+    // typed cloud-config, merged with userdata
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cloud_config: Option<CloudConfig>,
+
+    // named secret references, injected into userdata as ${secret:NAME}
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secrets: Option<Map<String, String>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub confidential: Option<Confidential>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub boot_order: Option<Vec<BootDevice>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cdroms: Option<Vec<Cdrom>>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iothreads: Option<u32>,
+
+    // seconds to wait for declared storage to appear before giving up
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wait_for_storage: Option<u64>,
+
+    // bus for spec.storage disks, the instance disk is always virtio-blk vda
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub storage_bus: Option<DiskBus>,
+
+    // guest cpu model, QEMU's default model if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_model: Option<CpuModel>,
+
+    // guest NUMA cells, their cpus and memory must add up to the spec's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numa: Option<Vec<NumaCell>>,
+
+    // dedicated or shared host cpus and whether memory is locked, for
+    // guests that need predictable latency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub placement: Option<PlacementHints>,
+
+    // let the guest run KVM itself, e.g. for CI jobs starting their own
+    // machines. The host's kvm module must allow nesting.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nested_virt: Option<bool>,
+
+    // a kernel booted in place of the image's bootloader
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub direct_boot: Option<DirectBoot>,
+
+    // how the config drive is laid out and attached, a NoCloud ISO by
+    // default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config_drive_format: Option<ConfigDriveFormat>,
+
+    // a probe `serve` runs against the running machine, restarting it
+    // after repeated failures if asked to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health_check: Option<HealthCheck>,
+
+    // whether `serve` starts the machine again when it stops by itself,
+    // never by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub restart_policy: Option<RestartPolicy>,
+
+    // machines created before this one, from the same model file or
+    // already on the host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub depends_on: Option<Vec<Dependency>>,
+
+    // how nic MAC addresses are picked, stable by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac_policy: Option<MacPolicy>,
+
+    // identity the guest reads from SMBIOS/DMI, e.g. for asset inventory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub smbios: Option<Smbios>,
+
+    // security driver label of the qemu process
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seclabel: Option<SecLabel>,
+
+    // pointer device, PS/2 by default; a tablet tracks the cursor
+    // absolutely, which graphical consoles need
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub input: Option<InputDevice>,
+
+    // display adapter, none by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoModel>,
+
+    // sound card for desktop guests, none if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sound: Option<SoundModel>,
+
+    // host USB devices handed to the guest, e.g. hardware tokens
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usb_devices: Option<Vec<UsbDevice>>,
+
+    // USB controller model, QEMU's default if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usb_controller: Option<UsbController>,
+
+    // mediated devices such as vGPU slices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mdev: Option<Vec<Mdev>>,
+
+    // where `bigiron-virt schedule` may put the machine, unused by create
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scheduling: Option<Scheduling>,
+
+    // host ports forwarded to the machine's address on its Network nic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port_forwards: Option<Vec<PortForward>>,
+
+    // the MachineClass the spec was filled in from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+
+    // raw device elements added at the end of the domain's <devices>
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extra_domain_xml: Option<String>,
+
+    // a <domain> whose children replace the generated ones of the same name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain_xml_override: Option<String>,
+}
+
+impl Spec {
+    /// User-data for the config drive, `userdata` merged over the rendered
+    /// `cloudConfig` when both are given
+    pub fn user_data(&self) -> Result<Option<String>, Error> {
+        let cloud_config = match self.cloud_config {
+            Some(ref cc) if !cc.is_empty() => cc.render()?,
+            _ => return Ok(self.userdata.clone()),
+        };
+
+        match self.userdata {
+            Some(ref userdata) => {
+                let parts = [
+                    (String::from("cloudConfig"), cloud_config),
+                    (String::from("userdata"), userdata.clone()),
+                ];
+                Ok(Some(crate::userdata::merge(&parts)?))
+            }
+            None => Ok(Some(cloud_config)),
+        }
+    }
+}
+
+/// SMBIOS system fields shown to the guest in `/sys/class/dmi/id`, e.g.
+/// `{manufacturer: ACME, product: Build Node, serial: BN-0042}`. Unset
+/// fields are left to QEMU.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Smbios {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub manufacturer: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    /// also used as the libvirt domain UUID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// chassis asset tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub asset_tag: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum MacPolicy {
+    /// derived from the machine name and nic index, so a re-created
+    /// machine gets the same MACs
+    #[default]
+    Stable,
+    /// random on every create
+    Random,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct NumaCell {
+    pub cpus: u32,
+    pub memory: Size,
+    /// host NUMA node the cell's memory is allocated from, strictly
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_node: Option<u32>,
+}
+
+/// Guest CPU model and feature flags, e.g. for nested virtualization
+/// `{mode: host-model, add: [vmx]}` or
+/// `{mode: custom, model: Skylake-Server, add: [avx512f], remove: [hle]}`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuModel {
+    pub mode: CpuMode,
+    /// named QEMU/libvirt model, only for `custom` mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// features the guest must have
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub add: Vec<String>,
+    /// features hidden from the guest
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remove: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CpuMode {
+    /// the host CPU exactly, machines can't migrate to other CPU types
+    HostPassthrough,
+    /// the closest named model to the host CPU
+    HostModel,
+    Custom,
+}
+
+/// How the machine shares the host with others, e.g. `{cpu: dedicated,
+/// hostCpus: [2, 3], emulatorCpus: [0], memoryLocked: true}` for a latency
+/// sensitive guest or `{cpuShares: 256, cpuLimit: 50}` for a batch one
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlacementHints {
+    #[serde(default)]
+    pub cpu: CpuPolicy,
+    /// host cpus the vcpus are pinned to, one each in order, for dedicated;
+    /// create refuses ones the host lacks or another machine already has
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub host_cpus: Vec<u32>,
+    /// host cpus QEMU's own threads run on, kept off the vcpus' ones
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub emulator_cpus: Vec<u32>,
+    /// weight against other machines when host cpus are contended, for
+    /// shared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_shares: Option<u64>,
+    /// percent of a host cpu each vcpu may use at most, for shared
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu_limit: Option<u32>,
+    /// keep guest memory in host RAM, never swapped out
+    #[serde(default)]
+    pub memory_locked: bool,
+}
+
+impl PlacementHints {
+    fn problems(&self, vcpus: u32) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        match self.cpu {
+            CpuPolicy::Dedicated => {
+                if self.host_cpus.len() != vcpus as usize {
+                    problems.push(format!(
+                        "placement cpu dedicated needs a hostCpus entry per vcpu, {} for {}",
+                        self.host_cpus.len(),
+                        vcpus
+                    ));
+                }
+                if self.cpu_shares.is_some() || self.cpu_limit.is_some() {
+                    problems.push(String::from(
+                        "placement cpuShares and cpuLimit only apply to shared cpus",
+                    ));
+                }
+            }
+            CpuPolicy::Shared => {
+                if !self.host_cpus.is_empty() {
+                    problems.push(String::from(
+                        "placement hostCpus only apply to dedicated cpus",
+                    ));
+                }
+            }
+        }
+
+        let mut seen = Vec::new();
+        for cpu in &self.host_cpus {
+            if seen.contains(cpu) {
+                problems.push(format!("placement host cpu {} is given twice", cpu));
+            }
+            seen.push(*cpu);
+        }
+        for cpu in self
+            .emulator_cpus
+            .iter()
+            .filter(|c| self.host_cpus.contains(c))
+        {
+            problems.push(format!(
+                "placement host cpu {} is both a vcpu's and the emulator's",
+                cpu
+            ));
+        }
+
+        if self.cpu_shares == Some(0) {
+            problems.push(String::from("placement cpuShares can't be 0"));
+        }
+        if self.cpu_limit.is_some_and(|l| l == 0 || l > 100) {
+            problems.push(String::from("placement cpuLimit must be 1 to 100 percent"));
+        }
+
+        problems
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CpuPolicy {
+    /// vcpus float over the host cpus with other machines' vcpus
+    #[default]
+    Shared,
+    /// each vcpu has a host cpu of its own
+    Dedicated,
+}
+
+/// A kernel to boot directly, e.g. `{kernel: /srv/boot/vmlinuz, initrd:
+/// /srv/boot/initrd.img, cmdline: "root=/dev/vda1 console=ttyS0"}`. Files
+/// are absolute paths or file:// URLs on the host and are read on every
+/// start, so a rebuilt kernel is picked up by restarting the machine.
+/// http(s) URLs aren't supported, the files have to be downloaded first.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DirectBoot {
+    pub kernel: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initrd: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cmdline: Option<String>,
+}
+
+impl DirectBoot {
+    pub fn kernel_path(&self) -> Result<PathBuf, Error> {
+        host_file(&self.kernel)
+    }
+
+    pub fn initrd_path(&self) -> Result<Option<PathBuf>, Error> {
+        self.initrd.as_deref().map(host_file).transpose()
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if let Err(e) = self.kernel_path() {
+            problems.push(format!("directBoot kernel: {}", e));
+        }
+        if let Err(e) = self.initrd_path() {
+            problems.push(format!("directBoot initrd: {}", e));
+        }
+        problems
+    }
+}
+
+// host file `s`, given as an absolute path or a file:// URL
+fn host_file(s: &str) -> Result<PathBuf, Error> {
+    if !s.contains("://") {
+        let path = PathBuf::from(s);
+        if !path.is_absolute() {
+            return Err(format!("'{}' is not an absolute path", s).into());
+        }
+        return Ok(path);
+    }
+
+    let url = Url::parse(s)?;
+    if url.scheme() != "file" {
+        return Err(format!("'{}' is not a file:// URL, download it first", s).into());
+    }
+    url.to_file_path()
+        .map_err(|_| format!("'{}' is not a file path", s).into())
+}
+
+/// What cloud-init finds the config drive as
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ConfigDriveFormat {
+    /// NoCloud files on an ISO labelled cidata, in the cdrom drive
+    #[default]
+    NoCloud,
+    /// NoCloud files on a FAT filesystem labelled cidata, attached as a
+    /// disk for guests that can't read ISOs
+    Vfat,
+    /// `openstack/latest/*.json` on an ISO labelled config-2, as OpenStack
+    /// config drives are, for images only looking for those
+    OpenStack,
+}
+
+impl ConfigDriveFormat {
+    /// Whether the drive is a cdrom, which a running machine can have
+    /// swapped
+    pub fn is_cdrom(&self) -> bool {
+        *self != ConfigDriveFormat::Vfat
+    }
+}
+
+/// A machine in `dependsOn`, either just its name or `{name: db, ready:
+/// true}` to also wait for cloud-init in it to be done
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum Dependency {
+    Name(String),
+    Machine {
+        name: String,
+        #[serde(default)]
+        ready: bool,
+    },
+}
+
+impl Dependency {
+    pub fn name(&self) -> &str {
+        match self {
+            Dependency::Name(name) | Dependency::Machine { name, .. } => name,
+        }
+    }
+
+    /// Whether the dependent waits for the machine to be ready, not only
+    /// created
+    pub fn ready(&self) -> bool {
+        matches!(self, Dependency::Machine { ready: true, .. })
+    }
+}
+
+/// When a machine that stopped without being asked to is started again.
+/// A guest kernel panic restarts it in place, other failures and, for
+/// `always`, shutting down from inside the guest have `serve` start it
+/// again with backoff.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    /// after a crash
+    OnFailure,
+    /// after a crash or the guest shutting down
+    Always,
+}
+
+/// A probe of a running machine, one of `{tcp: 22}`, `{http:
+/// "http://{address}:8080/healthz"}` and `{exec: [systemctl, is-active,
+/// nginx]}`. `{address}` in the URL is the machine's first address, the
+/// command runs through the guest agent and passes when it exits 0. After
+/// `retries` failures in a row the machine is unhealthy, and restarted if
+/// `restart` is set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthCheck {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tcp: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exec: Option<Vec<String>>,
+    /// seconds between probes, 30 if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval: Option<u64>,
+    /// seconds a probe may take, 5 if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// failures in a row before the machine is unhealthy, 3 if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+    /// seconds after the machine starts in which failures don't count
+    /// until it is ready, 300 if not set, for a first boot's cloud-init
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_period: Option<u64>,
+    #[serde(default)]
+    pub restart: bool,
+}
+
+impl HealthCheck {
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval.unwrap_or(30))
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout.unwrap_or(5))
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries.unwrap_or(3)
+    }
+
+    pub fn start_period(&self) -> Duration {
+        Duration::from_secs(self.start_period.unwrap_or(300))
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let probes = [self.tcp.is_some(), self.http.is_some(), self.exec.is_some()];
+        if probes.iter().filter(|p| **p).count() != 1 {
+            problems.push(String::from(
+                "healthCheck needs exactly one of tcp, http and exec",
+            ));
+        }
+
+        if let Some(ref url) = self.http {
+            match Url::parse(&url.replace("{address}", "127.0.0.1")) {
+                Ok(u) if u.scheme() == "http" && u.host().is_some() => {}
+                Ok(_) => problems.push(format!("healthCheck http '{}' is not an http URL", url)),
+                Err(e) => problems.push(format!("healthCheck http '{}': {}", url, e)),
+            }
+        }
+        if self.exec.as_ref().is_some_and(|argv| argv.is_empty()) {
+            problems.push(String::from("healthCheck exec needs a command"));
+        }
+
+        if self.interval == Some(0) || self.timeout == Some(0) || self.retries == Some(0) {
+            problems.push(String::from(
+                "healthCheck interval, timeout and retries must be more than 0",
+            ));
+        }
+        if self.timeout() > self.interval() {
+            problems.push(String::from(
+                "healthCheck timeout can't be longer than its interval",
+            ));
+        }
+
+        problems
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BootDevice {
+    Hd,
+    Cdrom,
+    Network,
+}
+
+/// Bus storage disks are attached on. virtio-blk disks are named vdb to vdz,
+/// virtio-scsi disks are named sda, sdb, ... and addressed by LUN on a
+/// single controller.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskBus {
+    #[default]
+    Virtio,
+    Scsi,
+}
+
+// LUNs a virtio-scsi target supports
+const SCSI_MAX_DISKS: usize = 16384;
+
+impl DiskBus {
+    /// Most storage disks a machine can have on this bus
+    pub fn max_disks(&self) -> usize {
+        match self {
+            // vda is the instance disk
+            DiskBus::Virtio => 25,
+            DiskBus::Scsi => SCSI_MAX_DISKS,
+        }
+    }
+
+    /// Target name of storage disk `index`
+    pub fn target(&self, index: usize) -> Result<String, TooManyDisksError> {
+        if index >= self.max_disks() {
+            return Err(TooManyDisksError {
+                bus: *self,
+                count: index + 1,
+            });
+        }
+
+        match self {
+            DiskBus::Virtio => Ok(format!("vd{}", (b'b' + index as u8) as char)),
+            DiskBus::Scsi => {
+                // sda..sdz, sdaa..sdzz, ... like the Linux sd driver
+                let mut letters = Vec::new();
+                let mut n = index + 1;
+                while n > 0 {
+                    n -= 1;
+                    letters.push(b'a' + (n % 26) as u8);
+                    n /= 26;
+                }
+                letters.reverse();
+
+                Ok(format!("sd{}", String::from_utf8_lossy(&letters)))
+            }
+        }
+    }
+
+    /// Storage disk index of target name `target`, the inverse of `target`
+    pub fn index(&self, target: &str) -> Option<usize> {
+        let letters = match self {
+            DiskBus::Virtio => {
+                return match target.as_bytes() {
+                    [b'v', b'd', c @ b'b'..=b'z'] => Some((c - b'b') as usize),
+                    _ => None,
+                }
+            }
+            DiskBus::Scsi => target.strip_prefix("sd")?,
+        };
+
+        if letters.is_empty() || !letters.bytes().all(|c| c.is_ascii_lowercase()) {
+            return None;
+        }
+
+        let mut n: usize = 0;
+        for c in letters.bytes() {
+            n = n.checked_mul(26)?.checked_add((c - b'a' + 1) as usize)?;
+        }
+
+        Some(n - 1).filter(|i| *i < self.max_disks())
+    }
+}
+
+/// A machine declares more storage disks than its bus can address
+#[derive(Debug, Clone, PartialEq)]
+pub struct TooManyDisksError {
+    pub bus: DiskBus,
+    pub count: usize,
+}
+
+impl std::fmt::Display for TooManyDisksError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} storage disks requested but the {:?} bus supports at most {}",
+            self.count,
+            self.bus,
+            self.bus.max_disks()
+        )?;
+
+        if self.bus == DiskBus::Virtio {
+            write!(f, ", set storageBus: scsi for more")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for TooManyDisksError {}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Image {
+    // url and hash can be left out when name is given, they're filled in
+    // from the image repo when the machine is created
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub url: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub hash: String,
+
+    /// friendly name or alias of an image in the repo, e.g. ubuntu-22.04.
+    /// Given with url and hash, the imported image gets the name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    pub resize: Option<Size>,
+
+    // how the instance disk is made from the image, cow by default
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<ImageMode>,
+
+    // LUKS encryption of the instance disk, which needs qcow2 instance
+    // storage
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+
+    #[serde(flatten)]
+    pub driver: DiskDriver,
+}
+
+/// Where the passphrase of an encrypted disk comes from
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Encryption {
+    /// UUID of a libvirt secret holding the passphrase, which must not be
+    /// private as qemu-img needs to read it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+
+    /// file holding the passphrase, relative to /etc/bigiron-virt/secrets
+    /// as `file:` secrets are, copied into a private libvirt secret for the
+    /// disk that's removed with the machine
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passphrase_file: Option<PathBuf>,
+}
+
+impl Image {
+    // neither a url nor a name, left for the host's default
+    fn is_unset(&self) -> bool {
+        self.url.is_empty() && self.hash.is_empty() && self.name.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageMode {
+    /// a thin qcow2 overlay backed by the repo image
+    #[default]
+    Cow,
+    /// a full copy, independent of the image repo
+    Flatten,
+}
+
+impl ImageMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageMode::Cow => "cow",
+            ImageMode::Flatten => "flatten",
+        }
+    }
+}
+
+/// Per-disk driver tuning, flattened into the disk definition
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskDriver {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<DiskCache>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io: Option<DiskIo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discard: Option<Discard>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detect_zeroes: Option<DetectZeroes>,
+
+    // virtio-blk queue count and iothread (1-based) to service the disk
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queues: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iothread: Option<u32>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iotune: Option<IoTune>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seclabel: Option<SecLabel>,
+}
+
+/// Disk IO limits, e.g. `{totalIopsSec: 1000, writeBytesSec: 50Mi}`.
+/// A total limit can't be combined with read or write limits of the same
+/// kind.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IoTune {
+    // bytes per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_bytes_sec: Option<Size>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_bytes_sec: Option<Size>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_bytes_sec: Option<Size>,
+
+    // operations per second
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_iops_sec: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub read_iops_sec: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub write_iops_sec: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiskCache {
+    None,
+    Writethrough,
+    Writeback,
+    Directsync,
+    Unsafe,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiskIo {
+    Native,
+    Threads,
+    IoUring,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Discard {
+    Unmap,
+    Ignore,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectZeroes {
+    Off,
+    On,
+    Unmap,
+}
+
+impl DiskCache {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskCache::None => "none",
+            DiskCache::Writethrough => "writethrough",
+            DiskCache::Writeback => "writeback",
+            DiskCache::Directsync => "directsync",
+            DiskCache::Unsafe => "unsafe",
+        }
+    }
+}
+
+impl DiskIo {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DiskIo::Native => "native",
+            DiskIo::Threads => "threads",
+            DiskIo::IoUring => "io_uring",
+        }
+    }
+}
+
+impl Discard {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Discard::Unmap => "unmap",
+            Discard::Ignore => "ignore",
+        }
+    }
+}
+
+impl DetectZeroes {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DetectZeroes::Off => "off",
+            DetectZeroes::On => "on",
+            DetectZeroes::Unmap => "unmap",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InputDevice {
+    #[default]
+    Ps2,
+    /// virtio tablet
+    Tablet,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoModel {
+    #[default]
+    None,
+    Virtio,
+    Qxl,
+}
+
+/// A host USB device, picked by its vendor and product IDs
+/// (`{vendor: "1050", product: "0407"}`) or by where it's plugged in
+/// (`{bus: 1, device: 5}`, as `lsusb` shows them)
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UsbDevice {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vendor: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub product: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bus: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device: Option<u32>,
+}
+
+impl UsbDevice {
+    pub fn id(vendor: &str, product: &str) -> Self {
+        Self {
+            vendor: Some(vendor.to_string()),
+            product: Some(product.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn address(bus: u32, device: u32) -> Self {
+        Self {
+            bus: Some(bus),
+            device: Some(device),
+            ..Default::default()
+        }
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let by_id = self.vendor.is_some() || self.product.is_some();
+        let by_address = self.bus.is_some() || self.device.is_some();
+        match (by_id, by_address) {
+            (true, false) if self.vendor.is_none() || self.product.is_none() => {
+                problems.push(String::from("usb device needs both vendor and product"))
+            }
+            (false, true) if self.bus.is_none() || self.device.is_none() => {
+                problems.push(String::from("usb device needs both bus and device"))
+            }
+            (true, true) | (false, false) => problems.push(String::from(
+                "usb device needs either vendor and product or bus and device",
+            )),
+            _ => {}
+        }
+
+        for id in [&self.vendor, &self.product].into_iter().flatten() {
+            if id.len() != 4 || !id.bytes().all(|c| c.is_ascii_hexdigit()) {
+                problems.push(format!("usb id '{}' is not 4 hex digits", id));
+            }
+        }
+
+        problems
+    }
+}
+
+/// A mediated device, either one that exists (`uuid`) or one made on
+/// create from a `type` such as nvidia-63. Made ones get their `uuid`
+/// filled in and are removed again with the machine.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Mdev {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<String>,
+    // PCI address of the device to make it on, any with room if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+
+impl Mdev {
+    pub fn existing(uuid: &str) -> Self {
+        Self {
+            uuid: Some(uuid.to_string()),
+            ..Default::default()
+        }
+    }
+
+    pub fn of_type(kind: &str) -> Self {
+        Self {
+            kind: Some(kind.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        if self.uuid.is_none() && self.kind.is_none() {
+            problems.push(String::from("mdev needs a uuid or a type"));
+        }
+        if let Some(ref uuid) = self.uuid {
+            if uuid::Uuid::parse_str(uuid).is_err() {
+                problems.push(format!("mdev uuid '{}' is not a UUID", uuid));
+            }
+        }
+        if self.parent.is_some() && self.kind.is_none() {
+            problems.push(String::from("mdev parent is only used with a type"));
+        }
+
+        problems
+    }
+}
+
+/// Rules for placing a machine across hosts. `affinity` and
+/// `antiAffinity` are label selectors such as `app=db`: the machine goes on
+/// a host running a machine matching each `affinity` selector, once any
+/// such machine exists, and on no host running one matching an
+/// `antiAffinity` selector.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Scheduling {
+    /// labels the host must have
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host_selector: Option<Map<String, String>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub affinity: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub anti_affinity: Vec<String>,
+}
+
+impl Scheduling {
+    fn problems(&self) -> Vec<String> {
+        self.affinity
+            .iter()
+            .chain(&self.anti_affinity)
+            .filter_map(|s| s.parse::<Selector>().err())
+            .map(|e| format!("scheduling: {}", e))
+            .collect()
+    }
+}
+
+/// A host port forwarded to the machine, e.g. `{hostPort: 2222, guestPort:
+/// 22}`. The host rewrites connections to the address its Network nic got
+/// from the libvirt network, which otherwise only lets out connections
+/// the machine made.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PortForward {
+    pub host_port: u16,
+    pub guest_port: u16,
+    /// tcp unless given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Protocol>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Tcp => write!(f, "tcp"),
+            Protocol::Udp => write!(f, "udp"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum UsbController {
+    /// USB 3, what most guests want
+    QemuXhci,
+    NecXhci,
+    /// USB 2
+    Ich9Ehci1,
+    /// USB 1
+    Piix3Uhci,
+    /// no USB at all
+    None,
+}
+
+impl UsbController {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UsbController::QemuXhci => "qemu-xhci",
+            UsbController::NecXhci => "nec-xhci",
+            UsbController::Ich9Ehci1 => "ich9-ehci1",
+            UsbController::Piix3Uhci => "piix3-uhci",
+            UsbController::None => "none",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SoundModel {
+    /// Intel HD Audio on a Q35 chipset
+    Ich9,
+    /// Intel HD Audio
+    Ich6,
+    Ac97,
+}
+
+impl SoundModel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SoundModel::Ich9 => "ich9",
+            SoundModel::Ich6 => "ich6",
+            SoundModel::Ac97 => "ac97",
+        }
+    }
+}
+
+impl InputDevice {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InputDevice::Ps2 => "ps2",
+            InputDevice::Tablet => "tablet",
+        }
+    }
+}
+
+impl VideoModel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            VideoModel::None => "none",
+            VideoModel::Virtio => "virtio",
+            VideoModel::Qxl => "qxl",
+        }
+    }
+}
+
+/// Security driver labelling, e.g. `{model: selinux, type: static, label:
+/// "system_u:system_r:svirt_t:s0:c10,c20"}`. On the spec it labels the
+/// machine's qemu process, on a disk the disk's file or device, where
+/// `type` isn't used.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct SecLabel {
+    // libvirt's default security driver if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model: Option<SecurityModel>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub kind: Option<SecLabelKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    // whether libvirt labels files for the machine, yes unless type none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub relabel: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecurityModel {
+    Selinux,
+    Apparmor,
+    Dac,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SecLabelKind {
+    /// a unique label picked by libvirt
+    #[default]
+    Dynamic,
+    /// `label` as given
+    Static,
+    /// unconfined
+    None,
+}
+
+impl SecurityModel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecurityModel::Selinux => "selinux",
+            SecurityModel::Apparmor => "apparmor",
+            SecurityModel::Dac => "dac",
+        }
+    }
+}
+
+impl SecLabelKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecLabelKind::Dynamic => "dynamic",
+            SecLabelKind::Static => "static",
+            SecLabelKind::None => "none",
+        }
+    }
+}
+
+// launch options for confidential guests, rendered as <launchSecurity>
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind")]
+pub enum Confidential {
+    // IBM Secure Execution, s390x only so refused by validation as
+    // machines run as x86_64 domains
+    S390PV,
+    SEV(Sev),
+    SEVSNP(Sev),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Sev {
+    // guest policy bits, defaults to no-debug/no-key-sharing for SEV
+    // and SMT-allowed/reserved bit for SEV-SNP
+    pub policy: Option<u64>,
+    pub cbitpos: Option<u32>,
+    pub reduced_phys_bits: Option<u32>,
+}
+
+// extra ISO (e.g. an OS installer) imported through the image repo,
+// given either as a URL or a local path, relative to the model file
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Cdrom {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    pub hash: String,
+}
+
+impl Cdrom {
+    pub fn source_url(&self) -> Result<Url, Error> {
+        match (&self.url, &self.path) {
+            (Some(url), None) => Ok(Url::parse(url)?),
+            (None, Some(path)) => {
+                let path = path.canonicalize()?;
+                Url::from_file_path(&path)
+                    .map_err(|_| format!("invalid cdrom path: {:?}", path).into())
+            }
+            _ => Err("cdrom requires exactly one of 'url' or 'path'".into()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind")]
+pub enum StorageKind {
+    File(File),
+    Block(Block),
+    Volume(Volume),
+    Rbd(Rbd),
+    Iscsi(Iscsi),
+    Ephemeral(Ephemeral),
+}
+
+impl StorageKind {
+    pub fn driver(&self) -> &DiskDriver {
+        match self {
+            StorageKind::File(ref file) => &file.driver,
+            StorageKind::Block(ref block) => &block.driver,
+            StorageKind::Volume(ref vol) => &vol.driver,
+            StorageKind::Rbd(ref rbd) => &rbd.driver,
+            StorageKind::Iscsi(ref iscsi) => &iscsi.driver,
+            StorageKind::Ephemeral(ref scratch) => &scratch.driver,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct File {
+    pub path: PathBuf,
+
+    #[serde(flatten)]
+    pub driver: DiskDriver,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Block {
+    pub path: PathBuf,
+
+    #[serde(flatten)]
+    pub driver: DiskDriver,
+}
+
+// volume in a libvirt storage pool, the pool is activated if needed
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Volume {
+    pub pool: String,
+    pub volume: String,
+
+    #[serde(flatten)]
+    pub driver: DiskDriver,
+}
+
+// empty scratch disk made fresh whenever the domain is defined, left out of
+// backups and exports
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Ephemeral {
+    pub size: Size,
+
+    // a raw file on tmpfs, taken from host memory, instead of a qcow2 in
+    // the instance directory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tmpfs: Option<bool>,
+
+    #[serde(flatten)]
+    pub driver: DiskDriver,
+}
+
+// Ceph RBD image, monitors are given as host[:port]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Rbd {
+    pub pool: String,
+    pub image: String,
+    pub monitors: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<DiskAuth>,
+
+    #[serde(flatten)]
+    pub driver: DiskDriver,
+}
+
+// iSCSI LUN, portal is given as host[:port]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Iscsi {
+    pub portal: String,
+    pub target: String,
+    #[serde(default)]
+    pub lun: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<DiskAuth>,
+
+    #[serde(flatten)]
+    pub driver: DiskDriver,
+}
+
+// username plus the UUID of a libvirt secret holding the key/password
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct DiskAuth {
+    pub username: String,
+    pub secret: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Nic {
+    pub kind: String,
+    pub parent: String,
+    pub address: AddressKind,
+
+    // virtio-net multi-queue count
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queues: Option<u32>,
+
+    // guest interface name, e.g. uplink0, set through network config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+
+    // libvirt nwfilter against MAC/IP spoofing, e.g. clean-traffic, not on
+    // Macvtap nics
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filterref: Option<FilterRef>,
+
+    // let the guest change its MAC and multicast filters, Macvtap only
+    #[serde(
+        rename = "trustGuestRxFilters",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub trust_guest_rx_filters: Option<bool>,
+
+    // block traffic to other isolated ports on the bridge, Bridge only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub isolated: Option<bool>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bandwidth: Option<Bandwidth>,
+
+    // fixed MAC in place of one from macPolicy, e.g. an adopted domain's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+
+    // the MAC in use, picked from `mac` or macPolicy on create and stored
+    // with the machine
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub macaddress: String,
+}
+
+impl Nic {
+    /// A virtio nic on host bridge `parent`, addressed by SLAAC
+    pub fn bridge(parent: &str) -> Self {
+        Self::new("Bridge", parent)
+    }
+
+    /// A virtio nic on a macvtap device over host interface `parent`,
+    /// addressed by SLAAC
+    pub fn macvtap(parent: &str) -> Self {
+        Self::new("Macvtap", parent)
+    }
+
+    /// A virtio nic on libvirt network `parent`, e.g. the NAT network
+    /// `default`, addressed by SLAAC
+    pub fn network(parent: &str) -> Self {
+        Self::new("Network", parent)
+    }
+
+    fn new(kind: &str, parent: &str) -> Self {
+        Self {
+            kind: kind.to_string(),
+            parent: parent.to_string(),
+            address: AddressKind::IPv6SLAAC,
+            queues: None,
+            name: None,
+            filterref: None,
+            trust_guest_rx_filters: None,
+            isolated: None,
+            bandwidth: None,
+            mac: None,
+            macaddress: String::new(),
+        }
+    }
+
+    pub fn with_address(mut self, address: AddressKind) -> Self {
+        self.address = address;
+        self
+    }
+
+    /// Name the interface `name` in the guest
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Apply libvirt nwfilter `filter`, e.g. `clean-traffic`
+    pub fn with_filter(mut self, filter: &str) -> Self {
+        self.filterref = Some(FilterRef {
+            filter: filter.to_string(),
+            parameters: Map::new(),
+        });
+        self
+    }
+
+    /// Isolate the port from other isolated ports on the bridge
+    pub fn isolated(mut self) -> Self {
+        self.isolated = Some(true);
+        self
+    }
+
+    pub fn with_bandwidth(mut self, bandwidth: Bandwidth) -> Self {
+        self.bandwidth = Some(bandwidth);
+        self
+    }
+
+    /// Always use MAC `mac`, whatever the machine's MAC policy
+    pub fn with_mac(mut self, mac: &str) -> Self {
+        self.mac = Some(mac.to_string());
+        self
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(tag = "kind")]
-pub enum StorageKind {
-    File(File),
-    Block(Block),
+/// Nic rate limits, e.g. `{outbound: {average: 12500, peak: 25000}}`.
+/// Inbound is traffic to the guest, outbound traffic from it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Bandwidth {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inbound: Option<RateLimit>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub outbound: Option<RateLimit>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct File {
-    pub path: PathBuf,
+/// Rates in kilobytes per second, burst in kilobytes, as libvirt takes
+/// them
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct RateLimit {
+    pub average: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub peak: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub burst: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct Block {
-    pub path: PathBuf,
+impl RateLimit {
+    pub fn new(average: u64) -> Self {
+        Self {
+            average,
+            ..Default::default()
+        }
+    }
 }
 
+/// A libvirt nwfilter reference, e.g.
+/// `{filter: clean-traffic, parameters: {IP: 10.0.0.5}}`. For static IPv4
+/// nics `IP` defaults to the nic's address.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-pub struct Nic {
-    pub kind: String,
-    pub parent: String,
-    pub address: AddressKind,
-
-    // for internal use only, currently
-    #[serde(skip)]
-    pub macaddress: String,
+pub struct FilterRef {
+    pub filter: String,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub parameters: Map<String, String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -124,6 +2799,18 @@ pub struct Nic {
 pub enum AddressKind {
     IPv6SLAAC,
     IPv4Static(IPv4Static),
+    Pool(PoolAddress),
+}
+
+impl AddressKind {
+    /// The static IPv4 address, given or assigned from a pool
+    pub fn ipv4_static(&self) -> Option<&IPv4Static> {
+        match self {
+            AddressKind::IPv4Static(ref v4) => Some(v4),
+            AddressKind::Pool(ref p) => p.assigned.as_ref(),
+            AddressKind::IPv6SLAAC => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -135,6 +2822,16 @@ pub struct IPv4Static {
     pub nameservers: Vec<String>,
 }
 
+/// An address from the `AddressPool` named `pool`, assigned on create and
+/// given back on destroy
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PoolAddress {
+    pub pool: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub assigned: Option<IPv4Static>,
+}
+
 #[cfg(test)]
 mod test {
 
@@ -177,20 +2874,57 @@ spec:
     fn serialize() {
         let m = Machine{
             status: None,
-            metadata: Metadata{name: "othervm".to_string()},
+            metadata: Metadata{name: "othervm".to_string(), labels: None, uuid: None, ttl: None},
             spec: Spec{
                 cpu: 4,
-                memory: "512Mi".to_string(),
+                memory: Size(512 * 1024 * 1024),
                 image: Image{
                     url: "file:///home/mrodden/projects/bigiron-virt/ubuntu-22.04-server-cloudimg-amd64-disk-kvm.img".to_string(),
                     hash: "754129c5052756ee47a0c395e518bd3413f444dff69b98f8a8fa42f2fa3acc2d".to_string(),
-                    resize: Some("100G".to_string()),
+                    name: None,
+                    resize: Some(Size(100_000_000_000)),
+                    mode: None,
+                    encryption: None,
+                    driver: DiskDriver::default(),
                 },
                 storage: Some(vec![StorageKind::File(File{
                     path: "/home/mrodden/projects/bigiron-virt/localfile01.qcow2".into(),
+                    driver: DiskDriver::default(),
                 })]),
                 nics: None,
                 userdata: Some("#cloud-config\nallow_public_ssh_keys: true\n".to_string()),
+                userdata_file: None,
+                cloud_config: None,
+                secrets: None,
+                confidential: None,
+                boot_order: None,
+                cdroms: None,
+                iothreads: None,
+                wait_for_storage: None,
+                storage_bus: None,
+                cpu_model: None,
+                numa: None,
+                placement: None,
+                nested_virt: None,
+                direct_boot: None,
+                config_drive_format: None,
+                health_check: None,
+                restart_policy: None,
+                depends_on: None,
+                mac_policy: None,
+                smbios: None,
+                seclabel: None,
+                input: None,
+                video: None,
+                sound: None,
+                usb_devices: None,
+                usb_controller: None,
+                mdev: None,
+                scheduling: None,
+                port_forwards: None,
+                class: None,
+                extra_domain_xml: None,
+                domain_xml_override: None,
             },
         };
 
@@ -222,6 +2956,950 @@ spec:
         assert!(m2 == m);
     }
 
+    #[test]
+    fn deserialize_boot_order() {
+        let yaml = sample.to_string() + "  bootOrder: [cdrom, hd]\n";
+        let m = match serde_yaml::from_str(&yaml).unwrap() {
+            Resource::Machine(m) => m,
+        };
+
+        assert_eq!(
+            m.spec.boot_order,
+            Some(vec![BootDevice::Cdrom, BootDevice::Hd])
+        );
+    }
+
+    #[test]
+    fn deserialize_cpu_model() {
+        let yaml = sample.to_string()
+            + "  cpuModel:\n    mode: custom\n    model: Skylake-Server\n    add: [avx512f]\n    remove: [hle]\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+
+        assert_eq!(
+            m.spec.cpu_model,
+            Some(CpuModel {
+                mode: CpuMode::Custom,
+                model: Some("Skylake-Server".to_string()),
+                add: vec!["avx512f".to_string()],
+                remove: vec!["hle".to_string()],
+            })
+        );
+        assert!(m.validate().is_ok());
+
+        let yaml = sample.to_string()
+            + "  cpuModel: {mode: host-passthrough, add: [vmx], remove: [vmx]}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(
+            m.spec.cpu_model.as_ref().unwrap().mode,
+            CpuMode::HostPassthrough
+        );
+        assert!(m
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("cpu feature 'vmx' is both added and removed"));
+
+        let yaml = sample.to_string() + "  cpuModel: {mode: custom}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert!(m.validate().is_err());
+    }
+
+    #[test]
+    fn machine_names() {
+        let Resource::Machine(mut m) = serde_yaml::from_str(sample).unwrap();
+        for good in ["vm1", "web-01", "Web1", "vm_1"] {
+            m.metadata.name = good.to_string();
+            assert!(m.validate().is_ok(), "{}", good);
+        }
+        for bad in ["../vm", "a/b", ".."] {
+            m.metadata.name = bad.to_string();
+            let err = m.validate().unwrap_err().to_string();
+            assert!(err.contains("invalid name"), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn deserialize_numa() {
+        let yaml = sample.to_string()
+            + "  numa:\n    - {cpus: 3, memory: 384Mi, hostNode: 0}\n    - {cpus: 1, memory: 128Mi}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+
+        let cells = m.spec.numa.as_ref().unwrap();
+        assert_eq!(cells.len(), 2);
+        assert_eq!(cells[0].host_node, Some(0));
+        assert_eq!(cells[1].memory, Size(128 * 1024 * 1024));
+
+        // sample has 4 cpus and 512Mi memory
+        assert!(m.validate().is_ok());
+
+        let yaml = sample.to_string() + "  numa: [{cpus: 2, memory: 1Gi}]\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("numa cells have 2 cpus in total, spec has 4"));
+        assert!(err.contains("numa cells have 1 GiB memory in total"));
+    }
+
+    #[test]
+    fn placement() {
+        let yaml = sample.to_string()
+            + "  placement:\n    cpu: dedicated\n    hostCpus: [2, 3, 4, 5]\n    emulatorCpus: [0]\n    memoryLocked: true\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        let placement = m.spec.placement.as_ref().unwrap();
+        assert_eq!(placement.cpu, CpuPolicy::Dedicated);
+        assert_eq!(placement.host_cpus, [2, 3, 4, 5]);
+        assert!(placement.memory_locked);
+
+        let yaml = sample.to_string() + "  placement: {cpuShares: 256, cpuLimit: 50}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        assert_eq!(m.spec.placement.as_ref().unwrap().cpu, CpuPolicy::Shared);
+
+        let yaml = sample.to_string()
+            + "  placement: {cpu: dedicated, hostCpus: [2, 2], emulatorCpus: [2], cpuLimit: 150}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("needs a hostCpus entry per vcpu, 2 for 4"));
+        assert!(err.contains("cpuShares and cpuLimit only apply to shared cpus"));
+        assert!(err.contains("host cpu 2 is given twice"));
+        assert!(err.contains("host cpu 2 is both a vcpu's and the emulator's"));
+        assert!(err.contains("cpuLimit must be 1 to 100 percent"));
+    }
+
+    #[test]
+    fn health_check() {
+        let yaml = sample.to_string()
+            + "  healthCheck:\n    http: http://{address}:8080/healthz\n    interval: 10\n    restart: true\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        let check = m.spec.health_check.as_ref().unwrap();
+        assert_eq!(check.interval(), Duration::from_secs(10));
+        assert_eq!(check.timeout(), Duration::from_secs(5));
+        assert_eq!(check.retries(), 3);
+        assert_eq!(check.start_period(), Duration::from_secs(300));
+        assert!(check.restart);
+
+        let yaml = sample.to_string() + "  healthCheck: {exec: [systemctl, is-active, nginx]}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+
+        let yaml = sample.to_string()
+            + "  healthCheck: {tcp: 22, http: 'https://{address}/', interval: 2, timeout: 5}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("needs exactly one of tcp, http and exec"));
+        assert!(err.contains("'https://{address}/' is not an http URL"));
+        assert!(err.contains("timeout can't be longer than its interval"));
+    }
+
+    #[test]
+    fn restart_policy() {
+        let yaml = sample.to_string() + "  restartPolicy: on-failure\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(m.spec.restart_policy, Some(RestartPolicy::OnFailure));
+        assert!(m.to_yaml().unwrap().contains("restartPolicy: on-failure"));
+
+        let yaml = sample.to_string() + "  restartPolicy: sometimes\n";
+        assert!(serde_yaml::from_str::<Resource>(&yaml).is_err());
+    }
+
+    #[test]
+    fn depends_on() {
+        let yaml = sample.to_string() + "  dependsOn: [db, {name: cache, ready: true}]\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        let deps = m.spec.depends_on.as_ref().unwrap();
+        assert_eq!(deps[0], Dependency::Name("db".to_string()));
+        assert!(!deps[0].ready());
+        assert_eq!(deps[1].name(), "cache");
+        assert!(deps[1].ready());
+
+        let machine = |name: &str, deps: &[&str]| {
+            let Resource::Machine(mut m) = serde_yaml::from_str(sample).unwrap();
+            m.metadata.name = name.to_string();
+            let deps = deps.iter().map(|d| Dependency::Name(d.to_string()));
+            m.spec.depends_on = Some(deps.collect());
+            m
+        };
+        let names = |machines: Vec<Machine>| -> Vec<String> {
+            machines.into_iter().map(|m| m.metadata.name).collect()
+        };
+
+        let machines = vec![
+            machine("app", &["db", "cache"]),
+            machine("db", &[]),
+            machine("cache", &["elsewhere"]),
+            machine("web", &[]),
+        ];
+        assert_eq!(
+            names(dependency_order(machines).unwrap()),
+            ["db", "cache", "app", "web"]
+        );
+
+        let machines = vec![
+            machine("a", &["b"]),
+            machine("b", &["a"]),
+            machine("c", &[]),
+        ];
+        let err = dependency_order(machines).unwrap_err().to_string();
+        assert!(err.contains("cycle among a, b"), "{}", err);
+
+        let m = machine("a", &["a"]);
+        assert!(m
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("depend on itself"));
+    }
+
+    #[test]
+    fn nested_virt() {
+        let yaml = sample.to_string() + "  nestedVirt: true\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        assert_eq!(m.spec.nested_virt, Some(true));
+
+        let yaml = yaml + "  cpuModel: {mode: host-model, remove: [vmx]}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("nestedVirt needs cpu feature 'vmx', which cpuModel removes"));
+    }
+
+    #[test]
+    fn direct_boot() {
+        let yaml = sample.to_string()
+            + "  directBoot:\n    kernel: /srv/boot/vmlinuz\n    initrd: file:///srv/boot/initrd.img\n    cmdline: root=/dev/vda1 console=ttyS0\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        let boot = m.spec.direct_boot.as_ref().unwrap();
+        assert_eq!(boot.kernel_path().unwrap(), Path::new("/srv/boot/vmlinuz"));
+        assert_eq!(
+            boot.initrd_path().unwrap().unwrap(),
+            Path::new("/srv/boot/initrd.img")
+        );
+        assert_eq!(
+            boot.cmdline.as_deref(),
+            Some("root=/dev/vda1 console=ttyS0")
+        );
+
+        let yaml = sample.to_string()
+            + "  directBoot: {kernel: boot/vmlinuz, initrd: 'https://example.com/initrd'}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("directBoot kernel: 'boot/vmlinuz' is not an absolute path"));
+        assert!(
+            err.contains("directBoot initrd: 'https://example.com/initrd' is not a file:// URL")
+        );
+    }
+
+    #[test]
+    fn disk_bus_targets() {
+        let yaml = sample.to_string() + "  storageBus: scsi\n";
+        let m = match serde_yaml::from_str(&yaml).unwrap() {
+            Resource::Machine(m) => m,
+        };
+        assert_eq!(m.spec.storage_bus, Some(DiskBus::Scsi));
+
+        let virtio = DiskBus::Virtio;
+        assert_eq!(virtio.target(0).unwrap(), "vdb");
+        assert_eq!(virtio.target(24).unwrap(), "vdz");
+        assert_eq!(
+            virtio.target(25).unwrap_err(),
+            TooManyDisksError {
+                bus: DiskBus::Virtio,
+                count: 26
+            }
+        );
+        assert_eq!(virtio.index("vdc"), Some(1));
+        assert_eq!(virtio.index("vda"), None);
+        assert_eq!(virtio.index("sdb"), None);
+
+        let scsi = DiskBus::Scsi;
+        for (i, name) in [
+            (0, "sda"),
+            (25, "sdz"),
+            (26, "sdaa"),
+            (701, "sdzz"),
+            (702, "sdaaa"),
+        ] {
+            assert_eq!(scsi.target(i).unwrap(), name);
+            assert_eq!(scsi.index(name), Some(i));
+        }
+        assert!(scsi.target(SCSI_MAX_DISKS - 1).is_ok());
+        assert!(scsi.target(SCSI_MAX_DISKS).is_err());
+        assert_eq!(scsi.index("sd"), None);
+        assert_eq!(scsi.index("sdA"), None);
+        assert_eq!(scsi.index("vdb"), None);
+    }
+
+    #[test]
+    fn nic_names() {
+        let hash = "754129c5052756ee47a0c395e518bd3413f444dff69b98f8a8fa42f2fa3acc2d";
+        let builder = Machine::builder()
+            .name("vm")
+            .cpu(1)
+            .memory("1Gi")
+            .image("file:///images/ubuntu.img", hash);
+
+        let m = builder
+            .clone()
+            .nic(Nic::bridge("br0").with_name("uplink0"))
+            .nic(Nic::bridge("br1"))
+            .build()
+            .unwrap();
+        let yaml = m.to_yaml().unwrap();
+        assert!(yaml.contains("name: uplink0"));
+        assert!(!yaml.contains("macaddress"));
+
+        // the MAC picked on create is stored with the machine
+        let mut m = m;
+        m.spec.nics.as_mut().unwrap()[0].macaddress = "52:54:00:12:34:56".to_string();
+        let stored: Machine = serde_yaml::from_str(&m.to_yaml().unwrap()).unwrap();
+        assert_eq!(stored.spec.nics.unwrap()[0].macaddress, "52:54:00:12:34:56");
+
+        let err = builder
+            .nic(Nic::bridge("br0").with_name("uplink0"))
+            .nic(Nic::bridge("br1").with_name("uplink0"))
+            .nic(Nic::bridge("br2").with_name("a-name-far-too-long"))
+            .nic(Nic::bridge("br3").with_name("eth 1"))
+            .build()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("duplicate nic name 'uplink0'"));
+        assert!(err.contains("invalid nic name 'a-name-far-too-long'"));
+        assert!(err.contains("invalid nic name 'eth 1'"));
+    }
+
+    #[test]
+    fn port_forwards() {
+        let yaml = sample.to_string()
+            + "  portForwards:\n    - {hostPort: 2222, guestPort: 22}\n    - {hostPort: 5353, guestPort: 53, protocol: udp}\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let forwards = m.spec.port_forwards.as_ref().unwrap();
+        assert_eq!(forwards[0].protocol, None);
+        assert_eq!(forwards[1].protocol, Some(Protocol::Udp));
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("portForwards need a Network nic"));
+
+        let builder = Machine::builder()
+            .name("vm")
+            .cpu(1)
+            .memory("1Gi")
+            .image(
+                "file:///images/ubuntu.img",
+                "754129c5052756ee47a0c395e518bd3413f444dff69b98f8a8fa42f2fa3acc2d",
+            )
+            .nic(Nic::network("default"))
+            .port_forward(8080, 80);
+        let m = builder.clone().build().unwrap();
+        assert!(m
+            .to_yaml()
+            .unwrap()
+            .contains("portForwards:\n  - hostPort: 8080\n    guestPort: 80\n"));
+
+        let err = builder.port_forward(8080, 8080).build().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("host port 8080/tcp is forwarded twice"));
+    }
+
+    #[test]
+    fn pool_addresses() {
+        let yaml = sample.replace(
+            "  nics:\n",
+            "  nics:\n    - kind: Bridge\n      parent: br0\n      address:\n        kind: Pool\n        pool: lab\n",
+        );
+        let Resource::Machine(mut m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        let nics = m.spec.nics.as_mut().unwrap();
+        assert_eq!(nics[0].address.ipv4_static(), None);
+        assert!(nics[2].address.ipv4_static().is_some());
+
+        // the assignment is kept with the pool's name
+        let assigned = IPv4Static {
+            addr: String::from("192.168.50.100/24"),
+            gateway: String::from("192.168.50.1"),
+            nameservers: Vec::new(),
+        };
+        if let AddressKind::Pool(ref mut p) = nics[0].address {
+            p.assigned = Some(assigned.clone());
+        }
+        assert_eq!(nics[0].address.ipv4_static(), Some(&assigned));
+        assert!(m.to_yaml().unwrap().contains(
+            "    address:\n      kind: Pool\n      pool: lab\n      assigned:\n        addr: 192.168.50.100/24\n"
+        ));
+
+        let yaml = yaml.replace("pool: lab", "pool: ''");
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("nic address pool needs a name"));
+    }
+
+    #[test]
+    fn nic_filtering() {
+        let yaml = sample.replace(
+            "  nics:\n",
+            "  nics:\n    - kind: Macvtap\n      parent: eth0\n      address:\n        kind: IPv6SLAAC\n      filterref:\n        filter: clean-traffic\n        parameters:\n          CTRL_IP_LEARNING: dhcp\n      trustGuestRxFilters: true\n",
+        );
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let nic = &m.spec.nics.as_ref().unwrap()[0];
+        assert_eq!(nic.filterref.as_ref().unwrap().filter, "clean-traffic");
+        assert_eq!(
+            nic.filterref.as_ref().unwrap().parameters["CTRL_IP_LEARNING"],
+            "dhcp"
+        );
+        assert_eq!(nic.trust_guest_rx_filters, Some(true));
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("filterref isn't supported on Macvtap nics"));
+
+        let Resource::Machine(m) =
+            serde_yaml::from_str(&yaml.replacen("kind: Macvtap", "kind: Bridge", 1)).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("trustGuestRxFilters is only supported on Macvtap nics"));
+
+        let m = Machine::builder()
+            .name("vm")
+            .cpu(1)
+            .memory("1Gi")
+            .image(
+                "file:///images/ubuntu.img",
+                "754129c5052756ee47a0c395e518bd3413f444dff69b98f8a8fa42f2fa3acc2d",
+            )
+            .nic(Nic::bridge("br0").with_filter("clean-traffic").isolated())
+            .build()
+            .unwrap();
+        let yaml = m.to_yaml().unwrap();
+        assert!(yaml.contains("filterref:\n      filter: clean-traffic\n"));
+        assert!(yaml.contains("isolated: true"));
+    }
+
+    #[test]
+    fn machine_uuid() {
+        let uuid = "2ec115d7-3a88-3ceb-bc12-0ac909a6fd87";
+        let yaml = sample.replace(
+            "  name: othervm\n",
+            &format!("  name: othervm\n  uuid: {}\n", uuid),
+        );
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(m.metadata.uuid.as_deref(), Some(uuid));
+        m.validate().unwrap();
+
+        let Resource::Machine(m) = serde_yaml::from_str(
+            &(yaml.clone() + "  smbios:\n    uuid: 00000000-0000-0000-0000-000000000001\n"),
+        )
+        .unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("uuid and smbios uuid must be the same"));
+
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml.replace(uuid, "nope")).unwrap();
+        assert!(m.validate().is_err());
+    }
+
+    #[test]
+    fn deserialize_smbios() {
+        let yaml = sample.to_owned()
+            + "  smbios:\n    manufacturer: ACME\n    serial: BN-0042\n    assetTag: A1\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let smbios = m.spec.smbios.clone().unwrap();
+        assert_eq!(smbios.manufacturer.as_deref(), Some("ACME"));
+        assert_eq!(smbios.asset_tag.as_deref(), Some("A1"));
+        assert_eq!(smbios.product, None);
+        m.validate().unwrap();
+
+        let Resource::Machine(m) =
+            serde_yaml::from_str(&(yaml + "    uuid: not-a-uuid\n")).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("smbios uuid 'not-a-uuid' is not a UUID"));
+    }
+
+    #[test]
+    fn input_and_video() {
+        let Resource::Machine(m) = serde_yaml::from_str(sample).unwrap();
+        assert_eq!(m.spec.input, None);
+        assert_eq!(m.spec.video, None);
+
+        let yaml = sample.to_owned() + "  input: tablet\n  video: virtio\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(m.spec.input, Some(InputDevice::Tablet));
+        assert_eq!(m.spec.video, Some(VideoModel::Virtio));
+
+        let yaml = sample.to_owned() + "  video: none\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(m.spec.video, Some(VideoModel::None));
+
+        let yaml = sample.to_owned() + "  video: vga\n";
+        assert!(serde_yaml::from_str::<Resource>(&yaml).is_err());
+
+        let yaml = sample.to_owned() + "  sound: ich9\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(m.spec.sound, Some(SoundModel::Ich9));
+    }
+
+    #[test]
+    fn usb_devices() {
+        let yaml = sample.to_owned()
+            + "  usbController: qemu-xhci\n  usbDevices:\n    - vendor: \"1050\"\n      product: \"0407\"\n    - bus: 1\n      device: 5\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        assert_eq!(m.spec.usb_controller, Some(UsbController::QemuXhci));
+        assert_eq!(
+            m.spec.usb_devices.as_deref(),
+            Some(&[UsbDevice::id("1050", "0407"), UsbDevice::address(1, 5)][..])
+        );
+
+        let yaml = sample.to_owned()
+            + "  usbController: none\n  usbDevices:\n    - vendor: yubi\n    - bus: 1\n      product: \"0407\"\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("usb device needs both vendor and product"));
+        assert!(err.contains("usb id 'yubi' is not 4 hex digits"));
+        assert!(err.contains("usb device needs either vendor and product or bus and device"));
+        assert!(err.contains("usbDevices need a usbController other than none"));
+    }
+
+    #[test]
+    fn mdevs() {
+        let yaml = sample.to_owned()
+            + "  mdev:\n    - uuid: 4b20d080-1b54-4048-85b3-a6a62d165c01\n    - type: nvidia-63\n      parent: \"0000:41:00.0\"\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        let mdevs = m.spec.mdev.as_ref().unwrap();
+        assert_eq!(
+            mdevs[0],
+            Mdev::existing("4b20d080-1b54-4048-85b3-a6a62d165c01")
+        );
+        assert_eq!(mdevs[1].kind.as_deref(), Some("nvidia-63"));
+
+        let yaml =
+            sample.to_owned() + "  mdev:\n    - parent: \"0000:41:00.0\"\n    - uuid: gpu0\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("mdev needs a uuid or a type"));
+        assert!(err.contains("mdev parent is only used with a type"));
+        assert!(err.contains("mdev uuid 'gpu0' is not a UUID"));
+    }
+
+    #[test]
+    fn scheduling() {
+        let yaml = sample.to_owned()
+            + "  scheduling:\n    hostSelector:\n      zone: a\n    antiAffinity: [app=web]\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+        let scheduling = m.spec.scheduling.as_ref().unwrap();
+        assert_eq!(scheduling.host_selector.as_ref().unwrap()["zone"], "a");
+        assert!(scheduling.affinity.is_empty());
+        assert_eq!(scheduling.anti_affinity, ["app=web"]);
+
+        let yaml = sample.to_owned() + "  scheduling:\n    affinity: [db]\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("scheduling: invalid selector 'db', expected key=value"));
+    }
+
+    #[test]
+    fn cloud_config() {
+        let yaml = sample.to_owned()
+            + "  cloudConfig:\n    packages: [nginx]\n    sshAuthorizedKeys: [ssh-ed25519 AAAA ops]\n    writeFiles:\n      - path: /etc/motd\n        content: hello\n    runcmd:\n      - [systemctl, restart, nginx]\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        m.validate().unwrap();
+
+        let cc = m.spec.cloud_config.as_ref().unwrap();
+        assert_eq!(cc.write_files[0].path, "/etc/motd");
+
+        let userdata: serde_yaml::Value =
+            serde_yaml::from_str(&m.spec.user_data().unwrap().unwrap()).unwrap();
+        assert_eq!(userdata["packages"][0], "nginx");
+        assert_eq!(userdata["ssh_authorized_keys"][0], "ssh-ed25519 AAAA ops");
+        // from the inline userdata
+        assert_eq!(userdata["ssh_pwauth"], true);
+
+        let Resource::Machine(m) =
+            serde_yaml::from_str(&yaml.replace("    #cloud-config\n", "    #!/bin/sh\n")).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("cloudConfig can only be combined with #cloud-config userdata"));
+    }
+
+    #[test]
+    fn userdata_file() {
+        let yaml = sample.to_owned() + "  userdataFile: [base.yaml, web.yaml]\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(
+            m.spec.userdata_file.as_ref().unwrap().paths(),
+            [PathBuf::from("base.yaml"), PathBuf::from("web.yaml")]
+        );
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("userdata and userdataFile can't be used together"));
+
+        let m = Machine::builder()
+            .name("vm1")
+            .cpu(1)
+            .memory("1Gi")
+            .image("file:///images/ubuntu.img", &"0".repeat(64))
+            .userdata_file("cloud-init.yaml")
+            .build()
+            .unwrap();
+        assert_eq!(
+            m.spec.userdata_file,
+            Some(UserdataFile::One("cloud-init.yaml".into()))
+        );
+        assert!(m
+            .to_yaml()
+            .unwrap()
+            .contains("userdataFile: cloud-init.yaml\n"));
+    }
+
+    #[test]
+    fn nic_bandwidth() {
+        let yaml = sample.replace(
+            "      parent: obsbr0\n",
+            "      parent: obsbr0\n      bandwidth:\n        inbound:\n          average: 1000\n          peak: 5000\n          burst: 1024\n        outbound:\n          average: 128\n",
+        );
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let bw = m.spec.nics.as_ref().unwrap()[0].bandwidth.clone().unwrap();
+        assert_eq!(
+            bw.inbound,
+            Some(RateLimit {
+                average: 1000,
+                peak: Some(5000),
+                burst: Some(1024),
+            })
+        );
+        assert_eq!(bw.outbound, Some(RateLimit::new(128)));
+        m.validate().unwrap();
+
+        let Resource::Machine(m) =
+            serde_yaml::from_str(&yaml.replace("peak: 5000", "peak: 500")).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("nic inbound bandwidth peak is below its average"));
+    }
+
+    #[test]
+    fn machine_builder() {
+        let hash = "754129c5052756ee47a0c395e518bd3413f444dff69b98f8a8fa42f2fa3acc2d";
+
+        let m = Machine::builder()
+            .name("othervm")
+            .label("env", "test")
+            .cpu(4)
+            .memory("512Mi")
+            .image("file:///images/ubuntu.img", hash)
+            .resize("100G")
+            .nic(Nic::bridge("virbr0"))
+            .nic(
+                Nic::macvtap("eth0").with_address(AddressKind::IPv4Static(IPv4Static {
+                    addr: "192.168.3.160/24".to_string(),
+                    gateway: "192.168.3.1".to_string(),
+                    nameservers: Vec::new(),
+                })),
+            )
+            .build()
+            .unwrap();
+
+        assert_eq!(m.metadata.labels.as_ref().unwrap()["env"], "test");
+        assert_eq!(m.spec.image.resize, Some(Size(100_000_000_000)));
+        assert_eq!(m.spec.nics.as_ref().unwrap()[1].kind, "Macvtap");
+        assert_eq!(m.spec.storage, None);
+
+        // same as the equivalent model file
+        let yaml = m.to_yaml().unwrap();
+        assert_eq!(serde_yaml::from_str::<Machine>(&yaml).unwrap(), m);
+
+        let err = Machine::builder()
+            .memory("lots")
+            .image("not a url", "abc")
+            .build()
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("name is required"));
+        assert!(err.contains("cpu must be at least 1"));
+        assert!(err.contains("invalid memory size: invalid size 'lots'"));
+        assert!(err.contains("invalid image url"));
+        assert!(err.contains("not a sha256 hex digest"));
+
+        assert!(Machine::builder()
+            .name("vm")
+            .cpu(1)
+            .memory("1Gi")
+            .build()
+            .is_err());
+
+        let mut b = Machine::builder()
+            .name("vm")
+            .cpu(1)
+            .memory("1Gi")
+            .image("file:///images/ubuntu.img", hash);
+        for i in 0..26 {
+            b = b.storage(StorageKind::File(File {
+                path: format!("/data/{}.qcow2", i).into(),
+                driver: DiskDriver::default(),
+            }));
+        }
+        assert!(b.clone().build().is_err());
+        assert!(b.storage_bus(DiskBus::Scsi).build().is_ok());
+    }
+
+    #[test]
+    fn label_selectors() {
+        let yaml = sample.replace(
+            "  name: othervm\n",
+            "  name: othervm\n  labels:\n    env: test\n    team: infra\n",
+        );
+        let m = match serde_yaml::from_str(&yaml).unwrap() {
+            Resource::Machine(m) => m,
+        };
+
+        let sel = |s: &str| s.parse::<Selector>().unwrap();
+        assert!(sel("env=test").matches(&m.metadata));
+        assert!(sel("env=test, team=infra").matches(&m.metadata));
+        assert!(!sel("env=prod").matches(&m.metadata));
+        assert!(!sel("env=test,owner=me").matches(&m.metadata));
+
+        let unlabeled = Metadata {
+            name: "vm2".to_string(),
+            labels: None,
+            uuid: None,
+            ttl: None,
+        };
+        assert!(!sel("env=test").matches(&unlabeled));
+
+        assert!("env".parse::<Selector>().is_err());
+        assert!("=test".parse::<Selector>().is_err());
+    }
+
+    #[test]
+    fn cdrom_source_url() {
+        let c = Cdrom {
+            url: Some("file:///isos/installer.iso".to_string()),
+            path: None,
+            hash: "abc".to_string(),
+        };
+        assert_eq!(c.source_url().unwrap().path(), "/isos/installer.iso");
+
+        let c = Cdrom {
+            url: None,
+            path: None,
+            hash: "abc".to_string(),
+        };
+        assert!(c.source_url().is_err());
+
+        let mut m = Machine::builder()
+            .name("vm1")
+            .cpu(1)
+            .memory("1Gi")
+            .image("file:///images/ubuntu.img", &"0".repeat(64))
+            .cdrom(Cdrom {
+                url: None,
+                path: Some("isos/installer.iso".into()),
+                hash: "abc".to_string(),
+            })
+            .build()
+            .unwrap();
+        m.resolve_files(Path::new("/srv/models")).unwrap();
+        assert_eq!(
+            m.spec.cdroms.unwrap()[0].path.as_deref(),
+            Some(Path::new("/srv/models/isos/installer.iso"))
+        );
+    }
+
+    #[test]
+    fn deserialize_disk_driver() {
+        let yaml = "kind: Block\npath: /dev/sdb\ncache: none\nio: io_uring\ndiscard: unmap\ndetectZeroes: unmap\n";
+        let s: StorageKind = serde_yaml::from_str(yaml).unwrap();
+
+        let expected = DiskDriver {
+            cache: Some(DiskCache::None),
+            io: Some(DiskIo::IoUring),
+            discard: Some(Discard::Unmap),
+            detect_zeroes: Some(DetectZeroes::Unmap),
+            ..Default::default()
+        };
+
+        match s {
+            StorageKind::Block(b) => assert_eq!(b.driver, expected),
+            _ => panic!("expected Block storage"),
+        }
+
+        let out = serde_yaml::to_string(&expected).unwrap();
+        assert!(out.contains("detectZeroes: unmap"));
+    }
+
+    #[test]
+    fn image_name() {
+        let yaml = sample.replace(
+            "    url: \"file:///home/mrodden/projects/bigiron-virt/ubuntu-22.04-server-cloudimg-amd64-disk-kvm.img\"\n    hash: 754129c5052756ee47a0c395e518bd3413f444dff69b98f8a8fa42f2fa3acc2d\n",
+            "    name: ubuntu-22.04\n",
+        );
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(m.spec.image.name.as_deref(), Some("ubuntu-22.04"));
+        assert!(m.spec.image.url.is_empty());
+        m.validate().unwrap();
+        assert!(!m.to_yaml().unwrap().contains("url:"));
+
+        // a name doesn't excuse half a source
+        let Resource::Machine(m) =
+            serde_yaml::from_str(&yaml.replace("    name:", "    url: file:///a.img\n    name:"))
+                .unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("image hash '' is not a sha256 hex digest"));
+    }
+
+    #[test]
+    fn image_mode() {
+        let Resource::Machine(m) = serde_yaml::from_str(sample).unwrap();
+        assert_eq!(m.spec.image.mode, None);
+
+        let yaml = sample.replace(
+            "    resize: 100G\n",
+            "    resize: 100G\n    mode: flatten\n",
+        );
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(m.spec.image.mode, Some(ImageMode::Flatten));
+        assert!(m.to_yaml().unwrap().contains("mode: flatten"));
+
+        let yaml = sample.replace("    resize: 100G\n", "    resize: 100G\n    mode: copy\n");
+        assert!(serde_yaml::from_str::<Resource>(&yaml).is_err());
+    }
+
+    #[test]
+    fn image_encryption() {
+        let yaml = sample.replace(
+            "    resize: 100G\n",
+            "    resize: 100G\n    encryption:\n      passphraseFile: keys/vm1\n",
+        );
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let encryption = m.spec.image.encryption.as_ref().unwrap();
+        assert_eq!(
+            encryption.passphrase_file.as_deref(),
+            Some(Path::new("keys/vm1"))
+        );
+        m.validate().unwrap();
+        assert!(m.to_yaml().unwrap().contains("passphraseFile: keys/vm1"));
+
+        let yaml = yaml.replace(
+            "    encryption:\n",
+            "    encryption:\n      secret: 6f1e2c52-0b1d-4a8e-9c4f-2d7a9b3e5f10\n",
+        );
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("either secret or passphraseFile, not both"));
+    }
+
+    #[test]
+    fn deserialize_iotune() {
+        let yaml = "kind: File\npath: /var/lib/data.qcow2\niotune:\n  totalIopsSec: 1000\n  writeBytesSec: 50Mi\n";
+        let s: StorageKind = serde_yaml::from_str(yaml).unwrap();
+
+        let expected = IoTune {
+            total_iops_sec: Some(1000),
+            write_bytes_sec: Some(Size(50 << 20)),
+            ..Default::default()
+        };
+        assert_eq!(s.driver().iotune, Some(expected));
+
+        let yaml = sample.replace(
+            "    resize: 100G\n",
+            "    resize: 100G\n    iotune:\n      totalBytesSec: 100M\n      readBytesSec: 50M\n",
+        );
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("iotune totalBytesSec can't be combined"));
+    }
+
+    #[test]
+    fn deserialize_seclabel() {
+        let yaml = sample.to_owned()
+            + "  seclabel:\n    model: selinux\n    type: static\n    label: system_u:system_r:svirt_t:s0:c10,c20\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let seclabel = m.spec.seclabel.clone().unwrap();
+        assert_eq!(seclabel.model, Some(SecurityModel::Selinux));
+        assert_eq!(seclabel.kind, Some(SecLabelKind::Static));
+        m.validate().unwrap();
+
+        let Resource::Machine(m) =
+            serde_yaml::from_str(&yaml.replace("type: static", "type: dynamic")).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("seclabel label is only used with type static"));
+
+        let yaml = "kind: Block\npath: /dev/sdb\nseclabel:\n  model: selinux\n  relabel: false\n";
+        let s: StorageKind = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(s.driver().seclabel.as_ref().unwrap().relabel, Some(false));
+
+        let yaml = sample.replace(
+            "    resize: 100G\n",
+            "    resize: 100G\n    seclabel:\n      type: none\n",
+        );
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("disk seclabel can't have a type"));
+    }
+
+    #[test]
+    fn deserialize_network_disks() {
+        let yaml = "
+- kind: Rbd
+  pool: vms
+  image: data01
+  monitors: [mon1:6789, mon2]
+  auth:
+    username: libvirt
+    secret: 2ec115d7-3a88-3ceb-bc12-0ac909a6fd87
+- kind: Iscsi
+  portal: san01:3260
+  target: iqn.2013-07.com.example:storage
+- kind: Ephemeral
+  size: 20Gi
+  tmpfs: true
+";
+        let s: Vec<StorageKind> = serde_yaml::from_str(yaml).unwrap();
+
+        match &s[0] {
+            StorageKind::Rbd(rbd) => {
+                assert_eq!(rbd.monitors.len(), 2);
+                assert_eq!(rbd.auth.as_ref().unwrap().username, "libvirt");
+            }
+            _ => panic!("expected Rbd storage"),
+        }
+
+        match &s[1] {
+            StorageKind::Iscsi(iscsi) => {
+                assert_eq!(iscsi.lun, 0);
+                assert!(iscsi.auth.is_none());
+            }
+            _ => panic!("expected Iscsi storage"),
+        }
+
+        match &s[2] {
+            StorageKind::Ephemeral(scratch) => {
+                assert_eq!(scratch.size.bytes(), 20 * 1024 * 1024 * 1024);
+                assert_eq!(scratch.tmpfs, Some(true));
+            }
+            _ => panic!("expected Ephemeral storage"),
+        }
+    }
+
+    #[test]
+    fn deserialize_confidential() {
+        let yaml = "kind: SEVSNP\npolicy: 196608\ncbitpos: 51\n";
+        let c: Confidential = serde_yaml::from_str(yaml).unwrap();
+
+        assert_eq!(
+            c,
+            Confidential::SEVSNP(Sev {
+                policy: Some(0x30000),
+                cbitpos: Some(51),
+                reduced_phys_bits: None,
+            })
+        );
+
+        let c: Confidential = serde_yaml::from_str("kind: S390PV").unwrap();
+        assert_eq!(c, Confidential::S390PV);
+
+        let yaml = sample.to_owned() + "  confidential:\n    kind: S390PV\n";
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("confidential S390PV needs an s390x domain"));
+    }
+
     #[test]
     fn test_sizestring_to_size() {
         assert_eq!(to_size("100M").unwrap(), 100_000_000);
@@ -230,6 +3908,149 @@ spec:
         assert_eq!(to_size("12g").unwrap(), 12_000_000_000);
         assert_eq!(to_size("12Gi").unwrap(), 12 * 1024 * 1024 * 1024);
 
+        assert_eq!(to_size("2T").unwrap(), 2_000_000_000_000);
+        assert_eq!(to_size("2Ti").unwrap(), 2 * 1024 * 1024 * 1024 * 1024);
+
+        assert_eq!(to_size("1.5Gi").unwrap(), 3 * 512 * 1024 * 1024);
+        assert_eq!(to_size("0.5K").unwrap(), 500);
+        assert_eq!(to_size("1024").unwrap(), 1024);
+        assert_eq!(to_size("1024B").unwrap(), 1024);
+        assert_eq!(to_size("512MiB").unwrap(), 512 * 1024 * 1024);
+        assert_eq!(to_size("2GB").unwrap(), 2_000_000_000);
+        assert_eq!(to_size("1.5 GiB").unwrap(), 3 * 512 * 1024 * 1024);
+
         assert!(to_size("12Timmies").is_err());
+        assert!(to_size("").is_err());
+        assert!(to_size("G").is_err());
+        assert!(to_size("1Gé").is_err());
+        assert!(to_size("1.2.3G").is_err());
+        assert!(to_size("20Q").is_err());
+        assert!(to_size("16EiB").is_err());
+        assert!(to_size("99999999999999999999").is_err());
+        assert!(to_size("20000000Pi").is_err());
+
+        let err = "1.5".parse::<Size>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid size '1.5': not a whole number of bytes"
+        );
+        let err = "10X".parse::<Size>().unwrap_err();
+        assert_eq!(err.to_string(), "invalid size '10X': unknown suffix");
+    }
+
+    #[test]
+    fn host_defaults() {
+        let yaml = "kind: Machine\nmetadata:\n  name: web1\nspec:\n  memory: 8Gi\n  image:\n    resize: 20G\n";
+        let Resource::Machine(mut m) = serde_yaml::from_str(yaml).unwrap();
+
+        let err = m.validate().unwrap_err().to_string();
+        assert!(err.contains("cpu must be at least 1"));
+        assert!(err.contains("image is required"));
+
+        let defaults = SpecDefaults {
+            cpu: Some(2),
+            memory: Some(Size(4 << 30)),
+            image: Some(Image {
+                name: Some("ubuntu-22.04".to_string()),
+                ..Default::default()
+            }),
+            nics: None,
+            userdata: Some("#cloud-config\npackages: [htop]\n".to_string()),
+        };
+        m.apply_defaults(&defaults);
+        m.validate().unwrap();
+
+        assert_eq!(m.spec.cpu, 2);
+        // set in the model
+        assert_eq!(m.spec.memory, Size(8 << 30));
+        assert_eq!(m.spec.image.name.as_deref(), Some("ubuntu-22.04"));
+        assert_eq!(m.spec.image.resize, Some(Size(20_000_000_000)));
+        assert_eq!(m.spec.userdata, defaults.userdata);
+
+        // only used for what's missing
+        let Resource::Machine(mut full) = serde_yaml::from_str(sample).unwrap();
+        let before = full.clone();
+        full.apply_defaults(&defaults);
+        assert_eq!(full, before);
+    }
+
+    #[test]
+    fn ttls() {
+        let ttl: Ttl = "4h".parse().unwrap();
+        assert_eq!(ttl.0, Duration::from_secs(4 * 3600));
+        assert_eq!("1d12h".parse::<Ttl>().unwrap().to_string(), "1d12h");
+        assert_eq!("90m".parse::<Ttl>().unwrap().to_string(), "1h30m");
+        assert_eq!("45s".parse::<Ttl>().unwrap().to_string(), "45s");
+
+        for bad in ["", "4", "h", "0s", "4w", "-1h", "99999999999999999d"] {
+            assert!(bad.parse::<Ttl>().is_err(), "{}", bad);
+        }
+
+        let yaml = sample.replace("  name: othervm\n", "  name: othervm\n  ttl: 4h\n");
+        let Resource::Machine(m) = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(m.metadata.ttl, Some(ttl));
+        assert!(m.to_yaml().unwrap().contains("ttl: 4h\n"));
+
+        let err = serde_yaml::from_str::<Resource>(
+            &sample.replace("  name: othervm\n", "  name: othervm\n  ttl: soon\n"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("invalid ttl 'soon'"));
+    }
+
+    #[test]
+    fn size_serde() {
+        let sizes: Vec<Size> = serde_yaml::from_str("[512Mi, 1.5Gi, 1073741824, 100G]").unwrap();
+        assert_eq!(
+            sizes,
+            [
+                Size(512 * 1024 * 1024),
+                Size(3 * 512 * 1024 * 1024),
+                Size(1024 * 1024 * 1024),
+                Size(100_000_000_000),
+            ]
+        );
+
+        assert_eq!(
+            serde_yaml::to_string(&sizes).unwrap(),
+            "- 512Mi\n- 1536Mi\n- 1Gi\n- 100G\n"
+        );
+
+        assert!(serde_yaml::from_str::<Size>("-1").is_err());
+        assert!(serde_yaml::from_str::<Size>("12Q").is_err());
+    }
+
+    #[test]
+    fn size_display() {
+        assert_eq!(Size(100).to_string(), "100 B");
+        assert_eq!(Size(1024).to_string(), "1 KiB");
+        assert_eq!(Size(512 * 1024 * 1024).to_string(), "512 MiB");
+        assert_eq!(Size(3 * 512 * 1024 * 1024).to_string(), "1.5 GiB");
+        assert_eq!(Size(100_000_000_000).to_string(), "93.1 GiB");
+        assert_eq!(Size(3 * 1024u64.pow(4)).to_string(), "3 TiB");
+
+        assert_eq!(Rate(1536.0).to_string(), "1.5 KiB/s");
+        assert_eq!(Rate(0.0).to_string(), "0 B/s");
+    }
+
+    #[test]
+    fn size_round_trip() {
+        let sizes = [
+            0,
+            1,
+            1000,
+            1024,
+            1536 * 1024 * 1024,
+            12 * 1024 * 1024 * 1024,
+            100_000_000_000,
+            2 * 1024u64.pow(4),
+        ];
+
+        for n in sizes {
+            let s = format!("{:#}", Size(n));
+            assert_eq!(s.parse::<Size>().unwrap(), Size(n), "{}", s);
+        }
+
+        assert_eq!(format!("{:#}", Size(1536 * 1024 * 1024)), "1536Mi");
     }
 }
@@ -0,0 +1,150 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Portable machine archives, a plain tarball holding:
+//!
+//! - `machine.yaml`, the machine as created including generated MACs
+//! - `disk.qcow2`, the instance disk flattened so it has no backing file
+//! - `cidata.iso`, the config drive
+
+use std::path::Path;
+use std::process::Command;
+
+use tracing::debug;
+
+use crate::api::models::Machine;
+use crate::error::Error;
+
+pub const MACHINE_FILE: &str = "machine.yaml";
+pub const DISK_FILE: &str = "disk.qcow2";
+pub const CONFIG_DRIVE_FILE: &str = "cidata.iso";
+
+/// Pack the archive members found in `dir` into `archive`
+pub fn pack<P: AsRef<Path>, A: AsRef<Path>>(dir: P, archive: A) -> Result<(), Error> {
+    let mut cmd = Command::new("tar");
+    cmd.arg("--create")
+        .arg("--sparse")
+        .arg("--file")
+        .arg(archive.as_ref())
+        .arg("--directory")
+        .arg(dir.as_ref())
+        .args([MACHINE_FILE, DISK_FILE, CONFIG_DRIVE_FILE]);
+
+    run(cmd)
+}
+
+/// Extract `members` of `archive` into `dir`
+pub fn unpack<A: AsRef<Path>, P: AsRef<Path>>(
+    archive: A,
+    dir: P,
+    members: &[&str],
+) -> Result<(), Error> {
+    let mut cmd = Command::new("tar");
+    cmd.arg("--extract")
+        .arg("--file")
+        .arg(archive.as_ref())
+        .arg("--directory")
+        .arg(dir.as_ref())
+        .args(members);
+
+    run(cmd)
+}
+
+/// Read the machine spec from an archive without extracting anything else
+pub fn read_machine<A: AsRef<Path>>(archive: A) -> Result<Machine, Error> {
+    let output = Command::new("tar")
+        .arg("--extract")
+        .arg("--to-stdout")
+        .arg("--file")
+        .arg(archive.as_ref())
+        .arg(MACHINE_FILE)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "error reading {} from {:?}: {}",
+            MACHINE_FILE,
+            archive.as_ref(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(serde_yaml::from_slice(&output.stdout)?)
+}
+
+fn run(mut cmd: Command) -> Result<(), Error> {
+    debug!("Running: {:?}", cmd);
+    let output = cmd
+        .output()
+        .map_err(|e| format!("error executing tar: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "tar failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pack_and_read() {
+        let dir = std::env::temp_dir().join("bigiron-virt-archive-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("out")).unwrap();
+
+        let machine: Machine = serde_yaml::from_str(
+            "
+metadata:
+  name: vm1
+status: null
+spec:
+  cpu: 2
+  memory: 1Gi
+  image:
+    url: file:///vm1.qcow2
+    hash: abc1234
+",
+        )
+        .unwrap();
+
+        std::fs::write(dir.join(MACHINE_FILE), machine.to_yaml().unwrap()).unwrap();
+        std::fs::write(dir.join(DISK_FILE), b"disk").unwrap();
+        std::fs::write(dir.join(CONFIG_DRIVE_FILE), b"iso").unwrap();
+
+        let archive = dir.join("vm1.tar");
+        pack(&dir, &archive).unwrap();
+
+        assert_eq!(read_machine(&archive).unwrap(), machine);
+
+        unpack(&archive, dir.join("out"), &[DISK_FILE]).unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("out").join(DISK_FILE)).unwrap(),
+            b"disk"
+        );
+        assert!(!dir.join("out").join(CONFIG_DRIVE_FILE).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
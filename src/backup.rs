@@ -0,0 +1,233 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Machine backups, kept as `<directory>/<machine>/<timestamp>/` holding the
+//! same files as an export archive.
+
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::archive;
+use crate::config::BackupConfig;
+use crate::error::Error;
+use crate::statestore::DirectoryStore;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackupInfo {
+    pub machine: String,
+    pub name: String,
+    pub path: PathBuf,
+    pub size_bytes: u64,
+}
+
+pub struct BackupStore {
+    store: DirectoryStore,
+    retention: Option<usize>,
+}
+
+impl BackupStore {
+    pub fn new(config: &BackupConfig) -> Result<Self, Error> {
+        Ok(Self {
+            store: DirectoryStore::new(&config.directory)?,
+            retention: config.retention,
+        })
+    }
+
+    /// Create an empty, uniquely named backup directory for machine `id`
+    pub fn new_backup(&mut self, id: &str) -> Result<BackupInfo, Error> {
+        let machine_dir = self.store.path().join(id);
+        std::fs::create_dir_all(&machine_dir)?;
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut name = utc_timestamp(now);
+
+        // more than one backup in the same second
+        let mut n = 1;
+        while machine_dir.join(&name).exists() {
+            name = format!("{}-{}", utc_timestamp(now), n);
+            n += 1;
+        }
+
+        let path = machine_dir.join(&name);
+        std::fs::create_dir(&path)?;
+
+        Ok(BackupInfo {
+            machine: id.to_string(),
+            name,
+            path,
+            size_bytes: 0,
+        })
+    }
+
+    /// Backups of machine `id`, or of every machine, oldest first
+    pub fn list(&self, id: Option<&str>) -> Result<Vec<BackupInfo>, Error> {
+        let machines = match id {
+            Some(id) => vec![id.to_string()],
+            None => self.store.list_dirs()?,
+        };
+
+        let mut backups = Vec::new();
+        for machine in machines {
+            let machine_dir = self.store.path().join(&machine);
+            if !machine_dir.is_dir() {
+                continue;
+            }
+
+            let mut names = DirectoryStore::new(&machine_dir)?.list_dirs()?;
+            names.sort();
+
+            for name in names {
+                let path = machine_dir.join(&name);
+
+                // skip anything left behind by an interrupted backup
+                if !path.join(archive::DISK_FILE).exists() {
+                    continue;
+                }
+
+                backups.push(BackupInfo {
+                    machine: machine.clone(),
+                    size_bytes: dir_size(&path)?,
+                    name,
+                    path,
+                });
+            }
+        }
+
+        Ok(backups)
+    }
+
+    pub fn get(&self, id: &str, name: &str) -> Result<BackupInfo, Error> {
+        self.list(Some(id))?
+            .into_iter()
+            .find(|b| b.name == name)
+            .ok_or_else(|| format!("no backup '{}' of machine '{}'", name, id).into())
+    }
+
+    pub fn remove(&mut self, backup: &BackupInfo) -> Result<(), Error> {
+        std::fs::remove_dir_all(&backup.path)?;
+        Ok(())
+    }
+
+    /// Remove the oldest backups of machine `id` beyond the retention count
+    pub fn prune(&mut self, id: &str) -> Result<Vec<BackupInfo>, Error> {
+        let retention = match self.retention {
+            Some(r) => r,
+            None => return Ok(Vec::new()),
+        };
+
+        let backups = self.list(Some(id))?;
+        let excess = backups.len().saturating_sub(retention);
+
+        let pruned: Vec<_> = backups.into_iter().take(excess).collect();
+        for backup in &pruned {
+            info!("Removing expired backup {} of '{}'", backup.name, id);
+            self.remove(backup)?;
+        }
+
+        Ok(pruned)
+    }
+}
+
+fn dir_size(path: &Path) -> Result<u64, Error> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        size += entry?.metadata()?.len();
+    }
+    Ok(size)
+}
+
+// seconds since the epoch as e.g. 20231016T093000Z, which sorts by time
+pub fn utc_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // civil from days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(utc_timestamp(0), "19700101T000000Z");
+        assert_eq!(utc_timestamp(951782400), "20000229T000000Z");
+        assert_eq!(utc_timestamp(1697448645), "20231016T093045Z");
+    }
+
+    #[test]
+    fn retention() {
+        let dir = std::env::temp_dir().join("bigiron-virt-backup-test");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut store = BackupStore::new(&BackupConfig {
+            directory: dir.clone(),
+            retention: Some(2),
+        })
+        .unwrap();
+
+        let mut names = Vec::new();
+        for _ in 0..3 {
+            let b = store.new_backup("vm1").unwrap();
+            std::fs::write(b.path.join(archive::DISK_FILE), b"disk").unwrap();
+            names.push(b.name);
+        }
+
+        // an interrupted backup isn't listed or counted
+        store.new_backup("vm1").unwrap();
+
+        assert_eq!(store.list(None).unwrap().len(), 3);
+
+        let pruned = store.prune("vm1").unwrap();
+        assert_eq!(pruned.len(), 1);
+        assert_eq!(pruned[0].name, names[0]);
+
+        let left: Vec<_> = store
+            .list(Some("vm1"))
+            .unwrap()
+            .into_iter()
+            .map(|b| b.name)
+            .collect();
+        assert_eq!(left, names[1..]);
+        assert_eq!(store.get("vm1", &names[2]).unwrap().size_bytes, 4);
+        assert!(store.get("vm1", &names[0]).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,238 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Host bridges for `Bridge` nics, set up over netlink.
+//!
+//! Setting up a bridge only ever adds to the host: a missing bridge is
+//! created, the uplink enslaved to it and the address added, and both are
+//! brought up. Nothing already there is taken away, so applying the same
+//! model again changes nothing. Addresses on the uplink are left alone and
+//! usually want moving to the bridge, since an enslaved interface no
+//! longer carries the host's own traffic.
+
+use futures_util::TryStreamExt;
+use ipnet::IpNet;
+use rtnetlink::packet_route::address::AddressAttribute;
+use rtnetlink::packet_route::link::{InfoKind, LinkAttribute, LinkInfo, LinkMessage};
+use rtnetlink::{Handle, LinkBridge, LinkUnspec};
+use tracing::info;
+
+use crate::api::models::HostBridge;
+use crate::error::Error;
+
+// IFNAMSIZ less the terminating nul
+const MAX_NAME: usize = 15;
+
+/// Create bridge `bridge` on the host if it isn't there yet, and make
+/// sure it has its uplink and address
+pub fn ensure(bridge: &HostBridge) -> Result<(), Error> {
+    let address = check(bridge)?;
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+    rt.block_on(async {
+        let (conn, handle, _) = rtnetlink::new_connection()?;
+        tokio::spawn(conn);
+
+        let name = &bridge.metadata.name;
+        let index = bridge_index(&handle, name).await?;
+        if let Some(uplink) = &bridge.spec.uplink {
+            enslave(&handle, name, index, uplink).await?;
+        }
+        if let Some(address) = address {
+            add_address(&handle, name, index, address).await?;
+        }
+        handle
+            .link()
+            .set(LinkUnspec::new_with_index(index).up().build())
+            .execute()
+            .await?;
+
+        Ok(())
+    })
+}
+
+// the address of `bridge`, once its names are ones the kernel takes
+fn check(bridge: &HostBridge) -> Result<Option<IpNet>, Error> {
+    let name = &bridge.metadata.name;
+    let uplink = bridge.spec.uplink.as_deref();
+
+    for ifname in std::iter::once(name.as_str()).chain(uplink) {
+        if ifname.is_empty() || ifname.len() > MAX_NAME {
+            return Err(format!(
+                "interface name {:?} needs 1 to {} characters",
+                ifname, MAX_NAME
+            )
+            .into());
+        }
+        if ifname.contains(|c: char| c == '/' || c.is_whitespace()) {
+            return Err(format!("interface name {:?} has a '/' or a space", ifname).into());
+        }
+    }
+    if uplink == Some(name) {
+        return Err(format!("bridge {} can't be its own uplink", name).into());
+    }
+
+    bridge
+        .spec
+        .address
+        .as_deref()
+        .map(|a| {
+            a.parse()
+                .map_err(|e| format!("bridge {} address {}: {}", name, a, e).into())
+        })
+        .transpose()
+}
+
+async fn find_link(handle: &Handle, name: &str) -> Result<Option<LinkMessage>, Error> {
+    let mut links = handle.link().get().execute();
+    while let Some(link) = links.try_next().await? {
+        let named = link
+            .attributes
+            .iter()
+            .any(|a| matches!(a, LinkAttribute::IfName(n) if n == name));
+        if named {
+            return Ok(Some(link));
+        }
+    }
+    Ok(None)
+}
+
+fn is_bridge(link: &LinkMessage) -> bool {
+    link.attributes.iter().any(|a| match a {
+        LinkAttribute::LinkInfo(infos) => infos
+            .iter()
+            .any(|i| matches!(i, LinkInfo::Kind(InfoKind::Bridge))),
+        _ => false,
+    })
+}
+
+// the index of bridge `name`, created if there's no such link
+async fn bridge_index(handle: &Handle, name: &str) -> Result<u32, Error> {
+    if let Some(link) = find_link(handle, name).await? {
+        if !is_bridge(&link) {
+            return Err(format!("{} is already an interface other than a bridge", name).into());
+        }
+        return Ok(link.header.index);
+    }
+
+    handle
+        .link()
+        .add(LinkBridge::new(name).build())
+        .execute()
+        .await?;
+    info!("Created bridge {}", name);
+
+    let link = find_link(handle, name)
+        .await?
+        .ok_or_else(|| format!("bridge {} is gone after creating it", name))?;
+    Ok(link.header.index)
+}
+
+async fn enslave(handle: &Handle, name: &str, index: u32, uplink: &str) -> Result<(), Error> {
+    let link = find_link(handle, uplink)
+        .await?
+        .ok_or_else(|| format!("uplink {} of bridge {} not found", uplink, name))?;
+
+    let controller = link.attributes.iter().find_map(|a| match a {
+        LinkAttribute::Controller(c) => Some(*c),
+        _ => None,
+    });
+    let mut message = LinkUnspec::new_with_index(link.header.index).up();
+    match controller {
+        Some(c) if c == index => {}
+        Some(_) => {
+            return Err(
+                format!("uplink {} is already enslaved to another interface", uplink).into(),
+            )
+        }
+        None => {
+            message = message.controller(index);
+            info!("Enslaving {} to bridge {}", uplink, name);
+        }
+    }
+    handle.link().set(message.build()).execute().await?;
+
+    Ok(())
+}
+
+async fn add_address(handle: &Handle, name: &str, index: u32, address: IpNet) -> Result<(), Error> {
+    let mut addrs = handle
+        .address()
+        .get()
+        .set_link_index_filter(index)
+        .execute();
+    while let Some(msg) = addrs.try_next().await? {
+        let found = msg.header.prefix_len == address.prefix_len()
+            && msg
+                .attributes
+                .iter()
+                .any(|a| matches!(a, AddressAttribute::Address(ip) if *ip == address.addr()));
+        if found {
+            return Ok(());
+        }
+    }
+
+    handle
+        .address()
+        .add(index, address.addr(), address.prefix_len())
+        .execute()
+        .await?;
+    info!("Added address {} to bridge {}", address, name);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::api::models::{HostBridgeSpec, Metadata};
+
+    fn bridge(name: &str, uplink: Option<&str>, address: Option<&str>) -> HostBridge {
+        HostBridge {
+            metadata: Metadata {
+                name: name.to_string(),
+                labels: None,
+                uuid: None,
+                ttl: None,
+            },
+            spec: HostBridgeSpec {
+                uplink: uplink.map(String::from),
+                address: address.map(String::from),
+            },
+        }
+    }
+
+    #[test]
+    fn checks() {
+        let ok = check(&bridge("br0", Some("eth0"), Some("192.168.50.1/24"))).unwrap();
+        assert_eq!(ok, Some("192.168.50.1/24".parse().unwrap()));
+        assert_eq!(check(&bridge("br0", None, None)).unwrap(), None);
+
+        let err = check(&bridge("br-with-a-long-name", None, None)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "interface name \"br-with-a-long-name\" needs 1 to 15 characters"
+        );
+        let err = check(&bridge("br0", Some("br0"), None)).unwrap_err();
+        assert_eq!(err.to_string(), "bridge br0 can't be its own uplink");
+        assert!(check(&bridge("br0", Some("eth 0"), None)).is_err());
+        assert!(check(&bridge("br0", None, Some("192.168.50.1"))).is_err());
+    }
+}
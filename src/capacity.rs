@@ -0,0 +1,541 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::models::Size;
+use crate::error::Error;
+use crate::hypervisor::Hypervisor;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Datastore {
+    pub path: PathBuf,
+    /// other datastore paths on the same filesystem
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub also: Vec<PathBuf>,
+    pub total_bytes: u64,
+    pub free_bytes: u64,
+}
+
+impl Datastore {
+    pub fn probe<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let output = Command::new("df")
+            .arg("-B1")
+            .arg("--output=size,avail")
+            .arg(path.as_ref())
+            .output()?;
+
+        if !output.status.success() {
+            return Err(format!("df failed for {:?}: {:?}", path.as_ref(), output).into());
+        }
+
+        let (total_bytes, free_bytes) = parse_df(&String::from_utf8_lossy(&output.stdout))
+            .ok_or_else(|| format!("unexpected df output for {:?}", path.as_ref()))?;
+
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            also: Vec::new(),
+            total_bytes,
+            free_bytes,
+        })
+    }
+}
+
+// parses `df -B1 --output=size,avail` output
+fn parse_df(out: &str) -> Option<(u64, u64)> {
+    let line = out.lines().nth(1)?;
+    let mut fields = line.split_whitespace();
+    let total = fields.next()?.parse().ok()?;
+    let free = fields.next()?.parse().ok()?;
+    Some((total, free))
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HostCapacity {
+    pub hostname: String,
+    pub cpus: u32,
+    pub memory_bytes: u64,
+    /// memory not in use on the host right now, if the driver reports it
+    pub free_memory_bytes: Option<u64>,
+    pub datastores: Vec<Datastore>,
+}
+
+impl HostCapacity {
+    pub fn probe<P: AsRef<Path>>(hv: &dyn Hypervisor, datastores: &[P]) -> Result<Self, Error> {
+        let (cpus, memory_bytes) = hv.host_resources()?;
+
+        let hostname = std::fs::read_to_string("/proc/sys/kernel/hostname")
+            .map(|h| h.trim().to_string())
+            .unwrap_or_else(|_| String::from("localhost"));
+
+        // paths on one filesystem share its free space
+        let mut probed: Vec<(u64, Datastore)> = Vec::new();
+        for path in datastores {
+            let dev = std::fs::metadata(path)?.dev();
+            match probed.iter_mut().find(|(d, _)| *d == dev) {
+                Some((_, ds)) => ds.also.push(path.as_ref().to_path_buf()),
+                None => probed.push((dev, Datastore::probe(path)?)),
+            }
+        }
+        let datastores = probed.into_iter().map(|(_, ds)| ds).collect();
+
+        Ok(Self {
+            hostname,
+            cpus,
+            memory_bytes,
+            free_memory_bytes: hv.host_free_memory().ok(),
+            datastores,
+        })
+    }
+}
+
+/// How far allocations may exceed physical capacity, e.g. a cpu ratio of 4.0
+/// allows four vcpus per host cpu
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OvercommitRatios {
+    pub cpu: f64,
+    pub memory: f64,
+    pub disk: f64,
+}
+
+impl Default for OvercommitRatios {
+    fn default() -> Self {
+        Self {
+            cpu: 4.0,
+            memory: 1.0,
+            disk: 1.0,
+        }
+    }
+}
+
+/// Resources a machine needs, with storage given per datastore path
+#[derive(Debug, Clone, Serialize)]
+pub struct Demand {
+    pub name: String,
+    pub cpus: u32,
+    pub memory_bytes: u64,
+    pub storage: Vec<(PathBuf, u64)>,
+    /// host cpus pinned for this machine alone, its vcpus then don't count
+    /// against the shared, overcommitted ones
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub host_cpus: Vec<u32>,
+}
+
+impl Demand {
+    // vcpus that run on the shared host cpus
+    fn shared_cpus(&self) -> u64 {
+        if self.host_cpus.is_empty() {
+            self.cpus as u64
+        } else {
+            0
+        }
+    }
+}
+
+/// A machine doesn't fit on the host within the overcommit ratios
+#[derive(Debug, Clone, PartialEq)]
+pub struct InsufficientCapacity {
+    pub machine: String,
+    pub shortfalls: Vec<String>,
+}
+
+impl std::fmt::Display for InsufficientCapacity {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "insufficient capacity for '{}': {}",
+            self.machine,
+            self.shortfalls.join("; ")
+        )
+    }
+}
+
+impl std::error::Error for InsufficientCapacity {}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "result", rename_all = "lowercase")]
+pub enum Placement {
+    Fits {
+        host: String,
+        datastores: Vec<PathBuf>,
+    },
+    Rejected {
+        reasons: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Overcommit {
+    pub cpu: f64,
+    pub memory: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MachinePlan {
+    pub demand: Demand,
+    pub placement: Placement,
+}
+
+/// Outcome of simulating the creation of a set of machines on a host
+#[derive(Debug, Clone, Serialize)]
+pub struct PlanReport {
+    pub host: HostCapacity,
+    pub machines: Vec<MachinePlan>,
+    pub overcommit: Overcommit,
+}
+
+/// Places demands against a host's capacity, tracking what has already
+/// been allocated
+pub struct Planner {
+    capacity: HostCapacity,
+    ratios: OvercommitRatios,
+    used_cpus: u64,
+    used_memory: u64,
+    used_storage: Vec<u64>,
+    dedicated: Vec<u32>,
+}
+
+impl Planner {
+    /// `committed` is what existing machines already hold; their disk usage
+    /// is assumed to be reflected in the datastore free space already
+    pub fn new(capacity: HostCapacity, ratios: OvercommitRatios, committed: &[Demand]) -> Self {
+        let used_storage = vec![0; capacity.datastores.len()];
+
+        Self {
+            used_cpus: committed.iter().map(Demand::shared_cpus).sum(),
+            used_memory: committed.iter().map(|d| d.memory_bytes).sum(),
+            dedicated: committed
+                .iter()
+                .flat_map(|d| d.host_cpus.iter().copied())
+                .collect(),
+            capacity,
+            ratios,
+            used_storage,
+        }
+    }
+
+    pub fn capacity(&self) -> &HostCapacity {
+        &self.capacity
+    }
+
+    pub fn overcommit(&self) -> Overcommit {
+        Overcommit {
+            cpu: self.used_cpus as f64 / self.shared_cpus(0).max(1) as f64,
+            memory: self.used_memory as f64 / self.capacity.memory_bytes.max(1) as f64,
+        }
+    }
+
+    /// Check a demand without allocating it, returning the shortfalls
+    pub fn check(&self, demand: &Demand) -> Vec<String> {
+        let mut reasons = Vec::new();
+
+        for cpu in &demand.host_cpus {
+            if *cpu >= self.capacity.cpus {
+                reasons.push(format!(
+                    "host cpu {} doesn't exist, the host has {}",
+                    cpu, self.capacity.cpus
+                ));
+            } else if self.dedicated.contains(cpu) {
+                reasons.push(format!(
+                    "host cpu {} is already dedicated to another machine",
+                    cpu
+                ));
+            }
+        }
+
+        let shared = self.shared_cpus(demand.host_cpus.len());
+        let cpu_limit = (shared as f64 * self.ratios.cpu) as u64;
+        let cpus = self.used_cpus + demand.shared_cpus();
+        if cpus > cpu_limit {
+            if demand.host_cpus.is_empty() {
+                reasons.push(format!(
+                    "insufficient cpu: need {} vcpus, {} of {} available",
+                    demand.cpus,
+                    cpu_limit.saturating_sub(self.used_cpus),
+                    cpu_limit
+                ));
+            } else {
+                reasons.push(format!(
+                    "insufficient cpu: dedicating {} host cpus leaves {} vcpus for the {} \
+                     already shared",
+                    demand.host_cpus.len(),
+                    cpu_limit,
+                    self.used_cpus
+                ));
+            }
+        }
+
+        let memory_limit = (self.capacity.memory_bytes as f64 * self.ratios.memory) as u64;
+        let memory = self.used_memory + demand.memory_bytes;
+        if memory > memory_limit {
+            reasons.push(format!(
+                "insufficient memory: need {}, {} available",
+                Size(demand.memory_bytes),
+                Size(memory_limit.saturating_sub(self.used_memory))
+            ));
+        }
+
+        for (path, bytes) in &demand.storage {
+            match self.datastore_index(path) {
+                None => reasons.push(format!("unknown datastore {:?}", path)),
+                Some(i) => {
+                    let available = self.storage_available(i);
+                    if *bytes > available {
+                        reasons.push(format!(
+                            "insufficient disk on {:?}: need {}, {} available",
+                            path,
+                            Size(*bytes),
+                            Size(available)
+                        ));
+                    }
+                }
+            }
+        }
+
+        reasons
+    }
+
+    /// Fail with the shortfalls if a demand doesn't fit
+    pub fn require(&self, demand: &Demand) -> Result<(), InsufficientCapacity> {
+        let shortfalls = self.check(demand);
+        if !shortfalls.is_empty() {
+            return Err(InsufficientCapacity {
+                machine: demand.name.clone(),
+                shortfalls,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Place a demand, allocating its resources if it fits
+    pub fn place(&mut self, demand: &Demand) -> Placement {
+        let reasons = self.check(demand);
+        if !reasons.is_empty() {
+            return Placement::Rejected { reasons };
+        }
+
+        self.used_cpus += demand.shared_cpus();
+        self.used_memory += demand.memory_bytes;
+        self.dedicated.extend(&demand.host_cpus);
+
+        let mut datastores = Vec::new();
+        for (path, bytes) in &demand.storage {
+            if let Some(i) = self.datastore_index(path) {
+                self.used_storage[i] += bytes;
+                datastores.push(path.clone());
+            }
+        }
+
+        Placement::Fits {
+            host: self.capacity.hostname.clone(),
+            datastores,
+        }
+    }
+
+    // host cpus left to share once `more` are dedicated on top of the
+    // existing pins
+    fn shared_cpus(&self, more: usize) -> u64 {
+        (self.capacity.cpus as u64).saturating_sub((self.dedicated.len() + more) as u64)
+    }
+
+    fn datastore_index(&self, path: &Path) -> Option<usize> {
+        self.capacity
+            .datastores
+            .iter()
+            .position(|d| d.path == path || d.also.iter().any(|p| p == path))
+    }
+
+    fn storage_available(&self, i: usize) -> u64 {
+        let ds = &self.capacity.datastores[i];
+        let limit = (ds.free_bytes as f64 * self.ratios.disk) as u64;
+        limit.saturating_sub(self.used_storage[i])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn host() -> HostCapacity {
+        HostCapacity {
+            hostname: "kvm01".to_string(),
+            cpus: 4,
+            memory_bytes: 16 * 1024 * 1024 * 1024,
+            free_memory_bytes: None,
+            datastores: vec![Datastore {
+                path: "/var/lib/bigiron-virt/instances".into(),
+                also: Vec::new(),
+                total_bytes: 200 * 1024 * 1024 * 1024,
+                free_bytes: 100 * 1024 * 1024 * 1024,
+            }],
+        }
+    }
+
+    fn demand(name: &str, cpus: u32, memory_gib: u64, disk_gib: u64) -> Demand {
+        Demand {
+            name: name.to_string(),
+            cpus,
+            memory_bytes: memory_gib * 1024 * 1024 * 1024,
+            storage: vec![(
+                "/var/lib/bigiron-virt/instances".into(),
+                disk_gib * 1024 * 1024 * 1024,
+            )],
+            host_cpus: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn place_until_full() {
+        let mut p = Planner::new(host(), OvercommitRatios::default(), &[]);
+
+        assert!(matches!(
+            p.place(&demand("vm1", 8, 8, 40)),
+            Placement::Fits { .. }
+        ));
+        assert!(matches!(
+            p.place(&demand("vm2", 4, 6, 40)),
+            Placement::Fits { .. }
+        ));
+
+        match p.place(&demand("vm3", 4, 4, 40)) {
+            Placement::Rejected { reasons } => {
+                eprintln!("{:?}", reasons);
+                assert_eq!(reasons.len(), 2);
+                assert!(reasons[0].starts_with("insufficient memory"));
+                assert!(reasons[1].starts_with("insufficient disk"));
+            }
+            _ => panic!("expected vm3 to be rejected"),
+        }
+
+        let oc = p.overcommit();
+        assert_eq!(oc.cpu, 3.0);
+        assert_eq!(oc.memory, 14.0 / 16.0);
+    }
+
+    #[test]
+    fn committed_counts_against_capacity() {
+        let p = Planner::new(
+            host(),
+            OvercommitRatios::default(),
+            &[demand("existing", 16, 1, 0)],
+        );
+
+        let reasons = p.check(&demand("vm1", 1, 1, 1));
+        assert_eq!(reasons.len(), 1);
+        assert!(reasons[0].starts_with("insufficient cpu"));
+    }
+
+    #[test]
+    fn require_reports_shortfall() {
+        let ratios = OvercommitRatios {
+            cpu: 1.0,
+            ..Default::default()
+        };
+        let p = Planner::new(host(), ratios, &[]);
+
+        assert!(p.require(&demand("vm1", 4, 16, 100)).is_ok());
+
+        let err = p.require(&demand("vm2", 5, 17, 1)).unwrap_err();
+        assert_eq!(err.machine, "vm2");
+        assert_eq!(err.shortfalls.len(), 2);
+        assert_eq!(
+            err.to_string(),
+            "insufficient capacity for 'vm2': insufficient cpu: need 5 vcpus, 4 of 4 available; \
+             insufficient memory: need 17 GiB, 16 GiB available"
+        );
+    }
+
+    #[test]
+    fn dedicated_cpus_are_reserved() {
+        let pinned = |name, host_cpus: Vec<u32>| Demand {
+            cpus: host_cpus.len() as u32,
+            host_cpus,
+            ..demand(name, 0, 1, 1)
+        };
+        let mut p = Planner::new(host(), OvercommitRatios::default(), &[]);
+
+        assert!(matches!(
+            p.place(&pinned("rt1", vec![2, 3])),
+            Placement::Fits { .. }
+        ));
+
+        let reasons = p.check(&pinned("rt2", vec![3, 4]));
+        assert_eq!(
+            reasons,
+            vec![
+                "host cpu 3 is already dedicated to another machine",
+                "host cpu 4 doesn't exist, the host has 4",
+            ]
+        );
+
+        // only host cpus 0 and 1 are left to overcommit
+        assert!(matches!(
+            p.place(&demand("vm1", 8, 1, 1)),
+            Placement::Fits { .. }
+        ));
+        let reasons = p.check(&demand("vm2", 1, 1, 1));
+        assert_eq!(reasons.len(), 1);
+        assert!(reasons[0].starts_with("insufficient cpu"));
+        assert_eq!(p.overcommit().cpu, 4.0);
+
+        let reasons = p.check(&pinned("rt3", vec![1]));
+        assert_eq!(
+            reasons,
+            vec![
+                "insufficient cpu: dedicating 1 host cpus leaves 4 vcpus for the 8 already shared"
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_filesystem() {
+        let mut capacity = host();
+        capacity.datastores[0]
+            .also
+            .push("/var/lib/bigiron-virt/images".into());
+        let mut p = Planner::new(capacity, OvercommitRatios::default(), &[]);
+
+        let mut vm1 = demand("vm1", 1, 1, 60);
+        vm1.storage.push((
+            "/var/lib/bigiron-virt/images".into(),
+            30 * 1024 * 1024 * 1024,
+        ));
+        assert!(matches!(p.place(&vm1), Placement::Fits { .. }));
+
+        let mut vm2 = demand("vm2", 1, 1, 0);
+        vm2.storage[0].0 = "/var/lib/bigiron-virt/images".into();
+        vm2.storage[0].1 = 20 * 1024 * 1024 * 1024;
+        let reasons = p.check(&vm2);
+        assert_eq!(
+            reasons,
+            vec!["insufficient disk on \"/var/lib/bigiron-virt/images\": need 20 GiB, 10 GiB available"]
+        );
+    }
+
+    #[test]
+    fn df_output() {
+        let out = "    1B-blocks       Avail\n 502468108288 120393564160\n";
+        assert_eq!(parse_df(out), Some((502468108288, 120393564160)));
+        assert_eq!(parse_df("garbage"), None);
+    }
+}
@@ -0,0 +1,292 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Typed cloud-config for `spec.cloudConfig`.
+//!
+//! Covers the handful of cloud-config modules most machines need, written
+//! as part of the model instead of as YAML in a string. Anything else still
+//! goes in `userdata`, which is merged with the rendered cloud-config.
+
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping, Value};
+
+use crate::error::Error;
+use crate::userdata::CLOUD_CONFIG_HEADER;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CloudConfig {
+    /// users to create. Given at all, the image's default user is only
+    /// kept if listed as a user named `default`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub users: Vec<User>,
+
+    /// keys for the default user
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_authorized_keys: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub write_files: Vec<WriteFile>,
+
+    /// commands run once on first boot, in order
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub runcmd: Vec<RunCmd>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub name: String,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+
+    /// sudoers rule, e.g. `ALL=(ALL) NOPASSWD:ALL`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sudo: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ssh_authorized_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct WriteFile {
+    pub path: String,
+    pub content: String,
+
+    /// octal mode, e.g. `0644`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<String>,
+
+    /// `user:group`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+}
+
+/// A command run by a shell, or an argv run as it is
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum RunCmd {
+    Shell(String),
+    Exec(Vec<String>),
+}
+
+impl User {
+    pub fn new(name: &str) -> Self {
+        User {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        let mut m = Mapping::new();
+        put(&mut m, "name", self.name.as_str());
+        put_list(&mut m, "groups", &self.groups);
+        put_opt(&mut m, "sudo", &self.sudo);
+        put_opt(&mut m, "shell", &self.shell);
+        put_list(&mut m, "ssh_authorized_keys", &self.ssh_authorized_keys);
+        Value::Mapping(m)
+    }
+}
+
+impl WriteFile {
+    pub fn new(path: &str, content: &str) -> Self {
+        WriteFile {
+            path: path.to_string(),
+            content: content.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        let mut m = Mapping::new();
+        put(&mut m, "path", self.path.as_str());
+        put(&mut m, "content", self.content.as_str());
+        put_opt(&mut m, "permissions", &self.permissions);
+        put_opt(&mut m, "owner", &self.owner);
+        Value::Mapping(m)
+    }
+}
+
+impl CloudConfig {
+    pub fn is_empty(&self) -> bool {
+        *self == CloudConfig::default()
+    }
+
+    /// Render as a `#cloud-config` user-data document
+    pub fn render(&self) -> Result<String, Error> {
+        let mut m = Mapping::new();
+
+        if !self.users.is_empty() {
+            let users = self.users.iter().map(|u| match u.name.as_str() {
+                // cloud-init's name for the image's own user
+                "default" if *u == User::new("default") => Value::from("default"),
+                _ => u.to_value(),
+            });
+            m.insert("users".into(), Value::Sequence(users.collect()));
+        }
+        put_list(&mut m, "ssh_authorized_keys", &self.ssh_authorized_keys);
+        put_list(&mut m, "packages", &self.packages);
+        if !self.write_files.is_empty() {
+            let files = self.write_files.iter().map(WriteFile::to_value);
+            m.insert("write_files".into(), Value::Sequence(files.collect()));
+        }
+        if !self.runcmd.is_empty() {
+            m.insert("runcmd".into(), serde_yaml::to_value(&self.runcmd)?);
+        }
+
+        Ok(format!(
+            "{}\n{}",
+            CLOUD_CONFIG_HEADER,
+            serde_yaml::to_string(&m)?
+        ))
+    }
+
+    pub(crate) fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for user in &self.users {
+            if user.name.is_empty() {
+                problems.push(String::from("cloudConfig user name is required"));
+            }
+        }
+
+        for file in &self.write_files {
+            if !file.path.starts_with('/') {
+                problems.push(format!(
+                    "cloudConfig file path '{}' must be absolute",
+                    file.path
+                ));
+            }
+            if let Some(ref mode) = file.permissions {
+                if u32::from_str_radix(mode, 8).map_or(true, |m| m > 0o7777) {
+                    problems.push(format!(
+                        "cloudConfig file permissions '{}' are not an octal mode",
+                        mode
+                    ));
+                }
+            }
+        }
+
+        for cmd in &self.runcmd {
+            let empty = match cmd {
+                RunCmd::Shell(s) => s.trim().is_empty(),
+                RunCmd::Exec(argv) => argv.is_empty(),
+            };
+            if empty {
+                problems.push(String::from("cloudConfig runcmd can't be empty"));
+            }
+        }
+
+        problems
+    }
+}
+
+fn put(m: &mut Mapping, key: &str, value: &str) {
+    m.insert(key.into(), value.into());
+}
+
+fn put_opt(m: &mut Mapping, key: &str, value: &Option<String>) {
+    if let Some(ref v) = value {
+        put(m, key, v);
+    }
+}
+
+fn put_list(m: &mut Mapping, key: &str, values: &[String]) {
+    if !values.is_empty() {
+        let values = values.iter().map(|v| Value::from(v.as_str()));
+        m.insert(key.into(), Value::Sequence(values.collect()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let cc = CloudConfig {
+            users: vec![
+                User::new("default"),
+                User {
+                    groups: vec!["wheel".to_string()],
+                    sudo: Some("ALL=(ALL) NOPASSWD:ALL".to_string()),
+                    ssh_authorized_keys: vec!["ssh-ed25519 AAAA ops".to_string()],
+                    ..User::new("ops")
+                },
+            ],
+            packages: vec!["nginx".to_string()],
+            write_files: vec![WriteFile {
+                permissions: Some("0600".to_string()),
+                ..WriteFile::new("/etc/app.conf", "port: 80\n")
+            }],
+            runcmd: vec![
+                RunCmd::Shell("systemctl enable --now nginx".to_string()),
+                RunCmd::Exec(vec!["touch".to_string(), "/run/ready".to_string()]),
+            ],
+            ..Default::default()
+        };
+
+        let out = cc.render().unwrap();
+        assert!(out.starts_with("#cloud-config\n"));
+
+        let v: Value = serde_yaml::from_str(&out).unwrap();
+        assert_eq!(v["users"][0], Value::from("default"));
+        assert_eq!(v["users"][1]["name"], Value::from("ops"));
+        assert_eq!(
+            v["users"][1]["ssh_authorized_keys"][0],
+            Value::from("ssh-ed25519 AAAA ops")
+        );
+        assert_eq!(v["packages"][0], Value::from("nginx"));
+        assert_eq!(v["write_files"][0]["permissions"], Value::from("0600"));
+        assert_eq!(v["write_files"][0]["content"], Value::from("port: 80\n"));
+        assert_eq!(v["runcmd"][0], Value::from("systemctl enable --now nginx"));
+        assert_eq!(v["runcmd"][1][1], Value::from("/run/ready"));
+        assert!(v.get("ssh_authorized_keys").is_none());
+
+        assert!(cc.problems().is_empty());
+    }
+
+    #[test]
+    fn problems() {
+        let cc = CloudConfig {
+            write_files: vec![WriteFile {
+                permissions: Some("rw-r--r--".to_string()),
+                ..WriteFile::new("etc/motd", "hi")
+            }],
+            runcmd: vec![RunCmd::Exec(vec![])],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            cc.problems(),
+            [
+                "cloudConfig file path 'etc/motd' must be absolute",
+                "cloudConfig file permissions 'rw-r--r--' are not an octal mode",
+                "cloudConfig runcmd can't be empty",
+            ]
+        );
+    }
+}
@@ -0,0 +1,404 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Host level settings, read from `/etc/bigiron-virt/config.yaml`.
+//!
+//! Every setting is optional and a missing file is the same as an empty one.
+//! In session scope, run without root, the file and all state live in the
+//! user's home instead, see `Scope`.
+
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::models::{Image, Nic, Size};
+use crate::capacity::OvercommitRatios;
+use crate::error::Error;
+
+pub const CONFIG_PATH: &str = "/etc/bigiron-virt/config.yaml";
+
+/// Whose libvirt daemon runs the machines and where their state is kept
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scope {
+    /// the system daemon, state in `/var/lib/bigiron-virt`
+    System,
+    /// the user's `qemu:///session` daemon, state in
+    /// `~/.local/share/bigiron-virt` and config in `~/.config/bigiron-virt`
+    Session,
+}
+
+static SCOPE: OnceLock<Scope> = OnceLock::new();
+
+impl Scope {
+    /// The scope chosen with `select`, else session scope unless running
+    /// as root
+    pub fn current() -> Scope {
+        *SCOPE.get_or_init(|| match effective_uid() {
+            Some(uid) if uid != 0 => Scope::Session,
+            _ => Scope::System,
+        })
+    }
+
+    /// Use this scope for the rest of the process, before anything looks
+    /// at `current`
+    pub fn select(self) -> Result<(), Error> {
+        if SCOPE.get_or_init(|| self) != &self {
+            return Err("scope already chosen".into());
+        }
+        Ok(())
+    }
+
+    /// libvirt connection URI, empty for libvirt's default
+    pub fn libvirt_uri(self) -> &'static str {
+        match self {
+            Scope::System => "",
+            Scope::Session => "qemu:///session",
+        }
+    }
+
+    pub fn state_dir(self) -> PathBuf {
+        match self {
+            Scope::System => PathBuf::from("/var/lib/bigiron-virt"),
+            Scope::Session => xdg_dir("XDG_DATA_HOME", ".local/share").join("bigiron-virt"),
+        }
+    }
+
+    pub fn config_dir(self) -> PathBuf {
+        match self {
+            Scope::System => PathBuf::from("/etc/bigiron-virt"),
+            Scope::Session => xdg_dir("XDG_CONFIG_HOME", ".config").join("bigiron-virt"),
+        }
+    }
+
+    pub fn instance_dir(self) -> PathBuf {
+        self.state_dir().join("instances")
+    }
+
+    pub fn image_dir(self) -> PathBuf {
+        self.state_dir().join("images")
+    }
+
+    pub fn pool_dir(self) -> PathBuf {
+        self.state_dir().join("pools")
+    }
+}
+
+// $var if set, else `fallback` in the home directory
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    match std::env::var_os(var) {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var_os("HOME").unwrap_or_default();
+            PathBuf::from(home).join(fallback)
+        }
+    }
+}
+
+// std has no geteuid, the second Uid field of /proc/self/status is the
+// effective one
+fn effective_uid() -> Option<u32> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("Uid:"))?;
+    line.split_whitespace().nth(2)?.parse().ok()
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HostConfig {
+    #[serde(default)]
+    pub instance_storage: InstanceStorage,
+    #[serde(default)]
+    pub backup: BackupConfig,
+    #[serde(default)]
+    pub hypervisor: HypervisorConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    /// Limits checked before creating machines
+    #[serde(default)]
+    pub overcommit: OvercommitRatios,
+    /// Certificates for `serve --tls` and for managing hosts with `--host`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsConfig>,
+    /// Spec values for machines whose models leave them out
+    #[serde(default)]
+    pub defaults: SpecDefaults,
+    /// Where guests reach this host's `serve`, e.g. http://192.168.122.1:9180,
+    /// or its `--ready-listen` with `--tls`. Set, cloud-init in new machines
+    /// posts to `<url>/ready/<name>` once it is done, for `create --wait`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phone_home: Option<String>,
+    /// Register machine names with a dnsmasq on the host
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dns: Option<DnsConfig>,
+    /// Seconds `destroy` waits for a machine to shut down before powering
+    /// it off, 60 if unset; 0 powers it off right away
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destroy_timeout: Option<u64>,
+    /// mkisofs compatible program config drives are made with, the first
+    /// of genisoimage, xorrisofs and mkisofs in PATH if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub iso_tool: Option<PathBuf>,
+}
+
+/// Filled into machines created on this host where their spec has nothing,
+/// so a model can be little more than a name
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SpecDefaults {
+    pub cpu: Option<u32>,
+    pub memory: Option<Size>,
+    /// e.g. `name: ubuntu-22.04`, resize and mode still come from the spec
+    pub image: Option<Image>,
+    /// e.g. a Bridge nic on the host's bridge, for machines without nics
+    pub nics: Option<Vec<Nic>>,
+    /// for machines without userdata, userdataFile or cloudConfig
+    pub userdata: Option<String>,
+}
+
+/// PEM files for mutual TLS between hosts, see `remote`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsConfig {
+    /// this end's certificate chain
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA certificates the other end's certificate must be signed by
+    pub ca: PathBuf,
+    /// Names, as in the certificates' subjectAltName, of the clients
+    /// `serve --tls` accepts; any the CA signed if empty
+    #[serde(default)]
+    pub allowed_clients: Vec<String>,
+}
+
+/// Which hypervisor driver manages machines on this host
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum HypervisorConfig {
+    /// KVM guests through the local libvirt daemon
+    #[default]
+    Libvirt,
+    /// z/VM guests through the SMAPI server on `smapi_host`
+    #[serde(rename_all = "camelCase")]
+    Zvm { smapi_host: String, user: String },
+}
+
+/// Where instance root disks are allocated
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum InstanceStorage {
+    /// qcow2 overlays on the base image, in the instance directory
+    #[default]
+    Qcow2,
+    /// raw logical volumes in an LVM volume group, converted from the base image
+    #[serde(rename_all = "camelCase")]
+    Lvm { volume_group: String },
+    /// qcow2 files cloned from the base image with a reflink, for Btrfs,
+    /// XFS or ZFS (with block cloning) holding both the instance directory
+    /// and the image repo. Standalone like flattened images, but instant
+    /// and sharing blocks with the base until written.
+    Reflink,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackupConfig {
+    #[serde(default = "default_backup_directory")]
+    pub directory: PathBuf,
+    /// Number of backups to keep per machine, all are kept if unset
+    pub retention: Option<usize>,
+}
+
+impl Default for BackupConfig {
+    fn default() -> Self {
+        Self {
+            directory: default_backup_directory(),
+            retention: None,
+        }
+    }
+}
+
+fn default_backup_directory() -> PathBuf {
+    Scope::current().state_dir().join("backups")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HooksConfig {
+    /// Executables run on machine lifecycle events, see `hooks`
+    #[serde(default = "default_hooks_directory")]
+    pub directory: PathBuf,
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            directory: default_hooks_directory(),
+        }
+    }
+}
+
+fn default_hooks_directory() -> PathBuf {
+    Scope::current().config_dir().join("hooks.d")
+}
+
+/// A dnsmasq resolving machines by name, see `dns`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DnsConfig {
+    /// a file dnsmasq reads with `addn-hosts`, kept by bigiron-virt
+    pub hosts_file: PathBuf,
+    /// machines are `<name>.<domain>`
+    #[serde(default = "default_dns_domain")]
+    pub domain: String,
+    /// where dnsmasq writes its pid, to tell it to reread the hosts file
+    #[serde(default = "default_dnsmasq_pid_file")]
+    pub pid_file: PathBuf,
+}
+
+fn default_dns_domain() -> String {
+    String::from("bigiron.local")
+}
+
+fn default_dnsmasq_pid_file() -> PathBuf {
+    PathBuf::from("/run/dnsmasq/dnsmasq.pid")
+}
+
+impl HostConfig {
+    /// Load `CONFIG_PATH`, or `config.yaml` in the user's config directory
+    /// in session scope
+    pub fn load() -> Result<Self, Error> {
+        match Scope::current() {
+            Scope::System => Self::load_from(CONFIG_PATH),
+            Scope::Session => Self::load_from(Scope::Session.config_dir().join("config.yaml")),
+        }
+    }
+
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let s = std::fs::read_to_string(path)?;
+        serde_yaml::from_str(&s)
+            .map_err(|e| format!("invalid host config {:?}: {}", path, e).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scope_paths() {
+        assert_eq!(
+            Scope::System.instance_dir(),
+            Path::new("/var/lib/bigiron-virt/instances")
+        );
+        assert_eq!(Scope::System.libvirt_uri(), "");
+
+        assert_eq!(Scope::Session.libvirt_uri(), "qemu:///session");
+        assert!(Scope::Session.image_dir().ends_with("bigiron-virt/images"));
+        assert!(Scope::Session.config_dir().ends_with("bigiron-virt"));
+        assert_ne!(Scope::Session.state_dir(), Scope::System.state_dir());
+    }
+
+    #[test]
+    fn deserialize() {
+        let c: HostConfig = serde_yaml::from_str("{}").unwrap();
+        assert_eq!(c.instance_storage, InstanceStorage::Qcow2);
+        assert_eq!(c.hypervisor, HypervisorConfig::Libvirt);
+        assert_eq!(c.hooks.directory, default_hooks_directory());
+        assert_eq!(c.overcommit, OvercommitRatios::default());
+
+        let c: HostConfig = serde_yaml::from_str("instanceStorage:\n  kind: Reflink\n").unwrap();
+        assert_eq!(c.instance_storage, InstanceStorage::Reflink);
+
+        let c: HostConfig = serde_yaml::from_str(
+            "
+instanceStorage:
+  kind: Lvm
+  volumeGroup: vg_instances
+backup:
+  retention: 7
+hypervisor:
+  kind: Zvm
+  smapiHost: zvm01.example.com
+  user: MAINT
+overcommit:
+  memory: 1.5
+tls:
+  cert: /etc/bigiron-virt/tls/kvm01.crt
+  key: /etc/bigiron-virt/tls/kvm01.key
+  ca: /etc/bigiron-virt/tls/ca.crt
+  allowedClients: [operator.example.com]
+defaults:
+  cpu: 2
+  memory: 4Gi
+  image:
+    name: ubuntu-22.04
+  nics:
+    - kind: Bridge
+      parent: br0
+      address:
+        kind: IPv6SLAAC
+dns:
+  hostsFile: /etc/bigiron-virt/hosts
+",
+        )
+        .unwrap();
+        assert_eq!(
+            c.instance_storage,
+            InstanceStorage::Lvm {
+                volume_group: "vg_instances".to_string()
+            }
+        );
+
+        assert_eq!(c.overcommit.memory, 1.5);
+        let tls = c.tls.as_ref().unwrap();
+        assert_eq!(tls.ca, Path::new("/etc/bigiron-virt/tls/ca.crt"));
+        assert_eq!(tls.allowed_clients, ["operator.example.com"]);
+        assert_eq!(c.overcommit.cpu, OvercommitRatios::default().cpu);
+
+        assert_eq!(c.defaults.cpu, Some(2));
+        assert_eq!(c.defaults.memory, Some(Size(4 << 30)));
+        let image = c.defaults.image.as_ref().unwrap();
+        assert_eq!(image.name.as_deref(), Some("ubuntu-22.04"));
+        assert_eq!(c.defaults.nics.as_ref().unwrap()[0].parent, "br0");
+        assert_eq!(c.defaults.userdata, None);
+
+        let dns = c.dns.as_ref().unwrap();
+        assert_eq!(dns.domain, "bigiron.local");
+        assert_eq!(dns.pid_file, Path::new("/run/dnsmasq/dnsmasq.pid"));
+
+        assert_eq!(c.backup.directory, default_backup_directory());
+        assert_eq!(c.backup.retention, Some(7));
+        assert_eq!(
+            c.hypervisor,
+            HypervisorConfig::Zvm {
+                smapi_host: "zvm01.example.com".to_string(),
+                user: "MAINT".to_string()
+            }
+        );
+
+        assert_eq!(
+            HostConfig::load_from("/nonexistent/config.yaml").unwrap(),
+            HostConfig::default()
+        );
+    }
+}
@@ -15,15 +15,62 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use tracing::{debug, instrument};
 
+use crate::api::models::ConfigDriveFormat;
 use crate::error::Error;
+use crate::process::{self, Policy};
+
+// mkisofs compatible programs looked for in PATH, in order of preference
+const ISO_TOOLS: [&str; 3] = ["genisoimage", "xorrisofs", "mkisofs"];
+
+/// The program ISOs are made with: `configured` if given, which must be an
+/// executable, otherwise the first of genisoimage, xorrisofs and mkisofs
+/// in PATH
+pub fn find_iso_tool(configured: Option<&Path>) -> Result<PathBuf, Error> {
+    let path = std::env::var_os("PATH").unwrap_or_else(|| "/usr/sbin:/usr/bin:/sbin:/bin".into());
+    find_iso_tool_in(configured, &path)
+}
+
+fn find_iso_tool_in(configured: Option<&Path>, path: &OsStr) -> Result<PathBuf, Error> {
+    if let Some(tool) = configured {
+        return match executable(tool) {
+            true => Ok(tool.to_path_buf()),
+            false => Err(format!("isoTool {} is not an executable", tool.display()).into()),
+        };
+    }
+
+    ISO_TOOLS
+        .iter()
+        .flat_map(|name| {
+            std::env::split_paths(path)
+                .filter(|dir| dir.is_absolute())
+                .map(move |dir| dir.join(name))
+        })
+        .find(|p| executable(p))
+        .ok_or_else(|| {
+            format!(
+                "none of {} found in PATH, install genisoimage or xorriso, or set isoTool in the host config",
+                ISO_TOOLS.join(", ")
+            )
+            .into()
+        })
+}
+
+fn executable(path: &Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|md| md.is_file() && md.permissions().mode() & 0o111 != 0)
+}
 
 pub fn create_iso<P, Q, R, N>(
+    tool: &Path,
     output_path: P,
     user_data: Q,
     meta_data: R,
@@ -35,40 +82,128 @@ where
     R: AsRef<Path>,
     N: AsRef<Path>,
 {
-    let isoprog: &str = "/usr/bin/mkisofs";
+    let mut cmd = iso_command(tool, output_path, user_data, meta_data, network_data);
 
-    let mut cmd = Command::new(&isoprog);
+    let output = process::run(&mut cmd, &Policy::default())?;
 
-    cmd.arg("-output")
-        .arg(output_path.as_ref().to_str().unwrap())
-        .arg("-input-charset")
-        .arg("utf-8")
-        .arg("-volid")
-        .arg("cidata")
-        .arg("-joliet")
-        .arg("-r")
-        .arg(user_data.as_ref().to_str().unwrap())
-        .arg(meta_data.as_ref().to_str().unwrap());
+    debug!("{} output: {:?}", tool.display(), output);
+
+    Ok(())
+}
+
+// paths are passed through as OsStr, so spaces and non-UTF-8 bytes survive
+// intact. Only options all of ISO_TOOLS share are used.
+fn iso_command<P, Q, R, N>(
+    tool: &Path,
+    output_path: P,
+    user_data: Q,
+    meta_data: R,
+    network_data: &Option<N>,
+) -> Command
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    R: AsRef<Path>,
+    N: AsRef<Path>,
+{
+    let mut cmd = iso_base_command(tool, output_path.as_ref(), NOCLOUD_LABEL);
+
+    cmd.arg(user_data.as_ref()).arg(meta_data.as_ref());
 
     if let Some(nd) = network_data {
-        cmd.arg(nd.as_ref().to_str().unwrap());
+        cmd.arg(nd.as_ref());
     }
 
-    let output = cmd.output().expect("error executing mkisofs/genisoimage");
+    cmd
+}
+
+fn iso_base_command(tool: &Path, output_path: &Path, label: &str) -> Command {
+    let mut cmd = Command::new(tool);
+
+    cmd.arg("-o")
+        .arg(output_path)
+        .arg("-input-charset")
+        .arg("utf-8")
+        .arg("-V")
+        .arg(label)
+        .arg("-J")
+        .arg("-r");
+
+    cmd
+}
+
+// volume labels cloud-init's NoCloud and ConfigDrive datasources look for
+const NOCLOUD_LABEL: &str = "cidata";
+const OPENSTACK_LABEL: &str = "config-2";
+
+// where the OpenStack layout keeps its files
+const OPENSTACK_DIR: &str = "openstack/latest";
 
-    debug!("mkisofs output: {:?}", output);
+// the whole of `dir` as an ISO labelled `label`, e.g. for the OpenStack
+// layout's nested directories
+fn create_iso_from_dir(
+    tool: &Path,
+    output_path: &Path,
+    label: &str,
+    dir: &Path,
+) -> Result<(), Error> {
+    let mut cmd = iso_base_command(tool, output_path, label);
+    cmd.arg(dir);
 
-    if !output.status.success() {
-        return Err(format!("{:?}", output).into());
+    let output = process::run(&mut cmd, &Policy::default())?;
+    debug!("{} output: {:?}", tool.display(), output);
+
+    Ok(())
+}
+
+/// A FAT image at `output_path` labelled `label` holding `files`, made
+/// with mkfs.vfat from dosfstools and mcopy from mtools
+pub fn create_vfat(output_path: &Path, label: &str, files: &[PathBuf]) -> Result<(), Error> {
+    let mut size = 0;
+    for file in files {
+        size += std::fs::metadata(file)?.len();
     }
 
+    // mkfs.vfat refuses an existing file, as a rebuild would leave one
+    if output_path.exists() {
+        std::fs::remove_file(output_path)?;
+    }
+
+    let mut mkfs = Command::new("mkfs.vfat");
+    mkfs.arg("-n")
+        .arg(label.to_uppercase())
+        .arg("-C")
+        .arg(output_path)
+        .arg(vfat_blocks(size).to_string());
+    process::run(&mut mkfs, &Policy::default())
+        .map_err(|e| format!("{}, install dosfstools for vfat config drives", e))?;
+
+    let mut mcopy = Command::new("mcopy");
+    mcopy
+        .arg("-o")
+        .arg("-i")
+        .arg(output_path)
+        .args(files)
+        .arg("::");
+    process::run(&mut mcopy, &Policy::default())
+        .map_err(|e| format!("{}, install mtools for vfat config drives", e))?;
+
     Ok(())
 }
 
+// 1K blocks of a FAT filesystem holding `size` bytes of files, with room
+// for the FAT and clusters left part empty
+fn vfat_blocks(size: u64) -> u64 {
+    1024 + size.div_ceil(1024) * 2
+}
+
 pub struct Builder {
     metadata: Metadata,
     userdata: Option<Vec<u8>>,
     network_config: Option<Vec<u8>>,
+    format: ConfigDriveFormat,
+    // see `find_iso_tool`
+    iso_tool: Option<PathBuf>,
 }
 
 impl Builder {
@@ -79,9 +214,24 @@ impl Builder {
             metadata: md,
             userdata: None,
             network_config: None,
+            format: ConfigDriveFormat::default(),
+            iso_tool: None,
         }
     }
 
+    /// Lay the drive out as `format` rather than a NoCloud ISO
+    pub fn format(&mut self, format: ConfigDriveFormat) -> &mut Self {
+        self.format = format;
+        self
+    }
+
+    /// Make the ISO with mkisofs compatible program `tool` rather than one
+    /// found in PATH
+    pub fn iso_tool(&mut self, tool: &Path) -> &mut Self {
+        self.iso_tool = Some(tool.to_path_buf());
+        self
+    }
+
     pub fn metadata(&mut self) -> &mut Metadata {
         &mut self.metadata
     }
@@ -91,18 +241,36 @@ impl Builder {
         self
     }
 
+    /// Network config v2 YAML, or OpenStack `network_data.json` for the
+    /// OpenStack format
     pub fn add_network_config(&mut self, network_config: Vec<u8>) -> &mut Self {
         self.network_config = Some(network_config);
         self
     }
 
+    /// Write the drive to `cidata.iso` in `base_dir`, whatever its format,
+    /// so it's found in the same place
+    #[instrument(name = "configdrive_build", skip_all, fields(machine = %self.metadata.local_hostname))]
     pub fn build<P: AsRef<Path>>(&mut self, base_dir: P) -> Result<PathBuf, Error> {
-        let cd_dir = base_dir.as_ref().join("cidata-dir");
+        match self.format {
+            ConfigDriveFormat::NoCloud => self.build_nocloud(base_dir.as_ref(), true),
+            ConfigDriveFormat::Vfat => self.build_nocloud(base_dir.as_ref(), false),
+            ConfigDriveFormat::OpenStack => self.build_openstack(base_dir.as_ref()),
+        }
+    }
+
+    // NoCloud files on an ISO, or a FAT image without `iso`
+    fn build_nocloud(&self, base_dir: &Path, iso: bool) -> Result<PathBuf, Error> {
+        let tool = match iso {
+            true => Some(find_iso_tool(self.iso_tool.as_deref())?),
+            false => None,
+        };
+        let cd_dir = base_dir.join("cidata-dir");
 
         std::fs::create_dir_all(&cd_dir)?;
 
         // create iso outside data directory, since we will be cleaning up the data dir
-        let iso_path = base_dir.as_ref().join("cidata.iso");
+        let iso_path = base_dir.join("cidata.iso");
         let ud_path = cd_dir.join("user-data");
         let md_path = cd_dir.join("meta-data");
         let nc_path;
@@ -124,7 +292,20 @@ impl Builder {
 
         std::fs::write(&md_path, &self.metadata.to_bytes()?)?;
 
-        create_iso(&iso_path, &ud_path, &md_path, &nc_path)?;
+        match tool {
+            Some(tool) => create_iso(&tool, &iso_path, &ud_path, &md_path, &nc_path)?,
+            None => {
+                let files: Vec<PathBuf> = [
+                    Some(ud_path.clone()),
+                    Some(md_path.clone()),
+                    nc_path.clone(),
+                ]
+                .into_iter()
+                .flatten()
+                .collect();
+                create_vfat(&iso_path, NOCLOUD_LABEL, &files)?;
+            }
+        }
 
         std::fs::remove_file(&md_path)?;
         std::fs::remove_file(&ud_path)?;
@@ -137,6 +318,34 @@ impl Builder {
 
         Ok(iso_path)
     }
+
+    // openstack/latest/{meta_data.json,user_data,network_data.json} on an
+    // ISO labelled config-2
+    fn build_openstack(&self, base_dir: &Path) -> Result<PathBuf, Error> {
+        let tool = find_iso_tool(self.iso_tool.as_deref())?;
+        let cd_dir = base_dir.join("cidata-dir");
+        let latest = cd_dir.join(OPENSTACK_DIR);
+
+        std::fs::create_dir_all(&latest)?;
+
+        let iso_path = base_dir.join("cidata.iso");
+        std::fs::write(
+            latest.join("meta_data.json"),
+            self.metadata.to_openstack_json()?,
+        )?;
+        if let Some(ref userdata) = self.userdata {
+            std::fs::write(latest.join("user_data"), userdata)?;
+        }
+        if let Some(ref netdata) = self.network_config {
+            std::fs::write(latest.join("network_data.json"), netdata)?;
+        }
+
+        let result = create_iso_from_dir(&tool, &iso_path, OPENSTACK_LABEL, &cd_dir);
+        std::fs::remove_dir_all(&cd_dir)?;
+        result?;
+
+        Ok(iso_path)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -162,6 +371,12 @@ impl Metadata {
         }
     }
 
+    /// cloud-init runs its per-instance modules again when this changes,
+    /// the machine name by default
+    pub fn set_instance_id(&mut self, instance_id: &str) {
+        self.instance_id = instance_id.to_string();
+    }
+
     pub fn add_public_key(&mut self, public_key: &str) {
         self.public_keys.push(public_key.to_string());
     }
@@ -175,6 +390,284 @@ impl Metadata {
         serde_yaml::to_writer(&mut buf, &self)?;
         Ok(buf)
     }
+
+    /// The same metadata as OpenStack's `meta_data.json`
+    pub fn to_openstack_json(&self) -> Result<Vec<u8>, Error> {
+        let keys: serde_json::Map<String, serde_json::Value> = self
+            .public_keys
+            .iter()
+            .enumerate()
+            .map(|(i, key)| (format!("key{}", i), key.clone().into()))
+            .collect();
+
+        let md = serde_json::json!({
+            "uuid": self.instance_id,
+            "name": self.local_hostname,
+            "hostname": self.local_hostname,
+            "public_keys": keys,
+        });
+
+        Ok(serde_json::to_vec_pretty(&md)?)
+    }
+}
+
+const SECTOR: u64 = 2048;
+
+/// The files of config drive `path` as `(name, contents)`, whether it's an
+/// ISO or a FAT image
+pub fn read_files<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let path = path.as_ref();
+    let mut file = File::open(path).map_err(|e| format!("error opening {:?}: {}", path, e))?;
+
+    match read_at(&mut file, 16 * SECTOR + 1, 5) {
+        Ok(magic) if magic == b"CD001" => read_iso(path),
+        _ => read_vfat(path),
+    }
+}
+
+/// The files of config drive ISO `path` as `(name, contents)`, in the
+/// order they're recorded, with those in directories named by their path,
+/// e.g. `openstack/latest/meta_data.json`. Joliet or Rock Ridge names are
+/// used when present, as written by `create_iso`.
+pub fn read_iso<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let path = path.as_ref();
+    let mut iso = File::open(path).map_err(|e| format!("error opening {:?}: {}", path, e))?;
+
+    let mut primary = None;
+    let mut joliet = None;
+    for sector in 16.. {
+        let desc = read_at(&mut iso, sector * SECTOR, SECTOR as usize)
+            .map_err(|_| format!("{:?} is not an ISO 9660 image", path))?;
+        if &desc[1..6] != b"CD001" {
+            return Err(format!("{:?} is not an ISO 9660 image", path).into());
+        }
+
+        match desc[0] {
+            1 => primary = Some(desc),
+            // UCS-2 levels 1 to 3
+            2 if matches!(&desc[88..91], b"%/@" | b"%/C" | b"%/E") => joliet = Some(desc),
+            255 => break,
+            _ => {}
+        }
+    }
+
+    let (desc, is_joliet) = match (joliet, primary) {
+        (Some(desc), _) => (desc, true),
+        (None, Some(desc)) => (desc, false),
+        (None, None) => return Err(format!("{:?} has no primary volume descriptor", path).into()),
+    };
+
+    // the root directory record is embedded in the descriptor
+    let root = &desc[156..190];
+    let mut files = Vec::new();
+    read_iso_dir(&mut iso, path, root, "", is_joliet, &mut files)?;
+
+    Ok(files)
+}
+
+// the files under directory `record`, their names prefixed by `prefix`
+fn read_iso_dir(
+    iso: &mut File,
+    path: &Path,
+    record: &[u8],
+    prefix: &str,
+    is_joliet: bool,
+    files: &mut Vec<(String, Vec<u8>)>,
+) -> Result<(), Error> {
+    let dir = read_extent(iso, record)?;
+
+    let mut pos = 0;
+    while pos < dir.len() {
+        let len = dir[pos] as usize;
+        if len == 0 {
+            // records don't cross sectors, the rest of this one is padding
+            pos = (pos / SECTOR as usize + 1) * SECTOR as usize;
+            continue;
+        }
+
+        let record = dir
+            .get(pos..pos + len)
+            .filter(|r| r.len() > 33 && r.len() >= 33 + r[32] as usize)
+            .ok_or_else(|| format!("{:?} has a bad directory record", path))?;
+        pos += len;
+
+        let is_dir = record[25] & 2 != 0;
+        let id_len = record[32] as usize;
+        let id = &record[33..33 + id_len];
+        // the directory itself and its parent
+        if is_dir && (id == b"\0" || id == b"\x01") {
+            continue;
+        }
+
+        let name = if is_joliet {
+            let units: Vec<u16> = id
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16_lossy(&units)
+        } else {
+            // system use entries follow the id, padded to an even offset
+            let system_use = &record[(33 + id_len + (id_len + 1) % 2).min(len)..];
+            rock_ridge_name(system_use)
+                .unwrap_or_else(|| String::from_utf8_lossy(id).to_lowercase())
+        };
+        let name = match name.rsplit_once(';') {
+            Some((name, _version)) => name.trim_end_matches('.').to_string(),
+            None => name,
+        };
+
+        let name = format!("{}{}", prefix, name);
+        if is_dir {
+            // a bound on nesting, in case of a directory holding itself
+            if prefix.matches('/').count() < 8 {
+                read_iso_dir(iso, path, record, &format!("{}/", name), is_joliet, files)?;
+            }
+            continue;
+        }
+
+        let data = read_extent(iso, record)?;
+        files.push((name, data));
+    }
+
+    Ok(())
+}
+
+/// The files at the top of FAT config drive image `path`, read with mtools
+pub fn read_vfat<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let path = path.as_ref();
+
+    let mut mdir = Command::new("mdir");
+    mdir.arg("-b").arg("-i").arg(path).arg("::");
+    let output = process::run(&mut mdir, &Policy::default())
+        .map_err(|e| format!("{:?} is neither an ISO nor a FAT image: {}", path, e))?;
+
+    let mut files = Vec::new();
+    for entry in String::from_utf8_lossy(&output.stdout).lines() {
+        let name = entry
+            .trim()
+            .trim_start_matches("::")
+            .trim_start_matches('/');
+        if name.is_empty() || name.ends_with('/') {
+            continue;
+        }
+
+        let mut mtype = Command::new("mtype");
+        mtype.arg("-i").arg(path).arg(format!("::/{}", name));
+        let output = process::run(&mut mtype, &Policy::default())?;
+        files.push((name.to_lowercase(), output.stdout));
+    }
+
+    Ok(files)
+}
+
+// the `NM` alternate name entry, if any
+fn rock_ridge_name(mut system_use: &[u8]) -> Option<String> {
+    while system_use.len() >= 4 {
+        let len = system_use[2] as usize;
+        if len < 4 || len > system_use.len() {
+            return None;
+        }
+        if &system_use[..2] == b"NM" && len > 5 {
+            return Some(String::from_utf8_lossy(&system_use[5..len]).into_owned());
+        }
+        system_use = &system_use[len..];
+    }
+
+    None
+}
+
+// the data a directory record points to
+fn read_extent(file: &mut File, record: &[u8]) -> Result<Vec<u8>, Error> {
+    let le32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+    let sector = le32(&record[2..6]) as u64;
+    read_at(file, sector * SECTOR, le32(&record[10..14]) as usize)
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Problems cloud-init would have with the files of a config drive
+pub fn check(files: &[(String, Vec<u8>)]) -> Vec<String> {
+    let openstack = format!("{}/", OPENSTACK_DIR);
+    if files.iter().any(|(n, _)| n.starts_with(&openstack)) {
+        return check_openstack(files);
+    }
+
+    let mut problems = Vec::new();
+
+    let get = |name: &str| files.iter().find(|(n, _)| n == name).map(|(_, d)| d);
+
+    match get("meta-data") {
+        None => problems.push(String::from("meta-data is missing")),
+        Some(md) => match serde_yaml::from_slice::<serde_yaml::Value>(md) {
+            Err(e) => problems.push(format!("meta-data is not valid YAML: {}", e)),
+            Ok(md) if md.get("instance-id").is_none() => {
+                problems.push(String::from("meta-data has no instance-id"))
+            }
+            Ok(_) => {}
+        },
+    }
+
+    match get("user-data") {
+        None => problems.push(String::from("user-data is missing")),
+        Some(ud) if ud.starts_with(b"#cloud-config") => {
+            if let Err(e) = serde_yaml::from_slice::<serde_yaml::Value>(ud) {
+                problems.push(format!("user-data is not valid cloud-config: {}", e));
+            }
+        }
+        Some(_) => {}
+    }
+
+    if let Some(nc) = get("network-config") {
+        if let Err(e) = serde_yaml::from_slice::<serde_yaml::Value>(nc) {
+            problems.push(format!("network-config is not valid YAML: {}", e));
+        }
+    }
+
+    problems
+}
+
+// problems with the files of a config drive in the OpenStack layout
+fn check_openstack(files: &[(String, Vec<u8>)]) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    let get = |name: &str| {
+        files
+            .iter()
+            .find(|(n, _)| n == &format!("{}/{}", OPENSTACK_DIR, name))
+            .map(|(_, d)| d)
+    };
+
+    match get("meta_data.json") {
+        None => problems.push(String::from("meta_data.json is missing")),
+        Some(md) => match serde_json::from_slice::<serde_json::Value>(md) {
+            Err(e) => problems.push(format!("meta_data.json is not valid JSON: {}", e)),
+            Ok(md) if md.get("uuid").is_none() => {
+                problems.push(String::from("meta_data.json has no uuid"))
+            }
+            Ok(_) => {}
+        },
+    }
+
+    if let Some(ud) = get("user_data") {
+        if ud.starts_with(b"#cloud-config") {
+            if let Err(e) = serde_yaml::from_slice::<serde_yaml::Value>(ud) {
+                problems.push(format!("user_data is not valid cloud-config: {}", e));
+            }
+        }
+    }
+
+    if let Some(nd) = get("network_data.json") {
+        if let Err(e) = serde_json::from_slice::<serde_json::Value>(nd) {
+            problems.push(format!("network_data.json is not valid JSON: {}", e));
+        }
+    }
+
+    problems
 }
 
 #[cfg(test)]
@@ -187,5 +680,198 @@ mod test {
         assert!(String::from_utf8(md)
             .unwrap()
             .contains("instance-id: test123"));
+
+        let mut md = Metadata::new("test123");
+        md.set_instance_id("2ec115d7-3a88-3ceb-bc12-0ac909a6fd87");
+        let md = String::from_utf8(md.to_bytes().unwrap()).unwrap();
+        assert!(md.contains("instance-id: 2ec115d7-3a88-3ceb-bc12-0ac909a6fd87"));
+        assert!(md.contains("local-hostname: test123"));
+    }
+
+    #[test]
+    fn openstack_md() {
+        let mut md = Metadata::new("vm1");
+        md.set_instance_id("2ec115d7-3a88-3ceb-bc12-0ac909a6fd87");
+        md.add_public_key("ssh-ed25519 AAAA vm1");
+        let json: serde_json::Value =
+            serde_json::from_slice(&md.to_openstack_json().unwrap()).unwrap();
+        assert_eq!(json["uuid"], "2ec115d7-3a88-3ceb-bc12-0ac909a6fd87");
+        assert_eq!(json["hostname"], "vm1");
+        assert_eq!(json["public_keys"]["key0"], "ssh-ed25519 AAAA vm1");
+
+        let files = vec![
+            (
+                String::from("openstack/latest/meta_data.json"),
+                md.to_openstack_json().unwrap(),
+            ),
+            (
+                String::from("openstack/latest/network_data.json"),
+                b"{\"links\": [".to_vec(),
+            ),
+        ];
+        let problems = check(&files);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("network_data.json is not valid JSON"));
+    }
+
+    fn dir_record(sector: u32, size: u32, flags: u8, id: &[u8], system_use: &[u8]) -> Vec<u8> {
+        let pad = (id.len() + 1) % 2;
+        let mut r = vec![0; 33];
+        r[0] = (33 + id.len() + pad + system_use.len()) as u8;
+        r[2..6].copy_from_slice(&sector.to_le_bytes());
+        r[10..14].copy_from_slice(&size.to_le_bytes());
+        r[25] = flags;
+        r[32] = id.len() as u8;
+        r.extend_from_slice(id);
+        r.resize(r.len() + pad, 0);
+        r.extend_from_slice(system_use);
+        r
+    }
+
+    // a minimal image like mkisofs writes: an 8.3 directory with Rock Ridge
+    // names and, if `joliet`, a second directory with UCS-2 names
+    fn iso_image(files: &[(&str, &str)], joliet: bool) -> Vec<u8> {
+        let sector = SECTOR as usize;
+        let mut img = vec![0; sector * (21 + files.len())];
+
+        let mut plain = Vec::new();
+        let mut ucs2 = Vec::new();
+        for dir in [&mut plain, &mut ucs2] {
+            dir.extend(dir_record(0, 0, 2, b"\0", &[]));
+            dir.extend(dir_record(0, 0, 2, b"\x01", &[]));
+        }
+        for (i, (name, data)) in files.iter().enumerate() {
+            let at = 21 + i as u32;
+            img[at as usize * sector..][..data.len()].copy_from_slice(data.as_bytes());
+
+            let short = format!("{};1", name.to_uppercase().replace('-', "_"));
+            let mut nm = vec![b'N', b'M', 5 + name.len() as u8, 1, 0];
+            nm.extend_from_slice(name.as_bytes());
+            plain.extend(dir_record(at, data.len() as u32, 0, short.as_bytes(), &nm));
+
+            let wide: Vec<u8> = format!("{};1", name)
+                .encode_utf16()
+                .flat_map(u16::to_be_bytes)
+                .collect();
+            ucs2.extend(dir_record(at, data.len() as u32, 0, &wide, &[]));
+        }
+        img[19 * sector..][..plain.len()].copy_from_slice(&plain);
+        img[20 * sector..][..ucs2.len()].copy_from_slice(&ucs2);
+
+        let mut descriptor = |at: usize, kind: u8, root: u32| {
+            let desc = &mut img[at * sector..][..sector];
+            desc[0] = kind;
+            desc[1..6].copy_from_slice(b"CD001");
+            if kind == 2 {
+                desc[88..91].copy_from_slice(b"%/E");
+            }
+            if kind != 255 {
+                desc[156..190].copy_from_slice(&dir_record(root, SECTOR as u32, 2, b"\0", &[]));
+            }
+        };
+        descriptor(16, 1, 19);
+        if joliet {
+            descriptor(17, 2, 20);
+            descriptor(18, 255, 0);
+        } else {
+            descriptor(17, 255, 0);
+        }
+
+        img
+    }
+
+    #[test]
+    fn read_config_drive() {
+        let files = [
+            ("user-data", "#cloud-config\npackages: [htop]\n"),
+            ("meta-data", "instance-id: vm1\nlocal-hostname: vm1\n"),
+            ("network-config", "version: 2\n"),
+        ];
+        let iso = std::env::temp_dir().join("bigiron-virt-read-iso-test.iso");
+
+        for joliet in [true, false] {
+            std::fs::write(&iso, iso_image(&files, joliet)).unwrap();
+            let read = read_iso(&iso).unwrap();
+
+            let names: Vec<_> = read.iter().map(|(n, _)| n.as_str()).collect();
+            assert_eq!(names, ["user-data", "meta-data", "network-config"]);
+            assert_eq!(read[0].1, files[0].1.as_bytes());
+            assert!(check(&read).is_empty());
+        }
+
+        let bad = [
+            ("user-data", "#cloud-config\npackages: [htop\n"),
+            ("meta-data", "local-hostname: vm1\n"),
+        ];
+        std::fs::write(&iso, iso_image(&bad, true)).unwrap();
+        let problems = check(&read_iso(&iso).unwrap());
+        assert_eq!(problems.len(), 2);
+        assert_eq!(problems[0], "meta-data has no instance-id");
+        assert!(problems[1].starts_with("user-data is not valid cloud-config"));
+
+        std::fs::write(&iso, b"not an iso").unwrap();
+        assert!(read_iso(&iso).is_err());
+        assert!(read_files(&iso).is_err());
+        std::fs::remove_file(&iso).unwrap();
+    }
+
+    #[test]
+    fn iso_command_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let out = Path::new("/var/lib/my instances/vm1/cidata.iso");
+        let ud = Path::new(OsStr::from_bytes(b"/tmp/odd\xffdir/user-data"));
+        let md = Path::new("/tmp/meta data");
+
+        let cmd = iso_command(
+            Path::new("/usr/bin/xorrisofs"),
+            out,
+            ud,
+            md,
+            &None::<PathBuf>,
+        );
+        let args: Vec<_> = cmd.get_args().collect();
+
+        assert_eq!(cmd.get_program(), "/usr/bin/xorrisofs");
+        assert!(args.contains(&out.as_os_str()));
+        assert!(args.contains(&ud.as_os_str()));
+        assert!(args.contains(&md.as_os_str()));
+    }
+
+    #[test]
+    fn iso_tool_discovery() {
+        let root =
+            std::env::temp_dir().join(format!("bigiron-virt-isotool-{}", std::process::id()));
+        let (first, second) = (root.join("a"), root.join("b"));
+        std::fs::create_dir_all(&first).unwrap();
+        std::fs::create_dir_all(&second).unwrap();
+        let install = |dir: &Path, name: &str| {
+            let tool = dir.join(name);
+            std::fs::write(&tool, "#!/bin/sh\n").unwrap();
+            std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+            tool
+        };
+        let path = std::env::join_paths([&first, &second]).unwrap();
+
+        let err = find_iso_tool_in(None, &path).unwrap_err();
+        assert!(err.to_string().contains("install genisoimage or xorriso"));
+
+        // a non-executable file doesn't count
+        std::fs::write(first.join("genisoimage"), "").unwrap();
+        let xorrisofs = install(&second, "xorrisofs");
+        assert_eq!(find_iso_tool_in(None, &path).unwrap(), xorrisofs);
+
+        // preferred over xorrisofs wherever it is in PATH
+        let genisoimage = install(&second, "genisoimage");
+        assert_eq!(find_iso_tool_in(None, &path).unwrap(), genisoimage);
+
+        assert_eq!(
+            find_iso_tool_in(Some(&xorrisofs), &path).unwrap(),
+            xorrisofs
+        );
+        assert!(find_iso_tool_in(Some(&first.join("genisoimage")), &path).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
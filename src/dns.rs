@@ -0,0 +1,200 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Machine names in a dnsmasq `addn-hosts` file.
+//!
+//! Each address of a machine is a line `<addr> <name>.<domain> <name>` in
+//! the hosts file of the host config's `dns` section. The file is only
+//! ever replaced whole, and dnsmasq is sent SIGHUP to reread it, so the
+//! host and machines using it resolve `vm1.bigiron.local` right away.
+
+use std::net::IpAddr;
+use std::process::Command;
+
+use tracing::{info, warn};
+
+use crate::config::DnsConfig;
+use crate::error::{self, Error};
+use crate::process::{self, Policy};
+
+/// Whether `name` is an RFC 1123 label, e.g. web-1, which is what
+/// registered names must be
+pub fn is_label(name: &str) -> bool {
+    let alnum = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit();
+    name.len() <= 63
+        && name.starts_with(alnum)
+        && name.ends_with(alnum)
+        && name.chars().all(|c| alnum(c) || c == '-')
+}
+
+/// Point `name` at `addrs`, replacing what it had
+pub fn register(config: &DnsConfig, name: &str, addrs: &[IpAddr]) -> Result<(), Error> {
+    // anything else could add lines of its own to the hosts file
+    if !is_label(name) {
+        return Err(error::invalid(format!(
+            "'{}' can't be registered in DNS, use up to 63 lowercase letters, digits and '-', \
+             starting and ending with a letter or digit",
+            name
+        )));
+    }
+    update(config, name, addrs)?;
+    info!(
+        "Registered {}.{} in {:?}",
+        name, config.domain, config.hosts_file
+    );
+    Ok(())
+}
+
+/// Remove `name`, if it's registered
+pub fn unregister(config: &DnsConfig, name: &str) -> Result<(), Error> {
+    update(config, name, &[])
+}
+
+fn update(config: &DnsConfig, name: &str, addrs: &[IpAddr]) -> Result<(), Error> {
+    let path = &config.hosts_file;
+    let old = match std::fs::read_to_string(path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("error reading {:?}: {}", path, e).into()),
+    };
+
+    let new = with_host(&old, &config.domain, name, addrs);
+    if new == old {
+        return Ok(());
+    }
+
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, new)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|e| format!("error writing {:?}: {}", path, e))?;
+
+    reload(config)
+}
+
+// tell dnsmasq to reread its hosts files, if it's running
+fn reload(config: &DnsConfig) -> Result<(), Error> {
+    let pid = match std::fs::read_to_string(&config.pid_file) {
+        Ok(pid) => pid.trim().to_string(),
+        Err(e) => {
+            warn!(
+                "dnsmasq not told of the change, can't read {:?}: {}",
+                config.pid_file, e
+            );
+            return Ok(());
+        }
+    };
+
+    let mut cmd = Command::new("kill");
+    cmd.arg("-HUP").arg(&pid);
+    process::run(&mut cmd, &Policy::default())?;
+
+    Ok(())
+}
+
+// `hosts` with the lines of `name` replaced by one for each of `addrs`
+fn with_host(hosts: &str, domain: &str, name: &str, addrs: &[IpAddr]) -> String {
+    let fqdn = format!("{}.{}", name, domain);
+
+    let mut out = String::new();
+    for line in hosts.lines() {
+        if line.split_whitespace().nth(1) != Some(fqdn.as_str()) {
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    for addr in addrs {
+        out.push_str(&format!("{} {} {}\n", addr, fqdn, name));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        for good in ["vm1", "web-01", "a"] {
+            assert!(is_label(good), "{}", good);
+        }
+        let long = "a".repeat(64);
+        for bad in [
+            "Web1",
+            "vm_1",
+            "-vm",
+            "vm-",
+            "vm.lab",
+            "vm\n10.0.0.1 evil",
+            &long,
+        ] {
+            assert!(!is_label(bad), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn hosts_lines() {
+        let addrs: Vec<IpAddr> = vec![
+            "192.168.122.10".parse().unwrap(),
+            "2001:db8::10".parse().unwrap(),
+        ];
+        let hosts = with_host(
+            "# managed by bigiron-virt\n",
+            "bigiron.local",
+            "vm1",
+            &addrs,
+        );
+        assert_eq!(
+            hosts,
+            "# managed by bigiron-virt\n192.168.122.10 vm1.bigiron.local vm1\n2001:db8::10 vm1.bigiron.local vm1\n"
+        );
+
+        let hosts = with_host(&hosts, "bigiron.local", "vm10", &addrs[..1]);
+        let hosts = with_host(&hosts, "bigiron.local", "vm1", &addrs[1..]);
+        assert_eq!(
+            hosts,
+            "# managed by bigiron-virt\n192.168.122.10 vm10.bigiron.local vm10\n2001:db8::10 vm1.bigiron.local vm1\n"
+        );
+
+        let hosts = with_host(&hosts, "bigiron.local", "vm1", &[]);
+        assert!(!hosts.contains("vm1.bigiron.local"));
+    }
+
+    #[test]
+    fn register_and_unregister() {
+        let dir = std::env::temp_dir().join(format!("bigiron-virt-dns-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // no dnsmasq running
+        let config = DnsConfig {
+            hosts_file: dir.join("hosts"),
+            domain: String::from("lab"),
+            pid_file: dir.join("dnsmasq.pid"),
+        };
+        register(&config, "vm1", &["10.0.0.5".parse().unwrap()]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&config.hosts_file).unwrap(),
+            "10.0.0.5 vm1.lab vm1\n"
+        );
+
+        unregister(&config, "vm1").unwrap();
+        assert_eq!(std::fs::read_to_string(&config.hosts_file).unwrap(), "");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
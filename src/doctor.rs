@@ -0,0 +1,354 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Host preflight checks for `bigiron-virt doctor`.
+//!
+//! Unlike `selftest` nothing is created; each check only looks at the host
+//! and says what to do about anything missing.
+
+use std::collections::BTreeSet;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use crate::config::{HostConfig, HypervisorConfig, InstanceStorage, Scope};
+use crate::configdrive;
+use crate::libvirt;
+use crate::vmstore::VMStore;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Pass(String),
+    /// works, but something may not behave as expected
+    Warn(String),
+    Fail(String),
+}
+
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport {
+    pub fn passed(&self) -> bool {
+        !self
+            .checks
+            .iter()
+            .any(|c| matches!(c.outcome, Outcome::Fail(_)))
+    }
+
+    fn record<S: Into<String>>(&mut self, name: S, outcome: Outcome) {
+        self.checks.push(Check {
+            name: name.into(),
+            outcome,
+        });
+    }
+}
+
+/// Check this host can run machines, including `bridges` on top of the
+/// ones managed machines are attached to
+pub fn run(config: &HostConfig, bridges: &[String]) -> DoctorReport {
+    let mut report = DoctorReport { checks: Vec::new() };
+    let scope = Scope::current();
+
+    report.record(
+        "scope",
+        Outcome::Pass(match scope {
+            Scope::System => String::from("system"),
+            Scope::Session => format!(
+                "session ({}), Bridge nics need the bridge allowed in /etc/qemu/bridge.conf and Macvtap nics need root",
+                scope.libvirt_uri()
+            ),
+        }),
+    );
+
+    if config.hypervisor == HypervisorConfig::Libvirt {
+        report.record("kvm", check_kvm(Path::new("/dev/kvm")));
+        report.record("libvirt", check_libvirt());
+        report.record("nested-virt", check_nested(Path::new("/sys/module")));
+    }
+
+    report.record("iso-tool", check_iso_tool(config.iso_tool.as_deref()));
+    report.record(
+        "qemu-img",
+        check_program("/usr/bin/qemu-img", "install qemu-utils (qemu-img)"),
+    );
+
+    for dir in [scope.instance_dir(), scope.image_dir()] {
+        report.record(format!("store {}", dir.display()), check_directory(&dir));
+    }
+    if config.instance_storage == InstanceStorage::Reflink {
+        report.record(
+            "reflink",
+            check_same_filesystem(&scope.instance_dir(), &scope.image_dir()),
+        );
+    }
+    report.record(
+        format!("store {}", config.backup.directory.display()),
+        check_directory(&config.backup.directory),
+    );
+
+    let mut all_bridges: BTreeSet<String> = bridges.iter().cloned().collect();
+    match machine_bridges(config) {
+        Ok(b) => all_bridges.extend(b),
+        Err(e) => report.record(
+            "bridges",
+            Outcome::Warn(format!("can't read managed machines: {}", e)),
+        ),
+    }
+    for bridge in all_bridges {
+        report.record(
+            format!("bridge {}", bridge),
+            check_bridge(Path::new("/sys/class/net"), &bridge),
+        );
+    }
+
+    report
+}
+
+fn check_kvm(dev: &Path) -> Outcome {
+    match std::fs::OpenOptions::new().read(true).write(true).open(dev) {
+        Ok(_) => Outcome::Pass(format!("{} is usable", dev.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Outcome::Fail(format!(
+            "{} is missing, enable virtualization (VT-x/AMD-V) in the firmware and load the kvm_intel or kvm_amd module",
+            dev.display()
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => Outcome::Fail(format!(
+            "no access to {}, run as root or add the user to the kvm group",
+            dev.display()
+        )),
+        Err(e) => Outcome::Fail(format!("can't open {}: {}", dev.display(), e)),
+    }
+}
+
+fn check_libvirt() -> Outcome {
+    match libvirt::list_domains() {
+        Ok(domains) => Outcome::Pass(format!("connected, {} domains", domains.len())),
+        Err(e) => Outcome::Fail(format!(
+            "can't connect to libvirt ({}), start libvirtd and check the user may use it, e.g. is in the libvirt group",
+            e
+        )),
+    }
+}
+
+/// The loaded kvm module, kvm_intel or kvm_amd, and whether it lets
+/// guests run KVM themselves, from the module parameters under
+/// `sys_module`, usually /sys/module
+pub(crate) fn kvm_nesting(sys_module: &Path) -> Option<(&'static str, bool)> {
+    ["kvm_intel", "kvm_amd"].into_iter().find_map(|module| {
+        let param = sys_module.join(module).join("parameters/nested");
+        let value = std::fs::read_to_string(param).ok()?;
+        Some((module, matches!(value.trim(), "Y" | "y" | "1")))
+    })
+}
+
+/// How to turn on nesting in kvm `module`
+pub(crate) fn nesting_fix(module: &str) -> String {
+    format!(
+        "set `options {} nested=1` in /etc/modprobe.d and reload the module",
+        module
+    )
+}
+
+// nested virtualization only matters for guests running their own guests,
+// so it's never a failure
+fn check_nested(sys_module: &Path) -> Outcome {
+    match kvm_nesting(sys_module) {
+        Some((module, true)) => Outcome::Pass(format!("enabled in {}", module)),
+        Some((module, false)) => Outcome::Warn(format!(
+            "disabled, guests can't run KVM themselves; {}",
+            nesting_fix(module)
+        )),
+        None => Outcome::Warn(String::from(
+            "neither kvm_intel nor kvm_amd is loaded, can't tell",
+        )),
+    }
+}
+
+fn check_program(path: &str, fix: &str) -> Outcome {
+    match std::fs::metadata(path) {
+        Ok(md) if md.is_file() && md.permissions().mode() & 0o111 != 0 => {
+            Outcome::Pass(format!("found {}", path))
+        }
+        Ok(_) => Outcome::Fail(format!("{} is not executable", path)),
+        Err(_) => Outcome::Fail(format!("{} not found, {}", path, fix)),
+    }
+}
+
+fn check_iso_tool(configured: Option<&Path>) -> Outcome {
+    match configdrive::find_iso_tool(configured) {
+        Ok(tool) => Outcome::Pass(format!("found {}", tool.display())),
+        Err(e) => Outcome::Fail(e.to_string()),
+    }
+}
+
+fn check_directory(dir: &Path) -> Outcome {
+    if !dir.exists() {
+        // stores create their directories on first use
+        let parent = dir.ancestors().skip(1).find(|p| p.exists());
+        return match parent {
+            Some(p) if writable(p) => Outcome::Pass(String::from("missing, will be created")),
+            _ => Outcome::Fail(format!(
+                "{} is missing and can't be created, create it or run as root",
+                dir.display()
+            )),
+        };
+    }
+
+    if !dir.is_dir() {
+        return Outcome::Fail(format!("{} is not a directory", dir.display()));
+    }
+
+    if writable(dir) {
+        Outcome::Pass(String::from("writable"))
+    } else {
+        Outcome::Fail(format!(
+            "{} is not writable, fix its ownership or run as root",
+            dir.display()
+        ))
+    }
+}
+
+// reflinks can't cross filesystems, so instances must be made next to
+// their images
+fn check_same_filesystem(instances: &Path, images: &Path) -> Outcome {
+    let dev = |dir: &Path| {
+        dir.ancestors()
+            .find_map(|p| std::fs::metadata(p).ok())
+            .map(|m| m.dev())
+    };
+
+    if dev(instances) == dev(images) {
+        Outcome::Pass(String::from("instances and images share a filesystem"))
+    } else {
+        Outcome::Fail(format!(
+            "{} and {} are on different filesystems, reflink storage needs them on the same one",
+            instances.display(),
+            images.display()
+        ))
+    }
+}
+
+// actually try, permission bits don't account for root or read-only mounts
+fn writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".bigiron-virt-doctor-{}", std::process::id()));
+    let ok = std::fs::write(&probe, b"").is_ok();
+    let _ = std::fs::remove_file(&probe);
+    ok
+}
+
+fn check_bridge(sys_class_net: &Path, bridge: &str) -> Outcome {
+    let dev = sys_class_net.join(bridge);
+    if !dev.exists() {
+        Outcome::Fail(format!(
+            "{} doesn't exist, create it, e.g. with `ip link add {} type bridge`",
+            bridge, bridge
+        ))
+    } else if !dev.join("bridge").exists() {
+        Outcome::Fail(format!("{} exists but is not a bridge", bridge))
+    } else {
+        Outcome::Pass(String::from("exists"))
+    }
+}
+
+fn machine_bridges(config: &HostConfig) -> Result<Vec<String>, crate::error::Error> {
+    let dir = Scope::current().instance_dir();
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let store = VMStore::new(dir, config.instance_storage.clone())?;
+    let mut bridges = Vec::new();
+    for id in store.list_instances()? {
+        let machine = store.load_machine(&id)?;
+        for nic in machine.spec.nics.iter().flatten() {
+            if nic.kind == "Bridge" {
+                bridges.push(nic.parent.clone());
+            }
+        }
+    }
+
+    Ok(bridges)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn checks() {
+        let root = std::env::temp_dir().join(format!("bigiron-virt-doctor-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+
+        let params = root.join("module/kvm_intel/parameters");
+        std::fs::create_dir_all(&params).unwrap();
+        std::fs::write(params.join("nested"), "N\n").unwrap();
+        let Outcome::Warn(warning) = check_nested(&root.join("module")) else {
+            panic!("nested virt should be a warning");
+        };
+        assert!(warning.contains("options kvm_intel nested=1"));
+
+        std::fs::write(params.join("nested"), "Y\n").unwrap();
+        assert_eq!(
+            check_nested(&root.join("module")),
+            Outcome::Pass(String::from("enabled in kvm_intel"))
+        );
+
+        let net = root.join("net");
+        std::fs::create_dir_all(net.join("br0/bridge")).unwrap();
+        std::fs::create_dir_all(net.join("eth0")).unwrap();
+        assert_eq!(check_bridge(&net, "br0"), Outcome::Pass("exists".into()));
+        assert_eq!(
+            check_bridge(&net, "eth0"),
+            Outcome::Fail(String::from("eth0 exists but is not a bridge"))
+        );
+        let Outcome::Fail(failure) = check_bridge(&net, "br1") else {
+            panic!("missing bridge should fail");
+        };
+        assert!(failure.contains("`ip link add br1 type bridge`"));
+
+        assert_eq!(check_directory(&net), Outcome::Pass("writable".into()));
+        assert!(matches!(
+            check_same_filesystem(&root.join("store/instances"), &net),
+            Outcome::Pass(_)
+        ));
+        assert!(matches!(
+            check_same_filesystem(&root, Path::new("/proc")),
+            Outcome::Fail(_)
+        ));
+        assert_eq!(
+            check_directory(&root.join("store/instances")),
+            Outcome::Pass(String::from("missing, will be created"))
+        );
+
+        let Outcome::Fail(failure) = check_kvm(&root.join("kvm")) else {
+            panic!("missing /dev/kvm should fail");
+        };
+        assert!(failure.contains("kvm is missing, enable virtualization"));
+
+        let mkisofs = root.join("mkisofs");
+        assert_eq!(
+            check_program(mkisofs.to_str().unwrap(), "install it"),
+            Outcome::Fail(format!("{} not found, install it", mkisofs.display()))
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
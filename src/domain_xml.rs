@@ -0,0 +1,269 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! User supplied domain XML, for libvirt features the machine model
+//! doesn't cover.
+//!
+//! `spec.extraDomainXml` is a run of elements written as is at the end of
+//! `<devices>`. `spec.domainXmlOverride` is a `<domain>` element whose
+//! children each replace the generated children of the same name, or are
+//! added after them, e.g. `<domain><features><acpi/><hyperv/></features></domain>`
+//! swaps out the generated `<features>`.
+
+use std::ops::Range;
+
+use quick_xml::events::Event;
+use quick_xml::name::QName;
+use quick_xml::reader::Reader;
+
+use crate::error::Error;
+
+// children the machine is managed by, which an override can't replace
+const KEPT: [&str; 2] = ["name", "uuid"];
+
+/// What is wrong with `extraDomainXml` `xml`, if anything
+pub(crate) fn extra_problems(xml: &str) -> Option<String> {
+    children(&format!("<devices>{}</devices>", xml))
+        .err()
+        .map(|e| format!("extraDomainXml is not well formed: {}", e))
+}
+
+/// What is wrong with `domainXmlOverride` `xml`, if anything
+pub(crate) fn override_problems(xml: &str) -> Option<String> {
+    let (root, _, children) = match children(xml) {
+        Ok(c) => c,
+        Err(e) => return Some(format!("domainXmlOverride is not well formed: {}", e)),
+    };
+    if root != "domain" {
+        return Some(String::from("domainXmlOverride must be a <domain> element"));
+    }
+    children
+        .iter()
+        .find(|(name, _)| KEPT.contains(&name.as_str()))
+        .map(|(name, _)| format!("domainXmlOverride can't replace <{}>", name))
+}
+
+/// Slots on PCI bus 0 that `extraDomainXml` `xml` gives its devices
+/// explicitly, generated devices have to keep out of them
+pub(crate) fn pci_slots(xml: &str) -> Result<Vec<u32>, Error> {
+    let mut reader = Reader::from_str(xml);
+    let mut slots = Vec::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.name() == QName(b"address") => {
+                let mut pci = false;
+                let mut bus = 0;
+                let mut domain = 0;
+                let mut slot = None;
+                for a in e.attributes() {
+                    let a = a?;
+                    let value = a.unescape_value()?;
+                    match a.key {
+                        QName(b"type") => pci = value == "pci",
+                        QName(b"domain") => domain = pci_number(&value)?,
+                        QName(b"bus") => bus = pci_number(&value)?,
+                        QName(b"slot") => slot = Some(pci_number(&value)?),
+                        _ => {}
+                    }
+                }
+                if let Some(slot) = slot.filter(|_| pci && bus == 0 && domain == 0) {
+                    slots.push(slot);
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(slots)
+}
+
+// a PCI address part as libvirt takes them, hex with 0x or decimal
+fn pci_number(value: &str) -> Result<u32, Error> {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid PCI address part '{}'", value).into())
+}
+
+/// Domain XML `generated` with the children of `<domain>` override `xml`
+/// in place of its own
+pub(crate) fn merge(generated: &str, xml: &str) -> Result<String, Error> {
+    if let Some(problem) = override_problems(xml) {
+        return Err(problem.into());
+    }
+    let (_, inner, ours) = children(generated)?;
+    let (_, _, theirs) = children(xml)?;
+    let replaces = |name: &str| theirs.iter().any(|(n, _)| n == name);
+
+    let mut merged = String::from(&generated[..inner.start]);
+    let mut placed: Vec<&str> = Vec::new();
+    for (name, span) in &ours {
+        if !replaces(name) {
+            merged.push_str(&generated[span.clone()]);
+        } else if !placed.contains(&name.as_str()) {
+            for (_, span) in theirs.iter().filter(|(n, _)| n == name) {
+                merged.push_str(&xml[span.clone()]);
+            }
+            placed.push(name);
+        }
+    }
+    for (name, span) in &theirs {
+        if !ours.iter().any(|(n, _)| n == name) {
+            merged.push_str(&xml[span.clone()]);
+        }
+    }
+    merged.push_str(&generated[inner.end..]);
+
+    Ok(merged)
+}
+
+// the root element name of document `xml`, the span of its content and
+// the name and span of each child element, failing unless it's one
+// balanced element
+fn children(xml: &str) -> Result<(String, Range<usize>, Vec<(String, Range<usize>)>), Error> {
+    let mut reader = Reader::from_str(xml);
+    let mut root = None;
+    let mut inner = 0..0;
+    let mut children = Vec::new();
+    let mut depth = 0;
+    let mut child_start = 0;
+
+    loop {
+        let start = reader.buffer_position();
+        let event = reader.read_event()?;
+        let end = reader.buffer_position();
+        let name = |e: &quick_xml::events::BytesStart| {
+            String::from_utf8_lossy(e.name().as_ref()).into_owned()
+        };
+
+        match event {
+            Event::Start(_) | Event::Empty(_) if depth == 0 && root.is_some() => {
+                return Err("more than one root element".into());
+            }
+            Event::Start(e) => {
+                match depth {
+                    0 => {
+                        root = Some(name(&e));
+                        inner.start = end;
+                    }
+                    1 => {
+                        children.push((name(&e), 0..0));
+                        child_start = start;
+                    }
+                    _ => {}
+                }
+                depth += 1;
+            }
+            Event::Empty(e) => match depth {
+                0 => {
+                    root = Some(name(&e));
+                    inner = end..end;
+                }
+                1 => children.push((name(&e), start..end)),
+                _ => {}
+            },
+            Event::End(_) => {
+                depth -= 1;
+                match depth {
+                    0 => inner.end = start,
+                    1 => {
+                        if let Some((_, span)) = children.last_mut() {
+                            *span = child_start..end;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::Text(t) if depth == 0 && !t.unescape()?.trim().is_empty() => {
+                return Err("text outside the root element".into());
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    match root {
+        Some(root) if depth == 0 => Ok((root, inner, children)),
+        Some(root) => Err(format!("<{}> is not closed", root).into()),
+        None => Err("no element".into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const GENERATED: &str = "<domain type=\"kvm\"><name>vm1</name><vcpu>2</vcpu>\
+        <features><acpi/><apic/></features><clock offset=\"utc\"/>\
+        <devices><memballoon model=\"virtio\"/></devices></domain>";
+
+    #[test]
+    fn merge_override() {
+        let xml =
+            "<domain>\n  <features><acpi/><hyperv><relaxed state='on'/></hyperv></features>\n  \
+            <memoryBacking><hugepages/></memoryBacking>\n</domain>";
+        assert_eq!(
+            merge(GENERATED, xml).unwrap(),
+            "<domain type=\"kvm\"><name>vm1</name><vcpu>2</vcpu>\
+            <features><acpi/><hyperv><relaxed state='on'/></hyperv></features>\
+            <clock offset=\"utc\"/><devices><memballoon model=\"virtio\"/></devices>\
+            <memoryBacking><hugepages/></memoryBacking></domain>"
+        );
+
+        assert_eq!(merge(GENERATED, "<domain/>").unwrap(), GENERATED);
+    }
+
+    #[test]
+    fn problems() {
+        assert_eq!(
+            extra_problems("<watchdog model='i6300esb'/><rng model='virtio'/>"),
+            None
+        );
+        assert!(extra_problems("<watchdog model='i6300esb'>").is_some());
+        assert!(extra_problems("<rng></watchdog>").is_some());
+
+        assert_eq!(
+            override_problems("<domain><on_crash>destroy</on_crash></domain>"),
+            None
+        );
+        assert_eq!(
+            override_problems("<devices/>").unwrap(),
+            "domainXmlOverride must be a <domain> element"
+        );
+        assert_eq!(
+            override_problems("<domain><name>other</name></domain>").unwrap(),
+            "domainXmlOverride can't replace <name>"
+        );
+        assert!(override_problems("<domain/><domain/>").is_some());
+        assert!(override_problems("<domain>").is_some());
+    }
+
+    #[test]
+    fn explicit_pci_slots() {
+        let xml = "<watchdog model='i6300esb'>\
+            <address type='pci' domain='0x0000' bus='0x00' slot='0x07' function='0x0'/>\
+            </watchdog><rng model='virtio'><address type='pci' bus='1' slot='3'/></rng>\
+            <hostdev mode='subsystem' type='pci'><source><address domain='0x0000' \
+            bus='0x00' slot='0x19' function='0x0'/></source><address type='pci' slot='9'/>\
+            </hostdev><controller type='usb'><address type='usb' bus='0' port='1'/></controller>";
+        assert_eq!(pci_slots(xml).unwrap(), [7, 9]);
+        assert!(pci_slots("<rng><address type='pci' slot='seven'/></rng>").is_err());
+    }
+}
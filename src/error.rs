@@ -15,4 +15,131 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::fmt;
+
+use serde::Serialize;
+
 pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// What sort of failure an error is, which the CLI turns into its exit
+/// code so scripts can tell failures apart without reading the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorKind {
+    Other,
+    /// a spec, file or argument that doesn't make sense
+    Invalid,
+    /// a machine, image or file that isn't there
+    NotFound,
+    /// libvirt or another hypervisor refused
+    Hypervisor,
+    /// the machine isn't in a state that allows it, e.g. already exists
+    Conflict,
+    Timeout,
+}
+
+impl ErrorKind {
+    /// Exit code of the CLI for a failure of this kind. These are stable,
+    /// new kinds get new codes.
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Other => 1,
+            ErrorKind::Invalid => 2,
+            ErrorKind::NotFound => 3,
+            ErrorKind::Hypervisor => 4,
+            ErrorKind::Conflict => 5,
+            ErrorKind::Timeout => 6,
+        }
+    }
+
+    /// The kind of `err`, from the first error in its source chain that
+    /// has one
+    pub fn of(err: &(dyn std::error::Error + 'static)) -> Self {
+        let mut next = Some(err);
+        while let Some(e) = next {
+            if let Some(e) = e.downcast_ref::<KindError>() {
+                return e.kind;
+            }
+            if e.is::<virt::error::Error>() {
+                return ErrorKind::Hypervisor;
+            }
+            if e.is::<serde_yaml::Error>() || e.is::<serde_json::Error>() {
+                return ErrorKind::Invalid;
+            }
+            if let Some(e) = e.downcast_ref::<std::io::Error>() {
+                match e.kind() {
+                    std::io::ErrorKind::NotFound => return ErrorKind::NotFound,
+                    std::io::ErrorKind::TimedOut => return ErrorKind::Timeout,
+                    std::io::ErrorKind::InvalidInput => return ErrorKind::Invalid,
+                    _ => {}
+                }
+            }
+            next = e.source();
+        }
+
+        ErrorKind::Other
+    }
+}
+
+/// An error of a known kind, see `not_found` and friends
+#[derive(Debug)]
+pub struct KindError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl fmt::Display for KindError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for KindError {}
+
+fn kind_error(kind: ErrorKind, message: impl Into<String>) -> Error {
+    Box::new(KindError {
+        kind,
+        message: message.into(),
+    })
+}
+
+pub fn invalid(message: impl Into<String>) -> Error {
+    kind_error(ErrorKind::Invalid, message)
+}
+
+pub fn not_found(message: impl Into<String>) -> Error {
+    kind_error(ErrorKind::NotFound, message)
+}
+
+pub fn conflict(message: impl Into<String>) -> Error {
+    kind_error(ErrorKind::Conflict, message)
+}
+
+pub fn timeout(message: impl Into<String>) -> Error {
+    kind_error(ErrorKind::Timeout, message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kinds() {
+        let kind = |e: Error| ErrorKind::of(e.as_ref());
+
+        assert_eq!(kind("something broke".into()), ErrorKind::Other);
+        assert_eq!(kind(not_found("no machine vm1")), ErrorKind::NotFound);
+        assert_eq!(not_found("no machine vm1").to_string(), "no machine vm1");
+        assert_eq!(kind(conflict("vm1 already exists")), ErrorKind::Conflict);
+
+        let e = serde_yaml::from_str::<u32>("[").unwrap_err();
+        assert_eq!(kind(e.into()), ErrorKind::Invalid);
+        let e = std::fs::read("/nonexistent/machine.yaml").unwrap_err();
+        assert_eq!(kind(e.into()), ErrorKind::NotFound);
+
+        assert_eq!(ErrorKind::Other.exit_code(), 1);
+        assert_eq!(ErrorKind::Invalid.exit_code(), 2);
+        assert_eq!(ErrorKind::NotFound.exit_code(), 3);
+        assert_eq!(ErrorKind::Hypervisor.exit_code(), 4);
+    }
+}
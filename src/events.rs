@@ -0,0 +1,128 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Domain lifecycle events (started, stopped, crashed, ...).
+//!
+//! The virt crate has no bindings for libvirt's event loop, so libvirt
+//! events are read from `virsh event --loop`, which prints one line per
+//! event.
+
+use std::io::{BufRead, BufReader};
+use std::process::Stdio;
+
+use serde::Serialize;
+
+use crate::error::Error;
+use crate::libvirt;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct LifecycleEvent {
+    /// when the event was received, e.g. `2023-10-16 09:30:45.123+0000`
+    pub timestamp: String,
+    pub machine: String,
+    /// lowercase event, e.g. "started", "stopped" or "crashed"
+    pub event: String,
+    /// lowercase reason, e.g. "booted", "destroyed" or "panicked"
+    pub detail: String,
+}
+
+/// Call `on_event` for every libvirt domain lifecycle event until it
+/// returns false or the connection to libvirt is lost
+pub fn watch_libvirt(on_event: &mut dyn FnMut(LifecycleEvent) -> bool) -> Result<(), Error> {
+    let mut child = libvirt::virsh()
+        .args([
+            "event",
+            "--all",
+            "--loop",
+            "--timestamp",
+            "--event",
+            "lifecycle",
+        ])
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("error executing virsh: {}", e))?;
+
+    let stdout = child.stdout.take().ok_or("no output from virsh event")?;
+
+    for line in BufReader::new(stdout).lines() {
+        let event = match parse(&line?) {
+            Some(event) => event,
+            None => continue,
+        };
+
+        if !on_event(event) {
+            child.kill()?;
+            child.wait()?;
+            return Ok(());
+        }
+    }
+
+    let status = child.wait()?;
+    Err(format!(
+        "virsh event exited ({}), lost connection to libvirt",
+        status
+    )
+    .into())
+}
+
+// parses lines like
+// `2023-10-16 09:30:45.123+0000: event 'lifecycle' for domain 'vm1': Started Booted`,
+// older virsh doesn't quote the domain name
+fn parse(line: &str) -> Option<LifecycleEvent> {
+    let (timestamp, rest) = line.split_once(": event 'lifecycle' for domain ")?;
+    let (domain, what) = rest.rsplit_once(": ")?;
+    let (event, detail) = what.split_once(' ').unwrap_or((what, ""));
+
+    let machine = domain
+        .strip_prefix('\'')
+        .and_then(|d| d.strip_suffix('\''))
+        .unwrap_or(domain);
+
+    Some(LifecycleEvent {
+        timestamp: timestamp.to_string(),
+        machine: machine.to_string(),
+        event: event.to_lowercase(),
+        detail: detail.trim().to_lowercase(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_virsh_events() {
+        let e = parse(
+            "2023-10-16 09:30:45.123+0000: event 'lifecycle' for domain 'web: 01': Crashed Panicked",
+        )
+        .unwrap();
+        assert_eq!(e.timestamp, "2023-10-16 09:30:45.123+0000");
+        assert_eq!(e.machine, "web: 01");
+        assert_eq!(e.event, "crashed");
+        assert_eq!(e.detail, "panicked");
+
+        let e = parse(
+            "2023-10-16 09:31:00.000+0000: event 'lifecycle' for domain vm1: Stopped Destroyed",
+        )
+        .unwrap();
+        assert_eq!(e.machine, "vm1");
+        assert_eq!(e.event, "stopped");
+
+        assert_eq!(parse("events received: 2"), None);
+        assert_eq!(parse(""), None);
+    }
+}
@@ -0,0 +1,294 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! The HostManager operations over gRPC for `bigiron-virt grpc`, with the
+//! service published in `proto/bigiron_virt.proto`.
+//!
+//! Each call runs on a blocking thread through the same `api` functions
+//! the CLI uses. Creates and watches stream from that thread through a
+//! channel, a watch ending at its next event once the caller has gone.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tracing::info;
+
+use crate::api;
+use crate::error::{self, Error};
+
+pub mod proto {
+    tonic::include_proto!("bigironvirt.v1");
+}
+
+use proto::host_manager_server::{HostManager, HostManagerServer};
+
+/// Serve on `listen`, e.g. "127.0.0.1:8701", until the process is stopped.
+/// There's no authentication, so only loopback addresses are accepted.
+pub fn serve(listen: &str) -> Result<(), Error> {
+    let addr: SocketAddr = listen
+        .parse()
+        .map_err(|e| format!("invalid listen address '{}': {}", listen, e))?;
+    if !addr.ip().is_loopback() {
+        return Err(error::invalid(format!(
+            "refusing to serve gRPC on {}, which isn't a loopback address",
+            addr
+        )));
+    }
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        info!("Serving gRPC on {}", addr);
+        tonic::transport::Server::builder()
+            .add_service(HostManagerServer::new(Service))
+            .serve(addr)
+            .await
+    })?;
+
+    Ok(())
+}
+
+struct Service;
+
+#[tonic::async_trait]
+impl HostManager for Service {
+    async fn list_machines(
+        &self,
+        request: Request<proto::ListMachinesRequest>,
+    ) -> Result<Response<proto::ListMachinesResponse>, Status> {
+        let selector = request.into_inner().selector;
+        let machines = blocking(move || {
+            let selector = match selector.is_empty() {
+                true => None,
+                false => Some(selector.parse()?),
+            };
+            api::list_machines(selector.as_ref())
+        })
+        .await?;
+
+        Ok(Response::new(proto::ListMachinesResponse {
+            machines: machines.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn show_machine(
+        &self,
+        request: Request<proto::ShowMachineRequest>,
+    ) -> Result<Response<proto::MachineInfo>, Status> {
+        let id = request.into_inner().id;
+        let info = blocking(move || {
+            let info = api::show_machine(&id)?;
+            proto::MachineInfo::try_from(info)
+        })
+        .await?;
+
+        Ok(Response::new(info))
+    }
+
+    type CreateMachinesStream = ReceiverStream<Result<proto::CreateProgress, Status>>;
+
+    async fn create_machines(
+        &self,
+        request: Request<proto::CreateMachinesRequest>,
+    ) -> Result<Response<Self::CreateMachinesStream>, Status> {
+        let yaml = request.into_inner().model_yaml;
+        let (tx, rx) = mpsc::channel(16);
+
+        tokio::task::spawn_blocking(move || {
+            let progress = tx.clone();
+            let result = api::create_with_progress(&yaml, move |machine, phase| {
+                let _ = progress.blocking_send(Ok(proto::CreateProgress {
+                    machine: machine.to_string(),
+                    phase: phase.to_string(),
+                }));
+            });
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(status(e)));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn stop_machine(
+        &self,
+        request: Request<proto::StopMachineRequest>,
+    ) -> Result<Response<proto::StopMachineResponse>, Status> {
+        let request = request.into_inner();
+        let timeout = match request.timeout_seconds {
+            0 => Duration::from_secs(120),
+            secs => Duration::from_secs(secs),
+        };
+        blocking(move || api::stop_machine(&request.id, timeout)).await?;
+
+        Ok(Response::new(proto::StopMachineResponse {}))
+    }
+
+    async fn destroy_machine(
+        &self,
+        request: Request<proto::DestroyMachineRequest>,
+    ) -> Result<Response<proto::DestroyMachineResponse>, Status> {
+        let id = request.into_inner().id;
+        blocking(move || api::destroy_machine(&id)).await?;
+
+        Ok(Response::new(proto::DestroyMachineResponse {}))
+    }
+
+    type WatchStream = ReceiverStream<Result<proto::LifecycleEvent, Status>>;
+
+    async fn watch(
+        &self,
+        request: Request<proto::WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let ids = request.into_inner().ids;
+        let (tx, rx) = mpsc::channel(64);
+
+        tokio::task::spawn_blocking(move || {
+            let events = tx.clone();
+            let result = api::watch(&ids, move |event| {
+                events.blocking_send(Ok(event.into())).is_ok()
+            });
+            if let Err(e) = result {
+                let _ = tx.blocking_send(Err(status(e)));
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+}
+
+// run `f` off the async threads, HostManager calls block on libvirt and
+// the filesystem
+async fn blocking<T, F>(f: F) -> Result<T, Status>
+where
+    F: FnOnce() -> Result<T, Error> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+        .map_err(status)
+}
+
+fn status(e: Error) -> Status {
+    Status::unknown(e.to_string())
+}
+
+impl From<api::MachineStatus> for proto::MachineStatus {
+    fn from(m: api::MachineStatus) -> Self {
+        Self {
+            id: m.id,
+            uuid: m.uuid.unwrap_or_default(),
+            status: m.status,
+            addresses: m.addresses.iter().map(|a| a.to_string()).collect(),
+            timestamps: Some(m.timestamps.into()),
+            health: m.health.unwrap_or_default(),
+        }
+    }
+}
+
+impl TryFrom<api::MachineInfo> for proto::MachineInfo {
+    type Error = Error;
+
+    fn try_from(info: api::MachineInfo) -> Result<Self, Error> {
+        Ok(Self {
+            spec_yaml: info.machine.to_yaml()?,
+            id: info.machine.metadata.name,
+            uuid: info.machine.metadata.uuid.unwrap_or_default(),
+            state: info.state,
+            addresses: info.addresses.iter().map(|a| a.to_string()).collect(),
+            timestamps: Some(info.timestamps.into()),
+        })
+    }
+}
+
+impl From<api::Timestamps> for proto::Timestamps {
+    fn from(t: api::Timestamps) -> Self {
+        Self {
+            created_at: t.created_at.unwrap_or_default(),
+            started_at: t.started_at.unwrap_or_default(),
+            state_changed_at: t.state_changed_at.unwrap_or_default(),
+            ready_at: t.ready_at.unwrap_or_default(),
+        }
+    }
+}
+
+impl From<crate::events::LifecycleEvent> for proto::LifecycleEvent {
+    fn from(e: crate::events::LifecycleEvent) -> Self {
+        Self {
+            timestamp: e.timestamp,
+            machine: e.machine,
+            event: e.event,
+            detail: e.detail,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::api::models::Machine;
+
+    #[test]
+    fn messages() {
+        let machine = Machine::builder()
+            .name("web1")
+            .cpu(1)
+            .memory("1Gi")
+            .image(
+                "file:///jammy.qcow2",
+                "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            )
+            .build()
+            .unwrap();
+        let info = api::MachineInfo {
+            machine,
+            state: String::from("running"),
+            addresses: vec!["10.0.0.5".parse().unwrap()],
+            timestamps: api::Timestamps {
+                created_at: Some(1697448645),
+                started_at: None,
+                state_changed_at: Some(1697448645),
+                ready_at: None,
+            },
+        };
+
+        let info = proto::MachineInfo::try_from(info).unwrap();
+        assert_eq!(info.id, "web1");
+        assert_eq!(info.uuid, "");
+        assert_eq!(info.addresses, ["10.0.0.5"]);
+        let timestamps = info.timestamps.unwrap();
+        assert_eq!(timestamps.created_at, 1697448645);
+        assert_eq!(timestamps.started_at, 0);
+        let spec: Machine = serde_yaml::from_str(&info.spec_yaml).unwrap();
+        assert_eq!(spec.metadata.name, "web1");
+
+        let status = proto::MachineStatus::from(api::MachineStatus {
+            id: String::from("web1"),
+            uuid: Some(String::from("0e7a3c52-9a8d-4b8e-9f4c-3f3f0b1d2c11")),
+            status: String::from("building: creating disk"),
+            addresses: Vec::new(),
+            timestamps: api::Timestamps::default(),
+            health: None,
+        });
+        assert_eq!(status.uuid, "0e7a3c52-9a8d-4b8e-9f4c-3f3f0b1d2c11");
+        assert_eq!(status.status, "building: creating disk");
+    }
+}
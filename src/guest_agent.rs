@@ -0,0 +1,225 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Commands to the qemu-guest-agent running inside a machine, over the
+//! `org.qemu.guest_agent.0` channel every domain is given.
+
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::error::Error;
+use crate::hypervisor::Hypervisor;
+
+// seconds to wait for the agent to answer a single command
+const AGENT_TIMEOUT: i32 = 10;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExecResult {
+    pub exit_code: i64,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+fn command(
+    hv: &dyn Hypervisor,
+    name: &str,
+    execute: &str,
+    arguments: Option<Value>,
+) -> Result<Value, Error> {
+    let mut cmd = json!({ "execute": execute });
+    if let Some(args) = arguments {
+        cmd["arguments"] = args;
+    }
+
+    let out = hv.agent_command(name, &cmd.to_string(), AGENT_TIMEOUT)?;
+    let resp: Value = serde_json::from_str(&out)?;
+
+    Ok(resp["return"].clone())
+}
+
+/// Whether the agent in machine `name` is up and answering
+pub fn ping(hv: &dyn Hypervisor, name: &str) -> bool {
+    command(hv, name, "guest-ping", None).is_ok()
+}
+
+/// Run `argv` in the guest and wait up to `timeout` for it to finish
+pub fn exec(
+    hv: &dyn Hypervisor,
+    name: &str,
+    argv: &[String],
+    timeout: Duration,
+) -> Result<ExecResult, Error> {
+    let (path, args) = argv.split_first().ok_or("no command given")?;
+
+    let ret = command(
+        hv,
+        name,
+        "guest-exec",
+        Some(json!({ "path": path, "arg": args, "capture-output": true })),
+    )?;
+    let pid = ret["pid"]
+        .as_i64()
+        .ok_or("guest-exec did not return a pid")?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let status = command(hv, name, "guest-exec-status", Some(json!({ "pid": pid })))?;
+
+        if status["exited"].as_bool().unwrap_or(false) {
+            return exec_result(&status);
+        }
+
+        if Instant::now() >= deadline {
+            return Err(format!("command {:?} still running after {:?}", path, timeout).into());
+        }
+
+        std::thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn exec_result(status: &Value) -> Result<ExecResult, Error> {
+    let data = |key: &str| match status[key].as_str() {
+        Some(s) => decode_base64(s),
+        None => Ok(Vec::new()),
+    };
+
+    Ok(ExecResult {
+        // killed by a signal if there is no exit code
+        exit_code: status["exitcode"].as_i64().unwrap_or(-1),
+        stdout: data("out-data")?,
+        stderr: data("err-data")?,
+    })
+}
+
+/// Addresses configured on the guest's interfaces, loopback excluded
+pub fn ip_addrs(hv: &dyn Hypervisor, name: &str) -> Result<Vec<IpAddr>, Error> {
+    let ret = command(hv, name, "guest-network-get-interfaces", None)?;
+    Ok(interface_addrs(&ret))
+}
+
+fn interface_addrs(interfaces: &Value) -> Vec<IpAddr> {
+    let mut addrs = Vec::new();
+
+    for iface in interfaces.as_array().into_iter().flatten() {
+        for addr in iface["ip-addresses"].as_array().into_iter().flatten() {
+            if let Some(Ok(ip)) = addr["ip-address"].as_str().map(str::parse::<IpAddr>) {
+                if !ip.is_loopback() {
+                    addrs.push(ip);
+                }
+            }
+        }
+    }
+
+    addrs
+}
+
+/// Ask the guest OS to power off
+pub fn shutdown(hv: &dyn Hypervisor, name: &str) -> Result<(), Error> {
+    // the agent doesn't reply once the guest starts shutting down
+    match command(
+        hv,
+        name,
+        "guest-shutdown",
+        Some(json!({ "mode": "powerdown" })),
+    ) {
+        Ok(_) => Ok(()),
+        Err(e) if e.to_string().contains("timeout") => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+pub(crate) fn decode_base64(s: &str) -> Result<Vec<u8>, Error> {
+    let mut out = Vec::with_capacity(s.len() / 4 * 3);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+
+    for c in s.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return Err(format!("invalid base64 character {:?}", c as char).into()),
+        };
+
+        acc = (acc << 6) | v as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("aGk=").unwrap(), b"hi");
+        assert_eq!(decode_base64("aGVsbG8K").unwrap(), b"hello\n");
+        assert_eq!(decode_base64("YWJjZA==").unwrap(), b"abcd");
+        assert!(decode_base64("not*base64").is_err());
+    }
+
+    #[test]
+    fn exec_status() {
+        let status = json!({"exited": true, "exitcode": 3, "out-data": "b2sK"});
+        let r = exec_result(&status).unwrap();
+
+        assert_eq!(r.exit_code, 3);
+        assert_eq!(r.stdout, b"ok\n");
+        assert!(r.stderr.is_empty());
+    }
+
+    #[test]
+    fn interfaces() {
+        let ret = json!([
+            {
+                "name": "lo",
+                "ip-addresses": [
+                    {"ip-address-type": "ipv4", "ip-address": "127.0.0.1", "prefix": 8},
+                    {"ip-address-type": "ipv6", "ip-address": "::1", "prefix": 128}
+                ]
+            },
+            {
+                "name": "eth0",
+                "hardware-address": "52:54:00:12:34:56",
+                "ip-addresses": [
+                    {"ip-address-type": "ipv4", "ip-address": "192.168.122.10", "prefix": 24},
+                    {"ip-address-type": "ipv6", "ip-address": "fe80::5054:ff:fe12:3456", "prefix": 64}
+                ]
+            },
+            {"name": "eth1"}
+        ]);
+
+        let addrs: Vec<String> = interface_addrs(&ret)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(addrs, vec!["192.168.122.10", "fe80::5054:ff:fe12:3456"]);
+    }
+}
@@ -0,0 +1,246 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Health checks of running machines, declared as `spec.healthCheck` and
+//! run by `bigiron-virt serve`.
+//!
+//! Every probe's outcome is kept with the instance, so `list` shows the
+//! health the last one found rather than probing again itself. A machine
+//! is unhealthy after `retries` failures in a row and healthy again after
+//! one success. Failures in the start period after the machine starts
+//! aren't counted, unless cloud-init in it is done.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::api::models::HealthCheck;
+use crate::error::Error;
+
+/// What the probes of a machine's health check found so far
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthStatus {
+    /// failed probes in a row
+    pub failures: u32,
+    /// when the last probe ran, in seconds since the epoch
+    pub checked_at: Option<u64>,
+    /// why the last probe failed, if it did
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// restarts for failing the check
+    pub restarts: u32,
+}
+
+impl HealthStatus {
+    /// Whether `check` is due to be probed again at `now`
+    pub fn due(&self, check: &HealthCheck, now: u64) -> bool {
+        self.checked_at
+            .is_none_or(|at| now >= at + check.interval().as_secs())
+    }
+
+    /// Count the outcome of a probe at `now`, a failure only if the
+    /// machine isn't `starting`
+    pub fn record(&mut self, result: Result<(), Error>, now: u64, starting: bool) {
+        self.checked_at = Some(now);
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                self.last_error = None;
+            }
+            Err(e) => {
+                if !starting {
+                    self.failures += 1;
+                }
+                self.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    /// Probe again from scratch, after the machine was restarted
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.checked_at = None;
+        self.last_error = None;
+    }
+
+    pub fn is_healthy(&self, check: &HealthCheck) -> bool {
+        self.failures < check.retries()
+    }
+
+    /// "healthy" or "unhealthy", "starting" before the first probe and
+    /// while failures aren't counted yet
+    pub fn summary(&self, check: &HealthCheck) -> &'static str {
+        match (self.checked_at, self.is_healthy(check)) {
+            (None, _) => "starting",
+            (Some(_), true) if self.failures == 0 && self.last_error.is_some() => "starting",
+            (Some(_), true) => "healthy",
+            (Some(_), false) => "unhealthy",
+        }
+    }
+}
+
+/// Pass if something accepts a connection on `port` of `addr` within
+/// `timeout`
+pub fn probe_tcp(addr: IpAddr, port: u16, timeout: Duration) -> Result<(), Error> {
+    let addr = SocketAddr::new(addr, port);
+    TcpStream::connect_timeout(&addr, timeout)
+        .map_err(|e| format!("connecting to {}: {}", addr, e))?;
+    Ok(())
+}
+
+/// Pass if a GET of `url`, with `{address}` replaced by `addr`, answers
+/// with a 2xx or 3xx status within `timeout`
+pub fn probe_http(url: &str, addr: Option<IpAddr>, timeout: Duration) -> Result<(), Error> {
+    let url = match addr {
+        Some(IpAddr::V6(v6)) => url.replace("{address}", &format!("[{}]", v6)),
+        Some(IpAddr::V4(v4)) => url.replace("{address}", &v4.to_string()),
+        None if url.contains("{address}") => return Err("no address known".into()),
+        None => url.to_string(),
+    };
+    let url = Url::parse(&url)?;
+    let host = url.host_str().ok_or("no host in URL")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let sock = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| format!("{} has no address", host))?;
+    let mut stream = TcpStream::connect_timeout(&sock, timeout)
+        .map_err(|e| format!("connecting to {}: {}", sock, e))?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut target = url.path().to_string();
+    if let Some(query) = url.query() {
+        target = format!("{}?{}", target, query);
+    }
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        target, host
+    );
+    stream.write_all(request.as_bytes())?;
+
+    // the status line is all that matters
+    let mut head = [0; 32];
+    let mut len = 0;
+    while len < head.len() {
+        match stream.read(&mut head[len..])? {
+            0 => break,
+            n => len += n,
+        }
+    }
+
+    match http_status(&head[..len]) {
+        Some(code) if (200..400).contains(&code) => Ok(()),
+        Some(code) => Err(format!("{} answered {}", url, code).into()),
+        None => Err(format!("{} gave no HTTP response", url).into()),
+    }
+}
+
+// the status code of the response starting with `head`
+fn http_status(head: &[u8]) -> Option<u16> {
+    let line = std::str::from_utf8(head).ok()?.lines().next()?;
+    let mut parts = line.split_whitespace();
+    parts.next().filter(|v| v.starts_with("HTTP/"))?;
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    #[test]
+    fn status() {
+        let check = HealthCheck {
+            tcp: Some(22),
+            interval: Some(10),
+            retries: Some(2),
+            ..Default::default()
+        };
+        let mut status = HealthStatus::default();
+        assert!(status.due(&check, 100));
+        assert_eq!(status.summary(&check), "starting");
+
+        // still booting, failures don't count
+        status.record(Err("refused".into()), 90, true);
+        assert_eq!(status.failures, 0);
+        assert_eq!(status.summary(&check), "starting");
+
+        status.record(Err("refused".into()), 100, false);
+        assert!(!status.due(&check, 109));
+        assert!(status.due(&check, 110));
+        assert_eq!(status.summary(&check), "healthy");
+
+        status.record(Err("refused".into()), 110, false);
+        assert_eq!(status.summary(&check), "unhealthy");
+        assert_eq!(status.last_error.as_deref(), Some("refused"));
+
+        status.record(Ok(()), 120, false);
+        assert_eq!(status.failures, 0);
+        assert_eq!(status.summary(&check), "healthy");
+
+        status.reset();
+        assert!(status.due(&check, 121));
+        assert_eq!(status.summary(&check), "starting");
+    }
+
+    #[test]
+    fn probes() {
+        let localhost = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let timeout = Duration::from_secs(2);
+        let listener = TcpListener::bind((localhost, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        assert!(probe_tcp(localhost, port, timeout).is_ok());
+        listener.accept().unwrap();
+
+        let url = format!("http://{{address}}:{}/healthz", port);
+        for (response, healthy) in [
+            ("HTTP/1.1 200 OK\r\n\r\n", true),
+            ("HTTP/1.1 503 Busy\r\n\r\n", false),
+        ] {
+            let server = std::thread::spawn({
+                let listener = listener.try_clone().unwrap();
+                move || {
+                    let (mut conn, _) = listener.accept().unwrap();
+                    let mut request = [0; 256];
+                    let n = conn.read(&mut request).unwrap();
+                    conn.write_all(response.as_bytes()).unwrap();
+                    String::from_utf8_lossy(&request[..n]).into_owned()
+                }
+            });
+
+            let result = probe_http(&url, Some(localhost), timeout);
+            assert_eq!(result.is_ok(), healthy);
+            assert!(server
+                .join()
+                .unwrap()
+                .starts_with("GET /healthz HTTP/1.0\r\n"));
+        }
+
+        drop(listener);
+        assert!(probe_tcp(localhost, port, timeout).is_err());
+        assert!(probe_http(&url, None, timeout).is_err());
+        assert_eq!(http_status(b"HTTP/1.0 301 Moved\r\n"), Some(301));
+        assert_eq!(http_status(b"SSH-2.0-OpenSSH"), None);
+    }
+}
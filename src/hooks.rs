@@ -0,0 +1,255 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Machine lifecycle hooks, for wiring in DNS registration, monitoring,
+//! IPAM and the like.
+//!
+//! Every executable in the hooks directory (`/etc/bigiron-virt/hooks.d` by
+//! default) is run in name order with the event as its only argument and
+//! a JSON `HookContext` on stdin. Programs embedding the crate can
+//! `register` callbacks instead, which run before the scripts.
+//!
+//! A failing `pre-*` hook aborts the operation, failing `post-*` hooks are
+//! only logged since the machine has already changed.
+
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::api::models::Machine;
+use crate::error::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HookEvent {
+    PreCreate,
+    PostCreate,
+    PreDestroy,
+    PostDestroy,
+}
+
+impl HookEvent {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HookEvent::PreCreate => "pre-create",
+            HookEvent::PostCreate => "post-create",
+            HookEvent::PreDestroy => "pre-destroy",
+            HookEvent::PostDestroy => "post-destroy",
+        }
+    }
+
+    fn is_pre(&self) -> bool {
+        matches!(self, HookEvent::PreCreate | HookEvent::PreDestroy)
+    }
+}
+
+/// What hooks are told about the machine
+#[derive(Debug, Clone, Serialize)]
+pub struct HookContext<'a> {
+    pub event: HookEvent,
+    pub name: &'a str,
+    /// The stored spec, missing for machines created before specs were
+    /// stored, with the MAC address of each nic filled in
+    pub machine: Option<&'a Machine>,
+}
+
+pub type HookFn = Box<dyn Fn(&HookContext) -> Result<(), Error> + Send + Sync>;
+
+static CALLBACKS: Mutex<Vec<HookFn>> = Mutex::new(Vec::new());
+
+/// Run `callback` on every lifecycle event in this process
+pub fn register<F>(callback: F)
+where
+    F: Fn(&HookContext) -> Result<(), Error> + Send + Sync + 'static,
+{
+    CALLBACKS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(Box::new(callback));
+}
+
+pub struct Hooks {
+    directory: PathBuf,
+}
+
+impl Hooks {
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        Self {
+            directory: directory.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Run callbacks then scripts for `event`. Errors are only returned
+    /// for pre-* events.
+    pub fn run(
+        &self,
+        event: HookEvent,
+        name: &str,
+        machine: Option<&Machine>,
+    ) -> Result<(), Error> {
+        let ctx = HookContext {
+            event,
+            name,
+            machine,
+        };
+
+        match self.run_all(&ctx) {
+            Err(e) if !event.is_pre() => {
+                warn!("{} hook for '{}' failed: {}", event.as_str(), name, e);
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn run_all(&self, ctx: &HookContext) -> Result<(), Error> {
+        for callback in CALLBACKS.lock().unwrap_or_else(|e| e.into_inner()).iter() {
+            callback(ctx)?;
+        }
+
+        let json = serde_json::to_vec(ctx)?;
+        for script in self.scripts()? {
+            run_script(&script, ctx, &json)?;
+        }
+
+        Ok(())
+    }
+
+    // executables in the hooks directory, in name order
+    fn scripts(&self) -> Result<Vec<PathBuf>, Error> {
+        if !self.directory.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut scripts = Vec::new();
+        for entry in std::fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            let meta = std::fs::metadata(&path)?;
+
+            if meta.is_file() && meta.permissions().mode() & 0o111 != 0 {
+                scripts.push(path);
+            }
+        }
+
+        scripts.sort();
+        Ok(scripts)
+    }
+}
+
+fn run_script(script: &Path, ctx: &HookContext, json: &[u8]) -> Result<(), Error> {
+    debug!("Running {} hook {:?}", ctx.event.as_str(), script);
+
+    let mut child = Command::new(script)
+        .arg(ctx.event.as_str())
+        .env("BIGIRON_VIRT_EVENT", ctx.event.as_str())
+        .env("BIGIRON_VIRT_MACHINE", ctx.name)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| format!("error executing hook {:?}: {}", script, e))?;
+
+    // a hook that doesn't read its input closes the pipe early, that's fine
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(json);
+    }
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "hook {:?} failed ({}): {}",
+            script,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn write_script(dir: &Path, name: &str, body: &str) {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn scripts_and_callbacks() {
+        let dir = std::env::temp_dir().join("bigiron-virt-hooks-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        write_script(&dir, "10-record", "cat > \"$(dirname $0)/$1.json\"");
+        write_script(
+            &dir,
+            "20-veto",
+            "[ \"$1\" = pre-destroy ] && echo protected >&2 && exit 3; exit 0",
+        );
+        // not executable, so never run
+        std::fs::write(dir.join("README"), "exit 1").unwrap();
+
+        static SEEN: AtomicUsize = AtomicUsize::new(0);
+        register(|ctx| {
+            if ctx.name == "hooked-vm" {
+                SEEN.fetch_add(1, Ordering::SeqCst);
+            }
+            Ok(())
+        });
+
+        let hooks = Hooks::new(&dir);
+        hooks.run(HookEvent::PreCreate, "hooked-vm", None).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(dir.join("pre-create.json")).unwrap()).unwrap();
+        assert_eq!(json["event"], "pre-create");
+        assert_eq!(json["name"], "hooked-vm");
+        assert!(json["machine"].is_null());
+
+        let err = hooks
+            .run(HookEvent::PreDestroy, "hooked-vm", None)
+            .unwrap_err();
+        assert!(err.to_string().contains("protected"));
+
+        // post hooks only warn
+        std::fs::remove_file(dir.join("10-record")).unwrap();
+        write_script(&dir, "10-record", "exit 1");
+        hooks
+            .run(HookEvent::PostDestroy, "hooked-vm", None)
+            .unwrap();
+
+        assert_eq!(SEEN.load(Ordering::SeqCst), 3);
+
+        // a missing directory has no hooks
+        Hooks::new(dir.join("missing"))
+            .run(HookEvent::PreCreate, "other-vm", None)
+            .unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
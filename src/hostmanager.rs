@@ -15,169 +15,2704 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
-use tracing::info;
+use std::collections::HashSet;
+use std::io::{Read, Seek, SeekFrom};
+use std::net::{IpAddr, SocketAddr, SocketAddrV6, TcpStream};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
 use url::Url;
 
-use crate::api::models::Machine;
+use crate::adopt;
+use crate::api::models::{
+    AddressKind, AddressPool, Block, ConfigDriveFormat, CpuPolicy, DiskDriver, File, HealthCheck,
+    Image, MacPolicy, Machine, SecurityModel, Selector, Size, Spec, StorageKind,
+};
+use crate::archive;
+use crate::backup::{BackupInfo, BackupStore};
+use crate::capacity::{
+    Demand, HostCapacity, MachinePlan, OvercommitRatios, Placement, PlanReport, Planner,
+};
+use crate::config::{DnsConfig, HostConfig, Scope};
 use crate::configdrive;
-use crate::error::Error;
-use crate::image::repo::Directory;
-use crate::libvirt;
+use crate::dns;
+use crate::error::{self, Error};
+use crate::events::LifecycleEvent;
+use crate::guest_agent::{self, ExecResult};
+use crate::health;
+use crate::hooks::{HookEvent, Hooks};
+use crate::hypervisor::{self, DomainSpec, DomainStats, Hypervisor};
+use crate::image::oci;
+use crate::image::repo::{hash_file, Directory, ImageInfo};
+use crate::ipam::{PoolStore, PoolUsage};
 use crate::mac::Mac;
+use crate::mdev;
+use crate::metrics::Metrics;
+use crate::neighbors;
+use crate::netfilter;
 use crate::network_config;
-use crate::vmstore::VMStore;
+use crate::orphans::{self, Orphan};
+use crate::secret_provider;
+use crate::stats::MachineStats;
+use crate::update::{self, Change, UpdateReport};
+use crate::userdata::{self, CLOUD_CONFIG_HEADER};
+use crate::vmstore::{imgutil, InstanceImage, Phase, Timestamps, VMStore};
+
+// how long a machine's Network nic may take to get a DHCP lease before its
+// ports can be forwarded
+const LEASE_WAIT: Duration = Duration::from_secs(120);
+
+// how long destroy waits for a clean shutdown unless the host config says
+const DESTROY_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub struct HostManager {
     vmstore: VMStore,
     imagestore: Directory,
+    backups: BackupStore,
+    pools: PoolStore,
+    hypervisor: Box<dyn Hypervisor>,
+    hooks: Hooks,
+    overcommit: OvercommitRatios,
+    // base URL cloud-init phones home to, see `HostConfig::phone_home`
+    phone_home: Option<String>,
+    // where machine names are registered, see `HostConfig::dns`
+    dns: Option<DnsConfig>,
+    // how long destroy waits for a clean shutdown, zero to power off
+    destroy_timeout: Duration,
+    // config drive ISO maker, see `HostConfig::iso_tool`
+    iso_tool: Option<PathBuf>,
+    // told each phase a create reaches, see `on_phase`
+    progress: Option<PhaseObserver>,
 }
 
+type PhaseObserver = Box<dyn FnMut(&str, &Phase)>;
+
 pub type MachineList = Vec<MachineStatus>;
 
+#[derive(Serialize, Deserialize)]
 pub struct MachineStatus {
     pub id: String,
+    pub uuid: Option<String>,
     pub status: String,
+    pub addresses: Vec<IpAddr>,
+    #[serde(default)]
+    pub timestamps: Timestamps,
+    /// what the spec's health check found, for running machines with one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MachineInfo {
+    pub machine: Machine,
+    pub state: String,
+    pub addresses: Vec<IpAddr>,
+    #[serde(default)]
+    pub timestamps: Timestamps,
 }
 
 impl HostManager {
     pub fn new() -> Result<Self, Error> {
-        let vsp = "/var/lib/bigiron-virt/instances";
-        let isp = "/var/lib/bigiron-virt/images";
+        let config = HostConfig::load()?;
+        let scope = Scope::current();
 
         Ok(Self {
-            vmstore: VMStore::new(&vsp)?,
-            imagestore: Directory::new(&isp)?,
+            vmstore: VMStore::new(scope.instance_dir(), config.instance_storage)?,
+            imagestore: Directory::new(scope.image_dir())?,
+            backups: BackupStore::new(&config.backup)?,
+            pools: PoolStore::new(scope.pool_dir()),
+            hypervisor: hypervisor::from_config(&config.hypervisor)?,
+            hooks: Hooks::new(&config.hooks.directory),
+            overcommit: config.overcommit,
+            phone_home: config.phone_home,
+            dns: config.dns,
+            destroy_timeout: config
+                .destroy_timeout
+                .map_or(DESTROY_TIMEOUT, Duration::from_secs),
+            iso_tool: config.iso_tool,
+            progress: None,
         })
     }
 
+    /// Wait up to `timeout` for machines to shut down on destroy before
+    /// powering them off, in place of the host's `destroyTimeout`
+    pub fn set_destroy_timeout(&mut self, timeout: Duration) {
+        self.destroy_timeout = timeout;
+    }
+
+    /// Call `f` with the machine name and phase each time a create reaches
+    /// another phase, as it's recorded in the instance
+    pub fn on_phase<F: FnMut(&str, &Phase) + 'static>(&mut self, f: F) {
+        self.progress = Some(Box::new(f));
+    }
+
+    fn set_phase(&mut self, name: &str, phase: &Phase) -> Result<(), Error> {
+        self.vmstore.set_phase(name, phase)?;
+        if let Some(ref mut progress) = self.progress {
+            progress(name, phase);
+        }
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(machine = %machine.metadata.name))]
     pub fn create_machine(&mut self, machine: &mut Machine) -> Result<(), Error> {
+        let name = machine.metadata.name.clone();
+        if self.vmstore.list_instances()?.contains(&name) {
+            return Err(error::conflict(format!(
+                "machine '{}' already exists",
+                name
+            )));
+        }
+        // registered in DNS as <name>.<domain>
+        if self.dns.is_some() && !dns::is_label(&name) {
+            return Err(error::invalid(format!(
+                "machine name '{}' isn't a DNS label, use up to 63 lowercase letters, digits \
+                 and '-', starting and ending with a letter or digit",
+                name
+            )));
+        }
+
+        // picked first so hooks, network config and the domain all see them
+        assign_macs(&name, &mut machine.spec);
+        self.hooks.run(HookEvent::PreCreate, &name, Some(machine))?;
+
+        // recorded with the url and hash, so the spec still says what the
+        // machine was made from if the name moves to another image
+        machine.spec.image = self.resolve_image(&machine.spec.image)?;
+
+        // refuse before downloading or allocating anything
+        self.planner()?.require(&self.demand(machine)?)?;
+
+        // make sure declared storage is addressable and present before
+        // committing to anything
+        if let Some(ref storages) = machine.spec.storage {
+            if let Some(last) = storages.len().checked_sub(1) {
+                machine.spec.storage_bus.unwrap_or_default().target(last)?;
+            }
+
+            prepare_storage(
+                self.hypervisor.as_ref(),
+                storages,
+                machine.spec.wait_for_storage.unwrap_or(0),
+            )?;
+        }
+
+        // the instance exists from here on, with each phase recorded so a
+        // failed create shows in list and show until it's destroyed
+        self.vmstore.new_instance(&name)?;
+        if let Err(e) = self.assign_addresses(machine) {
+            self.set_phase(&name, &Phase::Error(e.to_string()))?;
+            return Err(e);
+        }
+        self.vmstore.save_machine(&name, machine)?;
+
+        if let Err(e) = self.build_machine(machine) {
+            self.set_phase(&name, &Phase::Error(e.to_string()))?;
+            return Err(e);
+        }
+        self.set_phase(&name, &Phase::Running)?;
+
+        if let Err(e) = self.register_dns(&name) {
+            warn!("{} not registered in DNS: {}", name, e);
+        }
+
+        self.hooks
+            .run(HookEvent::PostCreate, &machine.metadata.name, Some(machine))
+    }
+
+    // the steps of creating a machine once its instance directory exists
+    fn build_machine(&mut self, machine: &mut Machine) -> Result<(), Error> {
         let name = &machine.metadata.name;
+        let instance_dir = self.vmstore.path_for_instance(name);
 
         // ensure base image imported to repo
+        self.set_phase(name, &Phase::DownloadingImage)?;
         let image_url = Url::parse(&machine.spec.image.url)?;
         let image_base_id = self
             .imagestore
             .add_image(&image_url, &machine.spec.image.hash)?;
 
-        // create instance storage directory
-        let instance_dir = self.vmstore.new_instance(name)?;
+        // name the image, unless the name is how it was found
+        if let Some(ref image_name) = machine.spec.image.name {
+            if self
+                .imagestore
+                .find(image_name)
+                .map_or(true, |i| i.hash != image_base_id)
+            {
+                self.imagestore
+                    .set_name(&image_base_id, image_name, &[], None)?;
+            }
+        }
 
         // create instance image from base
-        let image_size = match machine.spec.image.resize {
+        self.set_phase(name, &Phase::CreatingDisk)?;
+        let image_size = machine.spec.image.resize.map(|s| s.bytes());
+
+        let disk_secret = match machine.spec.image.encryption {
+            Some(ref encryption) => Some(
+                self.hypervisor
+                    .disk_secret(self.vmstore.instance_image(name).path(), encryption)?,
+            ),
             None => None,
-            Some(ref size_string) => Some(crate::api::models::to_size(size_string)?),
         };
 
-        let image_path = self.vmstore.create_instance_image(
+        let image = self.vmstore.create_instance_image(
             name,
             self.imagestore.get_image(&image_base_id)?,
             image_size,
+            machine.spec.image.mode.unwrap_or_default(),
+            disk_secret
+                .as_ref()
+                .map(|(_, passphrase)| passphrase.as_slice()),
         )?;
 
-        // create base vm spec
-        let mut d = libvirt::DomainBuilder::new(
-            name,
-            machine.spec.cpu,
-            crate::api::models::to_size(&machine.spec.memory)?,
-            image_path,
-        );
+        // a stable identity for the domain and cloud-init, the smbios one
+        // if given since libvirt needs them to agree
+        let uuid = machine
+            .metadata
+            .uuid
+            .clone()
+            .or_else(|| machine.spec.smbios.as_ref().and_then(|s| s.uuid.clone()))
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        machine.metadata.uuid = Some(uuid);
 
-        let mut bridged_nic_info = None;
+        self.set_phase(name, &Phase::BuildingConfigDrive)?;
+        let cd_path = build_config_drive(
+            machine,
+            &instance_dir,
+            self.phone_home.as_deref(),
+            self.iso_tool.as_deref(),
+        )?
+        .canonicalize()?;
+        relabel_instance_dir(machine, &instance_dir)?;
 
-        // network config
-        if let Some(nics) = &mut machine.spec.nics {
-            for nic in nics.iter_mut() {
-                nic.macaddress = Mac::gen().to_string();
+        // record the machine as created, with its MAC addresses
+        self.vmstore.save_machine(name, machine)?;
 
-                match nic.kind.as_str() {
-                    "Bridge" => {
-                        d.add_bridged_interface(&nic.parent, &nic.macaddress);
-                        bridged_nic_info = Some(nic.macaddress.clone());
-                    }
-                    "Macvtap" => {
-                        d.add_macvtap_interface(&nic.parent, &nic.macaddress);
-                    }
-                    &_ => {}
+        self.set_phase(name, &Phase::DefiningDomain)?;
+        self.allocate_mdevs(machine)?;
+        let uuid = disk_secret.as_ref().map(|(uuid, _)| uuid.as_str());
+        self.start_domain(machine, &image, &cd_path, uuid)
+    }
+
+    /// Rebuild the config drive of machine `id`, taking userdata, secrets
+    /// and nic addressing from `update` if given, and swap it into the
+    /// machine if it is running. cloud-init only acts on the new data as
+    /// far as its modules run on every boot.
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn update_config_drive(&mut self, id: &str, update: Option<&Machine>) -> Result<(), Error> {
+        let id = &self.resolve(id)?;
+        let mut machine = self.load_machine(id)?;
+
+        if let Some(update) = update {
+            merge_cloud_init(&mut machine, update)?;
+        }
+
+        self.rebuild_config_drive(id, &machine)
+    }
+
+    // build the config drive of machine `id` from `machine` and record the
+    // machine, swapping the drive into it if it is running
+    fn rebuild_config_drive(&mut self, id: &str, machine: &Machine) -> Result<(), Error> {
+        // built aside and renamed into place, so a running machine keeps
+        // reading the old one until the media is swapped
+        let instance_dir = self.vmstore.path_for_instance(id);
+        let staging = instance_dir.join("cidata-update");
+        let iso = build_config_drive(
+            machine,
+            &staging,
+            self.phone_home.as_deref(),
+            self.iso_tool.as_deref(),
+        )?;
+
+        let cd_path = instance_dir.join(archive::CONFIG_DRIVE_FILE);
+        std::fs::rename(iso, &cd_path)?;
+        std::fs::remove_dir(&staging)?;
+
+        self.vmstore.save_machine(id, machine)?;
+
+        let format = machine.spec.config_drive_format.unwrap_or_default();
+        if !format.is_cdrom() {
+            info!(
+                "The new config drive of '{}' is read from its next start",
+                id
+            );
+        } else if self.hypervisor.is_active(id)? {
+            info!("Swapping the config drive of '{}'", id);
+            self.hypervisor
+                .swap_config_drive(id, &cd_path.canonicalize()?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Replace the spec of machine `id` with `update`, applying what the
+    /// running machine can take now. The rest waits for a restart, which
+    /// `restart` does, shutting the machine down within `timeout` and
+    /// starting it again from the new spec. Persistent domains, such as
+    /// adopted ones, are redefined from it as well.
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn update_machine(
+        &mut self,
+        id: &str,
+        update: &Machine,
+        restart: bool,
+        timeout: Duration,
+    ) -> Result<UpdateReport, Error> {
+        let id = &self.resolve(id)?;
+        let mut current = self.vmstore.load_machine(id)?;
+
+        let mut machine = update.clone();
+        machine.spec.image = self.resolve_image(&machine.spec.image)?;
+        carry_over(&current, &mut machine);
+        let changes = update::plan(&current, &machine)?;
+
+        for nic in machine.spec.nics.iter_mut().flatten() {
+            if let AddressKind::Pool(ref mut p) = nic.address {
+                if p.assigned.is_none() {
+                    p.assigned = Some(self.pools.assign(&p.pool, id)?);
+                }
+            }
+        }
+        // random MACs of machines stored before MACs were kept can't be
+        // told again, so there's no telling which nic to unplug
+        let random = current.spec.mac_policy == Some(MacPolicy::Random)
+            && current
+                .spec
+                .nics
+                .iter()
+                .flatten()
+                .any(|n| n.macaddress.is_empty());
+        assign_macs(id, &mut current.spec);
+        assign_macs(id, &mut machine.spec);
+
+        let running = self.hypervisor.is_active(id)? && !restart;
+        let hv = self.hypervisor.as_ref();
+        let mut report = UpdateReport::default();
+        for (change, what) in changes {
+            let applied = match change {
+                Change::Stored(_) => true,
+                Change::Restart(_) => false,
+                _ if !running => false,
+                Change::DetachNic(_) if random => false,
+                Change::Memory(bytes) => {
+                    hv.set_memory(id, bytes)?;
+                    true
+                }
+                Change::AttachNic(i) => {
+                    hv.attach_interface(id, &machine.spec.nics.as_ref().unwrap()[i])?;
+                    true
+                }
+                Change::DetachNic(i) => {
+                    hv.detach_interface(id, &current.spec.nics.as_ref().unwrap()[i])?;
+                    true
                 }
+                Change::AttachDisk(i) => {
+                    let target = machine.spec.storage_bus.unwrap_or_default().target(i)?;
+                    let path = match machine.spec.storage.as_ref().unwrap()[i] {
+                        StorageKind::File(ref f) => &f.path,
+                        StorageKind::Block(ref b) => &b.path,
+                        _ => return Err("only file and block disks are hot plugged".into()),
+                    };
+                    hv.attach_disk(id, path, &target)?;
+                    true
+                }
+            };
+            if applied {
+                info!("Updated '{}': {}", id, what);
+                report.applied.push(what);
+            } else {
+                info!("Updating '{}' on restart: {}", id, what);
+                report.pending.push(what);
             }
         }
 
-        let netconf = network_config::build_net_config(&machine.spec.nics)?;
+        // network config follows the nics
+        self.rebuild_config_drive(id, &machine)?;
 
-        // create config drive
-        let mut builder = configdrive::Builder::new(name);
+        if restart {
+            self.restart_machine(id, &current, &mut machine, timeout)?;
+            report.applied.append(&mut report.pending);
+            report.restarted = true;
+        }
 
-        if !netconf.is_empty() {
-            builder.add_network_config(netconf);
+        // a persistent domain starts from its own definition rather than the
+        // spec, e.g. when the host autostarts it
+        if self.hypervisor.status(id)?.is_some() {
+            self.redefine_domain(id, &mut machine)?;
         }
 
-        if let Some(ref userdata) = machine.spec.userdata {
-            builder.add_userdata(userdata.as_bytes().to_vec());
+        Ok(report)
+    }
+
+    // shut machine `id` down if it is running and start it from `machine`,
+    // its spec that was `current`
+    fn restart_machine(
+        &mut self,
+        id: &str,
+        current: &Machine,
+        machine: &mut Machine,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        // booting afresh would leave the saved memory behind its disks
+        if self.vmstore.has_saved_state(id) {
+            return Err(error::conflict(format!(
+                "machine '{}' has a saved state, restore it first",
+                id
+            )));
+        }
+        assign_macs(id, &mut machine.spec);
+        if self.hypervisor.is_active(id)? {
+            self.stop_machine(id, timeout)?;
+        }
+        // stopping leaves the port forwards, starting adds them again
+        if current.spec.port_forwards.is_some() {
+            netfilter::remove(id)?;
         }
 
-        let cd_path = builder.build(instance_dir)?.canonicalize()?;
+        self.allocate_mdevs(machine)?;
+        let image = self.vmstore.instance_image(id);
+        let cd_path = self
+            .vmstore
+            .path_for_instance(id)
+            .join(archive::CONFIG_DRIVE_FILE)
+            .canonicalize()?;
+        let disk_secret = match machine.spec.image.encryption {
+            Some(ref encryption) => Some(self.hypervisor.disk_secret(image.path(), encryption)?.0),
+            None => None,
+        };
 
-        // attach config drive
-        d.add_cdrom_from_iso(&cd_path)?;
+        info!("Starting '{}' from its updated spec", id);
+        self.start_domain(machine, &image, &cd_path, disk_secret.as_deref())?;
+        self.vmstore.state_changed(id, true)?;
 
-        // attach storage devices
-        if let Some(storages) = &machine.spec.storage {
-            let drive_letter_start: u8 = 98; // "b" in ASCII
-            use crate::api::models::StorageKind;
-            for (i, store) in storages.iter().enumerate() {
-                if i > 24 {
-                    panic!("not enough drive letters for storage drives");
-                }
-                // i already fits from above check
-                let i_u8: u8 = i.try_into().unwrap();
+        if let Err(e) = self.register_dns(id) {
+            warn!("{} not registered in DNS: {}", id, e);
+        }
+        Ok(())
+    }
 
-                let v = [118, 100, drive_letter_start + i_u8];
-                let target_name = std::str::from_utf8(&v).unwrap();
+    // make `machine` the definition of the domain of machine `id`, leaving
+    // the running domain as it is
+    fn redefine_domain(&mut self, id: &str, machine: &mut Machine) -> Result<(), Error> {
+        self.allocate_mdevs(machine)?;
+        let image = self.vmstore.instance_image(id);
+        let cd_path = self
+            .vmstore
+            .path_for_instance(id)
+            .join(archive::CONFIG_DRIVE_FILE)
+            .canonicalize()?;
+        let disk_secret = match machine.spec.image.encryption {
+            Some(ref encryption) => Some(self.hypervisor.disk_secret(image.path(), encryption)?.0),
+            None => None,
+        };
+        let cdroms = self.cdrom_isos(machine)?;
 
-                match store {
-                    StorageKind::File(ref file) => {
-                        d.add_file_backed_storage(&file.path, &target_name);
-                    }
-                    StorageKind::Block(ref block) => {
-                        d.add_block_backed_storage(&block.path, &target_name);
-                    }
-                }
+        // the scratch disks in use, which starting would make afresh
+        let bus = machine.spec.storage_bus.unwrap_or_default();
+        let mut scratch_disks = Vec::new();
+        for (i, store) in machine.spec.storage.iter().flatten().enumerate() {
+            if let StorageKind::Ephemeral(ref scratch) = store {
+                let target = bus.target(i)?;
+                scratch_disks.push(self.vmstore.scratch_disk_path(
+                    id,
+                    target.as_str(),
+                    scratch.tmpfs.unwrap_or(false),
+                ));
+            }
+        }
+
+        self.hypervisor.redefine(&DomainSpec {
+            machine,
+            image: &image,
+            config_drive: &cd_path,
+            cdroms: &cdroms,
+            disk_secret: disk_secret.as_deref(),
+            scratch_disks: &scratch_disks,
+            xml_file: &self.vmstore.domain_xml_path(id),
+            console_log: &std::path::absolute(self.vmstore.console_log_path(id))?,
+        })
+    }
+
+    /// Files on the config drive of machine `id`, as the guest sees them
+    pub fn config_drive_files(&self, id: &str) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        let id = &self.resolve(id)?;
+        self.vmstore.load_machine(id)?;
+
+        let cd_path = self
+            .vmstore
+            .path_for_instance(id)
+            .join(archive::CONFIG_DRIVE_FILE);
+        configdrive::read_files(cd_path)
+    }
+
+    /// Write a portable archive of machine `id` to `output`. The disk of a
+    /// running machine is copied as is, so stop it first for a clean copy.
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn export_machine(&self, id: &str, output: &Path) -> Result<(), Error> {
+        let machine = self.vmstore.load_machine(id)?;
+        if machine.spec.image.encryption.is_some() {
+            return Err(format!(
+                "machine '{}' has an encrypted disk, which can't be exported",
+                id
+            )
+            .into());
+        }
+
+        if self.hypervisor.is_active(id)? {
+            warn!(
+                "Machine '{}' is running, exported disk may be inconsistent",
+                id
+            );
+        }
+
+        let instance_dir = self.vmstore.path_for_instance(id);
+        let staging = staging_dir(output)?;
+        std::fs::create_dir(&staging)?;
+
+        let result = (|| -> Result<(), Error> {
+            for file in [archive::MACHINE_FILE, archive::CONFIG_DRIVE_FILE] {
+                std::fs::copy(instance_dir.join(file), staging.join(file))?;
+            }
+
+            info!("Flattening instance disk of '{}'", id);
+            self.vmstore
+                .export_instance_image(id, staging.join(archive::DISK_FILE))?;
+
+            archive::pack(&staging, output)
+        })();
+
+        std::fs::remove_dir_all(&staging)?;
+        result
+    }
+
+    /// Recreate a machine from an archive written by `export_machine`,
+    /// returning its name
+    #[instrument(skip_all, fields(archive = ?archive_path))]
+    pub fn import_machine(&mut self, archive_path: &Path) -> Result<String, Error> {
+        let machine = archive::read_machine(archive_path)?;
+
+        self.recreate_machine(machine, |instance_dir| {
+            archive::unpack(
+                archive_path,
+                instance_dir,
+                &[archive::DISK_FILE, archive::CONFIG_DRIVE_FILE],
+            )
+        })
+    }
+
+    /// Take domain `name`, made outside bigiron-virt, under management as
+    /// the machine its XML describes. Its first disk, which is recorded as
+    /// the image, stays where it is and is linked to as the instance disk,
+    /// so destroying the machine leaves its disks be. The config drive is
+    /// built now and attached from the next start on.
+    #[instrument(skip(self))]
+    pub fn adopt_machine(&mut self, name: &str) -> Result<Machine, Error> {
+        if self.vmstore.list_instances()?.iter().any(|i| i == name) {
+            return Err(error::conflict(format!(
+                "machine '{}' already exists",
+                name
+            )));
+        }
+        let InstanceImage::File(link) = self.vmstore.instance_image(name) else {
+            return Err("adopting needs qcow2 or reflink instance storage".into());
+        };
+
+        let domain = adopt::parse(&self.hypervisor.domain_xml(name)?)?;
+        let root = domain.root()?.path.clone();
+        info!("Hashing {:?} to record it as the image", root);
+        let url = Url::from_file_path(&root)
+            .map_err(|_| format!("disk path {:?} is not absolute", root))?;
+        let mut machine = domain.machine(url.as_str(), &hash_file(&root)?)?;
+        machine.validate()?;
+        assign_macs(name, &mut machine.spec);
+
+        let instance_dir = self.vmstore.new_instance(name)?;
+        let adopted = std::os::unix::fs::symlink(&root, &link)
+            .map_err(Error::from)
+            .and_then(|_| {
+                build_config_drive(
+                    &machine,
+                    &instance_dir,
+                    self.phone_home.as_deref(),
+                    self.iso_tool.as_deref(),
+                )
+            })
+            .and_then(|_| self.vmstore.save_machine(name, &machine));
+        if let Err(e) = adopted {
+            self.vmstore.remove_instance(name)?;
+            return Err(e);
+        }
+
+        self.set_phase(name, &Phase::Running)?;
+        if self.hypervisor.is_active(name)? {
+            self.vmstore.state_changed(name, true)?;
+        }
+        info!("Adopted domain '{}'", name);
+
+        Ok(machine)
+    }
+
+    /// Back up the instance disk, spec and config drive of machine `id`.
+    /// Running machines are snapshotted, crash consistent unless `quiesce`
+    /// has the guest agent freeze filesystems first.
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn backup_machine(&mut self, id: &str, quiesce: bool) -> Result<BackupInfo, Error> {
+        let machine = self.vmstore.load_machine(id)?;
+        let backup = self.backups.new_backup(id)?;
+
+        info!("Backing up '{}' to {:?}", id, backup.path);
+        if let Err(e) = self.write_backup(&machine, &backup.path, quiesce) {
+            self.backups.remove(&backup)?;
+            return Err(e);
+        }
+
+        self.backups.prune(id)?;
+        self.backups.get(id, &backup.name)
+    }
+
+    fn write_backup(&self, machine: &Machine, dest: &Path, quiesce: bool) -> Result<(), Error> {
+        let id = &machine.metadata.name;
+        let instance_dir = self.vmstore.path_for_instance(id);
+
+        for file in [archive::MACHINE_FILE, archive::CONFIG_DRIVE_FILE] {
+            std::fs::copy(instance_dir.join(file), dest.join(file))?;
+        }
+
+        let disk = dest.join(archive::DISK_FILE);
+
+        if !self.hypervisor.is_active(id)? {
+            if quiesce {
+                warn!("Machine '{}' is not running, nothing to quiesce", id);
+            }
+            return self.vmstore.export_instance_image(id, &disk);
+        }
+
+        // divert writes to an overlay so the instance image holds still
+        let overlay = instance_dir.join("backup-overlay.qcow2");
+        let data_disks = storage_targets(machine);
+        self.hypervisor
+            .snapshot_disk(id, "vda", &overlay, &data_disks, quiesce)?;
+
+        let copied = self.vmstore.export_instance_image(id, &disk);
+
+        // merge back even if the copy failed, so the overlay doesn't linger
+        self.hypervisor.commit_disk(id, "vda")?;
+        std::fs::remove_file(&overlay)?;
+
+        copied
+    }
+
+    /// Backups of machine `id`, or of every machine, oldest first
+    pub fn list_backups(&self, id: Option<&str>) -> Result<Vec<BackupInfo>, Error> {
+        self.backups.list(id)
+    }
+
+    /// Replace machine `id` with the state in backup `name`, the current
+    /// machine is destroyed first if it exists
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn restore_machine(&mut self, id: &str, name: &str) -> Result<(), Error> {
+        let backup = self.backups.get(id, name)?;
+
+        let s = std::fs::read_to_string(backup.path.join(archive::MACHINE_FILE))?;
+        let machine: Machine = serde_yaml::from_str(&s)?;
+
+        if self.vmstore.list_instances()?.iter().any(|i| i == id) {
+            info!("Destroying '{}' to restore backup {}", id, name);
+            self.destroy_machine(id)?;
+        }
+
+        self.recreate_machine(machine, |instance_dir| {
+            for file in [archive::DISK_FILE, archive::CONFIG_DRIVE_FILE] {
+                std::fs::copy(backup.path.join(file), instance_dir.join(file))?;
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    // create a machine from a saved disk and config drive, which `fill`
+    // places in the new instance directory
+    fn recreate_machine<F>(&mut self, mut machine: Machine, fill: F) -> Result<String, Error>
+    where
+        F: FnOnce(&Path) -> Result<(), Error>,
+    {
+        let name = machine.metadata.name.clone();
+        // archives from before MACs were stored have none, the config
+        // drive in them was built with the stable ones
+        assign_macs(&name, &mut machine.spec);
+
+        if self.vmstore.list_instances()?.contains(&name) {
+            return Err(error::conflict(format!(
+                "machine '{}' already exists",
+                name
+            )));
+        }
+
+        self.hooks
+            .run(HookEvent::PreCreate, &name, Some(&machine))?;
+
+        if let Some(ref storages) = machine.spec.storage {
+            prepare_storage(
+                self.hypervisor.as_ref(),
+                storages,
+                machine.spec.wait_for_storage.unwrap_or(0),
+            )?;
+        }
+
+        let instance_dir = self.vmstore.new_instance(&name)?;
+        fill(&instance_dir)?;
+
+        let image = self
+            .vmstore
+            .import_instance_image(&name, instance_dir.join(archive::DISK_FILE))?;
+        self.vmstore.save_machine(&name, &machine)?;
+
+        let cd_path = instance_dir
+            .join(archive::CONFIG_DRIVE_FILE)
+            .canonicalize()?;
+        self.start_domain(&machine, &image, &cd_path, None)?;
+        self.vmstore.state_changed(&name, true)?;
+
+        self.hooks
+            .run(HookEvent::PostCreate, &name, Some(&machine))?;
+
+        Ok(name)
+    }
+
+    // define and start the domain for an instance whose disk and config
+    // drive are already in place
+    fn start_domain(
+        &mut self,
+        machine: &Machine,
+        image: &InstanceImage,
+        cd_path: &Path,
+        disk_secret: Option<&str>,
+    ) -> Result<(), Error> {
+        let cdroms = self.cdrom_isos(machine)?;
+
+        // the devices made for the machine are gone after a host reboot
+        for m in machine.spec.mdev.iter().flatten() {
+            if let (Some(kind), Some(uuid)) = (&m.kind, &m.uuid) {
+                mdev::ensure(Path::new("/sys"), uuid, kind, m.parent.as_deref())?;
+            }
+        }
+
+        // scratch disks start out empty every time
+        let name = &machine.metadata.name;
+        let bus = machine.spec.storage_bus.unwrap_or_default();
+        let mut scratch_disks = Vec::new();
+        for (i, store) in machine.spec.storage.iter().flatten().enumerate() {
+            if let StorageKind::Ephemeral(ref scratch) = store {
+                let target = bus.target(i)?;
+                scratch_disks.push(self.vmstore.create_scratch_disk(
+                    name,
+                    target.as_str(),
+                    scratch.size.bytes(),
+                    scratch.tmpfs.unwrap_or(false),
+                )?);
             }
         }
 
-        // define/create domain
-        d.build()?;
+        self.hypervisor.create(&DomainSpec {
+            machine,
+            image,
+            config_drive: cd_path,
+            cdroms: &cdroms,
+            disk_secret,
+            scratch_disks: &scratch_disks,
+            xml_file: &self.vmstore.domain_xml_path(name),
+            console_log: &std::path::absolute(self.vmstore.console_log_path(name))?,
+        })?;
+
+        let mut restart = self.vmstore.restart_state(name);
+        if restart.stop_requested || restart.pending_since.is_some() {
+            restart.stop_requested = false;
+            restart.pending_since = None;
+            self.vmstore.set_restart_state(name, &restart)?;
+        }
+
+        self.forward_ports(machine)
+    }
+
+    // the ISOs of the machine's cdroms in spec order, fetched if they
+    // aren't in the image store yet
+    fn cdrom_isos(&mut self, machine: &Machine) -> Result<Vec<PathBuf>, Error> {
+        let mut cdroms = Vec::new();
+        for cdrom in machine.spec.cdroms.iter().flatten() {
+            let iso_id = self.imagestore.add_iso(&cdrom.source_url()?, &cdrom.hash)?;
+            cdroms.push(self.imagestore.get_iso(&iso_id)?);
+        }
+        Ok(cdroms)
+    }
+
+    // forward the machine's portForwards to the address of its first
+    // Network nic, waiting for it to get a DHCP lease unless it's static
+    fn forward_ports(&self, machine: &Machine) -> Result<(), Error> {
+        let Some(ref forwards) = machine.spec.port_forwards else {
+            return Ok(());
+        };
+        let name = &machine.metadata.name;
+        let nic = machine
+            .spec
+            .nics
+            .iter()
+            .flatten()
+            .find(|n| n.kind == "Network")
+            .ok_or("portForwards need a Network nic")?;
+
+        if let Some(v4) = nic.address.ipv4_static() {
+            let addr = v4.addr.split('/').next().unwrap_or_default();
+            let guest = addr
+                .parse()
+                .map_err(|e| format!("invalid address {}: {}", v4.addr, e))?;
+            return netfilter::add(name, guest, forwards);
+        }
+
+        let mac = nic.macaddress.to_lowercase();
+        let deadline = Instant::now() + LEASE_WAIT;
+        loop {
+            let lease = self
+                .hypervisor
+                .interface_addrs(name)
+                .unwrap_or_default()
+                .into_iter()
+                .find_map(|(m, addr)| match addr {
+                    IpAddr::V4(v4) if m == mac => Some(v4),
+                    _ => None,
+                });
+            if let Some(guest) = lease {
+                return netfilter::add(name, guest, forwards);
+            }
 
-        if let Some(info) = bridged_nic_info {
-            match info.parse::<Mac>() {
-                Ok(mac) => info!("IPv6 SLAAC: {}", mac.to_ipv6_slaac_addr()),
-                Err(_) => {}
+            if Instant::now() >= deadline {
+                return Err(format!(
+                    "{} got no address on network {} to forward ports to",
+                    name, nic.parent
+                )
+                .into());
             }
+            std::thread::sleep(Duration::from_secs(2));
         }
+    }
 
+    // take an address for each of the machine's Pool nics, recorded in the
+    // nic so the spec keeps naming the pool
+    fn assign_addresses(&self, machine: &mut Machine) -> Result<(), Error> {
+        let name = &machine.metadata.name;
+        for nic in machine.spec.nics.iter_mut().flatten() {
+            if let AddressKind::Pool(ref mut p) = nic.address {
+                p.assigned = Some(self.pools.assign(&p.pool, name)?);
+            }
+        }
         Ok(())
     }
 
+    /// Create or update address pool `pool`
+    pub fn save_pool(&self, pool: &AddressPool) -> Result<(), Error> {
+        self.pools.save(pool)
+    }
+
+    pub fn list_pools(&self) -> Result<Vec<PoolUsage>, Error> {
+        self.pools.list()
+    }
+
+    pub fn remove_pool(&self, name: &str) -> Result<(), Error> {
+        self.pools.remove(name)
+    }
+
+    // register machine `id` under its name in the dnsmasq hosts file, if
+    // there is one, waiting for it to get an address unless it has a
+    // static one
+    fn register_dns(&self, id: &str) -> Result<(), Error> {
+        let Some(ref config) = self.dns else {
+            return Ok(());
+        };
+        let statics: Vec<IpAddr> = self
+            .vmstore
+            .load_machine(id)?
+            .spec
+            .nics
+            .iter()
+            .flatten()
+            .filter_map(|n| {
+                n.address
+                    .ipv4_static()?
+                    .addr
+                    .split('/')
+                    .next()?
+                    .parse()
+                    .ok()
+            })
+            .collect();
+
+        let deadline = Instant::now() + LEASE_WAIT;
+        loop {
+            let mut found = statics.clone();
+            found.extend(self.find_addresses(id));
+            let addrs = dns_addresses(&found);
+            if !addrs.is_empty() {
+                return dns::register(config, id, &addrs);
+            }
+
+            if Instant::now() >= deadline {
+                return Err(format!("{} got no address to register", id).into());
+            }
+            std::thread::sleep(Duration::from_secs(2));
+        }
+    }
+
+    /// Remove machine `id` and its instance storage, shutting it down
+    /// first and powering it off if it isn't down within the destroy timeout
+    #[instrument(skip_all, fields(machine = %id))]
     pub fn destroy_machine(&mut self, id: &str) -> Result<(), Error> {
-        // destroy in libvirt
-        libvirt::destroy(id)?;
+        let id = &self.resolve(id)?;
+        let machine = self.load_machine(id).ok();
+        self.hooks
+            .run(HookEvent::PreDestroy, id, machine.as_ref())?;
+
+        // give the guest a chance to shut down cleanly before pulling the plug
+        let running = self
+            .hypervisor
+            .status(id)?
+            .is_some_and(|s| s.state == "running");
+        if running && !self.destroy_timeout.is_zero() {
+            if let Err(e) = self.shut_down(id, self.destroy_timeout) {
+                warn!("{}, powering it off", e);
+            }
+        }
+
+        // destroy in the hypervisor
+        self.hypervisor.destroy(id)?;
+
+        let encrypted = machine
+            .as_ref()
+            .is_some_and(|m| m.spec.image.encryption.is_some());
+        if encrypted {
+            self.hypervisor
+                .remove_disk_secret(self.vmstore.instance_image(id).path())?;
+        }
+
+        let forwarded = machine
+            .as_ref()
+            .is_some_and(|m| m.spec.port_forwards.is_some());
+        if forwarded {
+            netfilter::remove(id)?;
+        }
+
+        if let Some(ref config) = self.dns {
+            dns::unregister(config, id)?;
+        }
+        self.pools.release(id)?;
+
+        // mediated devices made for the machine
+        for mdev in machine.iter().flat_map(|m| m.spec.mdev.iter().flatten()) {
+            if let (Some(_), Some(uuid)) = (&mdev.kind, &mdev.uuid) {
+                mdev::release(Path::new("/sys"), uuid)?;
+            }
+        }
 
         // destroy in VM store
         self.vmstore.remove_instance(id)?;
 
+        self.hooks.run(HookEvent::PostDestroy, id, machine.as_ref())
+    }
+
+    /// Grow the disk on `target` to `size` bytes. The instance disk `vda` and
+    /// file backed storage can be resized; running machines are resized live.
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn resize_disk(&mut self, id: &str, target: &str, size: u64) -> Result<(), Error> {
+        let mut machine = self.vmstore.load_machine(id)?;
+
+        if !size.is_multiple_of(512) {
+            return Err(format!("disk size {} is not a multiple of 512 bytes", size).into());
+        }
+
+        let path = if target == "vda" {
+            self.vmstore.instance_image(id).path().to_path_buf()
+        } else {
+            let bus = machine.spec.storage_bus.unwrap_or_default();
+            let storage = bus
+                .index(target)
+                .and_then(|i| machine.spec.storage.as_ref()?.get(i))
+                .ok_or_else(|| format!("machine '{}' has no disk {}", id, target))?;
+
+            match storage {
+                StorageKind::File(ref file) => file.path.clone(),
+                _ => {
+                    return Err(format!(
+                        "{} is {}, only the instance disk and file storage can be resized",
+                        target,
+                        storage_description(storage)
+                    )
+                    .into())
+                }
+            }
+        };
+
+        let current = imgutil::virtual_size(&path)?;
+        if size <= current {
+            return Err(format!(
+                "{} is already {}, disks can only be grown",
+                target,
+                Size(current)
+            )
+            .into());
+        }
+
+        let live = self.hypervisor.is_active(id)?;
+        info!(
+            "Resizing {} of '{}' from {} to {}{}",
+            target,
+            id,
+            Size(current),
+            Size(size),
+            if live { " (live)" } else { "" }
+        );
+
+        if target == "vda" {
+            let passphrase = match machine.spec.image.encryption {
+                Some(ref encryption) if !live => {
+                    Some(self.hypervisor.disk_secret(&path, encryption)?.1)
+                }
+                _ => None,
+            };
+            self.vmstore
+                .resize_instance_image(id, size, live, passphrase.as_deref())?;
+        } else if !live {
+            imgutil::resize(&path, size, None)?;
+        }
+
+        if live {
+            self.hypervisor.resize_disk(id, target, size)?;
+        }
+
+        // file storage carries its own size, only the instance disk is in the spec
+        if target == "vda" {
+            machine.spec.image.resize = Some(Size(size));
+            self.vmstore.save_machine(id, &machine)?;
+        }
+
         Ok(())
     }
 
-    pub fn list_machines(&self) -> Result<MachineList, Error> {
-        let ids = self.vmstore.list_instances()?;
+    /// Simulate creating `machines` on this host without creating anything,
+    /// reporting where each would be placed or why it would be rejected
+    pub fn simulate(&self, machines: &[Machine]) -> Result<PlanReport, Error> {
+        let mut planner = self.planner()?;
+        let mut existing = self.vmstore.list_instances()?;
+        let mut plans = Vec::new();
+
+        for machine in machines {
+            let demand = self.demand(machine)?;
+
+            let placement = if existing.contains(&machine.metadata.name) {
+                Placement::Rejected {
+                    reasons: vec![String::from("machine already exists")],
+                }
+            } else {
+                planner.place(&demand)
+            };
+
+            if let Placement::Fits { .. } = placement {
+                existing.push(machine.metadata.name.clone());
+            }
+
+            plans.push(MachinePlan { demand, placement });
+        }
+
+        Ok(PlanReport {
+            overcommit: planner.overcommit(),
+            host: planner.capacity().clone(),
+            machines: plans,
+        })
+    }
+
+    // a planner for this host's current capacity and allocations
+    fn planner(&self) -> Result<Planner, Error> {
+        let capacity = HostCapacity::probe(
+            self.hypervisor.as_ref(),
+            &[self.vmstore.path(), self.imagestore.path()],
+        )?;
 
-        let get_status = |entry: String| MachineStatus {
-            id: entry,
-            status: String::from("unknown"),
+        // existing machines that aren't running don't hold cpu/memory, but
+        // their dedicated host cpus stay theirs
+        let committed: Vec<_> = self
+            .vmstore
+            .list_instances()?
+            .iter()
+            .filter_map(|id| {
+                let host_cpus = self
+                    .vmstore
+                    .load_machine(id)
+                    .map(|m| dedicated_cpus(&m))
+                    .unwrap_or_default();
+                let (cpus, memory_bytes) = match self.hypervisor.domain_resources(id) {
+                    Ok(resources) => resources,
+                    Err(_) if !host_cpus.is_empty() => (0, 0),
+                    Err(_) => return None,
+                };
+
+                Some(Demand {
+                    name: id.clone(),
+                    cpus,
+                    memory_bytes,
+                    storage: Vec::new(),
+                    host_cpus,
+                })
+            })
+            .collect();
+
+        Ok(Planner::new(capacity, self.overcommit, &committed))
+    }
+
+    // resources a machine would consume, disk sizes are upper bounds since
+    // instance images are thin provisioned
+    fn demand(&self, machine: &Machine) -> Result<Demand, Error> {
+        // an unknown name is reported on create, here it's just not in
+        // the repo yet
+        let image = &self
+            .resolve_image(&machine.spec.image)
+            .unwrap_or_else(|_| machine.spec.image.clone());
+
+        let source_size = Url::parse(&image.url)
+            .ok()
+            .and_then(|u| u.to_file_path().ok())
+            .and_then(|p| std::fs::metadata(p).ok())
+            .map(|m| m.len())
+            .unwrap_or(0);
+
+        let image_bytes = match self.imagestore.get_image(&image.hash) {
+            Ok(_) => 0,
+            Err(_) => source_size,
         };
 
-        let list = ids.into_iter().map(get_status).collect();
+        let mut disk_bytes = image.resize.map_or(source_size, |s| s.bytes());
 
-        Ok(list)
+        // tmpfs scratch disks can fill up to their size from host memory
+        let mut memory_bytes = machine.spec.memory.bytes();
+        for store in machine.spec.storage.iter().flatten() {
+            if let StorageKind::Ephemeral(ref scratch) = store {
+                match scratch.tmpfs {
+                    Some(true) => memory_bytes += scratch.size.bytes(),
+                    _ => disk_bytes += scratch.size.bytes(),
+                }
+            }
+        }
+
+        Ok(Demand {
+            name: machine.metadata.name.clone(),
+            cpus: machine.spec.cpu,
+            memory_bytes,
+            storage: vec![
+                (self.vmstore.path().to_path_buf(), disk_bytes),
+                (self.imagestore.path().to_path_buf(), image_bytes),
+            ],
+            host_cpus: dedicated_cpus(machine),
+        })
+    }
+
+    // make devices for the machine's mdev types and check the given ones
+    // aren't another machine's, saving the spec after each so a failure
+    // part way still has every made device recorded for destroy
+    fn allocate_mdevs(&mut self, machine: &mut Machine) -> Result<(), Error> {
+        let Some(mut mdevs) = machine.spec.mdev.clone() else {
+            return Ok(());
+        };
+        let name = machine.metadata.name.clone();
+
+        let mut in_use = HashSet::new();
+        for id in self.vmstore.list_instances()? {
+            if id == name {
+                continue;
+            }
+            if let Ok(other) = self.vmstore.load_machine(&id) {
+                in_use.extend(other.spec.mdev.into_iter().flatten().filter_map(|m| m.uuid));
+            }
+        }
+
+        for i in 0..mdevs.len() {
+            if let Some(ref uuid) = mdevs[i].uuid {
+                if in_use.contains(uuid) {
+                    let msg = format!("mdev {} is already used by another machine", uuid);
+                    return Err(msg.into());
+                }
+                continue;
+            }
+
+            let kind = mdevs[i]
+                .kind
+                .as_deref()
+                .ok_or("mdev needs a uuid or a type")?;
+            let parent = mdevs[i].parent.as_deref();
+            mdevs[i].uuid = Some(mdev::allocate(Path::new("/sys"), kind, parent)?);
+            machine.spec.mdev = Some(mdevs.clone());
+            self.vmstore.save_machine(&name, machine)?;
+        }
+
+        Ok(())
+    }
+
+    // `image` with its url and hash filled in from the repo if only named
+    fn resolve_image(&self, image: &Image) -> Result<Image, Error> {
+        let Some(ref name) = image.name else {
+            return Ok(image.clone());
+        };
+        if !image.url.is_empty() || !image.hash.is_empty() {
+            return Ok(image.clone());
+        }
+
+        let info = self.imagestore.find(name)?;
+        let url = match info.url {
+            Some(url) => url,
+            // named without ever being imported through a spec
+            None => Url::from_file_path(self.imagestore.get_image(&info.hash)?)
+                .map_err(|_| format!("image '{}' has no usable path", name))?
+                .to_string(),
+        };
+
+        Ok(Image {
+            url,
+            hash: info.hash,
+            ..image.clone()
+        })
+    }
+
+    /// Images in the repo with their names and where they came from
+    pub fn list_images(&self) -> Result<Vec<ImageInfo>, Error> {
+        self.imagestore.list()
+    }
+
+    /// Remove qcow2 image `hash` from the repo, unless a machine uses it
+    pub fn remove_image(&mut self, hash: &str) -> Result<(), Error> {
+        if self.machines()?.iter().any(|m| m.spec.image.hash == hash) {
+            return Err(error::conflict(format!(
+                "image {} is used by a machine",
+                hash
+            )));
+        }
+        self.imagestore.remove(&format!("{}.qcow2", hash))
+    }
+
+    /// Import an image into the repo ahead of any machine using it,
+    /// naming it `name` if given
+    pub fn pull_image(
+        &mut self,
+        url: &Url,
+        hash: &str,
+        iso: bool,
+        name: Option<&str>,
+    ) -> Result<ImageInfo, Error> {
+        let id = if iso {
+            self.imagestore.add_iso(url, hash)?
+        } else {
+            self.imagestore.add_image(url, hash)?
+        };
+
+        if let Some(name) = name {
+            return self.imagestore.set_name(&id, name, &[], None);
+        }
+
+        let format = if iso { "iso" } else { "qcow2" };
+        self.imagestore
+            .list()?
+            .into_iter()
+            .find(|i| i.hash == id && i.format == format)
+            .ok_or_else(|| format!("No image with id='{}' found", id).into())
+    }
+
+    /// Build a disk image from OCI image `reference` with `free` bytes to
+    /// spare and add it to the repo, naming it `name` if given
+    #[instrument(skip_all, fields(reference = %reference))]
+    pub fn import_oci_image(
+        &mut self,
+        reference: &str,
+        free: u64,
+        name: Option<&str>,
+    ) -> Result<ImageInfo, Error> {
+        let source = oci::source_url(reference)?;
+
+        // in the repo, so the finished image only needs renaming into it
+        let work_dir = self
+            .imagestore
+            .path()
+            .join(format!(".import-oci-{}", std::process::id()));
+        std::fs::create_dir_all(&work_dir)?;
+        let disk = work_dir.join("disk.qcow2");
+        let built = oci::build_disk(reference, &disk, free, &work_dir)
+            .and_then(|_| self.imagestore.add_built_image(&disk, &source));
+        if let Err(e) = std::fs::remove_dir_all(&work_dir) {
+            warn!("Failed to remove {:?}: {}", work_dir, e);
+        }
+        let id = built?;
+
+        if let Some(name) = name {
+            return self.imagestore.set_name(&id, name, &[], None);
+        }
+
+        self.imagestore
+            .list()?
+            .into_iter()
+            .find(|i| i.hash == id && i.format == "qcow2")
+            .ok_or_else(|| format!("No image with id='{}' found", id).into())
+    }
+
+    /// Name repo image `image` (a hash, name or alias), see
+    /// `Directory::set_name`
+    pub fn name_image(
+        &mut self,
+        image: &str,
+        name: &str,
+        aliases: &[String],
+        os: Option<&str>,
+    ) -> Result<ImageInfo, Error> {
+        self.imagestore.set_name(image, name, aliases, os)
+    }
+
+    /// Stored spec and current state of machine `id`
+    pub fn machine_info(&self, id: &str) -> Result<MachineInfo, Error> {
+        let id = &self.resolve(id)?;
+        let machine = self.load_machine(id)?;
+
+        let phase = self.vmstore.phase(id).filter(|p| *p != Phase::Running);
+        let state = match phase {
+            Some(ref phase) => phase.to_string(),
+            None => self.machine_state(id)?,
+        };
+        let addresses = match (phase, state.as_str()) {
+            (Some(_), _) | (None, "stopped" | "saved") => Vec::new(),
+            _ => self.find_addresses(id),
+        };
+
+        Ok(MachineInfo {
+            machine,
+            state,
+            addresses,
+            timestamps: self.vmstore.timestamps(id),
+        })
+    }
+
+    /// Run a command in machine `id` through the guest agent
+    pub fn guest_exec(
+        &self,
+        id: &str,
+        argv: &[String],
+        timeout: Duration,
+    ) -> Result<ExecResult, Error> {
+        self.require_running(id)?;
+        guest_agent::exec(self.hypervisor.as_ref(), id, argv, timeout)
+    }
+
+    pub fn guest_ip_addrs(&self, id: &str) -> Result<Vec<IpAddr>, Error> {
+        self.require_running(id)?;
+        guest_agent::ip_addrs(self.hypervisor.as_ref(), id)
+    }
+
+    /// An address of machine `id` answering on TCP `port`, waiting up to
+    /// `timeout` for one. Candidates are the addresses `show` finds and
+    /// the SLAAC link-local address of each bridged nic, IPv4 first.
+    pub fn reachable_address(
+        &self,
+        id: &str,
+        port: u16,
+        timeout: Duration,
+    ) -> Result<SocketAddr, Error> {
+        let id = &self.resolve(id)?;
+        self.require_running(id)?;
+
+        let machine = self.load_machine(id)?;
+        let link_local: Vec<SocketAddrV6> = machine
+            .spec
+            .nics
+            .iter()
+            .flatten()
+            .filter(|n| n.kind == "Bridge")
+            .filter_map(|n| {
+                let mac: Mac = n.macaddress.parse().ok()?;
+                let scope = ifindex(&n.parent)?;
+                Some(SocketAddrV6::new(mac.to_ipv6_link_local(), port, 0, scope))
+            })
+            .collect();
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            for addr in probe_order(&self.find_addresses(id), &link_local, port) {
+                if TcpStream::connect_timeout(&addr, Duration::from_secs(2)).is_ok() {
+                    return Ok(addr);
+                }
+            }
+
+            if Instant::now() >= deadline {
+                return Err(format!("no address of {} answers on port {}", id, port).into());
+            }
+            std::thread::sleep(Duration::from_secs(2));
+        }
+    }
+
+    /// Host keys of machine `id` as ssh saw them, kept with the instance so
+    /// a new machine on an old address isn't taken for an attack
+    pub fn known_hosts_path(&self, id: &str) -> Result<PathBuf, Error> {
+        let id = &self.resolve(id)?;
+        Ok(self.vmstore.path_for_instance(id).join("known_hosts"))
+    }
+
+    /// Record that cloud-init in machine `id` is done, as its phone_home
+    /// says, as long as `instance_id` is the one its config drive gave it
+    pub fn mark_ready(&mut self, id: &str, instance_id: &str) -> Result<(), Error> {
+        let id = &self.resolve(id)?;
+        let machine = self.vmstore.load_machine(id)?;
+        // the machine name when there's no UUID, as in build_config_drive
+        let expected = machine.metadata.uuid.as_deref().unwrap_or(id);
+        if instance_id != expected {
+            return Err(error::invalid(format!(
+                "instance-id '{}' posted for {} isn't its own",
+                instance_id, id
+            )));
+        }
+        self.vmstore.set_ready(id)?;
+        info!("{} phoned home, cloud-init is done", id);
+
+        // the guest agent knows all its addresses by now
+        if let Err(e) = self.register_dns(id) {
+            warn!("{} not registered in DNS: {}", id, e);
+        }
+        Ok(())
+    }
+
+    /// Wait until `deadline` for cloud-init in machine `id` to be done,
+    /// going by its phone_home or, without one, `cloud-init status`
+    /// through the guest agent
+    pub fn wait_ready(&mut self, id: &str, deadline: Instant) -> Result<(), Error> {
+        let id = &self.resolve(id)?;
+
+        loop {
+            if self.vmstore.timestamps(id).ready_at.is_some() {
+                return Ok(());
+            }
+
+            match self.cloud_init_status(id).as_deref() {
+                Some("done") => {
+                    self.vmstore.set_ready(id)?;
+                    if let Err(e) = self.register_dns(id) {
+                        warn!("{} not registered in DNS: {}", id, e);
+                    }
+                    return Ok(());
+                }
+                Some("error") => return Err(format!("cloud-init failed in {}", id).into()),
+                _ => {}
+            }
+
+            if Instant::now() >= deadline {
+                return Err(error::timeout(format!(
+                    "timed out waiting for {} to be ready",
+                    id
+                )));
+            }
+            std::thread::sleep(Duration::from_secs(2));
+        }
+    }
+
+    /// Check the machines `machine` depends on exist, waiting until
+    /// `deadline` for those it needs ready to be
+    pub fn wait_dependencies(&mut self, machine: &Machine, deadline: Instant) -> Result<(), Error> {
+        let name = &machine.metadata.name;
+        for dep in machine.spec.depends_on.iter().flatten() {
+            let id = &self.resolve(dep.name())?;
+            if self.vmstore.load_machine(id).is_err() {
+                return Err(error::not_found(format!(
+                    "'{}' depends on '{}', which doesn't exist",
+                    name,
+                    dep.name()
+                )));
+            }
+            if dep.ready() {
+                info!(
+                    "Waiting for '{}' to be ready before creating '{}'",
+                    id, name
+                );
+                self.wait_ready(id, deadline)?;
+            }
+        }
+        Ok(())
+    }
+
+    // what `cloud-init status` says in machine `id`, if the guest agent is
+    // up to ask
+    fn cloud_init_status(&self, id: &str) -> Option<String> {
+        let hv = self.hypervisor.as_ref();
+        if !guest_agent::ping(hv, id) {
+            return None;
+        }
+
+        let argv = ["cloud-init".to_string(), "status".to_string()];
+        let result = guest_agent::exec(hv, id, &argv, Duration::from_secs(10)).ok()?;
+        cloud_init_status(&String::from_utf8_lossy(&result.stdout)).map(String::from)
+    }
+
+    /// Shut machine `id` down cleanly, through the guest agent if it is
+    /// answering and ACPI otherwise, waiting up to `timeout` for it to stop
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn stop_machine(&mut self, id: &str, timeout: Duration) -> Result<(), Error> {
+        self.require_running(id)?;
+        self.shut_down(id, timeout)?;
+        self.vmstore.state_changed(id, false)
+    }
+
+    // ask the guest to shut down, through its agent if it answers, and
+    // wait up to `timeout` for it to stop
+    fn shut_down(&mut self, id: &str, timeout: Duration) -> Result<(), Error> {
+        // not a stop for the restartPolicy to undo
+        let mut restart = self.vmstore.restart_state(id);
+        restart.stop_requested = true;
+        self.vmstore.set_restart_state(id, &restart)?;
+
+        let hv = self.hypervisor.as_ref();
+        if guest_agent::ping(hv, id) {
+            info!("Shutting down '{}' through the guest agent", id);
+            guest_agent::shutdown(hv, id)?;
+        } else {
+            info!(
+                "Guest agent not answering, shutting down '{}' through ACPI",
+                id
+            );
+            hv.shutdown(id)?;
+        }
+
+        let deadline = Instant::now() + timeout;
+        while hv.is_active(id)? {
+            if Instant::now() >= deadline {
+                return Err(error::timeout(format!(
+                    "machine '{}' still running after {:?}",
+                    id, timeout
+                )));
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+
+        Ok(())
+    }
+
+    /// Pause running machine `id`, its memory stays allocated
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn pause_machine(&mut self, id: &str) -> Result<(), Error> {
+        self.require_running(id)?;
+
+        info!("Pausing '{}'", id);
+        self.hypervisor.pause(id)?;
+        self.vmstore.state_changed(id, false)
+    }
+
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn resume_machine(&mut self, id: &str) -> Result<(), Error> {
+        if self.machine_state(id)? != "paused" {
+            return Err(format!("machine '{}' is not paused", id).into());
+        }
+
+        info!("Resuming '{}'", id);
+        self.hypervisor.resume(id)?;
+        self.vmstore.state_changed(id, false)
+    }
+
+    /// Stop machine `id`, writing its memory state to the instance
+    /// directory, or to `file` if given, for `restore_saved_machine`
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn save_machine(&mut self, id: &str, file: Option<&Path>) -> Result<(), Error> {
+        self.require_running(id)?;
+        if self.vmstore.has_saved_state(id) {
+            return Err(format!("machine '{}' already has a saved state", id).into());
+        }
+
+        let managed = self.vmstore.saved_state_path(id);
+        match file {
+            Some(file) => {
+                // libvirtd doesn't share our working directory
+                let file = std::env::current_dir()?.join(file);
+
+                info!("Saving '{}' to {:?}", id, file);
+                self.hypervisor.save(id, &file)?;
+                std::os::unix::fs::symlink(&file, &managed)?;
+            }
+            None => {
+                info!("Saving '{}'", id);
+                self.hypervisor.save(id, &managed)?;
+            }
+        }
+
+        self.vmstore.state_changed(id, false)
+    }
+
+    /// Start machine `id` again from the state written by `save_machine`
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn restore_saved_machine(&mut self, id: &str) -> Result<(), Error> {
+        if !self.vmstore.has_saved_state(id) {
+            return Err(format!("machine '{}' has no saved state", id).into());
+        }
+        if self.hypervisor.is_active(id)? {
+            return Err(format!("machine '{}' is already running", id).into());
+        }
+
+        let path = self.vmstore.saved_state_path(id);
+        info!("Restoring '{}'", id);
+        self.hypervisor.restore(&path)?;
+
+        // the disks move on from here, restoring the same state again
+        // would corrupt them. A user's file is left, only our link goes.
+        std::fs::remove_file(&path)?;
+
+        self.vmstore.state_changed(id, false)
+    }
+
+    fn require_running(&self, id: &str) -> Result<(), Error> {
+        if !self.hypervisor.is_active(id)? {
+            return Err(error::conflict(format!("machine '{}' is not running", id)));
+        }
+        Ok(())
+    }
+
+    /// State of machine `id` as reported by the hypervisor. Inactive
+    /// machines are "saved" if stopped by `save_machine`, else "stopped".
+    pub fn machine_state(&self, id: &str) -> Result<String, Error> {
+        match self.hypervisor.status(id)? {
+            Some(status) if status.active => Ok(status.state),
+            _ => Ok(self.inactive_state(id)),
+        }
+    }
+
+    fn inactive_state(&self, id: &str) -> String {
+        match self.vmstore.has_saved_state(id) {
+            true => String::from("saved"),
+            false => String::from("stopped"),
+        }
+    }
+
+    /// Hot plug the file or block device at `path` into running machine
+    /// `id` as its next storage disk, recording it in the stored spec
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn attach_disk(&mut self, id: &str, path: &Path) -> Result<String, Error> {
+        let mut machine = self.vmstore.load_machine(id)?;
+        self.require_running(id)?;
+
+        let path = path.canonicalize()?;
+        let storage = if std::fs::metadata(&path)?.file_type().is_file() {
+            StorageKind::File(File {
+                path: path.clone(),
+                driver: DiskDriver::default(),
+            })
+        } else {
+            StorageKind::Block(Block {
+                path: path.clone(),
+                driver: DiskDriver::default(),
+            })
+        };
+
+        let bus = machine.spec.storage_bus.unwrap_or_default();
+        let storages = machine.spec.storage.get_or_insert_with(Vec::new);
+        let target = bus.target(storages.len())?;
+
+        info!("Attaching {:?} to '{}' as {}", path, id, target);
+        self.hypervisor.attach_disk(id, &path, &target)?;
+
+        storages.push(storage);
+        self.vmstore.save_machine(id, &machine)?;
+
+        Ok(target)
+    }
+
+    /// Name of the machine `id` refers to, which is either its name or
+    /// its UUID. Unknown ids are returned as they are, for the caller to
+    /// report missing.
+    pub fn resolve(&self, id: &str) -> Result<String, Error> {
+        let uuid = match uuid::Uuid::parse_str(id) {
+            Ok(uuid) if !self.vmstore.path_for_instance(id).exists() => uuid,
+            _ => return Ok(id.to_string()),
+        };
+
+        for name in self.vmstore.list_instances()? {
+            let machine_uuid = match self.vmstore.load_machine(&name) {
+                Ok(m) => m.metadata.uuid,
+                Err(_) => continue,
+            };
+
+            if machine_uuid.is_some_and(|u| uuid::Uuid::parse_str(&u) == Ok(uuid)) {
+                return Ok(name);
+            }
+        }
+
+        Ok(id.to_string())
+    }
+
+    /// Machines whose labels match `selector`, machines created before
+    /// their spec was stored have no labels
+    pub fn select_machines(&self, selector: &Selector) -> Result<Vec<String>, Error> {
+        let mut ids = self.vmstore.list_instances()?;
+
+        ids.retain(|id| match self.vmstore.load_machine(id) {
+            Ok(m) => selector.matches(&m.metadata),
+            Err(_) => false,
+        });
+
+        Ok(ids)
+    }
+
+    /// Machines whose `metadata.ttl` has run out by `now`
+    pub fn expired(&self, now: SystemTime) -> Result<Vec<String>, Error> {
+        let mut expired = Vec::new();
+        for id in self.vmstore.list_instances()? {
+            let Some(ttl) = self
+                .vmstore
+                .load_machine(&id)
+                .ok()
+                .and_then(|m| m.metadata.ttl)
+            else {
+                continue;
+            };
+
+            // a ttl too long to add never runs out
+            match self.vmstore.created(&id) {
+                Some(created) if created.checked_add(ttl.0).is_some_and(|end| end <= now) => {
+                    expired.push(id)
+                }
+                Some(_) => {}
+                None => warn!("{} has a ttl but no creation time, it won't expire", id),
+            }
+        }
+        Ok(expired)
+    }
+
+    /// Domains, instances and images where the records and the host
+    /// disagree as of `now`
+    pub fn orphans(&self, now: SystemTime) -> Result<Vec<Orphan>, Error> {
+        let ids = self.vmstore.list_instances()?;
+        let defined = self.hypervisor.list()?;
+        let mut found = Vec::new();
+
+        // other domains on the host are none of ours, unless they run on
+        // an instance disk
+        for name in defined.iter().filter(|d| !ids.contains(d)) {
+            let root = self.vmstore.instance_image(name);
+            if self
+                .hypervisor
+                .disks(name)?
+                .iter()
+                .any(|d| d == root.path())
+            {
+                found.push(Orphan::Domain(name.clone()));
+            }
+        }
+
+        let now = now.duration_since(UNIX_EPOCH)?.as_secs();
+        let mut used = HashSet::new();
+        let mut unread = false;
+        for id in &ids {
+            let machine = self.vmstore.load_machine(id).ok();
+            if let Some(ref m) = machine {
+                used.insert(m.spec.image.hash.clone());
+                used.extend(m.spec.cdroms.iter().flatten().map(|c| c.hash.clone()));
+            }
+            unread |= machine.is_none();
+
+            let phase = self.vmstore.phase(id);
+            let timestamps = self.vmstore.timestamps(id);
+            found.extend(orphans::instance(
+                id,
+                machine.is_some(),
+                phase.as_ref(),
+                defined.contains(id),
+                &timestamps,
+                now,
+            ));
+        }
+
+        // a machine that can't be read might be using any of them
+        if unread {
+            warn!("Not checking for unused images, some machines can't be read");
+        } else {
+            for image in self.imagestore.list()? {
+                if !used.contains(&image.hash) {
+                    found.extend(orphans::image(&image, now));
+                }
+            }
+        }
+
+        Ok(found)
+    }
+
+    /// Clean up or adopt `orphan`, as its kind says
+    pub fn fix_orphan(&mut self, orphan: &Orphan) -> Result<(), Error> {
+        match orphan {
+            Orphan::Domain(name) => self.hypervisor.destroy(name),
+            Orphan::Unadopted(id) => {
+                self.set_phase(id, &Phase::Running)?;
+                self.vmstore.state_changed(id, true)
+            }
+            Orphan::Instance(id) => self.destroy_machine(id),
+            Orphan::Image(file) => self.imagestore.remove(file),
+        }
+    }
+
+    /// Stored specs of all managed machines
+    pub fn machines(&self) -> Result<Vec<Machine>, Error> {
+        self.vmstore
+            .list_instances()?
+            .iter()
+            .map(|id| self.vmstore.load_machine(id))
+            .collect()
+    }
+
+    pub fn list_machines(&self, selector: Option<&Selector>) -> Result<MachineList, Error> {
+        let ids = match selector {
+            Some(sel) => self.select_machines(sel)?,
+            None => self.vmstore.list_instances()?,
+        };
+
+        // one listing up front instead of a lookup per stopped machine
+        let defined = self.hypervisor.list().unwrap_or_default();
+
+        let get_status = |entry: String| {
+            let phase = self.vmstore.phase(&entry).filter(|p| *p != Phase::Running);
+            let status = match (&phase, defined.contains(&entry)) {
+                (Some(phase), _) => phase.to_string(),
+                (None, true) => self
+                    .machine_state(&entry)
+                    .unwrap_or_else(|_| "unknown".into()),
+                (None, false) => self.inactive_state(&entry),
+            };
+
+            let addresses = match (phase, status.as_str()) {
+                (Some(_), _) | (None, "stopped" | "saved") => Vec::new(),
+                _ => self.find_addresses(&entry),
+            };
+
+            let machine = self.vmstore.load_machine(&entry).ok();
+            let health = match machine.as_ref().and_then(|m| m.spec.health_check.as_ref()) {
+                Some(check) if status == "running" => {
+                    Some(self.vmstore.health(&entry).summary(check).to_string())
+                }
+                _ => None,
+            };
+
+            MachineStatus {
+                timestamps: self.vmstore.timestamps(&entry),
+                id: entry,
+                uuid: machine.and_then(|m| m.metadata.uuid),
+                status,
+                addresses,
+                health,
+            }
+        };
+
+        let list = ids.into_iter().map(get_status).collect();
+
+        Ok(list)
+    }
+
+    /// Resource usage of running machine `id` over the next `interval`
+    #[instrument(skip_all, fields(machine = %id))]
+    pub fn machine_stats(&self, id: &str, interval: Duration) -> Result<MachineStats, Error> {
+        let id = &self.resolve(id)?;
+        self.require_running(id)?;
+
+        let sample = || -> Result<(DomainStats, Instant), Error> {
+            let stats = self
+                .hypervisor
+                .domain_stats()?
+                .into_iter()
+                .find(|d| d.name == *id)
+                .ok_or_else(|| error::conflict(format!("machine '{}' is not running", id)))?;
+            Ok((stats, Instant::now()))
+        };
+
+        let (before, start) = sample()?;
+        std::thread::sleep(interval);
+        let (after, end) = sample()?;
+
+        Ok(MachineStats::between(&before, &after, end - start))
+    }
+
+    /// Current machine, image and usage numbers for the metrics endpoint
+    pub fn metrics(&self) -> Result<Metrics, Error> {
+        let managed: HashSet<String> = self.vmstore.list_instances()?.into_iter().collect();
+
+        let domains: Vec<_> = self
+            .hypervisor
+            .domain_stats()?
+            .into_iter()
+            .filter(|d| managed.contains(&d.name))
+            .collect();
+
+        let mut timestamps: Vec<_> = managed
+            .iter()
+            .map(|id| (id.clone(), self.vmstore.timestamps(id)))
+            .collect();
+        timestamps.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(Metrics {
+            machines: managed.len(),
+            machines_running: domains.len(),
+            images: self.imagestore.images()?.len(),
+            host: self.hypervisor.host_resources().ok(),
+            domains,
+            timestamps,
+        })
+    }
+
+    /// Pass lifecycle events of managed machines, or only of `ids` if
+    /// given, to `on_event` until it returns false
+    pub fn watch(
+        &self,
+        ids: &[String],
+        on_event: &mut dyn FnMut(LifecycleEvent) -> bool,
+    ) -> Result<(), Error> {
+        let mut managed: HashSet<String> = self.vmstore.list_instances()?.into_iter().collect();
+
+        self.hypervisor.watch(&mut |event| {
+            // pick up machines created while watching; destroyed ones stay
+            // known so the stop that goes with their removal is reported
+            if !managed.contains(&event.machine)
+                && self.vmstore.path_for_instance(&event.machine).exists()
+            {
+                managed.insert(event.machine.clone());
+            }
+
+            let wanted = match ids.is_empty() {
+                true => managed.contains(&event.machine),
+                false => ids.contains(&event.machine),
+            };
+
+            !wanted || on_event(event)
+        })
+    }
+
+    /// Mark the machine of `event` to be started again by
+    /// `restart_stopped` if `event` is a stop its restartPolicy restarts
+    /// after
+    pub fn record_stop(&mut self, event: &LifecycleEvent) -> Result<(), Error> {
+        let id = &event.machine;
+        let policy = match self.vmstore.load_machine(id) {
+            Ok(machine) => machine.spec.restart_policy.unwrap_or_default(),
+            Err(_) => return Ok(()),
+        };
+        let mut restart = self.vmstore.restart_state(id);
+        if !restart.wants_restart(policy, event) {
+            return Ok(());
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        restart.stopped(now);
+        warn!(
+            "'{}' {} ({}), restarting it in {:?}",
+            id,
+            event.event,
+            event.detail,
+            restart.backoff()
+        );
+        self.vmstore.set_restart_state(id, &restart)
+    }
+
+    /// Start the machines marked by `record_stop` whose backoff is over
+    pub fn restart_stopped(&mut self) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        for id in self.vmstore.list_instances()? {
+            let mut restart = self.vmstore.restart_state(&id);
+            if !restart.due(now) {
+                continue;
+            }
+            let machine = match self.vmstore.load_machine(&id) {
+                Ok(machine) => machine,
+                Err(_) => continue,
+            };
+
+            // started some other way in the meantime, or saved on purpose
+            if self.hypervisor.is_active(&id)? || self.vmstore.has_saved_state(&id) {
+                restart.pending_since = None;
+                self.vmstore.set_restart_state(&id, &restart)?;
+                continue;
+            }
+
+            info!(
+                "Restarting '{}' per its restartPolicy, attempt {}",
+                id,
+                restart.attempts + 1
+            );
+            let mut restarted = machine.clone();
+            let result = self.restart_machine(&id, &machine, &mut restarted, self.destroy_timeout);
+            if let Err(ref e) = result {
+                warn!("Restarting '{}' failed: {}", id, e);
+            }
+
+            // starting changed it too
+            let mut restart = self.vmstore.restart_state(&id);
+            restart.attempted(result.is_ok(), now);
+            self.vmstore.set_restart_state(&id, &restart)?;
+        }
+
+        Ok(())
+    }
+
+    /// Probe the running machines whose health check is due, restarting
+    /// the ones found unhealthy that ask for it
+    pub fn check_health(&mut self) -> Result<(), Error> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        for id in self.vmstore.list_instances()? {
+            let machine = match self.vmstore.load_machine(&id) {
+                Ok(machine) => machine,
+                Err(_) => continue,
+            };
+            let check = match machine.spec.health_check {
+                Some(ref check) => check,
+                None => continue,
+            };
+            let mut health = self.vmstore.health(&id);
+            if !health.due(check, now) || self.machine_state(&id).ok().as_deref() != Some("running")
+            {
+                continue;
+            }
+
+            // a first boot's cloud-init can take longer than the retries
+            let times = self.vmstore.timestamps(&id);
+            let started = times.state_changed_at.max(times.started_at);
+            let starting = started.is_some_and(|at| {
+                now < at + check.start_period().as_secs()
+                    && times.ready_at.is_none_or(|ready| ready < at)
+            });
+
+            let result = self.probe(&id, check);
+            if let Err(ref e) = result {
+                warn!("Health check of '{}' failed: {}", id, e);
+            }
+            health.record(result, now, starting);
+
+            if !health.is_healthy(check) && check.restart {
+                warn!("Restarting unhealthy '{}'", id);
+                let mut restarted = machine.clone();
+                match self.restart_machine(&id, &machine, &mut restarted, self.destroy_timeout) {
+                    Ok(()) => {
+                        health.reset();
+                        health.restarts += 1;
+                    }
+                    Err(e) => warn!("Restarting '{}' failed: {}", id, e),
+                }
+            }
+
+            self.vmstore.set_health(&id, &health)?;
+        }
+
+        Ok(())
+    }
+
+    // run `check` against machine `id` once
+    fn probe(&self, id: &str, check: &HealthCheck) -> Result<(), Error> {
+        let timeout = check.timeout();
+        let address = || {
+            self.find_addresses(id)
+                .into_iter()
+                .next()
+                .ok_or_else(|| Error::from(format!("no address known for '{}'", id)))
+        };
+
+        if let Some(port) = check.tcp {
+            return health::probe_tcp(address()?, port, timeout);
+        }
+        if let Some(ref url) = check.http {
+            return health::probe_http(url, address().ok(), timeout);
+        }
+        if let Some(ref argv) = check.exec {
+            let result = guest_agent::exec(self.hypervisor.as_ref(), id, argv, timeout)?;
+            if result.exit_code != 0 {
+                return Err(format!(
+                    "{:?} exited {}: {}",
+                    argv,
+                    result.exit_code,
+                    String::from_utf8_lossy(&result.stderr).trim()
+                )
+                .into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pass what machine `id` wrote to its serial console to `on_data`,
+    /// then with `follow` whatever it writes next, until `on_data` returns
+    /// false
+    pub fn console_log(
+        &self,
+        id: &str,
+        follow: bool,
+        on_data: &mut dyn FnMut(&[u8]) -> bool,
+    ) -> Result<(), Error> {
+        let id = &self.resolve(id)?;
+        let path = self.vmstore.console_log_path(id);
+        if !path.exists() {
+            return Err(error::not_found(format!(
+                "machine '{}' has no console log, it's written from the next start",
+                id
+            )));
+        }
+
+        tail(&path, follow, on_data)
+    }
+
+    // the stored spec of machine `id` with its MACs, which machines
+    // stored before MACs were kept get from their macPolicy again
+    fn load_machine(&self, id: &str) -> Result<Machine, Error> {
+        let mut machine = self.vmstore.load_machine(id)?;
+        assign_macs(id, &mut machine.spec);
+        Ok(machine)
+    }
+
+    // best effort guest addresses of a running machine, trying the guest
+    // agent, then the hypervisor's own lookup, then the host
+    // neighbor table matched against the machine's stored MACs
+    fn find_addresses(&self, id: &str) -> Vec<IpAddr> {
+        let hv = self.hypervisor.as_ref();
+        if let Ok(addrs) = guest_agent::ip_addrs(hv, id) {
+            if !addrs.is_empty() {
+                return addrs;
+            }
+        }
+
+        if let Ok(entries) = hv.interface_addrs(id) {
+            if !entries.is_empty() {
+                return entries.into_iter().map(|(_, addr)| addr).collect();
+            }
+        }
+
+        let macs: Vec<String> = match self.load_machine(id) {
+            Ok(m) => m
+                .spec
+                .nics
+                .iter()
+                .flatten()
+                .map(|n| n.macaddress.to_lowercase())
+                .collect(),
+            Err(_) => return Vec::new(),
+        };
+
+        let mut addrs = Vec::new();
+        for (mac, addr) in neighbors::table().unwrap_or_default() {
+            if macs.contains(&mac) && !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+
+        addrs
+    }
+}
+
+// MAC addresses for the nics of machine `name` that have none yet, as its
+// macPolicy picks them
+fn assign_macs(name: &str, spec: &mut Spec) {
+    let policy = spec.mac_policy.unwrap_or_default();
+    for (i, nic) in spec.nics.iter_mut().flatten().enumerate() {
+        if let Some(ref mac) = nic.mac {
+            nic.macaddress = mac.clone();
+            continue;
+        }
+        if !nic.macaddress.is_empty() {
+            continue;
+        }
+        let mac = match policy {
+            MacPolicy::Stable => Mac::from_seed(name, i),
+            MacPolicy::Random => Mac::gen(),
+        };
+        nic.macaddress = mac.to_string();
+    }
+}
+
+// host cpus a machine's vcpus are pinned to for its sole use
+fn dedicated_cpus(machine: &Machine) -> Vec<u32> {
+    match machine.spec.placement {
+        Some(ref p) if p.cpu == CpuPolicy::Dedicated => p.host_cpus.clone(),
+        _ => Vec::new(),
+    }
+}
+
+// what creating `current` filled into its spec, kept in `update` where it
+// declares the same: the UUID, MAC and pool addresses and mediated devices
+fn carry_over(current: &Machine, update: &mut Machine) {
+    if update.metadata.uuid.is_none() {
+        update.metadata.uuid = current.metadata.uuid.clone();
+    }
+    // the disk keeps the size resize-disk gave it
+    update.spec.image.resize = current.spec.image.resize;
+
+    let nics = current.spec.nics.iter().flatten();
+    for (nic, cur) in update.spec.nics.iter_mut().flatten().zip(nics) {
+        if nic.macaddress.is_empty() && nic.kind == cur.kind && nic.parent == cur.parent {
+            nic.macaddress = cur.macaddress.clone();
+        }
+        if let (AddressKind::Pool(ref mut p), AddressKind::Pool(ref c)) =
+            (&mut nic.address, &cur.address)
+        {
+            if p.pool == c.pool && p.assigned.is_none() {
+                p.assigned = c.assigned.clone();
+            }
+        }
+    }
+
+    let mdevs = current.spec.mdev.iter().flatten();
+    for (mdev, cur) in update.spec.mdev.iter_mut().flatten().zip(mdevs) {
+        if mdev.uuid.is_none() && mdev.kind == cur.kind && mdev.parent == cur.parent {
+            mdev.uuid = cur.uuid.clone();
+        }
+    }
+}
+
+// activates pools and checks every declared disk is present, polling for up
+// to `wait_secs` for slow to attach devices such as SAN LUNs
+fn prepare_storage(
+    hv: &dyn Hypervisor,
+    storages: &[StorageKind],
+    wait_secs: u64,
+) -> Result<(), Error> {
+    for store in storages {
+        if let StorageKind::Volume(ref vol) = store {
+            hv.activate_pool(&vol.pool)?;
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(wait_secs);
+
+    loop {
+        let mut missing = Vec::new();
+
+        for store in storages {
+            let present = match store {
+                StorageKind::File(ref file) => file.path.exists(),
+                StorageKind::Block(ref block) => block.path.exists(),
+                StorageKind::Volume(ref vol) => hv.volume_exists(&vol.pool, &vol.volume)?,
+                // network disks are only reachable from qemu, so can't be checked here
+                StorageKind::Rbd(_) | StorageKind::Iscsi(_) => true,
+                // made when the domain is defined
+                StorageKind::Ephemeral(_) => true,
+            };
+
+            if !present {
+                missing.push(storage_description(store));
+            }
+        }
+
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            return Err(format!("storage not available: {}", missing.join(", ")).into());
+        }
+
+        info!("Waiting for storage: {}", missing.join(", "));
+        std::thread::sleep(Duration::from_secs(2));
+    }
+}
+
+fn storage_description(store: &StorageKind) -> String {
+    match store {
+        StorageKind::File(ref file) => format!("{:?}", file.path),
+        StorageKind::Block(ref block) => format!("{:?}", block.path),
+        StorageKind::Volume(ref vol) => format!("{}/{}", vol.pool, vol.volume),
+        StorageKind::Rbd(ref rbd) => format!("rbd:{}/{}", rbd.pool, rbd.image),
+        StorageKind::Iscsi(ref iscsi) => format!("iscsi:{}/{}", iscsi.target, iscsi.lun),
+        StorageKind::Ephemeral(ref scratch) => format!("ephemeral {}", scratch.size),
+    }
+}
+
+// hidden directory beside `output` to assemble an archive in
+fn staging_dir(output: &Path) -> Result<PathBuf, Error> {
+    let file_name = output
+        .file_name()
+        .ok_or_else(|| format!("invalid archive path {:?}", output))?;
+
+    let mut name = std::ffi::OsString::from(".");
+    name.push(file_name);
+    name.push(".staging");
+
+    Ok(output.with_file_name(name))
+}
+
+// targets of the storage devices attached after the instance disk
+fn storage_targets(machine: &Machine) -> Vec<String> {
+    let bus = machine.spec.storage_bus.unwrap_or_default();
+    let count = machine.spec.storage.as_ref().map_or(0, |s| s.len());
+
+    (0..count).filter_map(|i| bus.target(i).ok()).collect()
+}
+
+// Give the instance directory the SELinux type QEMU may use. Without it
+// files keep the label of wherever the state directory lives, which an
+// enforcing host won't let svirt_t open, even with relabelling by libvirt
+// for static labels or relabel: false.
+fn relabel_instance_dir(machine: &Machine, dir: &Path) -> Result<(), Error> {
+    let model = machine.spec.seclabel.as_ref().and_then(|s| s.model);
+    if !Path::new("/sys/fs/selinux/enforce").exists()
+        || matches!(model, Some(SecurityModel::Apparmor | SecurityModel::Dac))
+    {
+        return Ok(());
+    }
+
+    let output = std::process::Command::new("chcon")
+        .arg("--recursive")
+        .arg("--type")
+        .arg("virt_image_t")
+        .arg(dir)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "error relabelling {:?}: {}",
+            dir,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+// config drive for `machine` in `dir`, with secrets injected into the
+// userdata and network config for its nics. MACs must already be set.
+// With `phone_home` cloud-init tells that URL when it is done. ISOs are
+// made by `iso_tool`, or one found in PATH, in the spec's configDriveFormat.
+fn build_config_drive(
+    machine: &Machine,
+    dir: &Path,
+    phone_home: Option<&str>,
+    iso_tool: Option<&Path>,
+) -> Result<PathBuf, Error> {
+    let format = machine.spec.config_drive_format.unwrap_or_default();
+    let netconf = match format {
+        ConfigDriveFormat::OpenStack => network_config::build_network_data(&machine.spec.nics)?,
+        _ => network_config::build_net_config(&machine.spec.nics)?,
+    };
+
+    let mut builder = configdrive::Builder::new(&machine.metadata.name);
+    builder.format(format);
+    if let Some(ref uuid) = machine.metadata.uuid {
+        builder.metadata().set_instance_id(uuid);
+    }
+
+    if !netconf.is_empty() {
+        builder.add_network_config(netconf);
+    }
+
+    let mut userdata = machine.spec.user_data()?;
+    if let Some(url) = phone_home {
+        userdata = with_phone_home(userdata, url, &machine.metadata.name)?;
+    }
+
+    if let Some(userdata) = userdata {
+        let userdata = match machine.spec.secrets {
+            Some(ref secrets) => secret_provider::inject(&userdata, secrets)?,
+            None => userdata,
+        };
+        builder.add_userdata(userdata.into_bytes());
+    }
+
+    if let Some(tool) = iso_tool {
+        builder.iso_tool(tool);
+    }
+
+    builder.build(dir)
+}
+
+// how often a followed console log is checked for more output
+const TAIL_INTERVAL: Duration = Duration::from_millis(250);
+
+// the contents of `path`, then with `follow` what is appended to it,
+// starting over when virtlogd rotates it away
+fn tail(path: &Path, follow: bool, on_data: &mut dyn FnMut(&[u8]) -> bool) -> Result<(), Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut inode = file.metadata()?.ino();
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n > 0 {
+            if !on_data(&buf[..n]) {
+                return Ok(());
+            }
+            continue;
+        }
+        if !follow {
+            return Ok(());
+        }
+
+        std::thread::sleep(TAIL_INTERVAL);
+        match std::fs::metadata(path) {
+            Ok(md) if md.ino() != inode => {
+                file = std::fs::File::open(path)?;
+                inode = md.ino();
+            }
+            // truncated in place
+            Ok(md) if md.len() < file.stream_position()? => {
+                file.seek(SeekFrom::Start(0))?;
+            }
+            _ => {}
+        }
+    }
+}
+
+// `userdata` with cloud-init's phone_home module posting to `<url>/ready/
+// <name>`. Scripts can't take it, those machines are only seen as ready
+// through the guest agent.
+fn with_phone_home(
+    userdata: Option<String>,
+    url: &str,
+    name: &str,
+) -> Result<Option<String>, Error> {
+    let phone_home = format!(
+        "{}\nphone_home:\n  url: {}/ready/{}\n  post: [instance_id]\n  tries: 10\n",
+        CLOUD_CONFIG_HEADER,
+        url.trim_end_matches('/'),
+        name
+    );
+
+    match userdata {
+        None => Ok(Some(phone_home)),
+        Some(data) if data.starts_with(CLOUD_CONFIG_HEADER) => {
+            let parts = [
+                (String::from("userdata"), data),
+                (String::from("phone_home"), phone_home),
+            ];
+            Ok(Some(userdata::merge(&parts)?))
+        }
+        Some(data) => {
+            warn!(
+                "{} userdata is not a cloud-config, it won't phone home",
+                name
+            );
+            Ok(Some(data))
+        }
+    }
+}
+
+// the status in `cloud-init status` output, e.g. "running" or "done"
+fn cloud_init_status(output: &str) -> Option<&str> {
+    output
+        .lines()
+        .find_map(|l| l.strip_prefix("status:"))
+        .map(str::trim)
+}
+
+// `addrs` to try reaching a machine on, IPv4 before IPv6 and
+// `link_local` last. Link-local addresses found without their interface
+// can't be used.
+fn probe_order(addrs: &[IpAddr], link_local: &[SocketAddrV6], port: u16) -> Vec<SocketAddr> {
+    let mut order: Vec<SocketAddr> = addrs
+        .iter()
+        .filter(|a| match a {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(v6) => !v6.is_unicast_link_local(),
+        })
+        .map(|a| SocketAddr::new(*a, port))
+        .collect();
+    order.sort_by_key(|a| a.is_ipv6());
+    order.extend(link_local.iter().map(|a| SocketAddr::V6(*a)));
+    order
+}
+
+// the addresses of `addrs` others can reach a machine on, without
+// duplicates
+fn dns_addresses(addrs: &[IpAddr]) -> Vec<IpAddr> {
+    let mut out: Vec<IpAddr> = Vec::new();
+    for addr in addrs {
+        let local = match addr {
+            IpAddr::V4(v4) => v4.is_loopback() || v4.is_link_local(),
+            IpAddr::V6(v6) => v6.is_loopback() || v6.is_unicast_link_local(),
+        };
+        if !local && !out.contains(addr) {
+            out.push(*addr);
+        }
+    }
+    out
+}
+
+// index of network interface `dev`, as link-local addresses are scoped
+fn ifindex(dev: &str) -> Option<u32> {
+    let index = std::fs::read_to_string(Path::new("/sys/class/net").join(dev).join("ifindex"));
+    index.ok()?.trim().parse().ok()
+}
+
+// take what goes on the config drive from `update`. Nics keep their MACs
+// and can only change addressing and names, anything more needs the
+// machine recreated.
+fn merge_cloud_init(machine: &mut Machine, update: &Machine) -> Result<(), Error> {
+    let name = &machine.metadata.name;
+    if update.metadata.name != *name {
+        return Err(format!("spec is for '{}', not '{}'", update.metadata.name, name).into());
+    }
+
+    let nics = machine.spec.nics.as_deref().unwrap_or_default();
+    let new_nics = update.spec.nics.as_deref().unwrap_or_default();
+    let same_nics = nics.len() == new_nics.len()
+        && nics
+            .iter()
+            .zip(new_nics)
+            .all(|(a, b)| a.kind == b.kind && a.parent == b.parent);
+    if !same_nics {
+        return Err(format!(
+            "nics of '{}' can't be added, removed or moved without recreating it",
+            name
+        )
+        .into());
+    }
+
+    for (nic, new) in machine.spec.nics.iter_mut().flatten().zip(new_nics) {
+        nic.address = new.address.clone();
+        nic.name = new.name.clone();
+    }
+
+    machine.spec.userdata = update.spec.userdata.clone();
+    machine.spec.cloud_config = update.spec.cloud_config.clone();
+    machine.spec.secrets = update.spec.secrets.clone();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::api::models::{IPv4Static, Nic};
+
+    #[test]
+    fn tail_console_log() {
+        let path = std::env::temp_dir().join(format!("bigiron-virt-tail-{}", std::process::id()));
+        std::fs::write(&path, b"SeaBIOS\nBooting from Hard Disk...\n").unwrap();
+
+        let mut seen = Vec::new();
+        tail(&path, false, &mut |data| {
+            seen.extend_from_slice(data);
+            true
+        })
+        .unwrap();
+        assert_eq!(seen, b"SeaBIOS\nBooting from Hard Disk...\n");
+
+        // following stops once the reader has had enough
+        let mut calls = 0;
+        tail(&path, true, &mut |_| {
+            calls += 1;
+            false
+        })
+        .unwrap();
+        assert_eq!(calls, 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn merge_config_drive_changes() {
+        let hash = "754129c5052756ee47a0c395e518bd3413f444dff69b98f8a8fa42f2fa3acc2d";
+        let builder = Machine::builder()
+            .name("vm1")
+            .cpu(1)
+            .memory("1Gi")
+            .image("file:///images/ubuntu.img", hash);
+
+        let mut machine = builder
+            .clone()
+            .nic(Nic::bridge("br0"))
+            .userdata("#cloud-config\n")
+            .build()
+            .unwrap();
+        machine.spec.nics.as_mut().unwrap()[0].macaddress = "00:16:3e:00:00:01".to_string();
+
+        let address = AddressKind::IPv4Static(IPv4Static {
+            addr: "10.0.0.5/24".to_string(),
+            gateway: "10.0.0.1".to_string(),
+            nameservers: Vec::new(),
+        });
+        let update = builder
+            .clone()
+            .nic(Nic::bridge("br0").with_address(address.clone()))
+            .userdata("#cloud-config\npackages: [htop]\n")
+            .build()
+            .unwrap();
+
+        merge_cloud_init(&mut machine, &update).unwrap();
+        let nic = &machine.spec.nics.as_ref().unwrap()[0];
+        assert_eq!(nic.address, address);
+        assert_eq!(nic.macaddress, "00:16:3e:00:00:01");
+        assert_eq!(
+            machine.spec.userdata.as_deref(),
+            Some("#cloud-config\npackages: [htop]\n")
+        );
+
+        let moved = builder.nic(Nic::bridge("br1")).build().unwrap();
+        assert!(merge_cloud_init(&mut machine, &moved).is_err());
+    }
+
+    #[test]
+    fn phone_home() {
+        let url = "http://192.168.122.1:9180/";
+        let userdata = with_phone_home(None, url, "vm1").unwrap().unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&userdata).unwrap();
+        assert_eq!(
+            value["phone_home"]["url"],
+            "http://192.168.122.1:9180/ready/vm1"
+        );
+
+        let userdata = Some(String::from("#cloud-config\npackages: [htop]\n"));
+        let merged = with_phone_home(userdata, url, "vm1").unwrap().unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&merged).unwrap();
+        assert_eq!(value["packages"][0], "htop");
+        assert_eq!(value["phone_home"]["post"][0], "instance_id");
+
+        // scripts are left alone
+        let script = Some(String::from("#!/bin/sh\necho hi\n"));
+        assert_eq!(with_phone_home(script.clone(), url, "vm1").unwrap(), script);
+
+        assert_eq!(cloud_init_status("\nstatus: done\n"), Some("done"));
+        assert_eq!(cloud_init_status("status: running"), Some("running"));
+        assert_eq!(cloud_init_status(""), None);
+    }
+
+    #[test]
+    fn probe_addresses() {
+        let addrs: Vec<IpAddr> = ["2001:db8::5", "fe80::1", "192.0.2.10"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+        let mac: Mac = "52:54:00:12:34:56".parse().unwrap();
+        let link_local = [SocketAddrV6::new(mac.to_ipv6_link_local(), 22, 0, 4)];
+
+        let order: Vec<String> = probe_order(&addrs, &link_local, 22)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(
+            order,
+            [
+                "192.0.2.10:22",
+                "[2001:db8::5]:22",
+                "[fe80::5054:ff:fe12:3456%4]:22"
+            ]
+        );
+    }
+
+    #[test]
+    fn registered_addresses() {
+        let addrs: Vec<IpAddr> = [
+            "192.0.2.10",
+            "127.0.0.1",
+            "169.254.3.4",
+            "fe80::1",
+            "2001:db8::5",
+            "192.0.2.10",
+        ]
+        .iter()
+        .map(|a| a.parse().unwrap())
+        .collect();
+        let addrs: Vec<String> = dns_addresses(&addrs)
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(addrs, ["192.0.2.10", "2001:db8::5"]);
     }
 }
@@ -0,0 +1,726 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! KVM guests through the local libvirt daemon.
+
+use std::net::IpAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+
+use tracing::{info, instrument};
+
+use crate::api::models::{
+    AddressKind, Bandwidth, BootDevice, Confidential, ConfigDriveFormat, CpuMode, CpuModel,
+    CpuPolicy, DiskAuth, DiskBus, DiskDriver, Encryption, FilterRef, InputDevice, Nic,
+    PlacementHints, RateLimit, RestartPolicy, SecLabel, StorageKind, UsbDevice,
+};
+use crate::doctor;
+use crate::error::Error;
+use crate::events::{self, LifecycleEvent};
+use crate::hypervisor::{
+    DiskStats, DomainSpec, DomainStats, DomainStatus, Hypervisor, InterfaceStats,
+};
+use crate::libvirt;
+use crate::mac::Mac;
+use crate::secret_provider;
+use crate::secrets::{self, Usage};
+use crate::vmstore::InstanceImage;
+
+pub struct Libvirt;
+
+impl Hypervisor for Libvirt {
+    fn name(&self) -> &'static str {
+        "libvirt"
+    }
+
+    #[instrument(name = "libvirt_create", skip_all, fields(machine = %spec.machine.metadata.name))]
+    fn create(&self, spec: &DomainSpec) -> Result<(), Error> {
+        let machine = spec.machine;
+        let name = &machine.metadata.name;
+
+        let memory = machine.spec.memory;
+        info!(
+            "Creating machine '{}' with {} cpus and {} memory",
+            name, machine.spec.cpu, memory
+        );
+
+        // keeping the XML to see what libvirt was given
+        let xml = render(spec)?;
+        std::fs::write(spec.xml_file, &xml)?;
+        libvirt::create_domain(&xml)?;
+
+        let bridged = machine
+            .spec
+            .nics
+            .iter()
+            .flatten()
+            .filter(|n| n.kind == "Bridge");
+        if let Some(nic) = bridged.last() {
+            match nic.macaddress.parse::<Mac>() {
+                Ok(mac) => info!("IPv6 SLAAC: {}", mac.to_ipv6_link_local()),
+                Err(_) => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    fn redefine(&self, spec: &DomainSpec) -> Result<(), Error> {
+        libvirt::redefine_domain(&spec.machine.metadata.name, &render(spec)?)
+    }
+
+    #[instrument(name = "libvirt_destroy", skip(self))]
+    fn destroy(&self, name: &str) -> Result<(), Error> {
+        libvirt::destroy(name)
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        libvirt::list_domains()
+    }
+
+    fn status(&self, name: &str) -> Result<Option<DomainStatus>, Error> {
+        Ok(libvirt::domain_status(name)?.map(|(state, active)| DomainStatus { state, active }))
+    }
+
+    fn disks(&self, name: &str) -> Result<Vec<PathBuf>, Error> {
+        libvirt::domain_disks(name)
+    }
+
+    fn domain_xml(&self, name: &str) -> Result<String, Error> {
+        libvirt::domain_xml(name)
+    }
+
+    fn disk_secret(
+        &self,
+        image: &Path,
+        encryption: &Encryption,
+    ) -> Result<(String, Vec<u8>), Error> {
+        match (&encryption.secret, &encryption.passphrase_file) {
+            (Some(uuid), _) => Ok((uuid.clone(), secrets::value(uuid)?)),
+            (None, Some(file)) => {
+                let passphrase = secret_provider::resolve(&format!("file:{}", file.display()))?;
+                let usage = Usage::Volume(image.to_path_buf());
+                let description = format!("passphrase of {}", image.display());
+                let uuid = secrets::set(&usage, &passphrase, true, Some(&description))?;
+                Ok((uuid, passphrase))
+            }
+            (None, None) => Err("image encryption needs a secret or passphraseFile".into()),
+        }
+    }
+
+    fn remove_disk_secret(&self, image: &Path) -> Result<(), Error> {
+        match secrets::lookup(&Usage::Volume(image.to_path_buf()))? {
+            Some(uuid) => secrets::remove(&uuid),
+            None => Ok(()),
+        }
+    }
+
+    #[instrument(name = "libvirt_attach_disk", skip(self, path))]
+    fn attach_disk(&self, name: &str, path: &Path, target: &str) -> Result<(), Error> {
+        let block = std::fs::metadata(path)?.file_type().is_block_device();
+        libvirt::attach_disk(name, path, target, block, &libvirt::DiskDriver::default())
+    }
+
+    fn attach_interface(&self, name: &str, nic: &Nic) -> Result<(), Error> {
+        let (if_type, source) = interface_source(nic)?;
+        libvirt::attach_interface(
+            name,
+            if_type,
+            &source,
+            &nic.macaddress,
+            &interface_options(nic),
+        )
+    }
+
+    fn detach_interface(&self, name: &str, nic: &Nic) -> Result<(), Error> {
+        let (if_type, source) = interface_source(nic)?;
+        libvirt::detach_interface(name, if_type, &source, &nic.macaddress)
+    }
+
+    fn set_memory(&self, name: &str, bytes: u64) -> Result<(), Error> {
+        libvirt::set_memory(name, bytes)
+    }
+
+    fn swap_config_drive(&self, name: &str, iso: &Path) -> Result<(), Error> {
+        libvirt::change_media(name, libvirt::CONFIG_DRIVE_TARGET, iso)
+    }
+
+    #[instrument(name = "libvirt_shutdown", skip(self))]
+    fn shutdown(&self, name: &str) -> Result<(), Error> {
+        libvirt::shutdown(name)
+    }
+
+    fn pause(&self, name: &str) -> Result<(), Error> {
+        libvirt::suspend(name)
+    }
+
+    fn resume(&self, name: &str) -> Result<(), Error> {
+        libvirt::resume(name)
+    }
+
+    fn save(&self, name: &str, path: &Path) -> Result<(), Error> {
+        libvirt::save(name, path)
+    }
+
+    fn restore(&self, path: &Path) -> Result<(), Error> {
+        libvirt::restore(path)
+    }
+
+    fn host_resources(&self) -> Result<(u32, u64), Error> {
+        libvirt::node_resources()
+    }
+
+    fn host_free_memory(&self) -> Result<u64, Error> {
+        libvirt::node_free_memory()
+    }
+
+    fn domain_resources(&self, name: &str) -> Result<(u32, u64), Error> {
+        libvirt::domain_resources(name)
+    }
+
+    fn resize_disk(&self, name: &str, target: &str, size: u64) -> Result<(), Error> {
+        libvirt::block_resize(name, target, size)
+    }
+
+    fn snapshot_disk(
+        &self,
+        name: &str,
+        target: &str,
+        overlay: &Path,
+        exclude: &[String],
+        quiesce: bool,
+    ) -> Result<(), Error> {
+        libvirt::snapshot_disk(name, target, overlay, exclude, quiesce)
+    }
+
+    fn commit_disk(&self, name: &str, target: &str) -> Result<(), Error> {
+        libvirt::block_commit(name, target)
+    }
+
+    fn agent_command(&self, name: &str, command: &str, timeout_secs: i32) -> Result<String, Error> {
+        libvirt::agent_command(name, command, timeout_secs)
+    }
+
+    // DHCP leases only cover libvirt managed networks, fall back to ARP
+    fn interface_addrs(&self, name: &str) -> Result<Vec<(String, IpAddr)>, Error> {
+        let leases = libvirt::interface_addrs(name, libvirt::AddressSource::Lease)?;
+        if !leases.is_empty() {
+            return Ok(leases);
+        }
+
+        libvirt::interface_addrs(name, libvirt::AddressSource::Arp)
+    }
+
+    fn domain_stats(&self) -> Result<Vec<DomainStats>, Error> {
+        Ok(libvirt::domain_stats()?
+            .into_iter()
+            .map(|(name, fields)| domain_stats(name, &fields))
+            .collect())
+    }
+
+    fn activate_pool(&self, pool: &str) -> Result<(), Error> {
+        libvirt::activate_pool(pool)
+    }
+
+    fn volume_exists(&self, pool: &str, volume: &str) -> Result<bool, Error> {
+        libvirt::volume_exists(pool, volume)
+    }
+
+    fn watch(&self, on_event: &mut dyn FnMut(LifecycleEvent) -> bool) -> Result<(), Error> {
+        events::watch_libvirt(on_event)
+    }
+}
+
+// the domain XML for `spec`
+fn render(spec: &DomainSpec) -> Result<String, Error> {
+    let machine = spec.machine;
+    let name = &machine.metadata.name;
+
+    // create base vm spec
+    let memory = machine.spec.memory.bytes();
+    let mut d = libvirt::DomainBuilder::new(name, machine.spec.cpu, memory, spec.image.path());
+
+    if let Some(ref uuid) = machine.metadata.uuid {
+        d.set_uuid(uuid);
+    }
+
+    if let InstanceImage::Block(_) = spec.image {
+        d.set_image_block_device();
+    }
+
+    d.set_disk_driver(&disk_driver(&machine.spec.image.driver));
+
+    if let Some(uuid) = spec.disk_secret {
+        d.set_image_encryption(uuid);
+    }
+
+    if let Some(iothreads) = machine.spec.iothreads {
+        d.set_iothreads(iothreads);
+    }
+
+    if let Some(ref boot) = machine.spec.direct_boot {
+        d.set_direct_boot(
+            &boot.kernel_path()?,
+            boot.initrd_path()?.as_deref(),
+            boot.cmdline.as_deref(),
+        )?;
+    }
+
+    if let Some(ref boot_order) = machine.spec.boot_order {
+        let devices: Vec<_> = boot_order.iter().map(|b| boot_device(*b)).collect();
+        d.set_boot_order(&devices);
+    }
+
+    let mut cpu = match machine.spec.cpu_model {
+        Some(ref cpu) => Some(cpu_model(cpu)?),
+        None => None,
+    };
+    if machine.spec.nested_virt == Some(true) {
+        let feature = nesting_feature(Path::new("/sys/module"))?;
+        let cpu = cpu.get_or_insert_with(|| libvirt::CpuModel {
+            mode: libvirt::CpuMode::HostPassthrough,
+            require: Vec::new(),
+            disable: Vec::new(),
+        });
+        if !cpu.require.iter().any(|f| f == feature) {
+            cpu.require.push(feature.to_string());
+        }
+    }
+    if let Some(ref cpu) = cpu {
+        d.set_cpu_model(cpu);
+    }
+
+    if let Some(ref cells) = machine.spec.numa {
+        let cells: Vec<_> = cells
+            .iter()
+            .map(|c| libvirt::NumaCell {
+                cpus: c.cpus,
+                memory_bytes: c.memory.bytes(),
+                host_node: c.host_node,
+            })
+            .collect();
+        d.set_numa(&cells);
+    }
+
+    if let Some(ref placement) = machine.spec.placement {
+        d.set_cputune(&cputune(placement));
+        if placement.memory_locked {
+            d.set_memory_locked();
+        }
+    }
+
+    // confidential guest launch options
+    if let Some(ref conf) = machine.spec.confidential {
+        d.set_launch_security(&launch_security(conf))?;
+    }
+
+    if let Some(ref smbios) = machine.spec.smbios {
+        d.set_sysinfo(&libvirt::Sysinfo {
+            bios_vendor: None,
+            manufacturer: smbios.manufacturer.clone(),
+            product: smbios.product.clone(),
+            serial: smbios.serial.clone(),
+            uuid: smbios.uuid.clone(),
+            asset_tag: smbios.asset_tag.clone(),
+        });
+    }
+
+    d.set_serial_log(spec.console_log)?;
+
+    // a panicked guest is reset in place, other failures are left to
+    // `serve` to start again
+    match machine.spec.restart_policy.unwrap_or_default() {
+        RestartPolicy::Never => {}
+        RestartPolicy::OnFailure | RestartPolicy::Always => d.set_on_crash("restart"),
+    }
+
+    if machine.spec.input == Some(InputDevice::Tablet) {
+        d.set_tablet();
+    }
+    if let Some(video) = machine.spec.video {
+        d.set_video(video.as_str());
+    }
+    if let Some(sound) = machine.spec.sound {
+        d.set_sound(sound.as_str());
+    }
+
+    if let Some(controller) = machine.spec.usb_controller {
+        d.set_usb_controller(controller.as_str());
+    }
+    for usb in machine.spec.usb_devices.iter().flatten() {
+        d.add_usb_device(&usb_host_dev(usb)?);
+    }
+
+    // types were allocated a device by the host manager
+    for mdev in machine.spec.mdev.iter().flatten() {
+        let uuid = mdev.uuid.as_deref().ok_or("mdev has no device allocated")?;
+        d.add_mdev(uuid);
+    }
+
+    if let Some(ref seclabel) = machine.spec.seclabel {
+        d.set_seclabel(&libvirt::SecLabel {
+            kind: Some(seclabel.kind.unwrap_or_default().as_str().to_string()),
+            ..seclabel_of(seclabel)
+        });
+    }
+
+    // before the nics and disks, which keep out of its PCI slots
+    if let Some(ref xml) = machine.spec.extra_domain_xml {
+        d.add_device_xml(xml)?;
+    }
+
+    // network config
+    if let Some(nics) = &machine.spec.nics {
+        for nic in nics.iter() {
+            let opts = interface_options(nic);
+
+            match nic.kind.as_str() {
+                "Bridge" => {
+                    d.add_bridged_interface(&nic.parent, &nic.macaddress, &opts)?;
+                }
+                "Macvtap" => {
+                    d.add_macvtap_interface(&nic.parent, &nic.macaddress, &opts)?;
+                }
+                "Network" => {
+                    d.add_network_interface(&nic.parent, &nic.macaddress, &opts)?;
+                }
+                &_ => {}
+            }
+        }
+    }
+
+    // attach config drive
+    match machine.spec.config_drive_format.unwrap_or_default() {
+        ConfigDriveFormat::Vfat => d.add_config_disk(spec.config_drive)?,
+        _ => d.add_cdrom_from_iso(spec.config_drive)?,
+    }
+
+    // attach extra cdroms, skipping hdc which is taken by the config drive
+    let targets = ["hda", "hdb", "hdd"];
+    if spec.cdroms.len() > targets.len() {
+        return Err(format!("at most {} cdroms are supported", targets.len()).into());
+    }
+
+    for (iso, target) in spec.cdroms.iter().zip(targets) {
+        d.add_cdrom(iso, target, None)?;
+    }
+
+    // attach storage devices
+    let bus = machine.spec.storage_bus.unwrap_or_default();
+    if bus == DiskBus::Scsi {
+        d.add_scsi_controller();
+    }
+
+    let mut scratch_disks = spec.scratch_disks.iter();
+    if let Some(storages) = &machine.spec.storage {
+        for (i, store) in storages.iter().enumerate() {
+            let target = bus.target(i)?;
+            let target_name = target.as_str();
+
+            match store {
+                StorageKind::File(ref file) => {
+                    d.add_file_backed_storage(&file.path, target_name, &disk_driver(&file.driver))?;
+                }
+                StorageKind::Volume(ref vol) => {
+                    d.add_volume_backed_storage(
+                        &vol.pool,
+                        &vol.volume,
+                        target_name,
+                        &disk_driver(&vol.driver),
+                    )?;
+                }
+                StorageKind::Rbd(_) | StorageKind::Iscsi(_) => {
+                    d.add_network_storage(
+                        &network_disk(store)?,
+                        target_name,
+                        &disk_driver(store.driver()),
+                    )?;
+                }
+                StorageKind::Block(ref block) => {
+                    d.add_block_backed_storage(
+                        &block.path,
+                        target_name,
+                        &disk_driver(&block.driver),
+                    )?;
+                }
+                StorageKind::Ephemeral(ref scratch) => {
+                    let path = scratch_disks
+                        .next()
+                        .ok_or("ephemeral disk was not created")?;
+                    let format = match scratch.tmpfs {
+                        Some(true) => "raw",
+                        _ => "qcow2",
+                    };
+                    d.add_scratch_storage(
+                        path,
+                        target_name,
+                        format,
+                        &disk_driver(&scratch.driver),
+                    )?;
+                }
+            }
+        }
+    }
+
+    if let Some(ref xml) = machine.spec.domain_xml_override {
+        d.set_xml_override(xml);
+    }
+
+    d.render()
+}
+
+// maps raw domain stats fields, missing counters are 0
+fn domain_stats(name: String, fields: &libvirt::StatsFields) -> DomainStats {
+    let get = |key: String| -> u64 { fields.get(&key).and_then(|v| v.parse().ok()).unwrap_or(0) };
+    let name_of = |key: String| fields.get(&key).cloned().unwrap_or_default();
+
+    DomainStats {
+        cpu_time_ns: get("cpu.time".to_string()),
+        vcpus: get("vcpu.current".to_string()) as u32,
+        // balloon sizes are in KiB
+        memory_bytes: get("balloon.current".to_string()) * 1024,
+        memory_rss_bytes: get("balloon.rss".to_string()) * 1024,
+        disks: (0..get("block.count".to_string()))
+            .map(|i| DiskStats {
+                name: name_of(format!("block.{}.name", i)),
+                read_bytes: get(format!("block.{}.rd.bytes", i)),
+                write_bytes: get(format!("block.{}.wr.bytes", i)),
+            })
+            .collect(),
+        interfaces: (0..get("net.count".to_string()))
+            .map(|i| InterfaceStats {
+                name: name_of(format!("net.{}.name", i)),
+                rx_bytes: get(format!("net.{}.rx.bytes", i)),
+                tx_bytes: get(format!("net.{}.tx.bytes", i)),
+            })
+            .collect(),
+        name,
+    }
+}
+
+fn network_disk(store: &StorageKind) -> Result<libvirt::NetworkDisk, Error> {
+    let auth = |auth: &Option<DiskAuth>, secret_type: &str| {
+        auth.as_ref().map(|a| libvirt::DiskAuth {
+            username: a.username.clone(),
+            secret_type: secret_type.to_string(),
+            secret_uuid: a.secret.clone(),
+        })
+    };
+
+    match store {
+        StorageKind::Rbd(ref rbd) => Ok(libvirt::NetworkDisk {
+            protocol: "rbd".to_string(),
+            name: format!("{}/{}", rbd.pool, rbd.image),
+            hosts: rbd
+                .monitors
+                .iter()
+                .map(|m| split_host_port(m))
+                .collect::<Result<_, _>>()?,
+            auth: auth(&rbd.auth, "ceph"),
+        }),
+        StorageKind::Iscsi(ref iscsi) => Ok(libvirt::NetworkDisk {
+            protocol: "iscsi".to_string(),
+            name: format!("{}/{}", iscsi.target, iscsi.lun),
+            hosts: vec![split_host_port(&iscsi.portal)?],
+            auth: auth(&iscsi.auth, "iscsi"),
+        }),
+        _ => Err("not a network disk".into()),
+    }
+}
+
+fn split_host_port(s: &str) -> Result<(String, Option<u16>), Error> {
+    match s.rsplit_once(':') {
+        Some((host, port)) => Ok((host.to_string(), Some(port.parse()?))),
+        None => Ok((s.to_string(), None)),
+    }
+}
+
+fn disk_driver(driver: &DiskDriver) -> libvirt::DiskDriver {
+    libvirt::DiskDriver {
+        cache: driver.cache.map(|c| c.as_str().to_string()),
+        io: driver.io.map(|i| i.as_str().to_string()),
+        discard: driver.discard.map(|d| d.as_str().to_string()),
+        detect_zeroes: driver.detect_zeroes.map(|d| d.as_str().to_string()),
+        queues: driver.queues,
+        iothread: driver.iothread,
+        iotune: driver.iotune.as_ref().map(|t| libvirt::IoTune {
+            total_bytes_sec: t.total_bytes_sec.map(|s| s.bytes()),
+            read_bytes_sec: t.read_bytes_sec.map(|s| s.bytes()),
+            write_bytes_sec: t.write_bytes_sec.map(|s| s.bytes()),
+            total_iops_sec: t.total_iops_sec,
+            read_iops_sec: t.read_iops_sec,
+            write_iops_sec: t.write_iops_sec,
+        }),
+        seclabel: driver.seclabel.as_ref().map(seclabel_of),
+    }
+}
+
+fn usb_host_dev(usb: &UsbDevice) -> Result<libvirt::UsbHostDev, Error> {
+    match (&usb.vendor, &usb.product, usb.bus, usb.device) {
+        (Some(vendor), Some(product), None, None) => Ok(libvirt::UsbHostDev::Id {
+            vendor: vendor.to_lowercase(),
+            product: product.to_lowercase(),
+        }),
+        (None, None, Some(bus), Some(device)) => Ok(libvirt::UsbHostDev::Address { bus, device }),
+        _ => Err("usb device needs either vendor and product or bus and device".into()),
+    }
+}
+
+fn seclabel_of(seclabel: &SecLabel) -> libvirt::SecLabel {
+    libvirt::SecLabel {
+        model: seclabel.model.map(|m| m.as_str().to_string()),
+        kind: None,
+        relabel: seclabel.relabel,
+        label: seclabel.label.clone(),
+    }
+}
+
+fn interface_options(nic: &Nic) -> libvirt::InterfaceOptions {
+    libvirt::InterfaceOptions {
+        queues: nic.queues,
+        filter: nic.filterref.as_ref().map(|f| filter_ref(f, &nic.address)),
+        trust_guest_rx_filters: nic.trust_guest_rx_filters.unwrap_or(false),
+        isolated: nic.isolated.unwrap_or(false),
+        bandwidth: nic.bandwidth.as_ref().map(bandwidth),
+    }
+}
+
+type SourceAttrs<'a> = Vec<(&'static str, &'a str)>;
+
+// interface type and source attributes of a hot plugged `nic`, as the
+// domain builder's add_*_interface set them
+fn interface_source(nic: &Nic) -> Result<(&'static str, SourceAttrs<'_>), Error> {
+    let parent = nic.parent.as_str();
+    match nic.kind.as_str() {
+        "Bridge" => Ok(("bridge", vec![("bridge", parent)])),
+        "Macvtap" => Ok(("direct", vec![("dev", parent), ("mode", "bridge")])),
+        "Network" => Ok(("network", vec![("network", parent)])),
+        kind => Err(format!("unknown nic kind '{}'", kind).into()),
+    }
+}
+
+// parameters in name order, with IP filled in from a static address
+fn filter_ref(filter: &FilterRef, address: &AddressKind) -> libvirt::FilterRef {
+    let mut parameters: Vec<_> = filter
+        .parameters
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    if let Some(ip) = address.ipv4_static() {
+        if !filter.parameters.contains_key("IP") {
+            let addr = ip.addr.split_once('/').map_or(ip.addr.as_str(), |(a, _)| a);
+            parameters.push(("IP".to_string(), addr.to_string()));
+        }
+    }
+
+    parameters.sort();
+    libvirt::FilterRef {
+        filter: filter.filter.clone(),
+        parameters,
+    }
+}
+
+fn bandwidth(bw: &Bandwidth) -> libvirt::Bandwidth {
+    let rate = |r: &RateLimit| libvirt::RateLimit {
+        average: r.average,
+        peak: r.peak,
+        burst: r.burst,
+    };
+
+    libvirt::Bandwidth {
+        inbound: bw.inbound.as_ref().map(rate),
+        outbound: bw.outbound.as_ref().map(rate),
+    }
+}
+
+fn boot_device(dev: BootDevice) -> libvirt::BootDevice {
+    match dev {
+        BootDevice::Hd => libvirt::BootDevice::Hd,
+        BootDevice::Cdrom => libvirt::BootDevice::Cdrom,
+        BootDevice::Network => libvirt::BootDevice::Network,
+    }
+}
+
+fn cpu_model(cpu: &CpuModel) -> Result<libvirt::CpuModel, Error> {
+    let mode = match cpu.mode {
+        CpuMode::HostPassthrough => libvirt::CpuMode::HostPassthrough,
+        CpuMode::HostModel => libvirt::CpuMode::HostModel,
+        CpuMode::Custom => libvirt::CpuMode::Custom(
+            cpu.model
+                .clone()
+                .ok_or("cpuModel mode custom requires a model")?,
+        ),
+    };
+
+    Ok(libvirt::CpuModel {
+        mode,
+        require: cpu.add.clone(),
+        disable: cpu.remove.clone(),
+    })
+}
+
+// the cpu feature a guest needs to run KVM itself, once the host's kvm
+// module under `sys_module` is checked to allow it
+fn nesting_feature(sys_module: &Path) -> Result<&'static str, Error> {
+    match doctor::kvm_nesting(sys_module) {
+        Some(("kvm_amd", true)) => Ok("svm"),
+        Some((_, true)) => Ok("vmx"),
+        Some((module, false)) => Err(format!(
+            "nestedVirt needs nesting enabled in {}, {}",
+            module,
+            doctor::nesting_fix(module)
+        )
+        .into()),
+        None => Err("nestedVirt needs the kvm_intel or kvm_amd module loaded".into()),
+    }
+}
+
+fn cputune(placement: &PlacementHints) -> libvirt::CpuTune {
+    let mut cputune = libvirt::CpuTune {
+        emulator_cpus: placement.emulator_cpus.clone(),
+        ..Default::default()
+    };
+
+    match placement.cpu {
+        CpuPolicy::Dedicated => cputune.vcpu_pins = placement.host_cpus.clone(),
+        CpuPolicy::Shared => {
+            cputune.shares = placement.cpu_shares;
+            cputune.limit_percent = placement.cpu_limit;
+        }
+    }
+
+    cputune
+}
+
+fn launch_security(conf: &Confidential) -> libvirt::LaunchSecurity {
+    use libvirt::LaunchSecurity;
+
+    match conf {
+        Confidential::S390PV => LaunchSecurity::S390Pv,
+        Confidential::SEV(sev) => LaunchSecurity::Sev {
+            policy: sev.policy.unwrap_or(0x0003),
+            cbitpos: sev.cbitpos.unwrap_or(47),
+            reduced_phys_bits: sev.reduced_phys_bits.unwrap_or(1),
+        },
+        Confidential::SEVSNP(sev) => LaunchSecurity::SevSnp {
+            policy: sev.policy.unwrap_or(0x30000),
+            cbitpos: sev.cbitpos.unwrap_or(51),
+            reduced_phys_bits: sev.reduced_phys_bits.unwrap_or(1),
+        },
+    }
+}
@@ -0,0 +1,298 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Hypervisor drivers.
+//!
+//! `HostManager` only talks to the `Hypervisor` trait, the driver is picked
+//! by the `hypervisor` host config setting. Drivers must implement the core
+//! lifecycle calls; the rest default to returning an unsupported error.
+
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use crate::api::models::{Encryption, Machine, Nic};
+use crate::config::HypervisorConfig;
+use crate::error::Error;
+use crate::events::LifecycleEvent;
+use crate::vmstore::InstanceImage;
+
+pub mod libvirt;
+pub mod zvm;
+
+/// Everything needed to start a machine, with disks and ISOs already
+/// in place on the host
+pub struct DomainSpec<'a> {
+    pub machine: &'a Machine,
+    pub image: &'a InstanceImage,
+    pub config_drive: &'a Path,
+    /// extra ISOs to attach as cdroms, in spec order
+    pub cdroms: &'a [PathBuf],
+    /// the secret unlocking an encrypted `image`, from `disk_secret`
+    pub disk_secret: Option<&'a str>,
+    /// scratch disks made for the machine's Ephemeral storage, in spec order
+    pub scratch_disks: &'a [PathBuf],
+    /// where drivers that start domains from XML leave a copy of it
+    pub xml_file: &'a Path,
+    /// where the serial console is logged, for `bigiron-virt logs`
+    pub console_log: &'a Path,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainStatus {
+    /// lowercase state name, e.g. "running"
+    pub state: String,
+    pub active: bool,
+}
+
+/// Resource usage counters of a running domain
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DomainStats {
+    pub name: String,
+    pub cpu_time_ns: u64,
+    pub vcpus: u32,
+    /// memory currently given to the guest
+    pub memory_bytes: u64,
+    /// host memory actually used by the domain
+    pub memory_rss_bytes: u64,
+    pub disks: Vec<DiskStats>,
+    pub interfaces: Vec<InterfaceStats>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiskStats {
+    /// target name, e.g. "vda"
+    pub name: String,
+    pub read_bytes: u64,
+    pub write_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InterfaceStats {
+    /// host side device name, e.g. "vnet0"
+    pub name: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+pub trait Hypervisor {
+    /// Driver name used in error messages
+    fn name(&self) -> &'static str;
+
+    /// Define and start a domain
+    fn create(&self, spec: &DomainSpec) -> Result<(), Error>;
+
+    /// Stop and remove domain `name`, succeeding if it doesn't exist
+    fn destroy(&self, name: &str) -> Result<(), Error>;
+
+    /// Names of all domains the hypervisor knows about
+    fn list(&self) -> Result<Vec<String>, Error>;
+
+    /// Current status of domain `name`, `None` if it doesn't exist
+    fn status(&self, name: &str) -> Result<Option<DomainStatus>, Error>;
+
+    fn is_active(&self, name: &str) -> Result<bool, Error> {
+        Ok(self.status(name)?.is_some_and(|s| s.active))
+    }
+
+    /// Files and block devices behind the disks of domain `name`
+    fn disks(&self, _name: &str) -> Result<Vec<PathBuf>, Error> {
+        unsupported(self.name(), "listing disks")
+    }
+
+    /// The libvirt XML of domain `name`, which adopting reads its spec from
+    fn domain_xml(&self, _name: &str) -> Result<String, Error> {
+        unsupported(self.name(), "adopting domains")
+    }
+
+    /// The passphrase of encrypted disk `image` and the UUID of the secret
+    /// the domain will read it from, set up if it comes from a file
+    fn disk_secret(
+        &self,
+        _image: &Path,
+        _encryption: &Encryption,
+    ) -> Result<(String, Vec<u8>), Error> {
+        unsupported(self.name(), "disk encryption")
+    }
+
+    /// Remove what `disk_secret` set up for disk `image`, if anything
+    fn remove_disk_secret(&self, _image: &Path) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Hot plug a file or block device into a running domain
+    fn attach_disk(&self, _name: &str, _path: &Path, _target: &str) -> Result<(), Error> {
+        unsupported(self.name(), "attaching disks")
+    }
+
+    /// Hot plug `nic` into a running domain
+    fn attach_interface(&self, _name: &str, _nic: &Nic) -> Result<(), Error> {
+        unsupported(self.name(), "attaching nics")
+    }
+
+    /// Hot unplug `nic` from a running domain
+    fn detach_interface(&self, _name: &str, _nic: &Nic) -> Result<(), Error> {
+        unsupported(self.name(), "detaching nics")
+    }
+
+    /// Balloon a running domain to `bytes` of memory, at most what it
+    /// started with
+    fn set_memory(&self, _name: &str, _bytes: u64) -> Result<(), Error> {
+        unsupported(self.name(), "changing memory")
+    }
+
+    /// Make `spec` the definition a persistent domain next starts from,
+    /// leaving the running domain be. Domains that only exist while they
+    /// run have no definition to change.
+    fn redefine(&self, _spec: &DomainSpec) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Have a running domain reread its config drive from `iso`
+    fn swap_config_drive(&self, _name: &str, _iso: &Path) -> Result<(), Error> {
+        unsupported(self.name(), "changing cdrom media")
+    }
+
+    /// Ask the guest OS to shut down
+    fn shutdown(&self, _name: &str) -> Result<(), Error> {
+        unsupported(self.name(), "shutdown")
+    }
+
+    /// Pause a running domain
+    fn pause(&self, _name: &str) -> Result<(), Error> {
+        unsupported(self.name(), "pausing")
+    }
+
+    fn resume(&self, _name: &str) -> Result<(), Error> {
+        unsupported(self.name(), "pausing")
+    }
+
+    /// Stop a domain, writing its memory state to `path`
+    fn save(&self, _name: &str, _path: &Path) -> Result<(), Error> {
+        unsupported(self.name(), "save and restore")
+    }
+
+    /// Start a domain from a state file written by `save`
+    fn restore(&self, _path: &Path) -> Result<(), Error> {
+        unsupported(self.name(), "save and restore")
+    }
+
+    /// Host cpus and memory in bytes
+    fn host_resources(&self) -> Result<(u32, u64), Error> {
+        unsupported(self.name(), "host resource reporting")
+    }
+
+    /// Host memory in bytes not in use right now
+    fn host_free_memory(&self) -> Result<u64, Error> {
+        unsupported(self.name(), "host resource reporting")
+    }
+
+    /// Cpus and memory in bytes allocated to a running domain
+    fn domain_resources(&self, _name: &str) -> Result<(u32, u64), Error> {
+        unsupported(self.name(), "domain resource reporting")
+    }
+
+    /// Tell a running domain that the disk on `target` is now `size` bytes
+    fn resize_disk(&self, _name: &str, _target: &str, _size: u64) -> Result<(), Error> {
+        unsupported(self.name(), "live disk resize")
+    }
+
+    /// Redirect writes to `target` into a new `overlay`, leaving disks in
+    /// `exclude` alone
+    fn snapshot_disk(
+        &self,
+        _name: &str,
+        _target: &str,
+        _overlay: &Path,
+        _exclude: &[String],
+        _quiesce: bool,
+    ) -> Result<(), Error> {
+        unsupported(self.name(), "disk snapshots")
+    }
+
+    /// Merge the overlay made by `snapshot_disk` back into the disk
+    fn commit_disk(&self, _name: &str, _target: &str) -> Result<(), Error> {
+        unsupported(self.name(), "disk snapshots")
+    }
+
+    /// Send a JSON command to the guest agent, returning the JSON response
+    fn agent_command(
+        &self,
+        _name: &str,
+        _command: &str,
+        _timeout_secs: i32,
+    ) -> Result<String, Error> {
+        unsupported(self.name(), "guest agent commands")
+    }
+
+    /// Addresses of a domain's interfaces as `(mac, address)` pairs
+    fn interface_addrs(&self, _name: &str) -> Result<Vec<(String, IpAddr)>, Error> {
+        unsupported(self.name(), "interface address lookup")
+    }
+
+    /// Usage counters of all running domains
+    fn domain_stats(&self) -> Result<Vec<DomainStats>, Error> {
+        unsupported(self.name(), "domain stats")
+    }
+
+    /// Start a storage pool if it isn't already active
+    fn activate_pool(&self, _pool: &str) -> Result<(), Error> {
+        unsupported(self.name(), "storage pools")
+    }
+
+    fn volume_exists(&self, _pool: &str, _volume: &str) -> Result<bool, Error> {
+        unsupported(self.name(), "storage pools")
+    }
+
+    /// Call `on_event` for lifecycle events of every domain until it
+    /// returns false
+    fn watch(&self, _on_event: &mut dyn FnMut(LifecycleEvent) -> bool) -> Result<(), Error> {
+        unsupported(self.name(), "event watching")
+    }
+}
+
+fn unsupported<T>(driver: &str, what: &str) -> Result<T, Error> {
+    Err(format!("{} is not supported by the {} driver", what, driver).into())
+}
+
+/// The driver selected by the host config
+pub fn from_config(config: &HypervisorConfig) -> Result<Box<dyn Hypervisor>, Error> {
+    match config {
+        HypervisorConfig::Libvirt => Ok(Box::new(libvirt::Libvirt)),
+        HypervisorConfig::Zvm { smapi_host, user } => Ok(Box::new(zvm::Zvm::new(smapi_host, user))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defaults_unsupported() {
+        let hv = from_config(&HypervisorConfig::Zvm {
+            smapi_host: "zvm01".to_string(),
+            user: "MAINT".to_string(),
+        })
+        .unwrap();
+
+        assert_eq!(hv.name(), "zvm");
+        assert!(hv.list().is_err());
+        assert_eq!(
+            hv.shutdown("vm1").unwrap_err().to_string(),
+            "shutdown is not supported by the zvm driver"
+        );
+    }
+}
@@ -0,0 +1,68 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! z/VM guests through the Systems Management API (SMAPI).
+//!
+//! Only a placeholder for now: every call fails until the SMAPI client is
+//! written, but it lets hosts select the driver and exercises the trait.
+
+use crate::error::Error;
+use crate::hypervisor::{DomainSpec, DomainStatus, Hypervisor};
+
+pub struct Zvm {
+    smapi_host: String,
+    user: String,
+}
+
+impl Zvm {
+    pub fn new(smapi_host: &str, user: &str) -> Self {
+        Self {
+            smapi_host: smapi_host.to_string(),
+            user: user.to_string(),
+        }
+    }
+
+    fn not_implemented<T>(&self, call: &str) -> Result<T, Error> {
+        Err(format!(
+            "z/VM driver: {} through SMAPI on {} as {} is not implemented yet",
+            call, self.smapi_host, self.user
+        )
+        .into())
+    }
+}
+
+impl Hypervisor for Zvm {
+    fn name(&self) -> &'static str {
+        "zvm"
+    }
+
+    fn create(&self, spec: &DomainSpec) -> Result<(), Error> {
+        self.not_implemented(&format!("creating '{}'", spec.machine.metadata.name))
+    }
+
+    fn destroy(&self, name: &str) -> Result<(), Error> {
+        self.not_implemented(&format!("destroying '{}'", name))
+    }
+
+    fn list(&self) -> Result<Vec<String>, Error> {
+        self.not_implemented("listing guests")
+    }
+
+    fn status(&self, name: &str) -> Result<Option<DomainStatus>, Error> {
+        self.not_implemented(&format!("querying '{}'", name))
+    }
+}
@@ -0,0 +1,143 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Disk images built from OCI container images, for
+//! `bigiron-virt image import-oci`.
+//!
+//! podman pulls the image and exports a container of it as one flattened
+//! tarball, which virt-make-fs writes into an ext4 filesystem on the first
+//! partition of a qcow2. Images without a kernel under /boot are refused
+//! before building anything. The image still has no bootloader, so machines
+//! made from it boot that kernel with `spec.directBoot` and `root=/dev/vda1`
+//! on the cmdline.
+
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+
+use tracing::{info, warn};
+use url::Url;
+
+use crate::api::models::Size;
+use crate::error::Error;
+use crate::process::{self, Policy};
+
+const QUICK: Policy = Policy::new(Duration::from_secs(60));
+const PULL: Policy = Policy::new(Duration::from_secs(1800)).retries(1);
+const BUILD: Policy = Policy::new(Duration::from_secs(3600)).retries(0);
+
+/// Where an image built from OCI image `reference` is recorded as coming
+/// from, e.g. oci:quay.io/fedora/fedora:40
+pub fn source_url(reference: &str) -> Result<Url, Error> {
+    Ok(Url::parse(&format!("oci:{}", reference))?)
+}
+
+/// Build a qcow2 at `dest` holding the filesystem of OCI image `reference`
+/// with `free` bytes to spare, working in directory `work_dir`
+pub fn build_disk(reference: &str, dest: &Path, free: u64, work_dir: &Path) -> Result<(), Error> {
+    info!("Pulling OCI image {}", reference);
+    process::run(
+        Command::new("podman").args(["pull", "--quiet", "--", reference]),
+        &PULL,
+    )?;
+
+    // never run, the command only stands in for images without one
+    let output = process::run(
+        Command::new("podman").args(["create", "--", reference, "/bin/sh"]),
+        &QUICK,
+    )?;
+    let container = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    let rootfs = work_dir.join("rootfs.tar");
+    let exported = process::run(
+        Command::new("podman")
+            .arg("export")
+            .arg("--output")
+            .arg(&rootfs)
+            .arg(&container),
+        &BUILD,
+    );
+    if let Err(e) = process::run(Command::new("podman").args(["rm", &container]), &QUICK) {
+        warn!("Failed to remove container {}: {}", container, e);
+    }
+    exported?;
+
+    let listing = process::run(
+        Command::new("tar").arg("--list").arg("--file").arg(&rootfs),
+        &BUILD,
+    )?;
+    if !has_kernel(&String::from_utf8_lossy(&listing.stdout)) {
+        return Err(format!("OCI image {} has no kernel under /boot", reference).into());
+    }
+
+    info!(
+        "Building {:?} from {} with {} free",
+        dest,
+        reference,
+        Size(free)
+    );
+    process::run(
+        Command::new("virt-make-fs")
+            .arg("--format=qcow2")
+            .arg("--type=ext4")
+            .arg("--partition")
+            .arg(format!("--size=+{}M", free.div_ceil(1 << 20)))
+            .arg(&rootfs)
+            .arg(dest),
+        &BUILD,
+    )?;
+
+    std::fs::remove_file(&rootfs)?;
+
+    Ok(())
+}
+
+// whether a `tar --list` of a root filesystem shows a kernel in /boot
+fn has_kernel(listing: &str) -> bool {
+    listing.lines().any(|entry| {
+        entry
+            .trim_start_matches("./")
+            .strip_prefix("boot/")
+            .is_some_and(|f| !f.contains('/') && f.starts_with("vmlinu"))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sources() {
+        assert_eq!(
+            source_url("quay.io/fedora/fedora:40").unwrap().as_str(),
+            "oci:quay.io/fedora/fedora:40"
+        );
+        assert_eq!(source_url("alpine").unwrap().path(), "alpine");
+    }
+
+    #[test]
+    fn kernels() {
+        assert!(has_kernel(
+            "bin/\nboot/\nboot/vmlinuz-6.8.5-301.fc40.x86_64\n"
+        ));
+        assert!(has_kernel("./boot/vmlinux\n"));
+        assert!(!has_kernel(
+            "bin/\nboot/\nboot/grub2/vmlinuz\nusr/vmlinuz\n"
+        ));
+        assert!(!has_kernel(""));
+    }
+}
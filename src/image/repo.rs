@@ -17,15 +17,22 @@
 
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use hex;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tracing::info;
+use tracing::{info, instrument};
 use url::Url;
 
-use crate::error::Error;
+use crate::api::models::Size;
+use crate::backup::utc_timestamp;
+use crate::error::{self, Error};
 use crate::statestore::DirectoryStore;
 
+// metadata for the images in the repo, beside them
+const INDEX_FILE: &str = "index.json";
+
 // image repo based on a local directory
 pub struct Directory {
     store: DirectoryStore,
@@ -33,6 +40,49 @@ pub struct Directory {
 
 pub type ImageId = String;
 
+/// What the repo knows about an image. Images imported before the index
+/// existed only have their hash, format and size.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageInfo {
+    pub hash: String,
+    /// file extension, "qcow2" or "iso"
+    pub format: String,
+    pub size: u64,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// UTC, e.g. 20231016T093000Z
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub imported_at: Option<String>,
+
+    /// friendly name, e.g. ubuntu-22.04, usable as `spec.image.name`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// free form, e.g. "Ubuntu 22.04 LTS"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os: Option<String>,
+}
+
+impl ImageInfo {
+    /// the image's file in the repo, `<hash>.<format>`
+    pub fn file_name(&self) -> String {
+        format!("{}.{}", self.hash, self.format)
+    }
+
+    fn is_called(&self, name: &str) -> bool {
+        self.name.as_deref() == Some(name) || self.aliases.iter().any(|a| a == name)
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    #[serde(default)]
+    images: Vec<ImageInfo>,
+}
+
 impl Directory {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         Ok(Self {
@@ -40,23 +90,216 @@ impl Directory {
         })
     }
 
+    pub fn path(&self) -> &Path {
+        self.store.path()
+    }
+
     pub fn images(&self) -> Result<Vec<String>, Error> {
         Ok(self
             .store
-            .list_files()?
+            .list_files_with_ext("qcow2")?
             .into_iter()
-            .filter(|f| f.ends_with(".qcow2"))
+            .map(|f| f.name)
             .collect())
     }
 
     pub fn add_image(&mut self, url: &Url, hash: &str) -> Result<ImageId, Error> {
+        self.import(url, hash, "qcow2")
+    }
+
+    /// Move qcow2 `path`, built on this host rather than downloaded, into
+    /// the repo as coming from `source`. `path` should be on the repo's
+    /// filesystem for the move to be a rename.
+    pub fn add_built_image(&mut self, path: &Path, source: &Url) -> Result<ImageId, Error> {
+        let hash = hash_file(path)?;
+        let to_path = self.store.path().join(format!("{}.qcow2", hash));
+        if to_path.exists() {
+            std::fs::remove_file(path)?;
+        } else if std::fs::rename(path, &to_path).is_err() {
+            std::fs::copy(path, &to_path)?;
+            std::fs::remove_file(path)?;
+        }
+
+        self.record(source, &hash, "qcow2", std::fs::metadata(&to_path)?.len())?;
+        Ok(hash)
+    }
+
+    /// Import an ISO (e.g. an OS installer) into the repo
+    pub fn add_iso(&mut self, url: &Url, hash: &str) -> Result<ImageId, Error> {
+        self.import(url, hash, "iso")
+    }
+
+    /// All images in the repo with their metadata
+    pub fn list(&self) -> Result<Vec<ImageInfo>, Error> {
+        let index = self.load_index()?;
+        let mut images = Vec::new();
+
+        let mut files = self.store.list_files_with_ext("qcow2")?;
+        files.extend(self.store.list_files_with_ext("iso")?);
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        for file in files {
+            let Some((hash, format)) = file.name.split_once('.') else {
+                continue;
+            };
+
+            let size = file.size;
+            let info = match index.images.iter().find(|i| i.file_name() == file.name) {
+                Some(info) => ImageInfo {
+                    size,
+                    ..info.clone()
+                },
+                // imported before the index, the file is as old as the import
+                None => ImageInfo {
+                    hash: hash.to_string(),
+                    format: format.to_string(),
+                    size,
+                    imported_at: file
+                        .modified
+                        .duration_since(UNIX_EPOCH)
+                        .ok()
+                        .map(|d| utc_timestamp(d.as_secs())),
+                    ..Default::default()
+                },
+            };
+            images.push(info);
+        }
+
+        Ok(images)
+    }
+
+    /// The qcow2 image with friendly name or alias `name`
+    pub fn find(&self, name: &str) -> Result<ImageInfo, Error> {
+        self.load_index()?
+            .images
+            .into_iter()
+            .find(|i| i.format == "qcow2" && i.is_called(name))
+            .filter(|i| self.store.path().join(i.file_name()).is_file())
+            .ok_or_else(|| format!("No image named '{}' found", name).into())
+    }
+
+    /// Remove image file `file`, e.g. `<hash>.qcow2`, and what the index
+    /// knows about it
+    pub fn remove(&mut self, file: &str) -> Result<(), Error> {
+        let mut index = self.load_index()?;
+        index.images.retain(|i| i.file_name() != file);
+        self.save_index(&index)?;
+
+        std::fs::remove_file(self.store.path().join(file))?;
+        info!("Removed {} from image repo", file);
+
+        Ok(())
+    }
+
+    /// Give image `image` (a hash, name or alias) the friendly name `name`
+    /// and any `aliases`, taking them from other images that had them.
+    /// `os` replaces the recorded OS if given.
+    pub fn set_name(
+        &mut self,
+        image: &str,
+        name: &str,
+        aliases: &[String],
+        os: Option<&str>,
+    ) -> Result<ImageInfo, Error> {
+        let mut index = self.load_index()?;
+
+        let info = self
+            .list()?
+            .into_iter()
+            .find(|i| i.hash == image || i.is_called(image))
+            .ok_or_else(|| format!("No image with id='{}' found", image))?;
+
+        let taken = |n: &String| n == name || aliases.contains(n);
+        for other in index.images.iter_mut() {
+            if other.name.as_ref().is_some_and(taken) {
+                other.name = None;
+            }
+            other.aliases.retain(|a| !taken(a));
+        }
+
+        let file = info.file_name();
+        let entry = match index.images.iter().position(|i| i.file_name() == file) {
+            Some(i) => &mut index.images[i],
+            None => {
+                index.images.push(info);
+                index.images.last_mut().unwrap()
+            }
+        };
+
+        entry.name = Some(name.to_string());
+        for alias in aliases {
+            if alias != name && !entry.aliases.contains(alias) {
+                entry.aliases.push(alias.clone());
+            }
+        }
+        if let Some(os) = os {
+            entry.os = Some(os.to_string());
+        }
+        let info = entry.clone();
+
+        self.save_index(&index)?;
+
+        Ok(info)
+    }
+
+    fn load_index(&self) -> Result<Index, Error> {
+        let path = self.store.path().join(INDEX_FILE);
+        match std::fs::read(&path) {
+            Ok(data) => Ok(serde_json::from_slice(&data)
+                .map_err(|e| format!("error reading image index {:?}: {}", path, e))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Index::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // written aside and renamed so a reader never sees half an index
+    fn save_index(&self, index: &Index) -> Result<(), Error> {
+        let path = self.store.path().join(INDEX_FILE);
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(index)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    // note an image in the index, keeping what's already known about it
+    fn record(&self, url: &Url, hash: &str, ext: &str, size: u64) -> Result<(), Error> {
+        let mut index = self.load_index()?;
+        let file = format!("{}.{}", hash, ext);
+        if index.images.iter().any(|i| i.file_name() == file) {
+            return Ok(());
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        index.images.push(ImageInfo {
+            hash: hash.to_string(),
+            format: ext.to_string(),
+            size,
+            url: Some(url.to_string()),
+            imported_at: Some(utc_timestamp(now)),
+            ..Default::default()
+        });
+
+        self.save_index(&index)
+    }
+
+    #[instrument(name = "image_import", skip(self, url), fields(url = %url))]
+    fn import(&mut self, url: &Url, hash: &str, ext: &str) -> Result<ImageId, Error> {
         match url.scheme() {
             "file" => {}
             _ => return Err(format!("Url scheme not supported: {:?}", url.scheme()).into()),
         };
 
-        let to_path = self.store.path().join(format!("{}.qcow2", hash));
+        // it names the file in the repo
+        if !is_sha256(hash) {
+            return Err(error::invalid(format!(
+                "image hash '{}' must be a sha256, 64 lowercase hex characters",
+                hash
+            )));
+        }
+
+        let to_path = self.store.path().join(format!("{}.{}", hash, ext));
         if to_path.exists() {
+            // imported before there was an index
+            self.record(url, hash, ext, std::fs::metadata(&to_path)?.len())?;
             return Ok(hash.to_string());
         }
 
@@ -78,13 +321,17 @@ impl Directory {
         // copy image to repo, while hashing
         let mut buf = [0; 128 * 1024];
         let mut n = image_stream.read(&mut buf)?;
+        let mut copied: u64 = 0;
 
         while n > 0 {
             h.write_all(&buf[..n])?;
             out_stream.write_all(&buf[..n])?;
+            copied += n as u64;
             n = image_stream.read(&mut buf)?;
         }
 
+        info!("Copied {} into image repo", Size(copied));
+
         let r = h.finalize();
         let hx = hex::encode(r);
 
@@ -97,11 +344,21 @@ impl Directory {
             info!("New image hash='{}' matches given hash", hx);
         }
 
+        self.record(url, hash, ext, copied)?;
+
         Ok(hash.to_string())
     }
 
     pub fn get_image(&self, id: &ImageId) -> Result<PathBuf, Error> {
-        let path = self.store.path().join(format!("{}.qcow2", id));
+        self.get(id, "qcow2")
+    }
+
+    pub fn get_iso(&self, id: &ImageId) -> Result<PathBuf, Error> {
+        self.get(id, "iso")
+    }
+
+    fn get(&self, id: &ImageId, ext: &str) -> Result<PathBuf, Error> {
+        let path = self.store.path().join(format!("{}.{}", id, ext));
 
         if !path.is_file() {
             return Err(String::from(format!("No image with id='{}' found", id)).into());
@@ -111,6 +368,27 @@ impl Directory {
     }
 }
 
+fn is_sha256(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The sha256 of file `path` in hex, as image hashes are given
+pub fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut f = std::fs::File::open(path)?;
+    let mut h = Sha256::new();
+    let mut buf = [0; 128 * 1024];
+
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        h.update(&buf[..n]);
+    }
+
+    Ok(hex::encode(h.finalize()))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -123,4 +401,44 @@ mod test {
         eprintln!("{:?}", images);
         assert!(!images.contains(&"src".to_string()));
     }
+
+    #[test]
+    pub fn test_names() {
+        let dir = std::env::temp_dir().join(format!("bigiron-virt-repo-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut d = Directory::new(&dir).unwrap();
+
+        let source = dir.join("source.img");
+        std::fs::write(&source, b"jammy").unwrap();
+        let hash = hex::encode(Sha256::digest(b"jammy"));
+        let url = Url::from_file_path(&source).unwrap();
+        assert!(d.add_image(&url, "../jammy").is_err());
+        assert!(d.add_image(&url, &hash.to_uppercase()).is_err());
+        d.add_image(&url, &hash).unwrap();
+
+        let images = d.list().unwrap();
+        assert_eq!(images.len(), 1);
+        assert_eq!(images[0].url.as_deref(), Some(url.as_str()));
+        assert_eq!(images[0].size, 5);
+        assert!(images[0].imported_at.is_some());
+        assert!(d.find("ubuntu-22.04").is_err());
+
+        let aliases = ["jammy".to_string()];
+        d.set_name(&hash, "ubuntu-22.04", &aliases, Some("Ubuntu 22.04 LTS"))
+            .unwrap();
+        assert_eq!(d.find("jammy").unwrap().hash, hash);
+        assert_eq!(
+            d.find("ubuntu-22.04").unwrap().os.as_deref(),
+            Some("Ubuntu 22.04 LTS")
+        );
+
+        // names move to the image they're given to
+        std::fs::write(dir.join(format!("{}.qcow2", "0".repeat(64))), b"").unwrap();
+        d.set_name(&"0".repeat(64), "ubuntu-22.04", &[], None)
+            .unwrap();
+        assert_eq!(d.find("ubuntu-22.04").unwrap().hash, "0".repeat(64));
+        assert_eq!(d.find("jammy").unwrap().name, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
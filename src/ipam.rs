@@ -0,0 +1,405 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! IPv4 address pools that nics take static addresses from.
+//!
+//! A pool is declared as `kind: AddressPool` in a model file and kept in
+//! `<pool dir>/<name>.yaml` along with the address each machine was given,
+//! so creates hand out the lowest free address and destroys give it back.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::api::models::{AddressPool, IPv4Static};
+use crate::error::Error;
+
+/// The pools of a host, in a directory of their own
+pub struct PoolStore {
+    path: PathBuf,
+}
+
+/// A pool and how much of it is handed out
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PoolUsage {
+    pub name: String,
+    pub cidr: String,
+    pub used: usize,
+    pub free: usize,
+}
+
+// a pool as stored, with the machine each address went to
+#[derive(Debug, Serialize, Deserialize)]
+struct PoolState {
+    pool: AddressPool,
+    #[serde(default)]
+    assigned: BTreeMap<Ipv4Addr, String>,
+}
+
+// a pool's spec, parsed
+#[derive(Debug, PartialEq)]
+struct Subnet {
+    prefix: u8,
+    gateway: Ipv4Addr,
+    first: u32,
+    last: u32,
+    reserved: Vec<Ipv4Addr>,
+}
+
+impl PoolStore {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Create or update `pool`, keeping the addresses handed out so far,
+    /// which have to stay in its range
+    pub fn save(&self, pool: &AddressPool) -> Result<(), Error> {
+        let name = &pool.metadata.name;
+        let subnet = Subnet::of(pool)?;
+        let _lock = self.lock()?;
+
+        let assigned = match self.load(name) {
+            Ok(state) => state.assigned,
+            Err(_) => BTreeMap::new(),
+        };
+        if let Some((addr, machine)) = assigned.iter().find(|(a, _)| !subnet.contains(**a)) {
+            return Err(format!(
+                "address pool {} would no longer hold {} of {}",
+                name, addr, machine
+            )
+            .into());
+        }
+
+        self.store(&PoolState {
+            pool: pool.clone(),
+            assigned,
+        })
+    }
+
+    /// Give machine `machine` the lowest free address of pool `name`
+    pub fn assign(&self, name: &str, machine: &str) -> Result<IPv4Static, Error> {
+        let _lock = self.lock()?;
+        let mut state = self.load(name)?;
+        let subnet = Subnet::of(&state.pool)?;
+
+        let addr = subnet
+            .candidates()
+            .find(|a| !state.assigned.contains_key(a))
+            .ok_or_else(|| format!("address pool {} has no free addresses", name))?;
+        state.assigned.insert(addr, machine.to_string());
+        self.store(&state)?;
+        info!("Assigned {} from pool {} to {}", addr, name, machine);
+
+        Ok(IPv4Static {
+            addr: format!("{}/{}", addr, subnet.prefix),
+            gateway: subnet.gateway.to_string(),
+            nameservers: state.pool.spec.nameservers.clone(),
+        })
+    }
+
+    /// Give back every address machine `machine` has, from any pool
+    pub fn release(&self, machine: &str) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        for name in self.names()? {
+            let mut state = self.load(&name)?;
+            let before = state.assigned.len();
+            state.assigned.retain(|_, m| m != machine);
+            if state.assigned.len() != before {
+                self.store(&state)?;
+                info!("Released the addresses of {} in pool {}", machine, name);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn list(&self) -> Result<Vec<PoolUsage>, Error> {
+        let mut pools = Vec::new();
+        for name in self.names()? {
+            let state = self.load(&name)?;
+            let subnet = Subnet::of(&state.pool)?;
+            let used = state.assigned.len();
+            pools.push(PoolUsage {
+                name,
+                cidr: state.pool.spec.cidr.clone(),
+                used,
+                free: subnet.candidates().count().saturating_sub(used),
+            });
+        }
+
+        Ok(pools)
+    }
+
+    /// Remove pool `name`, as long as none of its addresses are in use
+    pub fn remove(&self, name: &str) -> Result<(), Error> {
+        let _lock = self.lock()?;
+        let state = self.load(name)?;
+        if !state.assigned.is_empty() {
+            let machines: Vec<&str> = state.assigned.values().map(String::as_str).collect();
+            return Err(format!(
+                "address pool {} is still used by {}",
+                name,
+                machines.join(", ")
+            )
+            .into());
+        }
+
+        std::fs::remove_file(self.path_for(name)?)?;
+        Ok(())
+    }
+
+    fn names(&self) -> Result<Vec<String>, Error> {
+        let entries = match std::fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut names: Vec<String> = entries
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .filter_map(|f| f.strip_suffix(".yaml").map(String::from))
+            .collect();
+        names.sort();
+        Ok(names)
+    }
+
+    // names are kept to a-z, 0-9 and '-' so they can't point out of the
+    // pool dir
+    fn path_for(&self, name: &str) -> Result<PathBuf, Error> {
+        let valid = |c: char| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-';
+        if name.is_empty() || !name.chars().all(valid) {
+            return Err(format!(
+                "invalid address pool name '{}', use lowercase letters, digits and '-'",
+                name
+            )
+            .into());
+        }
+        Ok(self.path.join(format!("{}.yaml", name)))
+    }
+
+    // held while a pool is read and replaced, so concurrent creates can't
+    // hand out the same address
+    fn lock(&self) -> Result<File, Error> {
+        std::fs::create_dir_all(&self.path)?;
+        let file = File::create(self.path.join(".lock"))?;
+        file.lock()?;
+        Ok(file)
+    }
+
+    fn load(&self, name: &str) -> Result<PoolState, Error> {
+        let yaml = std::fs::read_to_string(self.path_for(name)?)
+            .map_err(|_| format!("no address pool {}", name))?;
+        Ok(serde_yaml::from_str(&yaml)?)
+    }
+
+    // replaced whole, so a crash never leaves half a pool
+    fn store(&self, state: &PoolState) -> Result<(), Error> {
+        std::fs::create_dir_all(&self.path)?;
+        let path = self.path_for(&state.pool.metadata.name)?;
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_yaml::to_string(state)?)?;
+        std::fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+impl Subnet {
+    fn of(pool: &AddressPool) -> Result<Self, Error> {
+        let name = &pool.metadata.name;
+        let spec = &pool.spec;
+        let invalid =
+            |what: &str, value: &str| format!("address pool {}: invalid {} {}", name, what, value);
+        let addr = |what: &str, value: &str| -> Result<Ipv4Addr, Error> {
+            Ok(value.parse().map_err(|_| invalid(what, value))?)
+        };
+
+        let (network, prefix) = spec
+            .cidr
+            .split_once('/')
+            .ok_or_else(|| invalid("cidr", &spec.cidr))?;
+        let prefix: u8 = prefix
+            .parse()
+            .ok()
+            .filter(|p| (1..=30).contains(p))
+            .ok_or_else(|| invalid("cidr", &spec.cidr))?;
+        let mask = u32::MAX << (32 - prefix);
+        let network = u32::from(addr("cidr", network)?) & mask;
+
+        // without the network and broadcast addresses
+        let mut subnet = Subnet {
+            prefix,
+            gateway: addr("gateway", &spec.gateway)?,
+            first: network + 1,
+            last: (network | !mask) - 1,
+            reserved: Vec::new(),
+        };
+        if !subnet.contains(subnet.gateway) {
+            return Err(invalid("gateway", &spec.gateway).into());
+        }
+
+        if let Some(ref range) = spec.range {
+            let (first, last) = (addr("range", &range.start)?, addr("range", &range.end)?);
+            if !subnet.contains(first) || !subnet.contains(last) || first > last {
+                let range = format!("{}-{}", range.start, range.end);
+                return Err(invalid("range", &range).into());
+            }
+            subnet.first = first.into();
+            subnet.last = last.into();
+        }
+
+        for r in &spec.reservations {
+            subnet.reserved.push(addr("reservation", r)?);
+        }
+
+        Ok(subnet)
+    }
+
+    fn contains(&self, addr: Ipv4Addr) -> bool {
+        (self.first..=self.last).contains(&u32::from(addr))
+    }
+
+    // the addresses that can be handed out, lowest first
+    fn candidates(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        (self.first..=self.last)
+            .map(Ipv4Addr::from)
+            .filter(|a| *a != self.gateway && !self.reserved.contains(a))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::api::models::{AddressPoolSpec, AddressRange, Metadata};
+
+    fn pool(cidr: &str, range: Option<(&str, &str)>, reservations: &[&str]) -> AddressPool {
+        AddressPool {
+            metadata: Metadata {
+                name: String::from("lab"),
+                labels: None,
+                uuid: None,
+                ttl: None,
+            },
+            spec: AddressPoolSpec {
+                cidr: cidr.to_string(),
+                gateway: String::from("192.168.50.1"),
+                range: range.map(|(start, end)| AddressRange {
+                    start: start.to_string(),
+                    end: end.to_string(),
+                }),
+                reservations: reservations.iter().map(|r| r.to_string()).collect(),
+                nameservers: vec![String::from("192.168.50.1")],
+            },
+        }
+    }
+
+    #[test]
+    fn subnets() {
+        let s = Subnet::of(&pool("192.168.50.7/24", None, &[])).unwrap();
+        assert_eq!(s.prefix, 24);
+        assert_eq!(Ipv4Addr::from(s.first), Ipv4Addr::new(192, 168, 50, 1));
+        assert_eq!(Ipv4Addr::from(s.last), Ipv4Addr::new(192, 168, 50, 254));
+
+        let s = Subnet::of(&pool("192.168.50.0/29", None, &["192.168.50.3"])).unwrap();
+        let addrs: Vec<String> = s.candidates().map(|a| a.to_string()).collect();
+        assert_eq!(
+            addrs,
+            [
+                "192.168.50.2",
+                "192.168.50.4",
+                "192.168.50.5",
+                "192.168.50.6"
+            ]
+        );
+
+        for bad in [
+            pool("192.168.50.0", None, &[]),
+            pool("192.168.50.0/31", None, &[]),
+            pool("0.0.0.0/0", None, &[]),
+            pool("10.0.0.0/24", None, &[]),
+            pool(
+                "192.168.50.0/24",
+                Some(("192.168.50.20", "192.168.50.10")),
+                &[],
+            ),
+            pool(
+                "192.168.50.0/24",
+                Some(("192.168.50.10", "192.168.51.10")),
+                &[],
+            ),
+            pool("192.168.50.0/24", None, &["nope"]),
+        ] {
+            assert!(Subnet::of(&bad).is_err(), "{:?}", bad.spec);
+        }
+    }
+
+    #[test]
+    fn assign_and_release() {
+        let dir = std::env::temp_dir().join(format!("bigiron-virt-ipam-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = PoolStore::new(&dir);
+
+        let range = Some(("192.168.50.10", "192.168.50.12"));
+        store
+            .save(&pool("192.168.50.0/24", range, &["192.168.50.11"]))
+            .unwrap();
+
+        let a = store.assign("lab", "vm1").unwrap();
+        assert_eq!(a.addr, "192.168.50.10/24");
+        assert_eq!(a.gateway, "192.168.50.1");
+        assert_eq!(a.nameservers, ["192.168.50.1"]);
+        assert_eq!(store.assign("lab", "vm2").unwrap().addr, "192.168.50.12/24");
+
+        let err = store.assign("lab", "vm3").unwrap_err();
+        assert_eq!(err.to_string(), "address pool lab has no free addresses");
+        assert!(store.assign("other", "vm3").is_err());
+        assert!(store.assign("../lab", "vm3").is_err());
+
+        // the range can't shrink past a handed out address
+        let err = store.save(&pool(
+            "192.168.50.0/24",
+            Some(("192.168.50.10", "192.168.50.11")),
+            &[],
+        ));
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("192.168.50.12 of vm2"));
+
+        let err = store.remove("lab").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "address pool lab is still used by vm1, vm2"
+        );
+
+        store.release("vm1").unwrap();
+        let usage = store.list().unwrap();
+        assert_eq!((usage[0].used, usage[0].free), (1, 1));
+        assert_eq!(store.assign("lab", "vm3").unwrap().addr, "192.168.50.10/24");
+
+        store.release("vm2").unwrap();
+        store.release("vm3").unwrap();
+        store.remove("lab").unwrap();
+        assert!(store.list().unwrap().is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -15,18 +15,55 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+mod archive;
 mod statestore;
 
 pub mod error;
+pub mod events;
+pub mod hypervisor;
+mod ipam;
 pub mod libvirt;
+pub mod logging;
 
 pub mod api;
 mod image;
 
+mod adopt;
+pub mod backup;
+mod bridge;
+pub mod capacity;
+pub mod cloudconfig;
+pub mod config;
+pub mod doctor;
+#[cfg(feature = "grpc")]
+pub mod grpc;
 mod hostmanager;
+mod mdev;
 mod vmstore;
 
 pub mod configdrive;
+mod dns;
+mod domain_xml;
+pub mod guest_agent;
+pub mod health;
+pub mod hooks;
+mod neighbors;
+mod netfilter;
 mod network_config;
+mod userdata;
 
 pub mod mac;
+pub mod metrics;
+pub mod orphans;
+#[cfg(feature = "otel")]
+pub mod otel;
+pub mod process;
+pub mod reconcile;
+pub mod remote;
+pub mod scheduler;
+pub mod secret_provider;
+pub mod secrets;
+pub mod selftest;
+pub mod stats;
+pub mod supervise;
+pub mod update;
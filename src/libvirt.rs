@@ -15,24 +15,671 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
-use std::io::Cursor;
-use std::path::Path;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::{Cursor, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::BytesText;
+use quick_xml::name::QName;
 use quick_xml::writer::Writer;
-use virt::{connect::Connect, domain::Domain};
+use virt::{
+    connect::Connect, domain::Domain, domain_snapshot::DomainSnapshot, storage_pool::StoragePool,
+    storage_vol::StorageVol, sys,
+};
 
+use crate::api::models::DiskBus;
+use crate::config::Scope;
+use crate::domain_xml;
 use crate::error::Error;
 
+type XmlWriter = Writer<Cursor<Vec<u8>>>;
+
+#[derive(Debug)]
+pub struct NonUtf8PathError(PathBuf);
+
+impl std::fmt::Display for NonUtf8PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "path {:?} is not valid UTF-8 and cannot be used in domain XML",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for NonUtf8PathError {}
+
+// to the daemon of the current scope
+pub(crate) fn connect() -> Result<Connect, Error> {
+    Ok(Connect::open(Scope::current().libvirt_uri())?)
+}
+
+/// virsh, connected like the bindings are, for what the bindings lack
+pub fn virsh() -> Command {
+    let mut cmd = Command::new("virsh");
+    let uri = Scope::current().libvirt_uri();
+    if !uri.is_empty() {
+        cmd.args(["--connect", uri]);
+    }
+    cmd
+}
+
+// domain XML is always UTF-8, so paths that are not can't be referenced from it
+fn xml_path(path: &Path) -> Result<&str, NonUtf8PathError> {
+    path.to_str()
+        .ok_or_else(|| NonUtf8PathError(path.to_path_buf()))
+}
+
+/// cdrom the config drive is attached as
+pub const CONFIG_DRIVE_TARGET: &str = "hdc";
+
+// PCI slots on bus 0 of the pc machine. 0 to 2 are the host bridge, PIIX
+// and video, the root disk is pinned to 3 and nics, then virtio disks, take
+// the following ones in the order they are added, so guests name them the
+// same way every boot, skipping slots extraDomainXml devices give
+// themselves. libvirt places everything else, including nics and disks past
+// the last slot, adding a pci-bridge when bus 0 is full.
+const ROOT_DISK_PCI_SLOT: u32 = 3;
+const FIRST_PCI_SLOT: u32 = 4;
+const LAST_PCI_SLOT: u32 = 0x1f;
+
+pub enum LaunchSecurity {
+    S390Pv,
+    Sev {
+        policy: u64,
+        cbitpos: u32,
+        reduced_phys_bits: u32,
+    },
+    SevSnp {
+        policy: u64,
+        cbitpos: u32,
+        reduced_phys_bits: u32,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CpuMode {
+    HostPassthrough,
+    HostModel,
+    /// a named model, e.g. "Skylake-Server"
+    Custom(String),
+}
+
+/// Guest `<cpu>` model with features to require or disable on top of it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuModel {
+    pub mode: CpuMode,
+    pub require: Vec<String>,
+    pub disable: Vec<String>,
+}
+
+impl CpuModel {
+    fn attributes(&self) -> &'static [(&'static str, &'static str)] {
+        match self.mode {
+            CpuMode::HostPassthrough => &[("mode", "host-passthrough")],
+            CpuMode::HostModel => &[("mode", "host-model")],
+            CpuMode::Custom(_) => &[("mode", "custom"), ("match", "exact")],
+        }
+    }
+
+    fn has_children(&self) -> bool {
+        matches!(self.mode, CpuMode::Custom(_))
+            || !self.require.is_empty()
+            || !self.disable.is_empty()
+    }
+
+    // <model> and <feature> children of <cpu>
+    fn write_children(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        if let CpuMode::Custom(ref model) = self.mode {
+            w.create_element("model")
+                .with_attribute(("fallback", "forbid"))
+                .write_text_content(BytesText::new(model))?;
+        }
+
+        let features = self.require.iter().map(|f| ("require", f));
+        for (policy, name) in features.chain(self.disable.iter().map(|f| ("disable", f))) {
+            w.create_element("feature")
+                .with_attribute(("policy", policy))
+                .with_attribute(attr("name", name))
+                .write_empty()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// SMBIOS `<sysinfo>` entries, written for the guest when any is set
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sysinfo {
+    pub bios_vendor: Option<String>,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub serial: Option<String>,
+    /// must match the domain UUID, libvirt takes it as such if unset
+    pub uuid: Option<String>,
+    pub asset_tag: Option<String>,
+}
+
+impl Sysinfo {
+    /// What cloud-init's OpenStack datasource looks for to pick the
+    /// metadata API
+    pub fn openstack() -> Self {
+        Self {
+            bios_vendor: Some("BigIron".to_string()),
+            manufacturer: Some("BigIron".to_string()),
+            product: Some("OpenStack Nova".to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// A host USB device to pass through, by vendor and product ID (hex
+/// without 0x) or by bus and device number
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsbHostDev {
+    Id { vendor: String, product: String },
+    Address { bus: u32, device: u32 },
+}
+
+impl UsbHostDev {
+    fn write(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        w.create_element("hostdev")
+            .with_attribute(("mode", "subsystem"))
+            .with_attribute(("type", "usb"))
+            .write_inner_content(|w| {
+                w.create_element("source").write_inner_content(|w| {
+                    match self {
+                        UsbHostDev::Id { vendor, product } => {
+                            w.create_element("vendor")
+                                .with_attribute(("id", format!("0x{}", vendor).as_str()))
+                                .write_empty()?;
+                            w.create_element("product")
+                                .with_attribute(("id", format!("0x{}", product).as_str()))
+                                .write_empty()?;
+                        }
+                        UsbHostDev::Address { bus, device } => {
+                            w.create_element("address")
+                                .with_attribute(("bus", bus.to_string().as_str()))
+                                .with_attribute(("device", device.to_string().as_str()))
+                                .write_empty()?;
+                        }
+                    }
+                    Ok(())
+                })?;
+                Ok(())
+            })?;
+
+        Ok(())
+    }
+}
+
+/// `<cputune>` of a domain: vcpu and emulator pinning, or a cpu weight and
+/// limit for vcpus sharing host cpus
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CpuTune {
+    /// host cpu of each vcpu, in vcpu order
+    pub vcpu_pins: Vec<u32>,
+    pub emulator_cpus: Vec<u32>,
+    pub shares: Option<u64>,
+    /// percent of a host cpu each vcpu may use
+    pub limit_percent: Option<u32>,
+}
+
+// scheduler period a vcpu's quota is out of
+const CPU_PERIOD_US: u64 = 100_000;
+
+impl CpuTune {
+    fn write(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        if self == &CpuTune::default() {
+            return Ok(());
+        }
+
+        w.create_element("cputune").write_inner_content(|w| {
+            if let Some(shares) = self.shares {
+                write_text(w, "shares", &shares.to_string())?;
+            }
+            if let Some(limit) = self.limit_percent {
+                write_text(w, "period", &CPU_PERIOD_US.to_string())?;
+                let quota = CPU_PERIOD_US * limit as u64 / 100;
+                write_text(w, "quota", &quota.to_string())?;
+            }
+            for (vcpu, cpu) in self.vcpu_pins.iter().enumerate() {
+                w.create_element("vcpupin")
+                    .with_attribute(("vcpu", vcpu.to_string().as_str()))
+                    .with_attribute(("cpuset", cpu.to_string().as_str()))
+                    .write_empty()?;
+            }
+            if !self.emulator_cpus.is_empty() {
+                let cpuset: Vec<_> = self.emulator_cpus.iter().map(|c| c.to_string()).collect();
+                w.create_element("emulatorpin")
+                    .with_attribute(("cpuset", cpuset.join(",").as_str()))
+                    .write_empty()?;
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+}
+
+// kernel booted in place of the image's bootloader
+#[derive(Debug, Clone, PartialEq)]
+struct DirectBoot {
+    kernel: String,
+    initrd: Option<String>,
+    cmdline: Option<String>,
+}
+
+/// A guest NUMA cell, cells get consecutive guest cpus in order
+#[derive(Debug, Clone, PartialEq)]
+pub struct NumaCell {
+    pub cpus: u32,
+    pub memory_bytes: u64,
+    /// host node to allocate the cell's memory from
+    pub host_node: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BootDevice {
+    Hd,
+    Cdrom,
+    Network,
+}
+
+impl BootDevice {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BootDevice::Hd => "hd",
+            BootDevice::Cdrom => "cdrom",
+            BootDevice::Network => "network",
+        }
+    }
+}
+
+/// Optional `<driver>` tuning for a disk, values are passed through as
+/// libvirt attribute values (e.g. cache="none", io="native")
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DiskDriver {
+    pub cache: Option<String>,
+    pub io: Option<String>,
+    pub discard: Option<String>,
+    pub detect_zeroes: Option<String>,
+    pub queues: Option<u32>,
+    pub iothread: Option<u32>,
+    pub iotune: Option<IoTune>,
+    pub seclabel: Option<SecLabel>,
+}
+
+/// `<seclabel>` for the domain or a disk source, `kind` ("dynamic",
+/// "static" or "none") only applies to the domain
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SecLabel {
+    pub model: Option<String>,
+    pub kind: Option<String>,
+    pub relabel: Option<bool>,
+    pub label: Option<String>,
+}
+
+impl SecLabel {
+    fn write(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        let mut el = w.create_element("seclabel");
+        if let Some(ref kind) = self.kind {
+            el = el.with_attribute(("type", kind.as_str()));
+        }
+        if let Some(ref model) = self.model {
+            el = el.with_attribute(("model", model.as_str()));
+        }
+        if let Some(relabel) = self.relabel {
+            el = el.with_attribute(("relabel", if relabel { "yes" } else { "no" }));
+        }
+
+        match self.label {
+            Some(ref label) => {
+                el.write_inner_content(|w| write_text(w, "label", label))?;
+            }
+            None => {
+                el.write_empty()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// `<iotune>` throttling for a disk, unset limits are left to libvirt
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoTune {
+    pub total_bytes_sec: Option<u64>,
+    pub read_bytes_sec: Option<u64>,
+    pub write_bytes_sec: Option<u64>,
+    pub total_iops_sec: Option<u64>,
+    pub read_iops_sec: Option<u64>,
+    pub write_iops_sec: Option<u64>,
+}
+
+impl DiskDriver {
+    fn is_empty(&self) -> bool {
+        self == &DiskDriver::default()
+    }
+
+    fn write_iotune(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        let iotune = match self.iotune {
+            Some(ref iotune) => iotune,
+            None => return Ok(()),
+        };
+
+        let limits = [
+            ("total_bytes_sec", iotune.total_bytes_sec),
+            ("read_bytes_sec", iotune.read_bytes_sec),
+            ("write_bytes_sec", iotune.write_bytes_sec),
+            ("total_iops_sec", iotune.total_iops_sec),
+            ("read_iops_sec", iotune.read_iops_sec),
+            ("write_iops_sec", iotune.write_iops_sec),
+        ];
+
+        if limits.iter().all(|(_, v)| v.is_none()) {
+            return Ok(());
+        }
+
+        w.create_element("iotune").write_inner_content(|w| {
+            for (name, value) in limits {
+                if let Some(v) = value {
+                    write_text(w, name, &v.to_string())?;
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    fn attributes(&self) -> Vec<(&'static str, String)> {
+        let mut attrs = Vec::new();
+
+        let opts = [
+            ("cache", &self.cache),
+            ("io", &self.io),
+            ("discard", &self.discard),
+            ("detect_zeroes", &self.detect_zeroes),
+        ];
+
+        for (name, value) in opts {
+            if let Some(v) = value {
+                attrs.push((name, v.clone()));
+            }
+        }
+
+        let counts = [("queues", self.queues), ("iothread", self.iothread)];
+
+        for (name, value) in counts {
+            if let Some(v) = value {
+                attrs.push((name, v.to_string()));
+            }
+        }
+
+        attrs
+    }
+}
+
+/// A `<disk type="network">` source such as a Ceph RBD image or iSCSI LUN
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkDisk {
+    // "rbd" or "iscsi"
+    pub protocol: String,
+    // "pool/image" for rbd, "iqn/lun" for iscsi
+    pub name: String,
+    pub hosts: Vec<(String, Option<u16>)>,
+    pub auth: Option<DiskAuth>,
+}
+
+/// Credentials for a network disk, the secret is a libvirt secret UUID
+#[derive(Debug, Clone, PartialEq)]
+pub struct DiskAuth {
+    pub username: String,
+    // "ceph" or "iscsi"
+    pub secret_type: String,
+    pub secret_uuid: String,
+}
+
+/// Optional per-interface settings
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InterfaceOptions {
+    // virtio-net multi-queue count, usually the number of vcpus
+    pub queues: Option<u32>,
+    // nwfilter to apply, e.g. clean-traffic
+    pub filter: Option<FilterRef>,
+    // let the guest change its MAC and multicast filters, macvtap only
+    pub trust_guest_rx_filters: bool,
+    // no traffic to other isolated ports on the same bridge
+    pub isolated: bool,
+    pub bandwidth: Option<Bandwidth>,
+}
+
+/// Interface rate limits, as seen from the guest: `inbound` is traffic
+/// to the guest
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Bandwidth {
+    pub inbound: Option<RateLimit>,
+    pub outbound: Option<RateLimit>,
+}
+
+/// Rates are in kilobytes per second, burst in kilobytes
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RateLimit {
+    pub average: u64,
+    pub peak: Option<u64>,
+    pub burst: Option<u64>,
+}
+
+impl RateLimit {
+    fn write(&self, w: &mut XmlWriter, direction: &str) -> quick_xml::Result<()> {
+        let mut e = w
+            .create_element(direction)
+            .with_attribute(("average", self.average.to_string().as_str()));
+        if let Some(peak) = self.peak {
+            e = e.with_attribute(("peak", peak.to_string().as_str()));
+        }
+        if let Some(burst) = self.burst {
+            e = e.with_attribute(("burst", burst.to_string().as_str()));
+        }
+
+        e.write_empty()?;
+        Ok(())
+    }
+}
+
+/// A libvirt nwfilter with its parameters, e.g. `clean-traffic` with
+/// `IP=10.0.0.5`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FilterRef {
+    pub filter: String,
+    pub parameters: Vec<(String, String)>,
+}
+
+impl InterfaceOptions {
+    fn write(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        if let Some(queues) = self.queues {
+            w.create_element("driver")
+                .with_attribute(("name", "vhost"))
+                .with_attribute(("queues", queues.to_string().as_str()))
+                .write_empty()?;
+        }
+
+        if let Some(ref filter) = self.filter {
+            let e = w
+                .create_element("filterref")
+                .with_attribute(attr("filter", &filter.filter));
+
+            if filter.parameters.is_empty() {
+                e.write_empty()?;
+            } else {
+                e.write_inner_content(|w| {
+                    for (name, value) in filter.parameters.iter() {
+                        w.create_element("parameter")
+                            .with_attribute(attr("name", name))
+                            .with_attribute(attr("value", value))
+                            .write_empty()?;
+                    }
+                    Ok(())
+                })?;
+            }
+        }
+
+        if self.isolated {
+            w.create_element("port")
+                .with_attribute(("isolated", "yes"))
+                .write_empty()?;
+        }
+
+        match self.bandwidth {
+            Some(ref bw) if bw.inbound.is_some() || bw.outbound.is_some() => {
+                w.create_element("bandwidth").write_inner_content(|w| {
+                    if let Some(ref inbound) = bw.inbound {
+                        inbound.write(w, "inbound")?;
+                    }
+                    if let Some(ref outbound) = bw.outbound {
+                        outbound.write(w, "outbound")?;
+                    }
+                    Ok(())
+                })?;
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+// attribute with a user supplied value. quick-xml only escapes markup, but
+// parsers normalize tabs and newlines in attribute values to spaces, so
+// those are written as character references to survive the round trip
+fn attr<'a>(key: &'a str, value: &str) -> Attribute<'a> {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' => escaped.push_str("&#9;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\r' => escaped.push_str("&#13;"),
+            c => escaped.push(c),
+        }
+    }
+
+    Attribute {
+        key: QName(key.as_bytes()),
+        value: Cow::Owned(escaped.into_bytes()),
+    }
+}
+
+// <bios>, <system> etc. with an entry per set value, left out when none
+// are set since libvirt rejects empty blocks
+fn write_sysinfo_block(
+    w: &mut XmlWriter,
+    name: &str,
+    entries: &[(&str, &Option<String>)],
+) -> quick_xml::Result<()> {
+    if entries.iter().all(|(_, v)| v.is_none()) {
+        return Ok(());
+    }
+
+    w.create_element(name).write_inner_content(|w| {
+        for (key, value) in entries {
+            if let Some(value) = value {
+                w.create_element("entry")
+                    .with_attribute(("name", *key))
+                    .write_text_content(BytesText::new(value))?;
+            }
+        }
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+// <address> of a device in `slot` of PCI bus 0
+fn write_pci_address<W: std::io::Write>(w: &mut Writer<W>, slot: u32) -> quick_xml::Result<()> {
+    w.create_element("address")
+        .with_attribute(("type", "pci"))
+        .with_attribute(("domain", "0x0000"))
+        .with_attribute(("bus", "0x00"))
+        .with_attribute(("slot", format!("{:#04x}", slot).as_str()))
+        .with_attribute(("function", "0x0"))
+        .write_empty()?;
+    Ok(())
+}
+
+// element holding only escaped text, e.g. <name>vm1</name>
+fn write_text(w: &mut XmlWriter, name: &str, text: &str) -> quick_xml::Result<()> {
+    w.create_element(name)
+        .write_text_content(BytesText::new(text))?;
+    Ok(())
+}
+
 pub struct DomainBuilder {
     pub name: String,
     pub cpus: u32,
     pub memory_bytes: u64,
-    pub image_file: String,
+    pub image_file: PathBuf,
+    image_is_block: bool,
+    // libvirt secret unlocking a LUKS encrypted image
+    image_secret: Option<String>,
+    uuid: Option<String>,
 
+    // next PCI slot for a nic or disk, see FIRST_PCI_SLOT
+    next_pci_slot: u32,
+    // PCI slots extraDomainXml devices are given explicitly
+    reserved_pci_slots: Vec<u32>,
+    // disk and cdrom target names taken, starting with the root disk's
+    targets: Vec<String>,
     network_xml: String,
     block_device_xml: String,
+    launch_security_xml: String,
+
+    disk_driver: DiskDriver,
+    iothreads: Option<u32>,
+    scsi_controller: bool,
+    cpu_model: Option<CpuModel>,
+    numa_cells: Vec<NumaCell>,
+    cputune: CpuTune,
+    memory_locked: bool,
 
-    metadata_api: bool,
+    direct_boot: Option<DirectBoot>,
+
+    // os level boot order, ignored when any device has its own boot order
+    boot_devices: Vec<BootDevice>,
+    disk_boot_order: Option<u32>,
+    device_boot_order_set: bool,
+
+    sysinfo: Sysinfo,
+    seclabel: Option<SecLabel>,
+
+    // virtio tablet in place of the PS/2 mouse
+    tablet: bool,
+    video_model: Option<String>,
+    sound_model: Option<String>,
+
+    // file the serial console is copied to, by virtlogd
+    serial_log: Option<String>,
+
+    // what QEMU does when the guest crashes, libvirt's default destroys it
+    on_crash: Option<String>,
+
+    usb_controller: Option<String>,
+    usb_devices: Vec<UsbHostDev>,
+    mdevs: Vec<String>,
+
+    // user supplied, see `domain_xml`
+    extra_device_xml: String,
+    xml_override: Option<String>,
 }
 
 impl DomainBuilder {
@@ -41,32 +688,70 @@ impl DomainBuilder {
             name: name.to_string(),
             cpus,
             memory_bytes,
-            image_file: image_file.as_ref().to_str().unwrap().to_string(),
+            image_file: image_file.as_ref().to_path_buf(),
+            image_is_block: false,
+            image_secret: None,
+            uuid: None,
+            next_pci_slot: FIRST_PCI_SLOT,
+            reserved_pci_slots: Vec::new(),
+            targets: vec![String::from("vda")],
             network_xml: String::new(),
             block_device_xml: String::new(),
-            metadata_api: false,
+            launch_security_xml: String::new(),
+            disk_driver: DiskDriver::default(),
+            iothreads: None,
+            scsi_controller: false,
+            cpu_model: None,
+            numa_cells: Vec::new(),
+            cputune: CpuTune::default(),
+            memory_locked: false,
+            direct_boot: None,
+            boot_devices: vec![BootDevice::Hd],
+            disk_boot_order: None,
+            device_boot_order_set: false,
+            sysinfo: Sysinfo::default(),
+            seclabel: None,
+            tablet: false,
+            video_model: None,
+            sound_model: None,
+            serial_log: None,
+            on_crash: None,
+            usb_controller: None,
+            usb_devices: Vec::new(),
+            mdevs: Vec::new(),
+            extra_device_xml: String::new(),
+            xml_override: None,
         }
     }
 
+    /// Attach the config drive ISO, always as IDE `hdc`
     pub fn add_cdrom_from_iso<P: AsRef<Path>>(&mut self, iso_file_path: P) -> Result<(), Error> {
-        let iso_path_str = iso_file_path.as_ref().to_str().unwrap();
+        self.add_cdrom(iso_file_path, CONFIG_DRIVE_TARGET, None)
+    }
+
+    /// Attach a config drive FAT image as a read only IDE disk on `hdc`,
+    /// for guests that can't read ISOs
+    pub fn add_config_disk<P: AsRef<Path>>(&mut self, image_path: P) -> Result<(), Error> {
+        let path_str = xml_path(image_path.as_ref())?;
+        self.claim_target(CONFIG_DRIVE_TARGET)?;
 
         let mut w = Writer::new(Cursor::new(Vec::new()));
         w.create_element("disk")
             .with_attribute(("type", "file"))
-            .with_attribute(("device", "cdrom"))
+            .with_attribute(("device", "disk"))
             .write_inner_content(|w| {
+                w.create_element("driver")
+                    .with_attribute(("name", "qemu"))
+                    .with_attribute(("type", "raw"))
+                    .write_empty()?;
                 w.create_element("source")
-                    .with_attribute(("file", iso_path_str))
+                    .with_attribute(attr("file", path_str))
                     .write_empty()?;
-
                 w.create_element("readonly").write_empty()?;
-
                 w.create_element("target")
-                    .with_attribute(("dev", "hdc"))
+                    .with_attribute(("dev", CONFIG_DRIVE_TARGET))
                     .with_attribute(("bus", "ide"))
                     .write_empty()?;
-
                 Ok(())
             })?;
 
@@ -76,190 +761,2498 @@ impl DomainBuilder {
         Ok(())
     }
 
-    pub fn render(&self) -> String {
-        let smbios;
-
-        if self.metadata_api {
-            smbios = r#"
-  <sysinfo type="smbios">
-    <bios>
-      <entry name="vendor">BigIron</entry>
-    </bios>
-    <system>
-      <entry name="product">OpenStack Nova</entry>
-      <entry name="manufacturer">BigIron</entry>
-    </system>
-  </sysinfo>"#;
-        } else {
-            smbios = "<sysinfo type=\"smbios\"></sysinfo>";
-        }
-
-        format!(
-            r#"
-<domain type="kvm">
-  <name>{name}</name>
-  <memory unit="bytes">{memory_bytes}</memory>
-  <currentMemory unit="bytes">{memory_bytes}</currentMemory>
-  <vcpu>{cpus}</vcpu>
-  <os>
-    <smbios mode="sysinfo"/>
-    <type arch="x86_64" machine="pc">hvm</type>
-    <boot dev="hd"/>
-  </os>
-  <features>
-    <acpi/>
-    <apic/>
-  </features>
-  <clock offset="utc"/>
-  <pm>
-    <suspend-to-mem enabled="no"/>
-    <suspend-to-disk enabled="no"/>
-  </pm>
-  <devices>
-    <disk type="file" device="disk">
-      <driver name="qemu" type="qcow2" cache="writeback"/>
-      <source file="{image_file}"/>
-      <target dev="vda" bus="virtio"/>
-    </disk>
-    {block_devices}
-    <serial type="pty">
-      <source path="/dev/pts/0"/>
-      <target type="isa-serial" port="0"/>
-    </serial>
-    <input type="keyboard" bus="ps2"/>
-    <input type="mouse" bus="ps2"/>
-    {network_xml}
-    <memballoon model="virtio"/>
-  </devices>
-  {smbios_block}
-</domain>
-        "#,
-            name = &self.name,
-            memory_bytes = self.memory_bytes,
-            cpus = self.cpus,
-            image_file = &self.image_file,
-            network_xml = self.network_xml,
-            smbios_block = smbios,
-            block_devices = self.block_device_xml,
-        )
-    }
+    /// Attach an ISO as an IDE cdrom on `target_dev`, with an optional per-device boot order
+    pub fn add_cdrom<P: AsRef<Path>>(
+        &mut self,
+        iso_file_path: P,
+        target_dev: &str,
+        boot_order: Option<u32>,
+    ) -> Result<(), Error> {
+        let iso_path_str = xml_path(iso_file_path.as_ref())?;
+        self.claim_target(target_dev)?;
 
-    pub fn build(self) -> Result<(), Error> {
-        let domxml = self.render();
+        if boot_order.is_some() {
+            self.device_boot_order_set = true;
+        }
+
+        let xml = cdrom_xml(Some(iso_path_str), target_dev, boot_order)?;
+        self.block_device_xml.push_str(&xml);
 
-        let c = Connect::open("")?;
-        let _dom = Domain::create_xml(&c, &domxml.to_string(), 0)?;
         Ok(())
     }
 
-    pub fn add_bridged_interface(&mut self, name: &str, macaddr: &str) {
-        let xml = format!(
-            r#"<interface type="bridge">
-      <source bridge="{name}"/>
-      <mac address="{macaddr}"/>
-      <model type="virtio"/>
-    </interface>"#,
-            name = name,
-            macaddr = macaddr
-        );
-
-        self.network_xml.push_str(&xml);
+    /// Set the os level boot device order, e.g. `[Cdrom, Hd]`
+    pub fn set_boot_order(&mut self, devices: &[BootDevice]) {
+        self.boot_devices = devices.to_vec();
     }
 
-    pub fn add_macvtap_interface(&mut self, name: &str, macaddr: &str) {
-        let xml = format!(
-            r#"<interface type="direct">
-      <source dev="{name}" mode="bridge"/>
-      <mac address="{macaddr}"/>
-      <model type="virtio"/>
-    </interface>"#,
-            name = name,
-            macaddr = macaddr
-        );
+    /// Boot `kernel` with `initrd` and `cmdline` rather than the image's
+    /// bootloader
+    pub fn set_direct_boot(
+        &mut self,
+        kernel: &Path,
+        initrd: Option<&Path>,
+        cmdline: Option<&str>,
+    ) -> Result<(), Error> {
+        let initrd = match initrd {
+            Some(path) => Some(xml_path(path)?.to_string()),
+            None => None,
+        };
+        self.direct_boot = Some(DirectBoot {
+            kernel: xml_path(kernel)?.to_string(),
+            initrd,
+            cmdline: cmdline.map(String::from),
+        });
+        Ok(())
+    }
 
-        self.network_xml.push_str(&xml);
+    /// Treat the primary disk as a raw block device rather than a qcow2 file
+    pub fn set_image_block_device(&mut self) {
+        self.image_is_block = true;
     }
 
-    pub fn add_file_backed_storage<P: AsRef<Path>>(&mut self, path: P, target_dev: &str) {
-        self.add_storage(path, target_dev, "file", "file")
-            .expect("error building storage XML definition");
+    /// Unlock the primary disk, a LUKS encrypted qcow2, with the passphrase
+    /// in libvirt secret `uuid`
+    pub fn set_image_encryption(&mut self, uuid: &str) {
+        self.image_secret = Some(uuid.to_string());
     }
 
-    pub fn add_block_backed_storage<P: AsRef<Path>>(&mut self, path: P, target_dev: &str) {
-        self.add_storage(path, target_dev, "block", "dev")
-            .expect("error building storage XML definition");
+    /// Set driver tuning for the primary disk, cache defaults to writeback
+    pub fn set_disk_driver(&mut self, driver: &DiskDriver) {
+        self.disk_driver = driver.clone();
     }
 
-    fn add_storage<P: AsRef<Path>>(
-        &mut self,
-        path: P,
-        target_dev: &str,
-        disk_type: &str,
-        source_type: &str,
-    ) -> Result<(), Error> {
-        let path_str = path.as_ref().to_str().unwrap();
+    fn write_disk_driver(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        let mut driver = self.disk_driver.clone();
+        if driver.cache.is_none() {
+            driver.cache = Some("writeback".to_string());
+        }
 
-        let mut w = Writer::new(Cursor::new(Vec::new()));
-        w.create_element("disk")
-            .with_attribute(("type", disk_type))
-            .with_attribute(("device", "disk"))
-            .write_inner_content(|w| {
-                w.create_element("source")
-                    .with_attribute((source_type, path_str))
-                    .write_empty()?;
+        let format = if self.image_is_block { "raw" } else { "qcow2" };
+        let attrs = driver.attributes();
 
-                w.create_element("target")
-                    .with_attribute(("dev", target_dev))
-                    .with_attribute(("bus", "virtio"))
-                    .write_empty()?;
+        w.create_element("driver")
+            .with_attribute(("name", "qemu"))
+            .with_attribute(("type", format))
+            .with_attributes(attrs.iter().map(|(k, v)| (*k, v.as_str())))
+            .write_empty()?;
 
-                Ok(())
-            })?;
+        Ok(())
+    }
 
-        let xml = String::from_utf8(w.into_inner().into_inner())?;
-        self.block_device_xml.push_str(&xml);
+    /// Allocate `count` IO threads, which disks can be pinned to with
+    /// `DiskDriver::iothread`
+    pub fn set_iothreads(&mut self, count: u32) {
+        self.iothreads = Some(count);
+    }
+
+    /// Give the domain `uuid` instead of letting libvirt pick one
+    pub fn set_uuid(&mut self, uuid: &str) {
+        self.uuid = Some(uuid.to_string());
+    }
+
+    /// SMBIOS fields for the guest, unset ones are left to QEMU
+    pub fn set_sysinfo(&mut self, sysinfo: &Sysinfo) {
+        self.sysinfo = sysinfo.clone();
+    }
+
+    /// Label the QEMU process with `seclabel` instead of the security
+    /// driver's defaults
+    pub fn set_seclabel(&mut self, seclabel: &SecLabel) {
+        self.seclabel = Some(seclabel.clone());
+    }
 
+    /// Copy everything the guest writes to its serial console to `path`,
+    /// appending across restarts. virtlogd writes and rotates the file.
+    pub fn set_serial_log<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.serial_log = Some(xml_path(path.as_ref())?.to_string());
         Ok(())
     }
-}
 
-pub fn destroy(name: &str) -> Result<(), Error> {
-    let c = Connect::open("")?;
-    let dom = Domain::lookup_by_name(&c, name);
-    if let Err(ref e) = dom {
-        if e.to_string().contains("Domain not found") {
-            return Ok(());
-        }
-        dom?;
-    } else {
-        dom.unwrap().destroy()?;
+    /// Have QEMU take `action` when the guest crashes, e.g. "restart" or
+    /// "coredump-destroy", instead of destroying the domain. Adds a pvpanic
+    /// device for the guest kernel to report its panics through.
+    pub fn set_on_crash(&mut self, action: &str) {
+        self.on_crash = Some(action.to_string());
     }
-    Ok(())
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    /// Use a virtio tablet as the pointer device instead of a PS/2 mouse
+    pub fn set_tablet(&mut self) {
+        self.tablet = true;
+    }
 
-    #[test]
-    pub fn test_build_bridged() {
-        let mut d = DomainBuilder::new("test123", 4, 8 * 1024 * 1024 * 1024, "test123.qcow2");
-        d.add_bridged_interface("obsbr0", "00:11:22:33:44:55");
-        let xml = d.render();
+    /// Add a display adapter of `model`, e.g. "virtio", "qxl", or "none"
+    /// to have explicitly none
+    pub fn set_video(&mut self, model: &str) {
+        self.video_model = Some(model.to_string());
+    }
 
-        eprintln!("{}", &xml);
+    /// Use a USB controller of `model`, e.g. "qemu-xhci", or "none" for
+    /// no USB
+    pub fn set_usb_controller(&mut self, model: &str) {
+        self.usb_controller = Some(model.to_string());
+    }
 
-        assert!(xml.contains("source bridge=\"obsbr0\""));
+    /// Pass host USB device `dev` through to the guest
+    pub fn add_usb_device(&mut self, dev: &UsbHostDev) {
+        self.usb_devices.push(dev.clone());
     }
 
-    #[test]
-    pub fn test_build_macvtap() {
-        let mut d = DomainBuilder::new("test123", 4, 8 * 1024 * 1024 * 1024, "test123.qcow2");
-        d.add_macvtap_interface("eth0", "00:11:22:33:44:55");
-        let xml = d.render();
+    /// Pass mediated device `uuid`, e.g. a vGPU slice, through to the guest
+    pub fn add_mdev(&mut self, uuid: &str) {
+        self.mdevs.push(uuid.to_string());
+    }
 
-        eprintln!("{}", &xml);
+    /// Add a sound card of `model`, e.g. "ich9"
+    pub fn set_sound(&mut self, model: &str) {
+        self.sound_model = Some(model.to_string());
+    }
+
+    /// Use `model` instead of QEMU's default CPU model
+    pub fn set_cpu_model(&mut self, model: &CpuModel) {
+        self.cpu_model = Some(model.clone());
+    }
+
+    /// Split the guest into NUMA `cells`, binding their memory to host
+    /// nodes where given
+    pub fn set_numa(&mut self, cells: &[NumaCell]) {
+        self.numa_cells = cells.to_vec();
+    }
+
+    /// Pin the vcpus and emulator to host cpus, or weigh and cap them
+    pub fn set_cputune(&mut self, cputune: &CpuTune) {
+        self.cputune = cputune.clone();
+    }
+
+    /// Keep guest memory in host RAM. libvirt lifts QEMU's memlock limit
+    /// for it.
+    pub fn set_memory_locked(&mut self) {
+        self.memory_locked = true;
+    }
+
+    /// Add a virtio-scsi controller, which storage disks named sdX are
+    /// attached to
+    pub fn add_scsi_controller(&mut self) {
+        self.scsi_controller = true;
+    }
+
+    /// Add device elements `xml` as is, after the generated devices. Nics
+    /// and disks added later keep out of the PCI slots the elements give
+    /// themselves, which must not be ones already taken.
+    pub fn add_device_xml(&mut self, xml: &str) -> Result<(), Error> {
+        for slot in domain_xml::pci_slots(xml)? {
+            if slot < self.next_pci_slot {
+                return Err(format!(
+                    "extra device XML of {} puts a device in PCI slot {:#04x}, which is taken",
+                    self.name, slot
+                )
+                .into());
+            }
+            self.reserved_pci_slots.push(slot);
+        }
+        self.extra_device_xml.push_str(xml);
+
+        Ok(())
+    }
+
+    /// Replace generated children of `<domain>` with those of `<domain>`
+    /// element `xml` when rendering
+    pub fn set_xml_override(&mut self, xml: &str) {
+        self.xml_override = Some(xml.to_string());
+    }
+
+    /// Set a per-device boot order on the primary disk
+    pub fn set_disk_boot_order(&mut self, order: u32) {
+        self.disk_boot_order = Some(order);
+        self.device_boot_order_set = true;
+    }
+
+    fn write_cpu(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        if self.cpu_model.is_none() && self.numa_cells.is_empty() {
+            return Ok(());
+        }
+
+        let mut cpu = w.create_element("cpu");
+        if let Some(ref model) = self.cpu_model {
+            cpu = cpu.with_attributes(model.attributes().iter().copied());
+        }
+
+        if !self.cpu_model.as_ref().is_some_and(|m| m.has_children()) && self.numa_cells.is_empty()
+        {
+            cpu.write_empty()?;
+            return Ok(());
+        }
+
+        cpu.write_inner_content(|w| {
+            if let Some(ref model) = self.cpu_model {
+                model.write_children(w)?;
+            }
+
+            if self.numa_cells.is_empty() {
+                return Ok(());
+            }
+
+            w.create_element("numa").write_inner_content(|w| {
+                let mut first = 0;
+                for (id, cell) in self.numa_cells.iter().enumerate() {
+                    let last = first + cell.cpus.max(1) - 1;
+                    let cpus = match first == last {
+                        true => first.to_string(),
+                        false => format!("{}-{}", first, last),
+                    };
+
+                    w.create_element("cell")
+                        .with_attribute(("id", id.to_string().as_str()))
+                        .with_attribute(("cpus", cpus.as_str()))
+                        .with_attribute(("memory", cell.memory_bytes.to_string().as_str()))
+                        .with_attribute(("unit", "bytes"))
+                        .write_empty()?;
+
+                    first = last + 1;
+                }
+                Ok(())
+            })?;
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    // binds the memory of cells with a host node, libvirt needs the
+    // domain wide nodeset to cover every cell's
+    fn write_numatune(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        let mut nodes: Vec<_> = self.numa_cells.iter().filter_map(|c| c.host_node).collect();
+        if nodes.is_empty() {
+            return Ok(());
+        }
+
+        nodes.sort();
+        nodes.dedup();
+        let nodeset: Vec<_> = nodes.iter().map(|n| n.to_string()).collect();
+
+        w.create_element("numatune").write_inner_content(|w| {
+            w.create_element("memory")
+                .with_attribute(("mode", "strict"))
+                .with_attribute(("nodeset", nodeset.join(",").as_str()))
+                .write_empty()?;
+
+            for (id, cell) in self.numa_cells.iter().enumerate() {
+                if let Some(node) = cell.host_node {
+                    w.create_element("memnode")
+                        .with_attribute(("cellid", id.to_string().as_str()))
+                        .with_attribute(("mode", "strict"))
+                        .with_attribute(("nodeset", node.to_string().as_str()))
+                        .write_empty()?;
+                }
+            }
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    fn write_os(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        w.create_element("os").write_inner_content(|w| {
+            w.create_element("smbios")
+                .with_attribute(("mode", "sysinfo"))
+                .write_empty()?;
+            w.create_element("type")
+                .with_attribute(("arch", "x86_64"))
+                .with_attribute(("machine", "pc"))
+                .write_text_content(BytesText::new("hvm"))?;
+
+            if let Some(ref boot) = self.direct_boot {
+                write_text(w, "kernel", &boot.kernel)?;
+                if let Some(ref initrd) = boot.initrd {
+                    write_text(w, "initrd", initrd)?;
+                }
+                if let Some(ref cmdline) = boot.cmdline {
+                    write_text(w, "cmdline", cmdline)?;
+                }
+            }
+
+            // libvirt rejects mixing <os><boot dev/> with per-device <boot order/>
+            if !self.device_boot_order_set {
+                for dev in &self.boot_devices {
+                    w.create_element("boot")
+                        .with_attribute(("dev", dev.as_str()))
+                        .write_empty()?;
+                }
+            }
+
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    fn write_devices(&self, w: &mut XmlWriter, image_file: &str) -> quick_xml::Result<()> {
+        let (disk_type, source_attr) = if self.image_is_block {
+            ("block", "dev")
+        } else {
+            ("file", "file")
+        };
+
+        w.create_element("disk")
+            .with_attribute(("type", disk_type))
+            .with_attribute(("device", "disk"))
+            .write_inner_content(|w| {
+                self.write_disk_driver(w)?;
+                write_disk_source(w, &[(source_attr, image_file)], &self.disk_driver)?;
+                w.create_element("target")
+                    .with_attribute(("dev", "vda"))
+                    .with_attribute(("bus", "virtio"))
+                    .write_empty()?;
+                self.disk_driver.write_iotune(w)?;
+
+                if let Some(ref uuid) = self.image_secret {
+                    w.create_element("encryption")
+                        .with_attribute(("format", "luks"))
+                        .write_inner_content(|w| {
+                            w.create_element("secret")
+                                .with_attribute(("type", "passphrase"))
+                                .with_attribute(attr("uuid", uuid))
+                                .write_empty()?;
+                            Ok(())
+                        })?;
+                }
+
+                if let Some(order) = self.disk_boot_order {
+                    w.create_element("boot")
+                        .with_attribute(("order", order.to_string().as_str()))
+                        .write_empty()?;
+                }
+
+                write_pci_address(w, ROOT_DISK_PCI_SLOT)?;
+
+                Ok(())
+            })?;
+
+        if self.scsi_controller {
+            w.create_element("controller")
+                .with_attribute(("type", "scsi"))
+                .with_attribute(("index", "0"))
+                .with_attribute(("model", "virtio-scsi"))
+                .write_empty()?;
+        }
+
+        if let Some(ref model) = self.usb_controller {
+            w.create_element("controller")
+                .with_attribute(("type", "usb"))
+                .with_attribute(("index", "0"))
+                .with_attribute(("model", model.as_str()))
+                .write_empty()?;
+        }
+
+        // already escaped, built by the add_* methods
+        w.get_mut().write_all(self.block_device_xml.as_bytes())?;
+
+        w.create_element("serial")
+            .with_attribute(("type", "pty"))
+            .write_inner_content(|w| {
+                w.create_element("source")
+                    .with_attribute(("path", "/dev/pts/0"))
+                    .write_empty()?;
+                if let Some(ref path) = self.serial_log {
+                    w.create_element("log")
+                        .with_attribute(attr("file", path))
+                        .with_attribute(("append", "on"))
+                        .write_empty()?;
+                }
+                w.create_element("target")
+                    .with_attribute(("type", "isa-serial"))
+                    .with_attribute(("port", "0"))
+                    .write_empty()?;
+                Ok(())
+            })?;
+
+        w.create_element("channel")
+            .with_attribute(("type", "unix"))
+            .write_inner_content(|w| {
+                w.create_element("target")
+                    .with_attribute(("type", "virtio"))
+                    .with_attribute(("name", "org.qemu.guest_agent.0"))
+                    .write_empty()?;
+                Ok(())
+            })?;
+
+        w.create_element("input")
+            .with_attribute(("type", "keyboard"))
+            .with_attribute(("bus", "ps2"))
+            .write_empty()?;
+        if self.tablet {
+            w.create_element("input")
+                .with_attribute(("type", "tablet"))
+                .with_attribute(("bus", "virtio"))
+                .write_empty()?;
+        } else {
+            w.create_element("input")
+                .with_attribute(("type", "mouse"))
+                .with_attribute(("bus", "ps2"))
+                .write_empty()?;
+        }
+
+        if let Some(ref model) = self.video_model {
+            w.create_element("video").write_inner_content(|w| {
+                w.create_element("model")
+                    .with_attribute(("type", model.as_str()))
+                    .write_empty()?;
+                Ok(())
+            })?;
+        }
+
+        if let Some(ref model) = self.sound_model {
+            w.create_element("sound")
+                .with_attribute(("model", model.as_str()))
+                .write_empty()?;
+        }
+
+        w.get_mut().write_all(self.network_xml.as_bytes())?;
+
+        for dev in &self.usb_devices {
+            dev.write(w)?;
+        }
+
+        for uuid in &self.mdevs {
+            w.create_element("hostdev")
+                .with_attribute(("mode", "subsystem"))
+                .with_attribute(("type", "mdev"))
+                .with_attribute(("model", "vfio-pci"))
+                .write_inner_content(|w| {
+                    w.create_element("source").write_inner_content(|w| {
+                        w.create_element("address")
+                            .with_attribute(attr("uuid", uuid))
+                            .write_empty()?;
+                        Ok(())
+                    })?;
+                    Ok(())
+                })?;
+        }
+
+        // without it QEMU never learns of a guest panic
+        if self.on_crash.is_some() {
+            w.create_element("panic")
+                .with_attribute(("model", "isa"))
+                .write_empty()?;
+        }
+
+        w.create_element("memballoon")
+            .with_attribute(("model", "virtio"))
+            .write_empty()?;
+
+        // checked to be well formed by machine validation
+        w.get_mut().write_all(self.extra_device_xml.as_bytes())?;
+
+        Ok(())
+    }
+
+    fn write_sysinfo(&self, w: &mut XmlWriter) -> quick_xml::Result<()> {
+        let sysinfo = w
+            .create_element("sysinfo")
+            .with_attribute(("type", "smbios"));
+
+        let s = &self.sysinfo;
+        if s == &Sysinfo::default() {
+            sysinfo.write_empty()?;
+            return Ok(());
+        }
+
+        let bios = [("vendor", &s.bios_vendor)];
+        let system = [
+            ("manufacturer", &s.manufacturer),
+            ("product", &s.product),
+            ("serial", &s.serial),
+            ("uuid", &s.uuid),
+        ];
+        let chassis = [("asset", &s.asset_tag)];
+
+        sysinfo.write_inner_content(|w| {
+            write_sysinfo_block(w, "bios", &bios)?;
+            write_sysinfo_block(w, "system", &system)?;
+            write_sysinfo_block(w, "chassis", &chassis)?;
+            Ok(())
+        })?;
+
+        Ok(())
+    }
+
+    pub fn render(&self) -> Result<String, Error> {
+        let image_file = xml_path(&self.image_file)?;
+
+        let mut w = Writer::new(Cursor::new(Vec::new()));
+        w.create_element("domain")
+            .with_attribute(("type", "kvm"))
+            .write_inner_content(|w| {
+                write_text(w, "name", &self.name)?;
+                if let Some(ref uuid) = self.uuid {
+                    write_text(w, "uuid", uuid)?;
+                }
+
+                for element in ["memory", "currentMemory"] {
+                    w.create_element(element)
+                        .with_attribute(("unit", "bytes"))
+                        .write_text_content(BytesText::new(&self.memory_bytes.to_string()))?;
+                }
+
+                if self.memory_locked {
+                    w.create_element("memoryBacking").write_inner_content(|w| {
+                        w.create_element("locked").write_empty()?;
+                        Ok(())
+                    })?;
+                }
+
+                write_text(w, "vcpu", &self.cpus.to_string())?;
+
+                if let Some(n) = self.iothreads {
+                    write_text(w, "iothreads", &n.to_string())?;
+                }
+
+                self.cputune.write(w)?;
+                self.write_numatune(w)?;
+
+                self.write_os(w)?;
+
+                w.create_element("features").write_inner_content(|w| {
+                    w.create_element("acpi").write_empty()?;
+                    w.create_element("apic").write_empty()?;
+                    Ok(())
+                })?;
+
+                self.write_cpu(w)?;
+
+                w.create_element("clock")
+                    .with_attribute(("offset", "utc"))
+                    .write_empty()?;
+
+                if let Some(ref action) = self.on_crash {
+                    w.create_element("on_crash")
+                        .write_text_content(BytesText::new(action))?;
+                }
+
+                w.create_element("pm").write_inner_content(|w| {
+                    for state in ["suspend-to-mem", "suspend-to-disk"] {
+                        w.create_element(state)
+                            .with_attribute(("enabled", "no"))
+                            .write_empty()?;
+                    }
+                    Ok(())
+                })?;
+
+                w.create_element("devices")
+                    .write_inner_content(|w| self.write_devices(w, image_file))?;
+
+                self.write_sysinfo(w)?;
+
+                if let Some(ref seclabel) = self.seclabel {
+                    seclabel.write(w)?;
+                }
+
+                w.get_mut().write_all(self.launch_security_xml.as_bytes())?;
+
+                Ok(())
+            })?;
+
+        let xml = String::from_utf8(w.into_inner().into_inner())?;
+        match self.xml_override {
+            Some(ref xml_override) => domain_xml::merge(&xml, xml_override),
+            None => Ok(xml),
+        }
+    }
+
+    pub fn build(self) -> Result<(), Error> {
+        create_domain(&self.render()?)
+    }
+
+    pub fn set_launch_security(&mut self, sec: &LaunchSecurity) -> Result<(), Error> {
+        let mut w = Writer::new(Cursor::new(Vec::new()));
+
+        match sec {
+            LaunchSecurity::S390Pv => {
+                w.create_element("launchSecurity")
+                    .with_attribute(("type", "s390-pv"))
+                    .write_empty()?;
+            }
+            LaunchSecurity::Sev {
+                policy,
+                cbitpos,
+                reduced_phys_bits,
+            }
+            | LaunchSecurity::SevSnp {
+                policy,
+                cbitpos,
+                reduced_phys_bits,
+            } => {
+                let sec_type = match sec {
+                    LaunchSecurity::SevSnp { .. } => "sev-snp",
+                    _ => "sev",
+                };
+
+                w.create_element("launchSecurity")
+                    .with_attribute(("type", sec_type))
+                    .write_inner_content(|w| {
+                        w.create_element("cbitpos")
+                            .write_text_content(BytesText::new(&cbitpos.to_string()))?;
+                        w.create_element("reducedPhysBits")
+                            .write_text_content(BytesText::new(&reduced_phys_bits.to_string()))?;
+                        w.create_element("policy")
+                            .write_text_content(BytesText::new(&format!("{:#06x}", policy)))?;
+                        Ok(())
+                    })?;
+            }
+        }
+
+        self.launch_security_xml = String::from_utf8(w.into_inner().into_inner())?;
+
+        Ok(())
+    }
+
+    pub fn add_bridged_interface(
+        &mut self,
+        name: &str,
+        macaddr: &str,
+        opts: &InterfaceOptions,
+    ) -> Result<(), Error> {
+        self.add_interface("bridge", &[("bridge", name)], macaddr, opts)
+    }
+
+    pub fn add_macvtap_interface(
+        &mut self,
+        name: &str,
+        macaddr: &str,
+        opts: &InterfaceOptions,
+    ) -> Result<(), Error> {
+        self.add_interface(
+            "direct",
+            &[("dev", name), ("mode", "bridge")],
+            macaddr,
+            opts,
+        )
+    }
+
+    /// Attach a nic to libvirt network `network`, e.g. the NAT network
+    /// `default`
+    pub fn add_network_interface(
+        &mut self,
+        network: &str,
+        macaddr: &str,
+        opts: &InterfaceOptions,
+    ) -> Result<(), Error> {
+        self.add_interface("network", &[("network", network)], macaddr, opts)
+    }
+
+    fn add_interface(
+        &mut self,
+        if_type: &str,
+        source_attrs: &[(&str, &str)],
+        macaddr: &str,
+        opts: &InterfaceOptions,
+    ) -> Result<(), Error> {
+        let slot = self.take_pci_slot();
+        let xml = interface_xml(if_type, source_attrs, macaddr, opts, slot)?;
+        self.network_xml.push_str(&xml);
+
+        Ok(())
+    }
+
+    pub fn add_file_backed_storage<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        target_dev: &str,
+        driver: &DiskDriver,
+    ) -> Result<(), Error> {
+        self.add_storage(path, target_dev, "file", "file", driver)
+    }
+
+    pub fn add_block_backed_storage<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        target_dev: &str,
+        driver: &DiskDriver,
+    ) -> Result<(), Error> {
+        self.add_storage(path, target_dev, "block", "dev", driver)
+    }
+
+    /// Attach a scratch disk file in image `format` (raw or qcow2)
+    pub fn add_scratch_storage<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        target_dev: &str,
+        format: &str,
+        driver: &DiskDriver,
+    ) -> Result<(), Error> {
+        let path_str = xml_path(path.as_ref())?;
+        self.claim_target(target_dev)?;
+        let slot = self.disk_pci_slot(target_dev);
+        let xml = disk_xml(
+            "file",
+            &[("file", path_str)],
+            target_dev,
+            Some(format),
+            driver,
+            slot,
+        )?;
+        self.block_device_xml.push_str(&xml);
+
+        Ok(())
+    }
+
+    /// Attach a volume from a libvirt storage pool
+    pub fn add_volume_backed_storage(
+        &mut self,
+        pool: &str,
+        volume: &str,
+        target_dev: &str,
+        driver: &DiskDriver,
+    ) -> Result<(), Error> {
+        self.add_disk(
+            "volume",
+            &[("pool", pool), ("volume", volume)],
+            target_dev,
+            driver,
+        )
+    }
+
+    /// Attach a network disk (Ceph RBD, iSCSI)
+    pub fn add_network_storage(
+        &mut self,
+        disk: &NetworkDisk,
+        target_dev: &str,
+        driver: &DiskDriver,
+    ) -> Result<(), Error> {
+        self.claim_target(target_dev)?;
+        let slot = self.disk_pci_slot(target_dev);
+        let mut w = Writer::new(Cursor::new(Vec::new()));
+        w.create_element("disk")
+            .with_attribute(("type", "network"))
+            .with_attribute(("device", "disk"))
+            .write_inner_content(|w| {
+                let attrs = driver.attributes();
+                w.create_element("driver")
+                    .with_attribute(("name", "qemu"))
+                    .with_attribute(("type", "raw"))
+                    .with_attributes(attrs.iter().map(|(k, v)| (*k, v.as_str())))
+                    .write_empty()?;
+
+                w.create_element("source")
+                    .with_attribute(("protocol", disk.protocol.as_str()))
+                    .with_attribute(attr("name", &disk.name))
+                    .write_inner_content(|w| {
+                        for (host, port) in &disk.hosts {
+                            let port = port.map(|p| p.to_string());
+                            let mut el =
+                                w.create_element("host").with_attribute(attr("name", host));
+                            if let Some(ref port) = port {
+                                el = el.with_attribute(("port", port.as_str()));
+                            }
+                            el.write_empty()?;
+                        }
+                        Ok(())
+                    })?;
+
+                if let Some(ref auth) = disk.auth {
+                    w.create_element("auth")
+                        .with_attribute(attr("username", &auth.username))
+                        .write_inner_content(|w| {
+                            w.create_element("secret")
+                                .with_attribute(("type", auth.secret_type.as_str()))
+                                .with_attribute(("uuid", auth.secret_uuid.as_str()))
+                                .write_empty()?;
+                            Ok(())
+                        })?;
+                }
+
+                write_disk_target(w, target_dev)?;
+                driver.write_iotune(w)?;
+                if let Some(slot) = slot {
+                    write_pci_address(w, slot)?;
+                }
+
+                Ok(())
+            })?;
+
+        let xml = String::from_utf8(w.into_inner().into_inner())?;
+        self.block_device_xml.push_str(&xml);
+
+        Ok(())
+    }
+
+    fn add_storage<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        target_dev: &str,
+        disk_type: &str,
+        source_type: &str,
+        driver: &DiskDriver,
+    ) -> Result<(), Error> {
+        let path_str = xml_path(path.as_ref())?;
+        self.add_disk(disk_type, &[(source_type, path_str)], target_dev, driver)
+    }
+
+    fn add_disk(
+        &mut self,
+        disk_type: &str,
+        source_attrs: &[(&str, &str)],
+        target_dev: &str,
+        driver: &DiskDriver,
+    ) -> Result<(), Error> {
+        self.claim_target(target_dev)?;
+        let slot = self.disk_pci_slot(target_dev);
+        let xml = disk_xml(disk_type, source_attrs, target_dev, None, driver, slot)?;
+        self.block_device_xml.push_str(&xml);
+
+        Ok(())
+    }
+
+    // every disk and cdrom needs a target of its own, e.g. a storage disk
+    // can't take the config drive's hdc
+    fn claim_target(&mut self, target_dev: &str) -> Result<(), Error> {
+        if self.targets.iter().any(|t| t == target_dev) {
+            return Err(format!(
+                "target {} of {} is already taken by another disk",
+                target_dev, self.name
+            )
+            .into());
+        }
+        self.targets.push(target_dev.to_string());
+        Ok(())
+    }
+
+    // the next free slot on bus 0, none once they're all taken
+    fn take_pci_slot(&mut self) -> Option<u32> {
+        while self.reserved_pci_slots.contains(&self.next_pci_slot) {
+            self.next_pci_slot += 1;
+        }
+        let slot = self.next_pci_slot;
+        if slot > LAST_PCI_SLOT {
+            return None;
+        }
+        self.next_pci_slot += 1;
+        Some(slot)
+    }
+
+    // virtio disks take a PCI slot, SCSI disks sit on the controller's
+    fn disk_pci_slot(&mut self, target_dev: &str) -> Option<u32> {
+        match scsi_lun(target_dev) {
+            Some(_) => None,
+            None => self.take_pci_slot(),
+        }
+    }
+}
+
+// IDE cdrom on `target_dev`, empty without an ISO
+fn cdrom_xml(
+    iso_path: Option<&str>,
+    target_dev: &str,
+    boot_order: Option<u32>,
+) -> Result<String, Error> {
+    let mut w = Writer::new(Cursor::new(Vec::new()));
+    w.create_element("disk")
+        .with_attribute(("type", "file"))
+        .with_attribute(("device", "cdrom"))
+        .write_inner_content(|w| {
+            if let Some(path) = iso_path {
+                w.create_element("source")
+                    .with_attribute(attr("file", path))
+                    .write_empty()?;
+            }
+
+            w.create_element("readonly").write_empty()?;
+
+            w.create_element("target")
+                .with_attribute(("dev", target_dev))
+                .with_attribute(("bus", "ide"))
+                .write_empty()?;
+
+            if let Some(order) = boot_order {
+                w.create_element("boot")
+                    .with_attribute(("order", order.to_string().as_str()))
+                    .write_empty()?;
+            }
+
+            Ok(())
+        })?;
+
+    Ok(String::from_utf8(w.into_inner().into_inner())?)
+}
+
+// an interface of `if_type` with `source_attrs` on its source element,
+// pinned to `pci_slot` if given
+fn interface_xml(
+    if_type: &str,
+    source_attrs: &[(&str, &str)],
+    macaddr: &str,
+    opts: &InterfaceOptions,
+    pci_slot: Option<u32>,
+) -> Result<String, Error> {
+    let mut w = Writer::new(Cursor::new(Vec::new()));
+    let mut e = w
+        .create_element("interface")
+        .with_attribute(("type", if_type));
+    if opts.trust_guest_rx_filters {
+        e = e.with_attribute(("trustGuestRxFilters", "yes"));
+    }
+
+    e.write_inner_content(|w| {
+        w.create_element("source")
+            .with_attributes(source_attrs.iter().map(|(k, v)| attr(k, v)))
+            .write_empty()?;
+        w.create_element("mac")
+            .with_attribute(attr("address", macaddr))
+            .write_empty()?;
+        w.create_element("model")
+            .with_attribute(("type", "virtio"))
+            .write_empty()?;
+        opts.write(w)?;
+        if let Some(slot) = pci_slot {
+            write_pci_address(w, slot)?;
+        }
+        Ok(())
+    })?;
+
+    Ok(String::from_utf8(w.into_inner().into_inner())?)
+}
+
+// `format` sets the driver type, left for libvirt to probe otherwise.
+// Hot plugged disks have no `pci_slot`, libvirt picks a free one
+fn disk_xml(
+    disk_type: &str,
+    source_attrs: &[(&str, &str)],
+    target_dev: &str,
+    format: Option<&str>,
+    driver: &DiskDriver,
+    pci_slot: Option<u32>,
+) -> Result<String, Error> {
+    let mut w = Writer::new(Cursor::new(Vec::new()));
+    w.create_element("disk")
+        .with_attribute(("type", disk_type))
+        .with_attribute(("device", "disk"))
+        .write_inner_content(|w| {
+            if format.is_some() || !driver.is_empty() {
+                let attrs = driver.attributes();
+                let mut el = w.create_element("driver").with_attribute(("name", "qemu"));
+                if let Some(format) = format {
+                    el = el.with_attribute(("type", format));
+                }
+                el.with_attributes(attrs.iter().map(|(k, v)| (*k, v.as_str())))
+                    .write_empty()?;
+            }
+
+            write_disk_source(w, source_attrs, driver)?;
+            write_disk_target(w, target_dev)?;
+            driver.write_iotune(w)?;
+            if let Some(slot) = pci_slot {
+                write_pci_address(w, slot)?;
+            }
+
+            Ok(())
+        })?;
+
+    Ok(String::from_utf8(w.into_inner().into_inner())?)
+}
+
+// the disk's <source>, holding the driver's seclabel if any
+fn write_disk_source(
+    w: &mut XmlWriter,
+    source_attrs: &[(&str, &str)],
+    driver: &DiskDriver,
+) -> quick_xml::Result<()> {
+    let el = w
+        .create_element("source")
+        .with_attributes(source_attrs.iter().map(|(k, v)| attr(k, v)));
+
+    match driver.seclabel {
+        Some(ref seclabel) => {
+            el.write_inner_content(|w| seclabel.write(w))?;
+        }
+        None => {
+            el.write_empty()?;
+        }
+    }
+
+    Ok(())
+}
+
+// disks named sdX go on the virtio-scsi controller with their LUN taken
+// from the name, everything else is virtio-blk
+fn write_disk_target<W: std::io::Write>(
+    w: &mut Writer<W>,
+    target_dev: &str,
+) -> quick_xml::Result<()> {
+    let lun = match scsi_lun(target_dev) {
+        Some(lun) => lun.to_string(),
+        None => {
+            w.create_element("target")
+                .with_attribute(("dev", target_dev))
+                .with_attribute(("bus", "virtio"))
+                .write_empty()?;
+            return Ok(());
+        }
+    };
+
+    w.create_element("target")
+        .with_attribute(("dev", target_dev))
+        .with_attribute(("bus", "scsi"))
+        .write_empty()?;
+
+    w.create_element("address")
+        .with_attribute(("type", "drive"))
+        .with_attribute(("controller", "0"))
+        .with_attribute(("bus", "0"))
+        .with_attribute(("target", "0"))
+        .with_attribute(("unit", lun.as_str()))
+        .write_empty()?;
+
+    Ok(())
+}
+
+// sda is LUN 0, sdz 25, sdaa 26
+fn scsi_lun(target_dev: &str) -> Option<u32> {
+    DiskBus::Scsi.index(target_dev).map(|i| i as u32)
+}
+
+/// Start a transient domain from XML `xml`
+pub fn create_domain(xml: &str) -> Result<(), Error> {
+    let c = connect()?;
+    let _dom = Domain::create_xml(&c, xml, 0)?;
+    Ok(())
+}
+
+/// Replace the definition persistent domain `name` next starts from with
+/// XML `xml`, transient domains have none
+pub fn redefine_domain(name: &str, xml: &str) -> Result<(), Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    if is_persistent(&dom)? {
+        Domain::define_xml(&c, xml)?;
+    }
+    Ok(())
+}
+
+// whether `dom` is defined beyond its current run, as adopted domains are
+fn is_persistent(dom: &Domain) -> Result<bool, Error> {
+    // SAFETY: `dom` holds a reference to the domain for the call
+    match unsafe { sys::virDomainIsPersistent(dom.as_ptr()) } {
+        -1 => Err(virt::error::Error::last_error().into()),
+        persistent => Ok(persistent == 1),
+    }
+}
+
+// changes reach the running domain and the definition of a persistent
+// one, which libvirt refuses for transient domains
+fn affect_flags(dom: &Domain) -> Result<u32, Error> {
+    if is_persistent(dom)? {
+        Ok(sys::VIR_DOMAIN_AFFECT_LIVE | sys::VIR_DOMAIN_AFFECT_CONFIG)
+    } else {
+        Ok(sys::VIR_DOMAIN_AFFECT_LIVE)
+    }
+}
+
+/// Start a storage pool (e.g. an NFS or iSCSI backed pool) if it isn't
+/// already active
+pub fn activate_pool(name: &str) -> Result<(), Error> {
+    let c = connect()?;
+    let pool = StoragePool::lookup_by_name(&c, name)?;
+
+    if !pool.is_active()? {
+        pool.create(0)?;
+    }
+
+    Ok(())
+}
+
+/// Check whether a volume exists in an active storage pool
+pub fn volume_exists(pool: &str, volume: &str) -> Result<bool, Error> {
+    let c = connect()?;
+    let pool = StoragePool::lookup_by_name(&c, pool)?;
+    pool.refresh(0)?;
+
+    Ok(StorageVol::lookup_by_name(&pool, volume).is_ok())
+}
+
+/// Names of all domains libvirt knows about
+pub fn list_domains() -> Result<Vec<String>, Error> {
+    let c = connect()?;
+
+    let mut names = Vec::new();
+    for dom in c.list_all_domains(0)? {
+        names.push(dom.get_name()?);
+    }
+
+    Ok(names)
+}
+
+/// Files and block devices behind the disks of domain `name`
+pub fn domain_disks(name: &str) -> Result<Vec<PathBuf>, Error> {
+    disk_sources(&domain_xml(name)?)
+}
+
+/// The XML of domain `name` as it is now
+pub fn domain_xml(name: &str) -> Result<String, Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    Ok(dom.get_xml_desc(0)?)
+}
+
+// the `file` or `dev` of each `<disk><source>` in domain XML `xml`
+fn disk_sources(xml: &str) -> Result<Vec<PathBuf>, Error> {
+    use quick_xml::events::Event;
+    use quick_xml::reader::Reader;
+
+    let mut reader = Reader::from_str(xml);
+    let mut in_disk = false;
+    let mut sources = Vec::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) if e.name() == QName(b"disk") => in_disk = true,
+            Event::End(e) if e.name() == QName(b"disk") => in_disk = false,
+            Event::Start(e) | Event::Empty(e) if in_disk && e.name() == QName(b"source") => {
+                for a in e.attributes() {
+                    let a = a?;
+                    if a.key == QName(b"file") || a.key == QName(b"dev") {
+                        sources.push(PathBuf::from(a.unescape_value()?.into_owned()));
+                    }
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(sources)
+}
+
+/// State name and whether domain `name` is active, `None` if libvirt
+/// doesn't know the domain
+pub fn domain_status(name: &str) -> Result<Option<(String, bool)>, Error> {
+    let c = connect()?;
+    match Domain::lookup_by_name(&c, name) {
+        Ok(dom) => {
+            let (state, _reason) = dom.get_state()?;
+            Ok(Some((state_name(state).to_string(), dom.is_active()?)))
+        }
+        Err(e) if e.to_string().contains("Domain not found") => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Hot plug a file or block device into running domain `name` as `target`
+pub fn attach_disk(
+    name: &str,
+    path: &Path,
+    target: &str,
+    block: bool,
+    driver: &DiskDriver,
+) -> Result<(), Error> {
+    let path_str = xml_path(path)?;
+    let xml = if block {
+        disk_xml("block", &[("dev", path_str)], target, None, driver, None)?
+    } else {
+        disk_xml("file", &[("file", path_str)], target, None, driver, None)?
+    };
+
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    dom.attach_device_flags(&xml, affect_flags(&dom)?)?;
+
+    Ok(())
+}
+
+/// Hot plug a nic into running domain `name`, as `DomainBuilder` adds
+/// them, e.g. `("bridge", &[("bridge", "br0")])` for a bridged one
+pub fn attach_interface(
+    name: &str,
+    if_type: &str,
+    source_attrs: &[(&str, &str)],
+    macaddr: &str,
+    opts: &InterfaceOptions,
+) -> Result<(), Error> {
+    let xml = interface_xml(if_type, source_attrs, macaddr, opts, None)?;
+
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    dom.attach_device_flags(&xml, affect_flags(&dom)?)?;
+
+    Ok(())
+}
+
+/// Hot unplug the nic with MAC `macaddr` from running domain `name`
+pub fn detach_interface(
+    name: &str,
+    if_type: &str,
+    source_attrs: &[(&str, &str)],
+    macaddr: &str,
+) -> Result<(), Error> {
+    let xml = interface_xml(
+        if_type,
+        source_attrs,
+        macaddr,
+        &InterfaceOptions::default(),
+        None,
+    )?;
+
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    dom.detach_device_flags(&xml, affect_flags(&dom)?)?;
+
+    Ok(())
+}
+
+/// Balloon running domain `name` to `bytes` of memory, at most what it
+/// started with
+pub fn set_memory(name: &str, bytes: u64) -> Result<(), Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    let mut flags = sys::VIR_DOMAIN_MEM_LIVE;
+    if is_persistent(&dom)? {
+        flags |= sys::VIR_DOMAIN_MEM_CONFIG;
+    }
+    dom.set_memory_flags(bytes / 1024, flags)?;
+    Ok(())
+}
+
+/// Returns a domain's state as a lowercase name, e.g. "running"
+pub fn domain_state(name: &str) -> Result<String, Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    let (state, _reason) = dom.get_state()?;
+    Ok(state_name(state).to_string())
+}
+
+fn state_name(state: sys::virDomainState) -> &'static str {
+    match state {
+        sys::VIR_DOMAIN_RUNNING => "running",
+        sys::VIR_DOMAIN_BLOCKED => "blocked",
+        sys::VIR_DOMAIN_PAUSED => "paused",
+        sys::VIR_DOMAIN_SHUTDOWN => "shutting down",
+        sys::VIR_DOMAIN_SHUTOFF => "shutoff",
+        sys::VIR_DOMAIN_CRASHED => "crashed",
+        sys::VIR_DOMAIN_PMSUSPENDED => "suspended",
+        _ => "unknown",
+    }
+}
+
+/// Whether `name` is defined and running, missing domains are not an error
+pub fn domain_is_active(name: &str) -> Result<bool, Error> {
+    let c = connect()?;
+    match Domain::lookup_by_name(&c, name) {
+        Ok(dom) => Ok(dom.is_active()?),
+        Err(e) if e.to_string().contains("Domain not found") => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Send a JSON command to the guest agent of domain `name`, returning the
+/// raw JSON response
+pub fn agent_command(name: &str, command: &str, timeout_secs: i32) -> Result<String, Error> {
+    // the virt bindings don't include libvirt-qemu
+    let output = virsh()
+        .args(["qemu-agent-command", name, command, "--timeout"])
+        .arg(timeout_secs.to_string())
+        .output()
+        .map_err(|e| format!("error executing virsh: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "guest agent command on '{}' failed: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Where libvirt should look for a domain's interface addresses
+#[derive(Debug, Clone, Copy)]
+pub enum AddressSource {
+    /// DHCP leases of libvirt managed networks
+    Lease,
+    /// the host ARP table
+    Arp,
+}
+
+/// Addresses libvirt knows for the interfaces of domain `name`, as
+/// `(mac, address)` pairs
+pub fn interface_addrs(name: &str, source: AddressSource) -> Result<Vec<(String, IpAddr)>, Error> {
+    let source = match source {
+        AddressSource::Lease => sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_LEASE,
+        AddressSource::Arp => sys::VIR_DOMAIN_INTERFACE_ADDRESSES_SRC_ARP,
+    };
+
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+
+    let mut addrs = Vec::new();
+    for iface in dom.interface_addresses(source, 0)? {
+        for addr in &iface.addrs {
+            if let Ok(ip) = addr.addr.parse() {
+                addrs.push((iface.hwaddr.to_lowercase(), ip));
+            }
+        }
+    }
+
+    Ok(addrs)
+}
+
+/// Ask domain `name` to shut down through ACPI
+pub fn shutdown(name: &str) -> Result<(), Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    dom.shutdown()?;
+    Ok(())
+}
+
+/// Swap the ISO in cdrom `target` of running domain `name` for `iso`.
+/// The drive is emptied first, so QEMU reopens the file even when the
+/// path is the same.
+pub fn change_media(name: &str, target: &str, iso: &Path) -> Result<(), Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+
+    let eject = cdrom_xml(None, target, None)?;
+    dom.update_device_flags(&eject, sys::VIR_DOMAIN_AFFECT_LIVE)?;
+
+    let insert = cdrom_xml(Some(xml_path(iso)?), target, None)?;
+    dom.update_device_flags(&insert, sys::VIR_DOMAIN_AFFECT_LIVE)?;
+
+    Ok(())
+}
+
+/// Pause domain `name`, its memory stays allocated
+pub fn suspend(name: &str) -> Result<(), Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    dom.suspend()?;
+    Ok(())
+}
+
+pub fn resume(name: &str) -> Result<(), Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    dom.resume()?;
+    Ok(())
+}
+
+/// Write the memory state of domain `name` to `path` and stop it. The
+/// domain is gone until `restore` starts it again from the file.
+pub fn save(name: &str, path: &Path) -> Result<(), Error> {
+    // the virt bindings don't include virDomainSave
+    let output = virsh()
+        .args(["save", name])
+        .arg(path)
+        .output()
+        .map_err(|e| format!("error executing virsh: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "saving '{}' failed: {}",
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Start a domain again from a file written by `save`
+pub fn restore(path: &Path) -> Result<(), Error> {
+    let c = connect()?;
+    Domain::domain_restore(&c, xml_path(path)?)?;
+    Ok(())
+}
+
+/// Tell a running domain that the disk on `target` is now `size` bytes
+pub fn block_resize(name: &str, target: &str, size: u64) -> Result<(), Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    dom.block_resize(target, size, sys::VIR_DOMAIN_BLOCK_RESIZE_BYTES)?;
+    Ok(())
+}
+
+/// Redirect writes to disk `target` of a running domain into a new qcow2
+/// `overlay`, leaving the current image unchanged until `block_commit`.
+/// Disks in `exclude` are left as they are. With `quiesce` the guest agent
+/// freezes guest filesystems while the overlay is created.
+pub fn snapshot_disk(
+    name: &str,
+    target: &str,
+    overlay: &Path,
+    exclude: &[String],
+    quiesce: bool,
+) -> Result<(), Error> {
+    let overlay = xml_path(overlay)?;
+
+    let mut w = Writer::new(Cursor::new(Vec::new()));
+    w.create_element("domainsnapshot")
+        .write_inner_content(|w| {
+            w.create_element("disks").write_inner_content(|w| {
+                w.create_element("disk")
+                    .with_attributes([("name", target), ("snapshot", "external")])
+                    .write_inner_content(|w| {
+                        w.create_element("source")
+                            .with_attribute(attr("file", overlay))
+                            .write_empty()?;
+                        Ok(())
+                    })?;
+
+                for t in exclude {
+                    w.create_element("disk")
+                        .with_attributes([("name", t.as_str()), ("snapshot", "no")])
+                        .write_empty()?;
+                }
+
+                Ok(())
+            })?;
+            Ok(())
+        })?;
+    let xml = String::from_utf8(w.into_inner().into_inner())?;
+
+    let mut flags = sys::VIR_DOMAIN_SNAPSHOT_CREATE_DISK_ONLY
+        | sys::VIR_DOMAIN_SNAPSHOT_CREATE_NO_METADATA
+        | sys::VIR_DOMAIN_SNAPSHOT_CREATE_ATOMIC;
+    if quiesce {
+        flags |= sys::VIR_DOMAIN_SNAPSHOT_CREATE_QUIESCE;
+    }
+
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    DomainSnapshot::create_xml(&dom, &xml, flags)?;
+
+    Ok(())
+}
+
+/// Merge the active overlay of disk `target` back into its backing image
+/// and switch the domain back to it
+pub fn block_commit(name: &str, target: &str) -> Result<(), Error> {
+    // the virt bindings don't expose block jobs
+    let output = virsh()
+        .args(["blockcommit", name, target, "--active", "--pivot", "--wait"])
+        .output()
+        .map_err(|e| format!("error executing virsh: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "blockcommit of {} on '{}' failed: {}",
+            target,
+            name,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Returns the host's (cpus, memory bytes) as seen by libvirt
+pub fn node_resources() -> Result<(u32, u64), Error> {
+    let c = connect()?;
+    let info = c.get_node_info()?;
+    Ok((info.cpus, info.memory * 1024))
+}
+
+/// Returns the host's free memory in bytes
+pub fn node_free_memory() -> Result<u64, Error> {
+    let c = connect()?;
+    Ok(c.get_free_memory()?)
+}
+
+/// Returns a domain's (vcpus, max memory bytes)
+pub fn domain_resources(name: &str) -> Result<(u32, u64), Error> {
+    let c = connect()?;
+    let dom = Domain::lookup_by_name(&c, name)?;
+    let info = dom.get_info()?;
+    Ok((info.nr_virt_cpu, info.max_mem * 1024))
+}
+
+/// Stats fields of one domain, named and formatted as `virsh domstats
+/// --raw` prints them
+pub type StatsFields = HashMap<String, String>;
+
+/// Raw stats fields of every running domain, e.g.
+/// `("vm1", {"cpu.time": "123", "block.0.name": "vda", ...})`
+pub fn domain_stats() -> Result<Vec<(String, StatsFields)>, Error> {
+    let c = connect()?;
+    let stats = sys::VIR_DOMAIN_STATS_CPU_TOTAL
+        | sys::VIR_DOMAIN_STATS_BALLOON
+        | sys::VIR_DOMAIN_STATS_VCPU
+        | sys::VIR_DOMAIN_STATS_INTERFACE
+        | sys::VIR_DOMAIN_STATS_BLOCK;
+
+    // called directly rather than through Connect::get_all_domain_stats,
+    // which leaves the records it returns unfreed
+    let mut records: *mut sys::virDomainStatsRecordPtr = std::ptr::null_mut();
+    // SAFETY: on success `records` holds `found` records, which are only
+    // read before libvirt frees them
+    unsafe {
+        let found = sys::virConnectGetAllDomainStats(
+            c.as_ptr(),
+            stats,
+            &mut records,
+            sys::VIR_CONNECT_GET_ALL_DOMAINS_STATS_ACTIVE,
+        );
+        if found < 0 {
+            return Err(virt::error::Error::last_error().into());
+        }
+        if records.is_null() {
+            return Ok(Vec::new());
+        }
+
+        let domains = (0..found as usize)
+            .map(|i| stats_record(*records.add(i)))
+            .collect();
+        sys::virDomainStatsRecordListFree(records);
+
+        Ok(domains)
+    }
+}
+
+// the domain name and fields of a record from virConnectGetAllDomainStats
+unsafe fn stats_record(record: sys::virDomainStatsRecordPtr) -> (String, StatsFields) {
+    let name = CStr::from_ptr(sys::virDomainGetName((*record).dom))
+        .to_string_lossy()
+        .into_owned();
+    if (*record).params.is_null() {
+        return (name, HashMap::new());
+    }
+
+    let params = std::slice::from_raw_parts((*record).params, (*record).nparams as usize);
+    let fields = params
+        .iter()
+        .map(|p| {
+            let field = CStr::from_ptr(p.field.as_ptr()).to_string_lossy();
+            (field.into_owned(), typed_param_value(p))
+        })
+        .collect();
+
+    (name, fields)
+}
+
+// a typed parameter's value the way virsh prints it
+unsafe fn typed_param_value(p: &sys::virTypedParameter) -> String {
+    match p.type_ as u32 {
+        sys::VIR_TYPED_PARAM_INT => p.value.i.to_string(),
+        sys::VIR_TYPED_PARAM_UINT => p.value.ui.to_string(),
+        sys::VIR_TYPED_PARAM_LLONG => p.value.l.to_string(),
+        sys::VIR_TYPED_PARAM_ULLONG => p.value.ul.to_string(),
+        sys::VIR_TYPED_PARAM_DOUBLE => p.value.d.to_string(),
+        sys::VIR_TYPED_PARAM_BOOLEAN if p.value.b != 0 => String::from("yes"),
+        sys::VIR_TYPED_PARAM_BOOLEAN => String::from("no"),
+        sys::VIR_TYPED_PARAM_STRING => CStr::from_ptr(p.value.s).to_string_lossy().into_owned(),
+        _ => String::new(),
+    }
+}
+
+/// Power off domain `name` and undefine it, succeeding if it doesn't exist
+pub fn destroy(name: &str) -> Result<(), Error> {
+    let c = connect()?;
+    let dom = match Domain::lookup_by_name(&c, name) {
+        Err(e) if e.to_string().contains("Domain not found") => return Ok(()),
+        dom => dom?,
+    };
+    if dom.is_active()? {
+        dom.destroy()?;
+    }
+
+    // transient domains are gone once off, adopted ones are still defined
+    if let Ok(dom) = Domain::lookup_by_name(&c, name) {
+        dom.undefine()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn test_build_bridged() {
+        let mut d = DomainBuilder::new("test123", 4, 8 * 1024 * 1024 * 1024, "test123.qcow2");
+        d.add_bridged_interface("obsbr0", "00:11:22:33:44:55", &InterfaceOptions::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("source bridge=\"obsbr0\""));
+    }
+
+    #[test]
+    pub fn test_build_macvtap() {
+        let mut d = DomainBuilder::new("test123", 4, 8 * 1024 * 1024 * 1024, "test123.qcow2");
+        d.add_macvtap_interface("eth0", "00:11:22:33:44:55", &InterfaceOptions::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
 
         assert!(xml.contains("source dev=\"eth0\" mode=\"bridge\""));
     }
+
+    #[test]
+    pub fn test_build_network() {
+        let mut d = DomainBuilder::new("test123", 4, 8 * 1024 * 1024 * 1024, "test123.qcow2");
+        d.add_network_interface("default", "00:11:22:33:44:55", &InterfaceOptions::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains("<interface type=\"network\"><source network=\"default\"/>"));
+    }
+
+    #[test]
+    pub fn test_boot_order() {
+        let mut d = DomainBuilder::new("test123", 4, 8 * 1024 * 1024 * 1024, "test123.qcow2");
+        assert!(d.render().unwrap().contains("<boot dev=\"hd\"/>"));
+
+        d.set_boot_order(&[BootDevice::Cdrom, BootDevice::Hd]);
+        let xml = d.render().unwrap();
+        assert!(xml.contains("<boot dev=\"cdrom\"/><boot dev=\"hd\"/>"));
+
+        d.add_cdrom("install.iso", "hda", Some(1)).unwrap();
+        d.set_disk_boot_order(2);
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(!xml.contains("<boot dev="));
+        assert!(xml.contains("<target dev=\"hda\" bus=\"ide\"/><boot order=\"1\"/>"));
+        assert!(xml.contains("<boot order=\"2\"/>"));
+    }
+
+    #[test]
+    pub fn test_direct_boot() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.set_direct_boot(
+            Path::new("/srv/boot/vmlinuz"),
+            Some(Path::new("/srv/boot/initrd.img")),
+            Some("root=/dev/vda1 console=ttyS0 quiet&"),
+        )
+        .unwrap();
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains(
+            "<type arch=\"x86_64\" machine=\"pc\">hvm</type><kernel>/srv/boot/vmlinuz</kernel><initrd>/srv/boot/initrd.img</initrd><cmdline>root=/dev/vda1 console=ttyS0 quiet&amp;</cmdline><boot dev=\"hd\"/>"
+        ));
+    }
+
+    #[test]
+    pub fn test_spaced_paths() {
+        let mut d = DomainBuilder::new("test&123", 4, 1024, "/var/lib/my images/a&b.qcow2");
+        d.add_cdrom_from_iso("/tmp/config drive/cidata.iso")
+            .unwrap();
+        d.add_file_backed_storage("/data/\"quoted\" disk.qcow2", "vdb", &DiskDriver::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("<name>test&amp;123</name>"));
+        assert!(xml.contains("<source file=\"/var/lib/my images/a&amp;b.qcow2\"/>"));
+        assert!(xml.contains("<source file=\"/tmp/config drive/cidata.iso\"/>"));
+        assert!(xml.contains("<source file=\"/data/&quot;quoted&quot; disk.qcow2\"/>"));
+    }
+
+    // quotes and characters with meaning in XML, path tricks, whitespace,
+    // non-ASCII and a name longer than a file name can be
+    fn hostile_strings() -> Vec<String> {
+        let mut hostile: Vec<String> = [
+            "vm\"quoted\"",
+            "vm'quoted'",
+            "vm<b>",
+            "vm&amp;",
+            "a&b<c>\"d'e;#",
+            "..",
+            "../../etc/passwd",
+            "vm/sub",
+            "vm\nsecond line",
+            "vm\ttab",
+            " spaced ",
+            "é☃",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        hostile.push("a".repeat(256));
+        hostile
+    }
+
+    // parse rendered XML, collecting the unescaped text of every element
+    // and every attribute value
+    fn parse_values(xml: &str) -> (Vec<String>, Vec<String>) {
+        use quick_xml::events::Event;
+        use quick_xml::reader::Reader;
+
+        let mut reader = Reader::from_str(xml);
+        let (mut texts, mut attrs) = (Vec::new(), Vec::new());
+
+        loop {
+            match reader.read_event().expect("rendered XML is well formed") {
+                Event::Start(e) | Event::Empty(e) => {
+                    for a in e.attributes() {
+                        let a = a.unwrap();
+                        // would be normalized to spaces by libvirt's parser
+                        assert!(!a.value.iter().any(|b| b"\t\n\r".contains(b)));
+                        attrs.push(a.unescape_value().unwrap().into_owned());
+                    }
+                }
+                Event::Text(t) => texts.push(t.unescape().unwrap().into_owned()),
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        (texts, attrs)
+    }
+
+    #[test]
+    pub fn test_hostile_names_and_paths() {
+        for hostile in hostile_strings() {
+            let name = &hostile;
+            let image = format!("/images/{}", hostile);
+            let iso = format!("/isos/{}", hostile);
+            let data = format!("/data/{}", hostile);
+            let bridge = format!("br{}", hostile);
+            let pool = format!("pool{}", hostile);
+
+            let mut d = DomainBuilder::new(name, 2, 1024, &image);
+            d.add_cdrom_from_iso(&iso).unwrap();
+            d.add_file_backed_storage(&data, "vdb", &DiskDriver::default())
+                .unwrap();
+            d.add_volume_backed_storage(&pool, &data, "vdc", &DiskDriver::default())
+                .unwrap();
+            d.add_bridged_interface(&bridge, "52:54:00:00:00:01", &InterfaceOptions::default())
+                .unwrap();
+            d.add_macvtap_interface(&bridge, "52:54:00:00:00:02", &InterfaceOptions::default())
+                .unwrap();
+
+            let xml = d.render().unwrap();
+            let (texts, attrs) = parse_values(&xml);
+
+            assert!(texts.contains(name), "name {:?} lost in {}", name, xml);
+            for value in [&image, &iso, &data, &bridge, &pool] {
+                assert!(attrs.contains(value), "{:?} lost in {}", value, xml);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_non_utf8_paths() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let bad = Path::new(OsStr::from_bytes(b"/images/bad\xff.qcow2"));
+
+        let d = DomainBuilder::new("test123", 4, 1024, bad);
+        assert!(d.render().is_err());
+
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        assert!(d.add_cdrom_from_iso(bad).is_err());
+        assert!(d
+            .add_file_backed_storage(bad, "vdb", &DiskDriver::default())
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_disk_driver() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        assert!(d
+            .render()
+            .unwrap()
+            .contains("<driver name=\"qemu\" type=\"qcow2\" cache=\"writeback\"/>"));
+
+        let tuned = DiskDriver {
+            cache: Some("none".to_string()),
+            io: Some("native".to_string()),
+            discard: Some("unmap".to_string()),
+            detect_zeroes: Some("unmap".to_string()),
+            ..Default::default()
+        };
+        d.set_disk_driver(&tuned);
+        d.add_block_backed_storage("/dev/nvme0n1p2", "vdb", &tuned)
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains(
+            "<driver name=\"qemu\" type=\"qcow2\" cache=\"none\" io=\"native\" discard=\"unmap\" detect_zeroes=\"unmap\"/>"
+        ));
+        assert!(xml.contains(
+            "<disk type=\"block\" device=\"disk\"><driver name=\"qemu\" cache=\"none\" io=\"native\""
+        ));
+    }
+
+    #[test]
+    pub fn test_iotune() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        let throttled = DiskDriver {
+            iotune: Some(IoTune {
+                total_iops_sec: Some(1000),
+                write_bytes_sec: Some(50 << 20),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        d.set_disk_driver(&throttled);
+        d.add_file_backed_storage("data.qcow2", "vdb", &throttled)
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        let iotune = "<iotune><write_bytes_sec>52428800</write_bytes_sec><total_iops_sec>1000</total_iops_sec></iotune>";
+        assert!(xml.contains(&format!("<target dev=\"vda\" bus=\"virtio\"/>{}", iotune)));
+        assert!(xml.contains(&format!("<target dev=\"vdb\" bus=\"virtio\"/>{}", iotune)));
+
+        // no limits set, no element
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.set_disk_driver(&DiskDriver {
+            iotune: Some(IoTune::default()),
+            ..Default::default()
+        });
+        assert!(!d.render().unwrap().contains("<iotune>"));
+    }
+
+    #[test]
+    pub fn test_input_video() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        let xml = d.render().unwrap();
+        assert!(xml.contains("<input type=\"mouse\" bus=\"ps2\"/>"));
+        assert!(!xml.contains("<video>"));
+
+        d.set_tablet();
+        d.set_video("virtio");
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("<input type=\"keyboard\" bus=\"ps2\"/><input type=\"tablet\" bus=\"virtio\"/><video><model type=\"virtio\"/></video>"));
+        assert!(!xml.contains("type=\"mouse\""));
+        assert!(!xml.contains("<sound"));
+
+        d.set_sound("ich9");
+        assert!(d
+            .render()
+            .unwrap()
+            .contains("</video><sound model=\"ich9\"/>"));
+    }
+
+    #[test]
+    pub fn test_usb() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        assert!(!d.render().unwrap().contains("hostdev"));
+
+        d.set_usb_controller("qemu-xhci");
+        d.add_usb_device(&UsbHostDev::Id {
+            vendor: "1050".to_string(),
+            product: "0407".to_string(),
+        });
+        d.add_usb_device(&UsbHostDev::Address { bus: 1, device: 5 });
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("<controller type=\"usb\" index=\"0\" model=\"qemu-xhci\"/>"));
+        assert!(xml.contains("<hostdev mode=\"subsystem\" type=\"usb\"><source><vendor id=\"0x1050\"/><product id=\"0x0407\"/></source></hostdev>"));
+        assert!(xml.contains("<hostdev mode=\"subsystem\" type=\"usb\"><source><address bus=\"1\" device=\"5\"/></source></hostdev>"));
+    }
+
+    #[test]
+    pub fn test_mdev() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_mdev("4b20d080-1b54-4048-85b3-a6a62d165c01");
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains("<hostdev mode=\"subsystem\" type=\"mdev\" model=\"vfio-pci\"><source><address uuid=\"4b20d080-1b54-4048-85b3-a6a62d165c01\"/></source></hostdev>"));
+    }
+
+    #[test]
+    pub fn test_user_xml() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_device_xml("<watchdog model='i6300esb' action='reset'/>")
+            .unwrap();
+        d.set_xml_override(
+            "<domain><clock offset='localtime'/><on_crash>destroy</on_crash></domain>",
+        );
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains(
+            "<memballoon model=\"virtio\"/><watchdog model='i6300esb' action='reset'/></devices>"
+        ));
+        assert!(xml.contains("<clock offset='localtime'/><pm>"));
+        assert!(xml.ends_with("<on_crash>destroy</on_crash></domain>"));
+        assert!(!xml.contains("offset=\"utc\""));
+    }
+
+    #[test]
+    pub fn test_seclabel() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        assert!(!d.render().unwrap().contains("<seclabel"));
+
+        d.set_seclabel(&SecLabel {
+            model: Some("selinux".to_string()),
+            kind: Some("static".to_string()),
+            relabel: Some(true),
+            label: Some("system_u:system_r:svirt_t:s0:c10,c20".to_string()),
+        });
+        let shared = DiskDriver {
+            seclabel: Some(SecLabel {
+                model: Some("selinux".to_string()),
+                relabel: Some(false),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        d.set_disk_driver(&shared);
+        d.add_block_backed_storage("/dev/sdb", "vdb", &shared)
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("<seclabel type=\"static\" model=\"selinux\" relabel=\"yes\"><label>system_u:system_r:svirt_t:s0:c10,c20</label></seclabel>"));
+        assert!(xml.contains(
+            "<source file=\"test123.qcow2\"><seclabel model=\"selinux\" relabel=\"no\"/></source>"
+        ));
+        assert!(xml.contains(
+            "<source dev=\"/dev/sdb\"><seclabel model=\"selinux\" relabel=\"no\"/></source>"
+        ));
+    }
+
+    #[test]
+    pub fn test_uuid() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        assert!(!d.render().unwrap().contains("<uuid>"));
+
+        d.set_uuid("2ec115d7-3a88-3ceb-bc12-0ac909a6fd87");
+        assert!(d
+            .render()
+            .unwrap()
+            .contains("<name>test123</name><uuid>2ec115d7-3a88-3ceb-bc12-0ac909a6fd87</uuid>"));
+    }
+
+    #[test]
+    pub fn test_sysinfo() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        let xml = d.render().unwrap();
+        assert!(xml.contains("<sysinfo type=\"smbios\"/>"));
+
+        d.set_sysinfo(&Sysinfo {
+            manufacturer: Some("ACME & Sons".to_string()),
+            serial: Some("BN-0042".to_string()),
+            uuid: Some("2ec115d7-3a88-3ceb-bc12-0ac909a6fd87".to_string()),
+            asset_tag: Some("A1".to_string()),
+            ..Default::default()
+        });
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("<sysinfo type=\"smbios\"><system><entry name=\"manufacturer\">ACME &amp; Sons</entry><entry name=\"serial\">BN-0042</entry><entry name=\"uuid\">2ec115d7-3a88-3ceb-bc12-0ac909a6fd87</entry></system><chassis><entry name=\"asset\">A1</entry></chassis></sysinfo>"));
+
+        d.set_sysinfo(&Sysinfo::openstack());
+        let xml = d.render().unwrap();
+        assert!(xml.contains("<bios><entry name=\"vendor\">BigIron</entry></bios>"));
+        assert!(xml.contains("<entry name=\"product\">OpenStack Nova</entry>"));
+    }
+
+    #[test]
+    pub fn test_serial_log() {
+        let mut d = DomainBuilder::new("test123", 2, 2 << 30, "/tmp/test123.qcow2");
+        assert!(!d.render().unwrap().contains("<log "));
+
+        d.set_serial_log("/var/lib/bigiron/test 123/console.log")
+            .unwrap();
+        assert!(d.render().unwrap().contains(
+            "<serial type=\"pty\"><source path=\"/dev/pts/0\"/>\
+             <log file=\"/var/lib/bigiron/test 123/console.log\" append=\"on\"/>\
+             <target type=\"isa-serial\" port=\"0\"/></serial>"
+        ));
+    }
+
+    #[test]
+    pub fn test_on_crash() {
+        let mut d = DomainBuilder::new("test123", 2, 2 << 30, "/tmp/test123.qcow2");
+        let xml = d.render().unwrap();
+        assert!(!xml.contains("<on_crash>"));
+        assert!(!xml.contains("<panic "));
+
+        d.set_on_crash("restart");
+        let xml = d.render().unwrap();
+        assert!(xml.contains("<clock offset=\"utc\"/><on_crash>restart</on_crash><pm>"));
+        assert!(xml.contains("<panic model=\"isa\"/><memballoon model=\"virtio\"/>"));
+    }
+
+    #[test]
+    pub fn test_guest_agent_channel() {
+        let d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        assert!(d
+            .render()
+            .unwrap()
+            .contains("<target type=\"virtio\" name=\"org.qemu.guest_agent.0\"/>"));
+    }
+
+    #[test]
+    pub fn test_block_root_disk() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "/dev/vg0/bigiron-test123");
+        d.set_image_block_device();
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains("<disk type=\"block\" device=\"disk\">"));
+        assert!(xml.contains("<driver name=\"qemu\" type=\"raw\" cache=\"writeback\"/>"));
+        assert!(xml.contains("<source dev=\"/dev/vg0/bigiron-test123\"/>"));
+    }
+
+    #[test]
+    pub fn test_image_encryption() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.set_image_encryption("6f1e2c52-0b1d-4a8e-9c4f-2d7a9b3e5f10");
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains(
+            "<target dev=\"vda\" bus=\"virtio\"/><encryption format=\"luks\"><secret type=\"passphrase\" uuid=\"6f1e2c52-0b1d-4a8e-9c4f-2d7a9b3e5f10\"/></encryption>"
+        ));
+    }
+
+    #[test]
+    pub fn test_volume_storage() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_volume_backed_storage("nfspool", "data01.qcow2", "vdb", &DiskDriver::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains(
+            "<disk type=\"volume\" device=\"disk\"><source pool=\"nfspool\" volume=\"data01.qcow2\"/>"
+        ));
+    }
+
+    #[test]
+    pub fn test_scratch_storage() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_scratch_storage(
+            "/dev/shm/test123/vdb.raw",
+            "vdb",
+            "raw",
+            &DiskDriver::default(),
+        )
+        .unwrap();
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains(
+            "<disk type=\"file\" device=\"disk\"><driver name=\"qemu\" type=\"raw\"/><source file=\"/dev/shm/test123/vdb.raw\"/><target dev=\"vdb\" bus=\"virtio\"/>"
+        ));
+    }
+
+    #[test]
+    pub fn test_scsi_storage() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_scsi_controller();
+        d.add_file_backed_storage("/data/a.qcow2", "sda", &DiskDriver::default())
+            .unwrap();
+        d.add_block_backed_storage("/dev/sdq", "sdaa", &DiskDriver::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains("<controller type=\"scsi\" index=\"0\" model=\"virtio-scsi\"/>"));
+        assert!(xml.contains(
+            "<target dev=\"sda\" bus=\"scsi\"/><address type=\"drive\" controller=\"0\" bus=\"0\" target=\"0\" unit=\"0\"/>"
+        ));
+        assert!(xml.contains("<target dev=\"sdaa\" bus=\"scsi\"/><address type=\"drive\" controller=\"0\" bus=\"0\" target=\"0\" unit=\"26\"/>"));
+        assert!(xml.contains("<target dev=\"vda\" bus=\"virtio\"/>"));
+    }
+
+    #[test]
+    pub fn test_pci_addresses() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        let opts = InterfaceOptions::default();
+        d.add_bridged_interface("br0", "52:54:00:00:00:01", &opts)
+            .unwrap();
+        d.add_network_interface("default", "52:54:00:00:00:02", &opts)
+            .unwrap();
+        d.add_file_backed_storage("/data/a.qcow2", "vdb", &DiskDriver::default())
+            .unwrap();
+        d.add_file_backed_storage("/data/b.qcow2", "sda", &DiskDriver::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        let slots: Vec<&str> = xml
+            .split("<address type=\"pci\" domain=\"0x0000\" bus=\"0x00\" slot=\"")
+            .skip(1)
+            .map(|s| &s[..4])
+            .collect();
+        // the root disk's, then vdb's, then the nics' which were added first
+        assert_eq!(slots, ["0x03", "0x06", "0x04", "0x05"]);
+        assert!(xml.contains("<mac address=\"52:54:00:00:00:02\"/><model type=\"virtio\"/><address type=\"pci\" domain=\"0x0000\" bus=\"0x00\" slot=\"0x05\" function=\"0x0\"/></interface>"));
+
+        for i in 0..LAST_PCI_SLOT - FIRST_PCI_SLOT - 2 {
+            d.add_network_interface("default", "52:54:00:00:01:00", &opts)
+                .unwrap_or_else(|e| panic!("nic {}: {}", i, e));
+        }
+        // bus 0 is full, libvirt places it
+        d.add_file_backed_storage("/data/c.qcow2", "vdc", &DiskDriver::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+        assert!(xml.contains("<target dev=\"vdc\" bus=\"virtio\"/></disk>"));
+    }
+
+    #[test]
+    pub fn test_extra_device_pci_slots() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_device_xml("<watchdog model='i6300esb'><address type='pci' slot='0x04'/></watchdog>")
+            .unwrap();
+        d.add_network_interface("default", "52:54:00:00:00:01", &InterfaceOptions::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+        assert!(xml.contains("<model type=\"virtio\"/><address type=\"pci\" domain=\"0x0000\" bus=\"0x00\" slot=\"0x05\""));
+
+        let err = d
+            .add_device_xml("<rng model='virtio'><address type='pci' slot='5'/></rng>")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "extra device XML of test123 puts a device in PCI slot 0x05, which is taken"
+        );
+        assert!(d
+            .add_device_xml("<rng model='virtio'><address type='pci' slot='3'/></rng>")
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_config_disk() {
+        let mut d = DomainBuilder::new("test123", 2, 2 << 30, "/tmp/test123.qcow2");
+        d.add_config_disk("/var/lib/bigiron/test123/cidata.iso")
+            .unwrap();
+        let xml = d.render().unwrap();
+        assert!(xml.contains(
+            "<disk type=\"file\" device=\"disk\"><driver name=\"qemu\" type=\"raw\"/>\
+             <source file=\"/var/lib/bigiron/test123/cidata.iso\"/><readonly/>\
+             <target dev=\"hdc\" bus=\"ide\"/></disk>"
+        ));
+        assert!(!xml.contains("device=\"cdrom\""));
+        assert!(d
+            .add_cdrom("install.iso", CONFIG_DRIVE_TARGET, None)
+            .is_err());
+    }
+
+    #[test]
+    pub fn test_target_collisions() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_cdrom_from_iso("cidata.iso").unwrap();
+        d.add_file_backed_storage("/data/a.qcow2", "vdb", &DiskDriver::default())
+            .unwrap();
+
+        let err = d
+            .add_cdrom("install.iso", CONFIG_DRIVE_TARGET, None)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "target hdc of test123 is already taken by another disk"
+        );
+        assert!(d
+            .add_block_backed_storage("/dev/sdb", "vdb", &DiskDriver::default())
+            .is_err());
+        assert!(d
+            .add_volume_backed_storage("pool", "vol", "vda", &DiskDriver::default())
+            .is_err());
+        d.add_cdrom("install.iso", "hda", None).unwrap();
+    }
+
+    #[test]
+    pub fn test_iothreads_and_queues() {
+        let mut d = DomainBuilder::new("test123", 8, 1024, "test123.qcow2");
+        d.set_iothreads(2);
+        d.set_disk_driver(&DiskDriver {
+            queues: Some(8),
+            iothread: Some(1),
+            ..Default::default()
+        });
+        d.add_bridged_interface(
+            "obsbr0",
+            "00:11:22:33:44:55",
+            &InterfaceOptions {
+                queues: Some(8),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("<iothreads>2</iothreads>"));
+        assert!(xml.contains("cache=\"writeback\" queues=\"8\" iothread=\"1\"/>"));
+        assert!(xml.contains("<driver name=\"vhost\" queues=\"8\"/>"));
+    }
+
+    #[test]
+    pub fn test_bandwidth() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_bridged_interface(
+            "obsbr0",
+            "00:11:22:33:44:55",
+            &InterfaceOptions {
+                bandwidth: Some(Bandwidth {
+                    inbound: Some(RateLimit {
+                        average: 1000,
+                        peak: Some(5000),
+                        burst: Some(1024),
+                    }),
+                    outbound: Some(RateLimit {
+                        average: 128,
+                        ..Default::default()
+                    }),
+                }),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("<bandwidth><inbound average=\"1000\" peak=\"5000\" burst=\"1024\"/><outbound average=\"128\"/></bandwidth>"));
+
+        // no limits, no element
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_bridged_interface(
+            "obsbr0",
+            "00:11:22:33:44:55",
+            &InterfaceOptions {
+                bandwidth: Some(Bandwidth::default()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(!d.render().unwrap().contains("<bandwidth"));
+    }
+
+    #[test]
+    pub fn test_interface_filtering() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        d.add_bridged_interface(
+            "obsbr0",
+            "00:11:22:33:44:55",
+            &InterfaceOptions {
+                filter: Some(FilterRef {
+                    filter: "clean-traffic".to_string(),
+                    parameters: vec![("IP".to_string(), "10.0.0.5".to_string())],
+                }),
+                isolated: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        d.add_macvtap_interface(
+            "eth0",
+            "00:11:22:33:44:66",
+            &InterfaceOptions {
+                filter: Some(FilterRef {
+                    filter: "no-mac-spoofing".to_string(),
+                    parameters: Vec::new(),
+                }),
+                trust_guest_rx_filters: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("<filterref filter=\"clean-traffic\"><parameter name=\"IP\" value=\"10.0.0.5\"/></filterref><port isolated=\"yes\"/>"));
+        assert!(xml.contains("<interface type=\"direct\" trustGuestRxFilters=\"yes\">"));
+        assert!(xml.contains("<filterref filter=\"no-mac-spoofing\"/>"));
+        assert!(!xml.contains("<interface type=\"bridge\" trustGuestRxFilters"));
+    }
+
+    #[test]
+    pub fn test_network_storage() {
+        let mut d = DomainBuilder::new("test123", 4, 1024, "test123.qcow2");
+        let rbd = NetworkDisk {
+            protocol: "rbd".to_string(),
+            name: "vms/data01".to_string(),
+            hosts: vec![("mon1".to_string(), Some(6789)), ("mon2".to_string(), None)],
+            auth: Some(DiskAuth {
+                username: "libvirt".to_string(),
+                secret_type: "ceph".to_string(),
+                secret_uuid: "2ec115d7-3a88-3ceb-bc12-0ac909a6fd87".to_string(),
+            }),
+        };
+        d.add_network_storage(&rbd, "vdb", &DiskDriver::default())
+            .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains(
+            "<source protocol=\"rbd\" name=\"vms/data01\"><host name=\"mon1\" port=\"6789\"/><host name=\"mon2\"/></source>"
+        ));
+        assert!(xml.contains(
+            "<auth username=\"libvirt\"><secret type=\"ceph\" uuid=\"2ec115d7-3a88-3ceb-bc12-0ac909a6fd87\"/></auth>"
+        ));
+    }
+
+    #[test]
+    pub fn test_cpu_model() {
+        let mut d = DomainBuilder::new("vm1", 2, 1024, "/tmp/vm1.qcow2");
+        assert!(!d.render().unwrap().contains("<cpu"));
+
+        d.set_cpu_model(&CpuModel {
+            mode: CpuMode::HostPassthrough,
+            require: Vec::new(),
+            disable: Vec::new(),
+        });
+        assert!(d
+            .render()
+            .unwrap()
+            .contains(r#"<cpu mode="host-passthrough"/>"#));
+
+        d.set_cpu_model(&CpuModel {
+            mode: CpuMode::Custom("Skylake-Server".to_string()),
+            require: vec!["avx512f".to_string()],
+            disable: vec!["hle".to_string()],
+        });
+        assert!(d.render().unwrap().contains(concat!(
+            r#"<cpu mode="custom" match="exact">"#,
+            r#"<model fallback="forbid">Skylake-Server</model>"#,
+            r#"<feature policy="require" name="avx512f"/>"#,
+            r#"<feature policy="disable" name="hle"/>"#,
+            "</cpu>"
+        )));
+    }
+
+    #[test]
+    pub fn test_numa() {
+        let mut d = DomainBuilder::new("vm1", 6, 3 << 30, "/tmp/vm1.qcow2");
+        d.set_numa(&[
+            NumaCell {
+                cpus: 4,
+                memory_bytes: 2 << 30,
+                host_node: Some(1),
+            },
+            NumaCell {
+                cpus: 1,
+                memory_bytes: 1 << 30,
+                host_node: None,
+            },
+            NumaCell {
+                cpus: 1,
+                memory_bytes: 0,
+                host_node: Some(0),
+            },
+        ]);
+
+        let xml = d.render().unwrap();
+        assert!(xml.contains(concat!(
+            "<cpu><numa>",
+            r#"<cell id="0" cpus="0-3" memory="2147483648" unit="bytes"/>"#,
+            r#"<cell id="1" cpus="4" memory="1073741824" unit="bytes"/>"#,
+            r#"<cell id="2" cpus="5" memory="0" unit="bytes"/>"#,
+            "</numa></cpu>"
+        )));
+        assert!(xml.contains(concat!(
+            "<numatune>",
+            r#"<memory mode="strict" nodeset="0,1"/>"#,
+            r#"<memnode cellid="0" mode="strict" nodeset="1"/>"#,
+            r#"<memnode cellid="2" mode="strict" nodeset="0"/>"#,
+            "</numatune>"
+        )));
+
+        // shares <cpu> with the model
+        d.set_cpu_model(&CpuModel {
+            mode: CpuMode::HostPassthrough,
+            require: Vec::new(),
+            disable: Vec::new(),
+        });
+        assert!(d
+            .render()
+            .unwrap()
+            .contains(r#"<cpu mode="host-passthrough"><numa><cell id="0""#));
+    }
+
+    #[test]
+    pub fn test_placement() {
+        let mut d = DomainBuilder::new("test123", 2, 1024, "test123.qcow2");
+        d.set_cputune(&CpuTune {
+            vcpu_pins: vec![4, 5],
+            emulator_cpus: vec![0, 1],
+            ..Default::default()
+        });
+        d.set_memory_locked();
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains("<memoryBacking><locked/></memoryBacking>"));
+        assert!(xml.contains(
+            "<cputune><vcpupin vcpu=\"0\" cpuset=\"4\"/><vcpupin vcpu=\"1\" cpuset=\"5\"/><emulatorpin cpuset=\"0,1\"/></cputune>"
+        ));
+
+        let mut d = DomainBuilder::new("test123", 2, 1024, "test123.qcow2");
+        d.set_cputune(&CpuTune {
+            shares: Some(256),
+            limit_percent: Some(50),
+            ..Default::default()
+        });
+        let xml = d.render().unwrap();
+
+        assert!(xml.contains(
+            "<cputune><shares>256</shares><period>100000</period><quota>50000</quota></cputune>"
+        ));
+        assert!(!xml.contains("memoryBacking"));
+
+        let xml = DomainBuilder::new("test123", 2, 1024, "test123.qcow2")
+            .render()
+            .unwrap();
+        assert!(!xml.contains("cputune"));
+    }
+
+    #[test]
+    pub fn test_launch_security() {
+        let mut d = DomainBuilder::new("test123", 4, 8 * 1024 * 1024 * 1024, "test123.qcow2");
+        d.set_launch_security(&LaunchSecurity::Sev {
+            policy: 0x0003,
+            cbitpos: 47,
+            reduced_phys_bits: 1,
+        })
+        .unwrap();
+        let xml = d.render().unwrap();
+
+        eprintln!("{}", &xml);
+
+        assert!(xml.contains("<launchSecurity type=\"sev\"><cbitpos>47</cbitpos>"));
+        assert!(xml.contains("<policy>0x0003</policy>"));
+
+        d.set_launch_security(&LaunchSecurity::S390Pv).unwrap();
+        assert!(d
+            .render()
+            .unwrap()
+            .contains("<launchSecurity type=\"s390-pv\"/>"));
+    }
+
+    #[test]
+    pub fn test_disk_sources() {
+        let mut d = DomainBuilder::new(
+            "test123",
+            4,
+            1024,
+            "/var/lib/bigiron/test123/instance.qcow2",
+        );
+        d.add_block_backed_storage("/dev/vg0/data", "vdb", &DiskDriver::default())
+            .unwrap();
+        d.add_cdrom_from_iso("/var/lib/bigiron/test123/cidata.iso")
+            .unwrap();
+        d.add_bridged_interface("br0", "00:16:3e:00:00:01", &InterfaceOptions::default())
+            .unwrap();
+
+        assert_eq!(
+            disk_sources(&d.render().unwrap()).unwrap(),
+            [
+                PathBuf::from("/var/lib/bigiron/test123/instance.qcow2"),
+                PathBuf::from("/dev/vg0/data"),
+                PathBuf::from("/var/lib/bigiron/test123/cidata.iso"),
+            ]
+        );
+    }
 }
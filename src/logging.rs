@@ -0,0 +1,148 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Log output setup.
+//!
+//! Text logs are the usual `tracing_subscriber` lines. JSON logs are one
+//! object per line in tracing-subscriber's JSON format, plus an event when
+//! each span closes with how long it took (`time.busy`, `time.idle`), for
+//! collecting in a log aggregator.
+//!
+//! Built with the `otel` feature the same spans can also be exported to an
+//! OpenTelemetry collector, see `otel`.
+
+use std::str::FromStr;
+
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+#[cfg(feature = "otel")]
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}', expected text or json", s)),
+        }
+    }
+}
+
+/// Install the global subscriber, logging at `level` and above
+pub fn init(format: LogFormat, level: LevelFilter) {
+    let builder = tracing_subscriber::fmt().with_max_level(level);
+
+    match format {
+        LogFormat::Text => install(builder.finish()),
+        LogFormat::Json => install(builder.json().with_span_events(FmtSpan::CLOSE).finish()),
+    }
+}
+
+/// Send off what's still buffered, before the process exits
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    crate::otel::shutdown();
+}
+
+// make `subscriber` the global default, exporting its spans if configured
+fn install<S>(subscriber: S)
+where
+    S: Subscriber + for<'a> LookupSpan<'a> + Send + Sync + 'static,
+{
+    #[cfg(feature = "otel")]
+    let subscriber = subscriber.with(crate::otel::layer());
+
+    subscriber.init();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use serde_json::Value;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_lines() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_span_events(FmtSpan::CLOSE)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("create_machine", machine = "vm1");
+            let _entered = span.enter();
+            span.record("machine", "vm1");
+            tracing::info!(hash = "abc", "Importing image");
+        });
+
+        let out = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<Value> = out
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+
+        assert!(lines[0]["timestamp"].is_string());
+        assert_eq!(lines[0]["level"], "INFO");
+        assert_eq!(lines[0]["fields"]["message"], "Importing image");
+        assert_eq!(lines[0]["fields"]["hash"], "abc");
+        assert_eq!(lines[0]["span"]["name"], "create_machine");
+        assert_eq!(lines[0]["span"]["machine"], "vm1");
+
+        // the close event carries the span's timing
+        assert_eq!(lines[1]["fields"]["message"], "close");
+        assert!(lines[1]["fields"]["time.busy"].is_string());
+        assert_eq!(lines[1]["span"]["machine"], "vm1");
+    }
+
+    #[test]
+    fn parse_format() {
+        assert_eq!("json".parse(), Ok(LogFormat::Json));
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+}
@@ -15,8 +15,15 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::net::Ipv6Addr;
+
 use hex;
 use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// fe80::/64, the prefix of link-local addresses
+pub const LINK_LOCAL_PREFIX: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0);
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub struct Mac {
@@ -40,28 +47,100 @@ impl Mac {
         Self { octets: mac }
     }
 
-    /// Derives and returns an IPv6 Stateless Address Autoconfiguration address
-    /// from this Mac address
-    pub fn to_ipv6_slaac_addr(&self) -> String {
-        let octets = &self.octets;
+    /// Derive a MAC address for nic `index` of machine `name`, the same
+    /// inputs always give the same address in the same range as `gen`
+    pub fn from_seed(name: &str, index: usize) -> Self {
+        let mut h = Sha256::new();
+        h.update(name.as_bytes());
+        h.update([0]);
+        h.update((index as u64).to_be_bytes());
+        let digest = h.finalize();
 
-        // flip 7th bit of mac
-        let flipped = octets[0] | 0b0000_00010;
-        let addr = [
-            (flipped, octets[1]),
-            (octets[2], 0xff),
-            (0xfe, octets[3]),
-            (octets[4], octets[5]),
-        ];
+        let mac: [u8; 6] = [0x00, 0x16, 0x3e, digest[0] & 0x7f, digest[1], digest[2]];
+
+        Self { octets: mac }
+    }
+
+    pub fn octets(&self) -> [u8; 6] {
+        self.octets
+    }
+
+    /// Organizationally unique identifier, the first three octets
+    pub fn oui(&self) -> [u8; 3] {
+        [self.octets[0], self.octets[1], self.octets[2]]
+    }
 
-        let s = addr
-            .into_iter()
-            .map(|s| hex::encode([s.0, s.1]))
-            .collect::<Vec<_>>()
-            .join(":");
-        let s = "fe80::".to_owned() + s.trim_start_matches("0");
+    /// The last three octets, assigned by the OUI's owner
+    pub fn nic_specific(&self) -> [u8; 3] {
+        [self.octets[3], self.octets[4], self.octets[5]]
+    }
+
+    pub fn is_unicast(&self) -> bool {
+        self.octets[0] & 0x01 == 0
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        !self.is_unicast()
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.octets == [0xff; 6]
+    }
+
+    /// Set by whoever assigned it rather than burned in by the vendor
+    pub fn is_locally_administered(&self) -> bool {
+        self.octets[0] & 0x02 != 0
+    }
 
-        s
+    /// EUI-64 form of this EUI-48, with ff:fe inserted after the OUI
+    pub fn to_eui64(&self) -> [u8; 8] {
+        let o = &self.octets;
+        [o[0], o[1], o[2], 0xff, 0xfe, o[3], o[4], o[5]]
+    }
+
+    /// IPv6 interface identifier, the modified EUI-64 with the
+    /// universal/local bit inverted (RFC 4291)
+    pub fn to_interface_id(&self) -> [u8; 8] {
+        let mut id = self.to_eui64();
+        id[0] ^= 0x02;
+        id
+    }
+
+    /// IPv6 Stateless Address Autoconfiguration address of this MAC in
+    /// the /64 of `prefix`, e.g. `LINK_LOCAL_PREFIX` or a router advertised
+    /// global prefix. Only the top 64 bits of `prefix` are used.
+    pub fn to_ipv6_slaac_addr(&self, prefix: Ipv6Addr) -> Ipv6Addr {
+        let mut octets = prefix.octets();
+        octets[8..].copy_from_slice(&self.to_interface_id());
+        Ipv6Addr::from(octets)
+    }
+
+    pub fn to_ipv6_link_local(&self) -> Ipv6Addr {
+        self.to_ipv6_slaac_addr(LINK_LOCAL_PREFIX)
+    }
+
+    /// MAC an EUI-64 based SLAAC address was derived from, `None` for
+    /// addresses with other interface identifiers (e.g. privacy addresses)
+    pub fn from_ipv6_slaac_addr(addr: &Ipv6Addr) -> Option<Self> {
+        let id = &addr.octets()[8..];
+        if id[3] != 0xff || id[4] != 0xfe {
+            return None;
+        }
+
+        Some(Self {
+            octets: [id[0] ^ 0x02, id[1], id[2], id[5], id[6], id[7]],
+        })
+    }
+
+    /// Whether `addr` is the SLAAC address of this MAC in any prefix
+    pub fn is_slaac_addr(&self, addr: &Ipv6Addr) -> bool {
+        addr.octets()[8..] == self.to_interface_id()
+    }
+}
+
+impl From<[u8; 6]> for Mac {
+    fn from(octets: [u8; 6]) -> Self {
+        Self { octets }
     }
 }
 
@@ -74,22 +153,53 @@ impl std::fmt::Display for Mac {
 #[derive(Debug, PartialEq, Eq)]
 pub struct MacParseError;
 
+impl std::fmt::Display for MacParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid MAC address, expected six hex octets like 00:16:3e:12:34:56"
+        )
+    }
+}
+
+impl std::error::Error for MacParseError {}
+
 impl std::str::FromStr for Mac {
     type Err = MacParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let v: Vec<_> = s
-            .split(":")
-            .map(|o| hex::decode(o))
-            .flatten()
-            .flatten()
-            .collect();
-        let octets: [u8; 6] = v.try_into().map_err(|_| MacParseError)?;
+        let mut octets = [0u8; 6];
+        let mut parts = s.split(':');
+
+        for octet in octets.iter_mut() {
+            let part = parts.next().ok_or(MacParseError)?;
+            if part.len() != 2 {
+                return Err(MacParseError);
+            }
+            *octet = u8::from_str_radix(part, 16).map_err(|_| MacParseError)?;
+        }
+
+        if parts.next().is_some() {
+            return Err(MacParseError);
+        }
 
         Ok(Mac { octets })
     }
 }
 
+impl Serialize for Mac {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Mac {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -101,6 +211,20 @@ mod test {
         assert!(mac.starts_with("00:16:3e"));
     }
 
+    #[test]
+    fn seeded_mac() {
+        let mac = Mac::from_seed("vm1", 0);
+        assert!(mac.to_string().starts_with("00:16:3e"));
+        assert!(mac.octets[3] < 0x80);
+
+        assert_eq!(mac, Mac::from_seed("vm1", 0));
+        assert_ne!(mac, Mac::from_seed("vm1", 1));
+        assert_ne!(mac, Mac::from_seed("vm2", 0));
+
+        // name and index don't run together
+        assert_ne!(Mac::from_seed("vm1", 10), Mac::from_seed("vm11", 0));
+    }
+
     #[test]
     fn ipv6_from_mac() {
         let ts = [
@@ -109,11 +233,76 @@ mod test {
         ];
 
         for t in ts {
-            let res = t.0.parse::<Mac>().unwrap().to_ipv6_slaac_addr();
-            assert_eq!(res, t.1);
+            let res = t.0.parse::<Mac>().unwrap().to_ipv6_link_local();
+            assert_eq!(res.to_string(), t.1);
         }
     }
 
+    #[test]
+    fn eui64_and_prefixes() {
+        // locally administered, so the u/l bit flips off in the interface id
+        let mac: Mac = "02:16:3e:23:59:0f".parse().unwrap();
+        assert_eq!(
+            mac.to_eui64(),
+            [0x02, 0x16, 0x3e, 0xff, 0xfe, 0x23, 0x59, 0x0f]
+        );
+        assert_eq!(
+            mac.to_ipv6_link_local().to_string(),
+            "fe80::16:3eff:fe23:590f"
+        );
+
+        let prefix: Ipv6Addr = "2001:db8:1:2::".parse().unwrap();
+        let mac: Mac = "00:16:3e:23:59:0f".parse().unwrap();
+        assert_eq!(
+            mac.to_ipv6_slaac_addr(prefix).to_string(),
+            "2001:db8:1:2:216:3eff:fe23:590f"
+        );
+
+        // the low 64 bits of the prefix are ignored
+        let prefix: Ipv6Addr = "2001:db8:1:2::dead:beef".parse().unwrap();
+        let addr = mac.to_ipv6_slaac_addr(prefix);
+        assert_eq!(addr.to_string(), "2001:db8:1:2:216:3eff:fe23:590f");
+
+        assert!(mac.is_slaac_addr(&addr));
+        assert!(mac.is_slaac_addr(&mac.to_ipv6_link_local()));
+        assert!(!Mac::from_seed("vm1", 0).is_slaac_addr(&addr));
+        assert_eq!(Mac::from_ipv6_slaac_addr(&addr), Some(mac));
+
+        // privacy address, not derived from a MAC
+        let temp: Ipv6Addr = "2001:db8:1:2:8d3c:2a1b:7e4f:9a10".parse().unwrap();
+        assert_eq!(Mac::from_ipv6_slaac_addr(&temp), None);
+    }
+
+    #[test]
+    fn address_kinds() {
+        let mac: Mac = "00:16:3e:23:59:0f".parse().unwrap();
+        assert!(mac.is_unicast());
+        assert!(!mac.is_locally_administered());
+        assert_eq!(mac.oui(), [0x00, 0x16, 0x3e]);
+        assert_eq!(mac.nic_specific(), [0x23, 0x59, 0x0f]);
+
+        let bcast = Mac::from([0xff; 6]);
+        assert!(bcast.is_broadcast());
+        assert!(bcast.is_multicast());
+
+        let mcast: Mac = "01:00:5e:00:00:fb".parse().unwrap();
+        assert!(mcast.is_multicast());
+        assert!(!mcast.is_broadcast());
+
+        assert!(Mac::gen().is_unicast());
+        assert!(!Mac::gen().is_locally_administered());
+    }
+
+    #[test]
+    fn serde() {
+        let mac: Mac = serde_json::from_str("\"00:16:3E:23:59:0F\"").unwrap();
+        assert_eq!(
+            serde_json::to_string(&mac).unwrap(),
+            "\"00:16:3e:23:59:0f\""
+        );
+        assert!(serde_json::from_str::<Mac>("\"00:16:3e\"").is_err());
+    }
+
     #[test]
     fn test_copy() {
         let mac = Mac::gen();
@@ -136,6 +325,18 @@ mod test {
         let mac: Mac = s.parse().unwrap();
     }
 
+    #[test]
+    fn parse_strict() {
+        for s in [
+            "00:11:22:33:44:zz:55",
+            "0:11:22:33:44:55",
+            "001122:33:44:55",
+            "",
+        ] {
+            assert_eq!(s.parse::<Mac>(), Err(MacParseError), "{}", s);
+        }
+    }
+
     #[test]
     #[should_panic(expected = "MacParseError")]
     fn parse_too_long() {
@@ -15,56 +15,1577 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::io::Write;
 use std::path::PathBuf;
+use std::sync::OnceLock;
 
-use clap::{Parser, Subcommand};
-use tracing_subscriber;
+use clap::{ArgGroup, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use tracing_subscriber::filter::LevelFilter;
 
 use bigiron_virt::api;
+use bigiron_virt::api::models::{Selector, Size};
+use bigiron_virt::api::{MachineInfo, MachineStatus};
+use bigiron_virt::backup::utc_timestamp;
+use bigiron_virt::capacity::Placement;
+use bigiron_virt::config::Scope;
+use bigiron_virt::configdrive;
+use bigiron_virt::doctor::Outcome;
+use bigiron_virt::error::{self, Error, ErrorKind};
+use bigiron_virt::logging::{self, LogFormat};
+use bigiron_virt::reconcile::ReconcileOptions;
+use bigiron_virt::remote::Client;
+use bigiron_virt::secrets::Usage;
+use bigiron_virt::selftest::SelftestOptions;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[command(subcommand)]
     command: Commands,
+
+    /// Log line format, text or json
+    #[arg(long, global = true, default_value = "text")]
+    log_format: LogFormat,
+
+    /// Lowest level logged: error, warn, info, debug, trace or off
+    #[arg(long, global = true, default_value = "info")]
+    log_level: LevelFilter,
+
+    /// How a failure is reported on stderr, text or json. The exit code
+    /// tells what kind of failure it was: 2 invalid input, 3 not found,
+    /// 4 hypervisor error, 5 conflicting state, 6 timed out, 1 anything else
+    #[arg(long, global = true, default_value = "text")]
+    error_format: LogFormat,
+
+    /// Use the user's qemu:///session libvirt and state in the home
+    /// directory, the default when not run as root
+    #[arg(long, global = true, conflicts_with = "system")]
+    session: bool,
+
+    /// Use the system libvirt and state in /var/lib/bigiron-virt, the
+    /// default for root
+    #[arg(long, global = true)]
+    system: bool,
+
+    /// Manage the machines of the host running `serve --api` at this
+    /// endpoint, e.g. https://kvm01:8700, rather than this host's. Only
+    /// create, list, show and destroy work on another host
+    #[arg(long, global = true)]
+    host: Option<String>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
-    Create { model_file: PathBuf },
+    Create {
+        model_file: PathBuf,
+
+        /// Wait for cloud-init to be done in the new machines
+        #[arg(long)]
+        wait: bool,
+
+        /// Seconds --wait waits for all of the machines
+        #[arg(long, default_value_t = 600)]
+        wait_timeout: u64,
+    },
+    List {
+        /// Only list machines with these labels, e.g. env=test,team=infra
+        #[arg(short = 'l', long)]
+        selector: Option<Selector>,
+    },
+    #[command(group(ArgGroup::new("targets").required(true).args(["ids", "all", "files", "selector"])))]
+    Destroy {
+        /// Machine names or UUIDs
+        ids: Vec<String>,
+
+        /// Destroy every machine on this host
+        #[arg(long)]
+        all: bool,
+
+        /// Destroy the machines declared in these model files
+        #[arg(short = 'f', long = "file")]
+        files: Vec<PathBuf>,
+
+        /// Destroy every machine with these labels
+        #[arg(short = 'l', long)]
+        selector: Option<Selector>,
+
+        /// Don't ask for confirmation before destroying more than one machine
+        #[arg(short = 'y', long)]
+        yes: bool,
+
+        /// Seconds to wait for running machines to shut down before powering
+        /// them off, the host's destroyTimeout if not given
+        #[arg(long)]
+        timeout: Option<u64>,
+
+        /// Power running machines off without asking them to shut down
+        #[arg(long, conflicts_with = "timeout")]
+        now: bool,
+    },
+    /// Show a machine's spec, state and guest addresses
+    Show {
+        /// Machine name or UUID
+        id: String,
+    },
+    /// Run a command in a machine through the guest agent
+    Exec {
+        id: String,
+
+        /// Seconds to wait for the command to finish
+        #[arg(long, default_value_t = 60)]
+        timeout: u64,
+
+        #[arg(last = true, required = true)]
+        command: Vec<String>,
+    },
+    /// Log in to a machine with ssh once it answers on the ssh port
+    Ssh {
+        id: String,
+
+        #[arg(short = 'l', long, default_value = "ubuntu")]
+        user: String,
+
+        #[arg(short = 'p', long, default_value_t = 22)]
+        port: u16,
+
+        /// Seconds to wait for the machine to answer
+        #[arg(long, default_value_t = 120)]
+        timeout: u64,
+
+        /// Further ssh arguments, e.g. a command to run
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Show a running machine's CPU, memory, disk and network usage
+    Stats {
+        id: String,
+
+        /// Seconds to measure usage over
+        #[arg(long, default_value_t = 1)]
+        interval: u64,
+
+        /// Keep measuring until interrupted
+        #[arg(long)]
+        watch: bool,
+    },
+    /// Shut a machine down cleanly
+    Stop {
+        id: String,
+
+        /// Seconds to wait for the machine to stop
+        #[arg(long, default_value_t = 120)]
+        timeout: u64,
+    },
+    /// Change a machine's cpu, memory, nics, disks and the like to a new
+    /// spec, live where the running machine allows
+    Update {
+        id: String,
+
+        /// Model file with the machine's new spec
+        #[arg(short = 'f', long = "file")]
+        file: PathBuf,
+
+        /// Shut the machine down and start it from the new spec, for the
+        /// changes it can't take live
+        #[arg(long)]
+        restart: bool,
+
+        /// Seconds to wait for the machine to stop when restarting
+        #[arg(long, default_value_t = 120)]
+        timeout: u64,
+    },
+    /// Rebuild a machine's config drive and swap it in, live if running
+    UpdateConfigdrive {
+        id: String,
+
+        /// Model file to take userdata, secrets and nic addresses from,
+        /// the stored spec is used as is otherwise
+        #[arg(short = 'f', long = "file")]
+        file: Option<PathBuf>,
+    },
+    /// Print the files on a machine's config drive and check them
+    InspectConfigdrive {
+        id: String,
+
+        /// Only list file names and sizes
+        #[arg(short = 'l', long = "list")]
+        list: bool,
+    },
+    /// Pause a running machine, keeping its memory in place
+    Pause { id: String },
+    /// Continue a paused machine
+    Resume { id: String },
+    /// Stop a machine, saving its memory state to be restored later
+    Save {
+        id: String,
+
+        /// File to save the state to, kept with the machine by default
+        file: Option<PathBuf>,
+    },
+    /// Start a saved machine again where it left off
+    Restore { id: String },
+    /// Write a machine to a portable archive
+    Export {
+        id: String,
+
+        /// Archive to write, defaults to <id>.tar
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Recreate a machine from an exported archive
+    Import { file: PathBuf },
+    /// Manage a libvirt domain made some other way as a machine, with the
+    /// spec read from its XML
+    Adopt {
+        /// Name of the domain, which the machine keeps
+        name: String,
+    },
+    /// Back up machines and restore them from backups
+    Backup {
+        #[command(subcommand)]
+        command: BackupCommands,
+    },
+    /// List the image repo and name its images
+    Image {
+        #[command(subcommand)]
+        command: ImageCommands,
+    },
+    /// Grow a machine's disk, live if the machine is running
+    ResizeDisk {
+        id: String,
+
+        /// Disk target, e.g. vda for the instance disk
+        target: String,
+
+        /// New size, e.g. 40Gi
+        size: String,
+    },
+    /// Hot plug a disk image file or block device into a running machine
+    AttachDisk { id: String, path: PathBuf },
+    /// Print what a machine wrote to its serial console, e.g. to see why
+    /// it didn't boot
+    Logs {
+        id: String,
+
+        /// Keep printing what the machine writes next
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Stream machine lifecycle events (started, stopped, crashed, ...) as
+    /// JSON lines
+    Watch {
+        /// Only report these machines, all managed machines if none given
+        ids: Vec<String>,
+    },
+    /// Run in the foreground, serving Prometheus metrics on /metrics,
+    /// running machines' health checks and restarting machines that stop
+    /// as their restartPolicy asks
+    Serve {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:9180")]
+        listen: String,
+
+        /// Also manage machines for `schedule` and `--host` on other
+        /// hosts, which needs --tls so only allowed clients can
+        #[arg(long, requires = "tls")]
+        api: bool,
+
+        /// Serve HTTPS, only to clients with a certificate the host
+        /// config's tls section allows
+        #[arg(long)]
+        tls: bool,
+
+        /// With --tls, address to answer guests' phone_home on over plain
+        /// HTTP, e.g. 192.168.122.1:9181, where the host config's phoneHome
+        /// points
+        #[arg(long, requires = "tls")]
+        ready_listen: Option<String>,
+    },
+    /// Serve the machine operations over gRPC, as in proto/bigiron_virt.proto
+    #[cfg(feature = "grpc")]
+    Grpc {
+        /// Address to listen on, which must be a loopback address
+        #[arg(long, default_value = "127.0.0.1:8701")]
+        listen: String,
+    },
+    /// Destroy machines that have outlived their metadata.ttl, and list
+    /// domains, instances and images left over from machines
+    Gc {
+        /// Only list the expired machines
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Also clean up or adopt the leftovers
+        #[arg(long, conflicts_with = "dry_run")]
+        fix: bool,
+
+        /// Keep running, checking every this many seconds
+        #[arg(long)]
+        interval: Option<u64>,
+    },
+    /// Keep this host's machines to the ones declared in a directory of
+    /// model files, creating missing machines
+    Reconcile {
+        #[arg(long, default_value = "/etc/bigiron-virt/machines.d")]
+        dir: PathBuf,
+
+        /// Also destroy machines reconcile created that aren't declared
+        /// any more
+        #[arg(long)]
+        prune: bool,
+
+        /// Seconds between passes
+        #[arg(long, default_value_t = 30)]
+        interval: u64,
+
+        /// Make a single pass and exit
+        #[arg(long)]
+        once: bool,
+    },
+    /// Place the machines in the given model files across the hosts in an
+    /// inventory by their spec.scheduling rules, and create them there
+    Schedule {
+        #[arg(short = 'f', long = "file", required = true)]
+        files: Vec<PathBuf>,
+
+        /// YAML file listing the hosts, each running `serve --api`
+        #[arg(short = 'i', long)]
+        inventory: PathBuf,
+
+        /// Only print where each machine would go
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Report what creating the machines in the given model files would do,
+    /// as JSON listing the machines to create, declared differently from
+    /// the existing ones, and to destroy
+    Plan {
+        #[arg(short = 'f', long = "file", required = true)]
+        files: Vec<PathBuf>,
+
+        /// Check capacity and placement without creating anything
+        #[arg(long)]
+        simulate: bool,
+
+        /// Count machines reconcile created that aren't in the model files
+        /// as destroyed
+        #[arg(long, conflicts_with = "simulate")]
+        prune: bool,
+    },
+    /// Manage the libvirt secrets disks refer to, e.g. Ceph keys and LUKS
+    /// passphrases
+    Secret {
+        #[command(subcommand)]
+        command: SecretCommands,
+    },
+    /// Manage the address pools nics take static IPv4 addresses from,
+    /// declared as `kind: AddressPool` in model files
+    Pool {
+        #[command(subcommand)]
+        command: PoolCommands,
+    },
+    /// Check the host is set up to run machines, without creating any
+    Doctor {
+        /// Bridge to check for, besides those managed machines use
+        #[arg(long)]
+        bridge: Vec<String>,
+    },
+    /// Create, boot and destroy a throwaway machine to verify this host
+    Selftest {
+        /// qcow2 image to boot, defaults to the packaged selftest image
+        #[arg(long)]
+        image: Option<PathBuf>,
+
+        /// Bridge to attach the machine to, enables the network check
+        #[arg(long)]
+        bridge: Option<String>,
+
+        /// Seconds to wait for each check
+        #[arg(long, default_value_t = 300)]
+        timeout: u64,
+    },
+    /// Print a shell completion script, e.g. `bigiron-virt completions bash
+    /// > /etc/bash_completion.d/bigiron-virt`. Bash and fish scripts also
+    /// complete the ids of managed machines.
+    Completions { shell: Shell },
+    /// Print the ids of managed machines, for completion scripts
+    #[command(hide = true)]
+    CompleteIds,
+}
+
+#[derive(Subcommand)]
+enum BackupCommands {
+    /// Back up a machine's disk, spec and config drive
+    Create {
+        id: String,
+
+        /// Have the guest agent freeze filesystems during the snapshot
+        #[arg(long)]
+        quiesce: bool,
+    },
+    /// List backups, of one machine or all of them
+    List { id: Option<String> },
+    /// Replace a machine with a backup, destroying the current machine
+    Restore { id: String, backup: String },
+}
+
+#[derive(Subcommand)]
+enum PoolCommands {
+    /// List pools with how many addresses are handed out and free
     List,
-    Destroy { id: String },
+    /// Remove a pool none of whose addresses are in use
+    Rm { pool: String },
 }
 
-fn main() {
-    tracing_subscriber::fmt::init();
+#[derive(Subcommand)]
+enum SecretCommands {
+    /// Set a secret's value, defining the secret if there is none for the
+    /// usage yet, and print its UUID
+    Set {
+        /// What the secret is for: ceph:USER, iscsi:TARGET or volume:PATH
+        usage: Usage,
+
+        /// File holding the value, - for stdin
+        #[arg(long)]
+        file: PathBuf,
 
+        /// The value is base64 encoded, as `ceph auth get-key` prints it
+        #[arg(long)]
+        base64: bool,
+
+        /// Keep the value from being read back; qemu-img can't use private
+        /// LUKS passphrases
+        #[arg(long)]
+        private: bool,
+
+        #[arg(long)]
+        description: Option<String>,
+    },
+    /// List secrets with their usage, never their values
+    List,
+    /// Undefine a secret, by UUID or usage
+    Rm { secret: String },
+}
+
+#[derive(Subcommand)]
+enum ImageCommands {
+    /// List images with their names, sizes and sources
+    List,
+    /// Import an image into the repo ahead of creating machines from it
+    Pull {
+        url: String,
+
+        /// sha256 of the image, in lowercase hex
+        #[arg(long)]
+        hash: String,
+
+        /// Name to give the image
+        #[arg(long)]
+        name: Option<String>,
+
+        /// The image is an ISO for a cdrom rather than a disk image
+        #[arg(long)]
+        iso: bool,
+    },
+    /// Build a disk image from an OCI container image, e.g.
+    /// quay.io/fedora/fedora:40, with podman and virt-make-fs. The container
+    /// image needs a kernel under /boot; the filesystem is on /dev/vda1
+    /// with no bootloader, so boot it with spec.directBoot
+    ImportOci {
+        reference: String,
+
+        /// Free space on the filesystem beyond the image's files
+        #[arg(long, default_value = "1G")]
+        free: String,
+
+        /// Name to give the image
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Give an image a name to use as spec.image.name, moving the name
+    /// from any image that had it
+    Name {
+        /// Image hash, name or alias
+        image: String,
+        name: String,
+
+        /// Other names for the image, e.g. jammy
+        #[arg(long)]
+        alias: Vec<String>,
+
+        /// OS on the image, e.g. "Ubuntu 22.04 LTS"
+        #[arg(long)]
+        os: Option<String>,
+    },
+}
+
+fn main() {
     let args = Args::parse();
 
+    logging::init(args.log_format, args.log_level);
+    let _ = ERROR_FORMAT.set(args.error_format);
+
+    if args.session {
+        Scope::Session.select().unwrap();
+    } else if args.system {
+        Scope::System.select().unwrap();
+    }
+
+    if let Some(ref host) = args.host {
+        match api::connect(host) {
+            Ok(client) => remote(&client, &args.command),
+            Err(e) => fail(e),
+        }
+        logging::shutdown();
+        return;
+    }
+
     match &args.command {
-        Commands::Create { model_file } => {
-            create_resources_from_file(model_file);
+        Commands::Create {
+            model_file,
+            wait,
+            wait_timeout,
+        } => {
+            create_resources_from_file(model_file, wait.then_some(*wait_timeout));
+        }
+        Commands::List { selector } => list_machines(selector.as_ref()),
+        Commands::Destroy {
+            ids,
+            all,
+            files,
+            selector,
+            yes,
+            timeout,
+            now,
+        } => {
+            let timeout = match now {
+                true => Some(std::time::Duration::ZERO),
+                false => timeout.map(std::time::Duration::from_secs),
+            };
+            destroy_machines(ids, *all, files, selector.as_ref(), *yes, timeout, None)
+        }
+        Commands::Show { id } => show_machine(id),
+        Commands::Exec {
+            id,
+            timeout,
+            command,
+        } => guest_exec(id, command, *timeout),
+        Commands::Ssh {
+            id,
+            user,
+            port,
+            timeout,
+            args,
+        } => ssh(id, user, *port, *timeout, args),
+        Commands::Stats {
+            id,
+            interval,
+            watch,
+        } => machine_stats(id, *interval, *watch),
+        Commands::Stop { id, timeout } => stop_machine(id, *timeout),
+        Commands::Update {
+            id,
+            file,
+            restart,
+            timeout,
+        } => update_machine(id, file, *restart, *timeout),
+        Commands::UpdateConfigdrive { id, file } => update_config_drive(id, file),
+        Commands::InspectConfigdrive { id, list } => inspect_config_drive(id, *list),
+        Commands::Pause { id } => pause_machine(id),
+        Commands::Resume { id } => resume_machine(id),
+        Commands::Save { id, file } => save_machine(id, file),
+        Commands::Restore { id } => restore_saved_machine(id),
+        Commands::Export { id, output } => export_machine(id, output),
+        Commands::Import { file } => import_machine(file),
+        Commands::Adopt { name } => adopt_machine(name),
+        Commands::Backup { command } => backup(command),
+        Commands::Image { command } => image(command),
+        Commands::ResizeDisk { id, target, size } => resize_disk(id, target, size),
+        Commands::AttachDisk { id, path } => attach_disk(id, path),
+        Commands::Logs { id, follow } => console_log(id, *follow),
+        Commands::Watch { ids } => watch(ids),
+        Commands::Serve {
+            listen,
+            api,
+            tls,
+            ready_listen,
+        } => serve(listen, *api, *tls, ready_listen.as_deref()),
+        #[cfg(feature = "grpc")]
+        Commands::Grpc { listen } => serve_grpc(listen),
+        Commands::Gc {
+            dry_run,
+            fix,
+            interval,
+        } => gc(*dry_run, *fix, *interval),
+        Commands::Reconcile {
+            dir,
+            prune,
+            interval,
+            once,
+        } => reconcile(dir, *prune, *interval, *once),
+        Commands::Schedule {
+            files,
+            inventory,
+            dry_run,
+        } => schedule(files, inventory, *dry_run),
+        Commands::Plan {
+            files,
+            simulate,
+            prune,
+        } => plan(files, *simulate, *prune),
+        Commands::Secret { command } => secret(command),
+        Commands::Pool { command } => pool(command),
+        Commands::Doctor { bridge } => doctor(bridge),
+        Commands::Selftest {
+            image,
+            bridge,
+            timeout,
+        } => selftest(image, bridge, *timeout),
+        Commands::Completions { shell } => completions(*shell),
+        Commands::CompleteIds => complete_ids(),
+    }
+    logging::shutdown();
+}
+
+/// Exit with `code`, exporting the spans still buffered first
+fn exit(code: i32) -> ! {
+    logging::shutdown();
+    std::process::exit(code)
+}
+
+// set once from --error-format, for fail
+static ERROR_FORMAT: OnceLock<LogFormat> = OnceLock::new();
+
+/// Report `e` on stderr and exit with the code of its kind
+fn fail(e: impl Into<Error>) -> ! {
+    let e = e.into();
+    let kind = ErrorKind::of(e.as_ref());
+
+    match ERROR_FORMAT.get() {
+        Some(LogFormat::Json) => eprintln!("{}", error_json(&e)),
+        _ => eprintln!("{}", e),
+    }
+    exit(kind.exit_code())
+}
+
+// `e` as --error-format json reports it
+fn error_json(e: &Error) -> serde_json::Value {
+    let kind = ErrorKind::of(e.as_ref());
+    serde_json::json!({
+        "error": kind,
+        "code": kind.exit_code(),
+        "message": e.to_string(),
+    })
+}
+
+fn create_resources_from_file(model_file: &std::path::Path, wait: Option<u64>) {
+    let names = api::create_from_file(model_file).unwrap_or_else(|e| fail(e));
+
+    if let Some(timeout) = wait {
+        let result = api::wait_ready(&names, std::time::Duration::from_secs(timeout), |name| {
+            println!("{} is ready", name)
+        });
+        if let Err(e) = result {
+            fail(e);
+        }
+    }
+}
+
+// the commands that work on another host
+fn remote(client: &Client, command: &Commands) {
+    let result: Result<(), Error> = match command {
+        Commands::Create { wait: true, .. } => {
+            Err(error::invalid("--wait can't be used with --host"))
+        }
+        Commands::Create { model_file, .. } => api::machines_from_file(model_file)
+            .and_then(|machines| machines.iter().try_for_each(|m| client.create(m))),
+        Commands::List { selector } => client
+            .list(selector.as_ref())
+            .map(|list| print_machines(&list)),
+        Commands::Show { id } => client.show(id).map(|info| print_machine_info(&info)),
+        Commands::Destroy {
+            ids,
+            all,
+            files,
+            selector,
+            yes,
+            timeout: None,
+            now: false,
+        } => {
+            destroy_machines(
+                ids,
+                *all,
+                files,
+                selector.as_ref(),
+                *yes,
+                None,
+                Some(client),
+            );
+            Ok(())
+        }
+        Commands::Destroy { .. } => Err(error::invalid(
+            "--timeout and --now can't be used with --host",
+        )),
+        _ => Err(error::invalid(
+            "only create, list, show and destroy can be run with --host",
+        )),
+    };
+
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+fn list_machines(selector: Option<&Selector>) {
+    print_machines(&api::list_machines(selector).expect("error listing machines"));
+}
+
+fn print_machines(list: &[MachineStatus]) {
+    println!("ID\tUUID\tSTATUS\tHEALTH\tIP\tCREATED");
+    for stat in list {
+        let ips: Vec<_> = stat.addresses.iter().map(|a| a.to_string()).collect();
+        let ips = if ips.is_empty() {
+            String::from("-")
+        } else {
+            ips.join(",")
+        };
+
+        let uuid = stat.uuid.as_deref().unwrap_or("-");
+        let created = stat
+            .timestamps
+            .created_at
+            .map_or(String::from("-"), utc_timestamp);
+        let health = stat.health.as_deref().unwrap_or("-");
+        println!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            stat.id, uuid, stat.status, health, ips, created
+        );
+    }
+}
+
+fn destroy_machines(
+    ids: &[String],
+    all: bool,
+    files: &[PathBuf],
+    selector: Option<&Selector>,
+    yes: bool,
+    timeout: Option<std::time::Duration>,
+    remote: Option<&Client>,
+) {
+    let targets = if all {
+        match remote {
+            Some(c) => c
+                .list(None)
+                .map(|list| list.into_iter().map(|m| m.id).collect()),
+            None => api::all_machine_ids(),
+        }
+    } else if !files.is_empty() {
+        let mut docs = Vec::new();
+        for f in files {
+            docs.push(std::fs::read_to_string(f).expect("error reading model file"));
+        }
+        api::machine_ids_from_yaml(&docs.join("\n---\n"))
+    } else if let Some(selector) = selector {
+        match remote {
+            Some(c) => c.select(selector),
+            None => api::select_machines(selector),
+        }
+    } else {
+        Ok(ids.to_vec())
+    };
+
+    let targets = match targets {
+        Ok(t) => t,
+        Err(e) => fail(e),
+    };
+
+    if targets.is_empty() {
+        println!("No machines to destroy");
+        return;
+    }
+
+    // a lone id is taken as confirmation enough
+    let bulk = all || !files.is_empty() || selector.is_some() || targets.len() > 1;
+    let prompt = match remote {
+        Some(c) => format!("Destroy {} on {}?", targets.join(", "), c.endpoint()),
+        None => format!("Destroy {}?", targets.join(", ")),
+    };
+    if bulk && !yes && !confirm(&prompt) {
+        eprintln!("Aborted");
+        exit(1);
+    }
+
+    let results = match remote {
+        Some(c) => Ok(targets
+            .iter()
+            .map(|id| (id.clone(), c.destroy(id)))
+            .collect()),
+        None => api::destroy_machines(&targets, timeout),
+    };
+    let results = match results {
+        Ok(r) => r,
+        Err(e) => fail(e),
+    };
+
+    // the exit code of the first failure, the others are only reported
+    let mut code = None;
+    for (id, result) in results {
+        match result {
+            Ok(_) => println!("Destroyed {}", id),
+            Err(e) => {
+                match ERROR_FORMAT.get() {
+                    Some(LogFormat::Json) => {
+                        let mut report = error_json(&e);
+                        report["machine"] = id.into();
+                        eprintln!("{}", report);
+                    }
+                    _ => eprintln!("Failed to destroy {}: {}", id, e),
+                }
+                code = code.or(Some(ErrorKind::of(e.as_ref()).exit_code()));
+            }
+        }
+    }
+
+    if let Some(code) = code {
+        exit(code);
+    }
+}
+
+fn confirm(prompt: &str) -> bool {
+    use std::io::Write;
+
+    print!("{} [y/N] ", prompt);
+    let _ = std::io::stdout().flush();
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
+
+    matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+fn show_machine(id: &str) {
+    match api::show_machine(id) {
+        Ok(info) => print_machine_info(&info),
+        Err(e) => fail(e),
+    }
+}
+
+fn print_machine_info(info: &MachineInfo) {
+    let spec = &info.machine.spec;
+    println!("Name:\t{}", info.machine.metadata.name);
+    if let Some(ref uuid) = info.machine.metadata.uuid {
+        println!("UUID:\t{}", uuid);
+    }
+    println!("State:\t{}", info.state);
+    let t = &info.timestamps;
+    if let Some(created) = t.created_at {
+        println!("Created:\t{}", utc_timestamp(created));
+    }
+    if let Some(started) = t.started_at {
+        match t.created_at {
+            Some(created) => println!(
+                "Started:\t{} (created in {}s)",
+                utc_timestamp(started),
+                started.saturating_sub(created)
+            ),
+            None => println!("Started:\t{}", utc_timestamp(started)),
+        }
+    }
+    if let Some(changed) = t.state_changed_at {
+        println!("State changed:\t{}", utc_timestamp(changed));
+    }
+    if let Some(ready) = t.ready_at {
+        println!("Ready:\t{}", utc_timestamp(ready));
+    }
+    println!("CPUs:\t{}", spec.cpu);
+    println!("Memory:\t{}", spec.memory);
+    for nic in spec.nics.iter().flatten() {
+        println!("NIC:\t{} {} on {}", nic.macaddress, nic.kind, nic.parent);
+    }
+    for addr in &info.addresses {
+        println!("Address:\t{}", addr);
+    }
+}
+
+fn machine_stats(id: &str, interval: u64, watch: bool) {
+    let interval = std::time::Duration::from_secs(interval.max(1));
+
+    loop {
+        match api::machine_stats(id, interval) {
+            Ok(stats) => print_machine_stats(&stats),
+            Err(e) => fail(e),
+        }
+
+        if !watch {
+            break;
+        }
+        println!();
+    }
+}
+
+fn print_machine_stats(stats: &api::MachineStats) {
+    println!("CPU:\t{:.1}% of {} vcpus", stats.cpu_percent, stats.vcpus);
+    println!(
+        "Memory:\t{} used of {}",
+        Size(stats.memory_rss_bytes),
+        Size(stats.memory_bytes)
+    );
+    for d in &stats.disks {
+        println!(
+            "Disk {}:\tread {}/s, write {}/s",
+            d.name,
+            Size(d.read_bytes_per_sec),
+            Size(d.write_bytes_per_sec)
+        );
+    }
+    for i in &stats.interfaces {
+        println!(
+            "NIC {}:\trx {}/s, tx {}/s",
+            i.name,
+            Size(i.rx_bytes_per_sec),
+            Size(i.tx_bytes_per_sec)
+        );
+    }
+}
+
+fn guest_exec(id: &str, command: &[String], timeout: u64) {
+    let timeout = std::time::Duration::from_secs(timeout);
+
+    let result = match api::guest_exec(id, command, timeout) {
+        Ok(r) => r,
+        Err(e) => fail(e),
+    };
+
+    use std::io::Write;
+    let _ = std::io::stdout().write_all(&result.stdout);
+    let _ = std::io::stderr().write_all(&result.stderr);
+
+    exit(result.exit_code as i32);
+}
+
+fn ssh(id: &str, user: &str, port: u16, timeout: u64, args: &[String]) {
+    use std::os::unix::process::CommandExt;
+
+    let timeout = std::time::Duration::from_secs(timeout);
+    let target = api::reachable_address(id, port, timeout)
+        .and_then(|addr| Ok((addr, api::known_hosts_path(id)?)));
+    let (addr, known_hosts) = match target {
+        Ok(t) => t,
+        Err(e) => fail(e),
+    };
+
+    // ssh takes link-local addresses with their scope after a %
+    let host = match addr {
+        std::net::SocketAddr::V6(a) if a.scope_id() != 0 => format!("{}%{}", a.ip(), a.scope_id()),
+        _ => addr.ip().to_string(),
+    };
+
+    // a recreated machine has new host keys, so each one has its own
+    // known_hosts, trusting the keys first seen
+    let err = std::process::Command::new("ssh")
+        .arg("-p")
+        .arg(port.to_string())
+        .arg("-o")
+        .arg(format!("UserKnownHostsFile={}", known_hosts.display()))
+        .arg("-o")
+        .arg("StrictHostKeyChecking=accept-new")
+        .arg(format!("{}@{}", user, host))
+        .args(args)
+        .exec();
+
+    fail(format!("error executing ssh: {}", err));
+}
+
+fn stop_machine(id: &str, timeout: u64) {
+    match api::stop_machine(id, std::time::Duration::from_secs(timeout)) {
+        Err(e) => fail(e),
+        Ok(_) => println!("Stopped {}", id),
+    }
+}
+
+fn update_machine(id: &str, file: &std::path::Path, restart: bool, timeout: u64) {
+    let timeout = std::time::Duration::from_secs(timeout);
+    let report = match api::update_machine(id, file, restart, timeout) {
+        Ok(r) => r,
+        Err(e) => fail(e),
+    };
+
+    if report.applied.is_empty() && report.pending.is_empty() {
+        println!("{} is up to date", id);
+    }
+    for change in &report.applied {
+        println!("applied: {}", change);
+    }
+    for change in &report.pending {
+        println!("on restart: {}", change);
+    }
+    if report.restarted {
+        println!("Restarted {}", id);
+    } else if !report.pending.is_empty() {
+        println!("Run update with --restart to apply the rest");
+    }
+}
+
+fn update_config_drive(id: &str, file: &Option<PathBuf>) {
+    match api::update_config_drive(id, file.as_deref()) {
+        Err(e) => fail(e),
+        Ok(_) => println!("Updated the config drive of {}", id),
+    }
+}
+
+fn inspect_config_drive(id: &str, list: bool) {
+    let files = match api::config_drive_files(id) {
+        Ok(f) => f,
+        Err(e) => fail(e),
+    };
+
+    for (name, data) in &files {
+        if list {
+            println!("{}\t{}", name, data.len());
+        } else {
+            println!("==> {} <==", name);
+            print!("{}", String::from_utf8_lossy(data));
+            if !data.ends_with(b"\n") {
+                println!();
+            }
+        }
+    }
+
+    let problems = configdrive::check(&files);
+    for problem in &problems {
+        eprintln!("warning: {}", problem);
+    }
+    if !problems.is_empty() {
+        exit(1);
+    }
+}
+
+fn pause_machine(id: &str) {
+    match api::pause_machine(id) {
+        Err(e) => fail(e),
+        Ok(_) => println!("Paused {}", id),
+    }
+}
+
+fn resume_machine(id: &str) {
+    match api::resume_machine(id) {
+        Err(e) => fail(e),
+        Ok(_) => println!("Resumed {}", id),
+    }
+}
+
+fn save_machine(id: &str, file: &Option<PathBuf>) {
+    match api::save_machine(id, file.as_deref()) {
+        Err(e) => fail(e),
+        Ok(_) => println!("Saved {}", id),
+    }
+}
+
+fn restore_saved_machine(id: &str) {
+    match api::restore_saved_machine(id) {
+        Err(e) => fail(e),
+        Ok(_) => println!("Restored {}", id),
+    }
+}
+
+fn export_machine(id: &str, output: &Option<PathBuf>) {
+    let output = output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(format!("{}.tar", id)));
+
+    match api::export_machine(id, &output) {
+        Err(e) => fail(e),
+        Ok(_) => println!("Exported {} to {}", id, output.display()),
+    }
+}
+
+fn import_machine(file: &std::path::Path) {
+    match api::import_machine(file) {
+        Err(e) => fail(e),
+        Ok(name) => println!("Imported {}", name),
+    }
+}
+
+fn adopt_machine(name: &str) {
+    match api::adopt_machine(name) {
+        Err(e) => fail(e),
+        Ok(machine) => println!(
+            "Adopted {} ({} cpus, {} memory, {} storage disks, {} nics)",
+            name,
+            machine.spec.cpu,
+            machine.spec.memory,
+            machine.spec.storage.map_or(0, |s| s.len()),
+            machine.spec.nics.map_or(0, |n| n.len())
+        ),
+    }
+}
+
+fn backup(command: &BackupCommands) {
+    let result = match command {
+        BackupCommands::Create { id, quiesce } => api::backup_machine(id, *quiesce)
+            .map(|b| println!("Created backup {} of {}", b.name, id)),
+        BackupCommands::List { id } => api::list_backups(id.as_deref()).map(|backups| {
+            println!("MACHINE\tBACKUP\tSIZE");
+            for b in backups {
+                println!("{}\t{}\t{}", b.machine, b.name, Size(b.size_bytes));
+            }
+        }),
+        BackupCommands::Restore { id, backup } => {
+            api::restore_machine(id, backup).map(|_| println!("Restored {} from {}", id, backup))
+        }
+    };
+
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+fn image(command: &ImageCommands) {
+    let result = match command {
+        ImageCommands::List => api::list_images().map(|images| {
+            println!("HASH\tFORMAT\tSIZE\tNAME\tALIASES\tOS\tIMPORTED\tURL");
+            for i in images {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    i.hash,
+                    i.format,
+                    Size(i.size),
+                    i.name.as_deref().unwrap_or("-"),
+                    if i.aliases.is_empty() {
+                        String::from("-")
+                    } else {
+                        i.aliases.join(",")
+                    },
+                    i.os.as_deref().unwrap_or("-"),
+                    i.imported_at.as_deref().unwrap_or("-"),
+                    i.url.as_deref().unwrap_or("-"),
+                );
+            }
+        }),
+        ImageCommands::Pull {
+            url,
+            hash,
+            name,
+            iso,
+        } => api::pull_image(url, hash, *iso, name.as_deref())
+            .map(|i| println!("Pulled image {} ({})", i.hash, Size(i.size))),
+        ImageCommands::ImportOci {
+            reference,
+            free,
+            name,
+        } => api::import_oci_image(reference, free, name.as_deref())
+            .map(|i| println!("Imported image {} ({})", i.hash, Size(i.size))),
+        ImageCommands::Name {
+            image,
+            name,
+            alias,
+            os,
+        } => api::name_image(image, name, alias, os.as_deref())
+            .map(|i| println!("Named image {} {}", i.hash, name)),
+    };
+
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+fn secret(command: &SecretCommands) {
+    let result = match command {
+        SecretCommands::Set {
+            usage,
+            file,
+            base64,
+            private,
+            description,
+        } => {
+            let value = if file.as_os_str() == "-" {
+                let mut value = Vec::new();
+                std::io::Read::read_to_end(&mut std::io::stdin(), &mut value).map(|_| value)
+            } else {
+                std::fs::read(file)
+            };
+
+            value
+                .map_err(|e| format!("error reading {:?}: {}", file, e).into())
+                .and_then(|value| {
+                    api::set_secret(usage, &value, *base64, *private, description.as_deref())
+                })
+                .map(|uuid| println!("{}", uuid))
+        }
+        SecretCommands::List => api::list_secrets().map(|secrets| {
+            println!("UUID\tUSAGE\tPRIVATE\tDESCRIPTION");
+            for s in secrets {
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    s.uuid,
+                    s.usage.map_or(String::from("-"), |u| u.to_string()),
+                    if s.private { "yes" } else { "no" },
+                    s.description.as_deref().unwrap_or("-"),
+                );
+            }
+        }),
+        SecretCommands::Rm { secret } => {
+            api::remove_secret(secret).map(|_| println!("Removed secret {}", secret))
+        }
+    };
+
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+fn pool(command: &PoolCommands) {
+    let result = match command {
+        PoolCommands::List => api::list_pools().map(|pools| {
+            println!("NAME\tCIDR\tUSED\tFREE");
+            for p in pools {
+                println!("{}\t{}\t{}\t{}", p.name, p.cidr, p.used, p.free);
+            }
+        }),
+        PoolCommands::Rm { pool } => {
+            api::remove_pool(pool).map(|_| println!("Removed address pool {}", pool))
+        }
+    };
+
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+fn resize_disk(id: &str, target: &str, size: &str) {
+    match api::resize_disk(id, target, size) {
+        Err(e) => fail(e),
+        Ok(_) => println!("Resized {} of {} to {}", target, id, size),
+    }
+}
+
+fn attach_disk(id: &str, path: &std::path::Path) {
+    match api::attach_disk(id, path) {
+        Err(e) => fail(e),
+        Ok(target) => println!("Attached {:?} to {} as {}", path, id, target),
+    }
+}
+
+fn console_log(id: &str, follow: bool) {
+    let result = api::console_log(id, follow, |data| {
+        // stop once whatever is reading the output goes away
+        let mut out = std::io::stdout().lock();
+        out.write_all(data).and_then(|_| out.flush()).is_ok()
+    });
+
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+fn watch(ids: &[String]) {
+    let result = api::watch(ids, |event| {
+        let line = match serde_json::to_string(&event) {
+            Ok(line) => line,
+            Err(_) => return true,
+        };
+
+        // stop once whatever is reading the stream goes away
+        let mut out = std::io::stdout().lock();
+        writeln!(out, "{}", line).and_then(|_| out.flush()).is_ok()
+    });
+
+    if let Err(e) = result {
+        fail(e);
+    }
+}
+
+fn serve(listen: &str, machine_api: bool, tls: bool, ready_listen: Option<&str>) {
+    if let Err(e) = api::serve(listen, machine_api, tls, ready_listen) {
+        fail(e);
+    }
+}
+
+#[cfg(feature = "grpc")]
+fn serve_grpc(listen: &str) {
+    if let Err(e) = api::serve_grpc(listen) {
+        fail(e);
+    }
+}
+
+fn gc(dry_run: bool, fix: bool, interval: Option<u64>) {
+    loop {
+        let failed = match api::expired_machines() {
+            Ok(expired) if dry_run => {
+                for id in &expired {
+                    println!("Expired {}", id);
+                }
+                false
+            }
+            Ok(expired) => match api::destroy_machines(&expired, None) {
+                Ok(results) => {
+                    let mut failed = false;
+                    for (id, result) in results {
+                        match result {
+                            Ok(_) => println!("Destroyed {}", id),
+                            Err(e) => {
+                                println!("Failed to destroy {}: {}", id, e);
+                                failed = true;
+                            }
+                        }
+                    }
+                    failed
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    true
+                }
+            },
+            Err(e) => {
+                eprintln!("{}", e);
+                true
+            }
+        };
+        let failed = orphans(fix) || failed;
+
+        // a daemon carries on past failures
+        match interval {
+            Some(secs) => std::thread::sleep(std::time::Duration::from_secs(secs)),
+            None if failed => exit(1),
+            None => return,
         }
-        Commands::List => list_machines(),
-        Commands::Destroy { id } => destroy_machine(id),
     }
 }
 
-fn create_resources_from_file(model_file: &std::path::Path) {
-    let data = std::fs::read_to_string(&model_file).unwrap();
-    api::create_from_yaml(&data).unwrap();
+// list leftovers, or fix them if `fix`, returning whether any of it failed
+fn orphans(fix: bool) -> bool {
+    let found = match api::find_orphans() {
+        Ok(found) => found,
+        Err(e) => {
+            eprintln!("{}", e);
+            return true;
+        }
+    };
+    if !fix {
+        for orphan in &found {
+            println!("Orphaned: {}", orphan);
+        }
+        return false;
+    }
+
+    match api::fix_orphans(&found) {
+        Ok(results) => {
+            let mut failed = false;
+            for (orphan, result) in results {
+                match result {
+                    Ok(_) => println!("Fixed: {}", orphan),
+                    Err(e) => {
+                        println!("Failed to fix: {}: {}", orphan, e);
+                        failed = true;
+                    }
+                }
+            }
+            failed
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            true
+        }
+    }
 }
 
-fn list_machines() {
-    println!("{}\t{}", "ID", "STATUS");
-    for stat in api::list_machines().expect("error listing machines") {
-        println!("{}\t{}", stat.id, stat.status);
+fn reconcile(dir: &std::path::Path, prune: bool, interval: u64, once: bool) {
+    let opts = ReconcileOptions {
+        dir: dir.to_path_buf(),
+        prune,
+        interval: match once {
+            true => None,
+            false => Some(std::time::Duration::from_secs(interval)),
+        },
+    };
+
+    match api::reconcile(&opts) {
+        Ok(actions) => {
+            for name in &actions.create {
+                println!("created\t{}", name);
+            }
+            for name in &actions.prune {
+                println!("destroyed\t{}", name);
+            }
+        }
+        Err(e) => fail(e),
+    }
+}
+
+fn schedule(files: &[PathBuf], inventory: &std::path::Path, dry_run: bool) {
+    let placed = match api::schedule(inventory, files, dry_run) {
+        Ok(p) => p,
+        Err(e) => fail(e),
+    };
+
+    for (machine, host) in placed {
+        match dry_run {
+            true => println!("{} would go on {}", machine, host),
+            false => println!("Created {} on {}", machine, host),
+        }
+    }
+}
+
+fn plan(files: &[PathBuf], simulate: bool, prune: bool) {
+    if !simulate {
+        match api::plan_changes(files, prune) {
+            Ok(diff) => println!("{}", serde_json::to_string_pretty(&diff).unwrap()),
+            Err(e) => fail(e),
+        }
+        return;
+    }
+
+    let mut docs = Vec::new();
+    for f in files {
+        docs.push(std::fs::read_to_string(f).expect("error reading model file"));
+    }
+
+    let report = match api::simulate_plan(&docs.join("\n---\n")) {
+        Ok(r) => r,
+        Err(e) => fail(e),
+    };
+
+    let free = match report.host.free_memory_bytes {
+        Some(bytes) => format!(" ({} free)", Size(bytes)),
+        None => String::new(),
+    };
+    println!(
+        "Host {}: {} cpus, {} memory{}",
+        report.host.hostname,
+        report.host.cpus,
+        Size(report.host.memory_bytes),
+        free
+    );
+    for ds in &report.host.datastores {
+        let paths: Vec<_> = std::iter::once(&ds.path)
+            .chain(&ds.also)
+            .map(|p| p.display().to_string())
+            .collect();
+        println!(
+            "Datastore {}: {} free of {}",
+            paths.join(", "),
+            Size(ds.free_bytes),
+            Size(ds.total_bytes)
+        );
+    }
+
+    println!();
+    println!("{}\t{}\t{}\t{}", "NAME", "CPUS", "MEMORY", "RESULT");
+    for m in &report.machines {
+        let result = match &m.placement {
+            Placement::Fits { host, .. } => format!("fits on {}", host),
+            Placement::Rejected { reasons } => format!("rejected: {}", reasons.join("; ")),
+        };
+        println!(
+            "{}\t{}\t{}\t{}",
+            m.demand.name,
+            m.demand.cpus,
+            Size(m.demand.memory_bytes),
+            result
+        );
+    }
+
+    println!();
+    println!(
+        "Overcommit after plan: cpu {:.2}x, memory {:.2}x",
+        report.overcommit.cpu, report.overcommit.memory
+    );
+}
+
+fn doctor(bridges: &[String]) {
+    let report = match api::doctor(bridges) {
+        Ok(r) => r,
+        Err(e) => fail(e),
+    };
+
+    for check in &report.checks {
+        match &check.outcome {
+            Outcome::Pass(detail) => println!("PASS\t{}\t{}", check.name, detail),
+            Outcome::Warn(detail) => println!("WARN\t{}\t{}", check.name, detail),
+            Outcome::Fail(detail) => println!("FAIL\t{}\t{}", check.name, detail),
+        }
+    }
+
+    if !report.passed() {
+        exit(1);
+    }
+}
+
+fn selftest(image: &Option<PathBuf>, bridge: &Option<String>, timeout: u64) {
+    let opts = SelftestOptions {
+        image: image.clone(),
+        bridge: bridge.clone(),
+        timeout: std::time::Duration::from_secs(timeout),
+    };
+
+    let report = match api::selftest(&opts) {
+        Ok(r) => r,
+        Err(e) => fail(e),
+    };
+
+    for step in &report.steps {
+        match &step.result {
+            Ok(detail) => println!("PASS\t{}\t{}", step.name, detail),
+            Err(e) => println!("FAIL\t{}\t{}", step.name, e),
+        }
+    }
+
+    if !report.passed() {
+        exit(1);
+    }
+}
+
+fn completions(shell: Shell) {
+    let mut cmd = Args::command();
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, &name, &mut std::io::stdout());
+
+    // subcommands whose arguments are machine ids, e.g. show and destroy
+    let id_commands: Vec<&str> = cmd
+        .get_subcommands()
+        .filter(|c| {
+            c.get_positionals()
+                .any(|a| a.get_id() == "id" || a.get_id() == "ids")
+        })
+        .map(|c| c.get_name())
+        .collect();
+
+    // on top of the generated completion, which offers files for ids
+    match shell {
+        Shell::Bash => print!(
+            r#"
+_{fn_name}_ids() {{
+    _{name} "$@"
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    if [[ $COMP_CWORD -ge 2 && "$cur" != -* ]]; then
+        case "${{COMP_WORDS[1]}}" in
+            {commands})
+                COMPREPLY+=($(compgen -W "$({name} complete-ids 2>/dev/null)" -- "$cur"))
+                ;;
+        esac
+    fi
+}}
+complete -F _{fn_name}_ids -o bashdefault -o default {name}
+"#,
+            fn_name = name.replace('-', "_"),
+            name = name,
+            commands = id_commands.join("|"),
+        ),
+        Shell::Fish => println!(
+            "complete -c {name} -n \"__fish_seen_subcommand_from {commands}\" -f -a \"({name} complete-ids 2>/dev/null)\"",
+            name = name,
+            commands = id_commands.join(" "),
+        ),
+        _ => {}
     }
 }
 
-fn destroy_machine(id: &str) {
-    match api::destroy_machine(id) {
-        Err(e) => println!("{}", e),
-        Ok(_) => println!("Destroyed {}", id),
+fn complete_ids() {
+    // nothing to offer beats an error in the middle of the command line
+    for id in api::all_machine_ids().unwrap_or_default() {
+        println!("{}", id);
     }
 }
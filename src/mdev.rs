@@ -0,0 +1,147 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Mediated devices (vGPU slices and the like) through the kernel's mdev
+//! sysfs interface.
+//!
+//! Parent devices list the types they can create under
+//! `/sys/class/mdev_bus/<parent>/mdev_supported_types/<type>`, with how
+//! many more fit in `available_instances`. Writing a UUID to the type's
+//! `create` makes a device, which shows up in `/sys/bus/mdev/devices`.
+//! Devices don't survive a host reboot, so machines' are made again with
+//! the same UUID when they start.
+
+use std::path::Path;
+
+use tracing::info;
+
+use crate::error::Error;
+
+/// Create a mediated device of `kind` (e.g. nvidia-63) on `parent`, or the
+/// first parent with room for one, returning its UUID. `sys` is the sysfs
+/// mount point.
+pub fn allocate(sys: &Path, kind: &str, parent: Option<&str>) -> Result<String, Error> {
+    let uuid = uuid::Uuid::new_v4().to_string();
+    create(sys, &uuid, kind, parent)?;
+    Ok(uuid)
+}
+
+/// Make mediated device `uuid` of `kind` again, as `allocate` would, if it
+/// doesn't exist, e.g. after the host rebooted
+pub fn ensure(sys: &Path, uuid: &str, kind: &str, parent: Option<&str>) -> Result<(), Error> {
+    if sys.join("bus/mdev/devices").join(uuid).exists() {
+        return Ok(());
+    }
+    create(sys, uuid, kind, parent)
+}
+
+fn create(sys: &Path, uuid: &str, kind: &str, parent: Option<&str>) -> Result<(), Error> {
+    let bus = sys.join("class/mdev_bus");
+
+    let mut parents = match parent {
+        Some(p) => vec![p.to_string()],
+        None => std::fs::read_dir(&bus)
+            .map_err(|e| format!("no mediated device parents in {:?}: {}", bus, e))?
+            .filter_map(|e| e.ok()?.file_name().into_string().ok())
+            .collect(),
+    };
+    parents.sort();
+
+    for parent in parents {
+        let dir = bus.join(&parent).join("mdev_supported_types").join(kind);
+        let available = std::fs::read_to_string(dir.join("available_instances"))
+            .ok()
+            .and_then(|s| s.trim().parse::<u32>().ok())
+            .unwrap_or(0);
+        if available == 0 {
+            continue;
+        }
+
+        std::fs::write(dir.join("create"), uuid)
+            .map_err(|e| format!("error creating {} device on {}: {}", kind, parent, e))?;
+
+        info!("Created {} mediated device {} on {}", kind, uuid, parent);
+        return Ok(());
+    }
+
+    Err(format!("no room for another {} mediated device on this host", kind).into())
+}
+
+/// Remove mediated device `uuid`, if it still exists
+pub fn release(sys: &Path, uuid: &str) -> Result<(), Error> {
+    let remove = sys.join("bus/mdev/devices").join(uuid).join("remove");
+    if !remove.exists() {
+        return Ok(());
+    }
+
+    std::fs::write(&remove, "1")
+        .map_err(|e| format!("error removing mediated device {}: {}", uuid, e))?;
+    info!("Removed mediated device {}", uuid);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn allocate_and_release() {
+        let sys = std::env::temp_dir().join(format!("bigiron-virt-mdev-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&sys);
+
+        let types = |parent: &str| {
+            sys.join("class/mdev_bus")
+                .join(parent)
+                .join("mdev_supported_types/nvidia-63")
+        };
+        for (parent, available) in [("0000:41:00.0", "0\n"), ("0000:42:00.0", "3\n")] {
+            std::fs::create_dir_all(types(parent)).unwrap();
+            std::fs::write(types(parent).join("available_instances"), available).unwrap();
+        }
+
+        // the full parent is skipped
+        let uuid = allocate(&sys, "nvidia-63", None).unwrap();
+        let created = std::fs::read_to_string(types("0000:42:00.0").join("create")).unwrap();
+        assert_eq!(created, uuid);
+
+        let err = allocate(&sys, "nvidia-63", Some("0000:41:00.0")).unwrap_err();
+        assert!(err.to_string().contains("no room for another nvidia-63"));
+        assert!(allocate(&sys, "nvidia-99", None).is_err());
+
+        // made again with the same UUID only once it's gone
+        let made = "6a7b8c9d-0000-4000-8000-000000000001";
+        ensure(&sys, made, "nvidia-63", None).unwrap();
+        let created = std::fs::read_to_string(types("0000:42:00.0").join("create")).unwrap();
+        assert_eq!(created, made);
+
+        let dev = sys.join("bus/mdev/devices").join(&uuid);
+        std::fs::create_dir_all(&dev).unwrap();
+        ensure(&sys, &uuid, "nvidia-63", None).unwrap();
+        let created = std::fs::read_to_string(types("0000:42:00.0").join("create")).unwrap();
+        assert_eq!(created, made);
+
+        std::fs::write(dev.join("remove"), "").unwrap();
+        release(&sys, &uuid).unwrap();
+        assert_eq!(std::fs::read_to_string(dev.join("remove")).unwrap(), "1");
+
+        // already gone
+        release(&sys, "00000000-0000-0000-0000-000000000000").unwrap();
+
+        std::fs::remove_dir_all(&sys).unwrap();
+    }
+}
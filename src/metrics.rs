@@ -0,0 +1,736 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Prometheus metrics for `bigiron-virt serve`.
+//!
+//! Numbers are collected fresh on every scrape of `/metrics` and rendered
+//! in the text exposition format. Requests are answered one at a time,
+//! which is plenty for a scraper or two.
+//!
+//! With `--api` the same server manages machines for `bigiron-virt
+//! schedule` and `--host` on other hosts: `GET /machines` gives the stored
+//! specs, `GET /status` what `list` shows and `GET /machines/<id>` what
+//! `show` does, all as JSON. A YAML model POSTed to `/machines` is created
+//! and `DELETE /machines/<id>` destroys a machine. It needs `--tls`, and a
+//! posted model can't refer to files, devices or commands on the host.
+//!
+//! Machines on the host, through cloud-init's phone_home, POST their
+//! instance-id to `/ready/<name>` when they're done booting. Guests have no
+//! client certificate, so with `--tls` this is answered on a plain HTTP
+//! listener of its own, `--ready-listen`, that answers nothing else.
+
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::time::Duration;
+
+use tracing::{info, warn};
+
+use crate::api::models::Machine;
+use crate::error::Error;
+use crate::hostmanager::{MachineInfo, MachineStatus};
+use crate::hypervisor::DomainStats;
+use crate::remote::TlsServer;
+use crate::vmstore::Timestamps;
+
+// bigger model files than this aren't accepted over HTTP
+const BODY_LIMIT: usize = 1 << 20;
+
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    /// machines managed on this host
+    pub machines: usize,
+    pub machines_running: usize,
+    /// images in the image store
+    pub images: usize,
+    /// host (cpus, memory bytes), if the driver reports them
+    pub host: Option<(u32, u64)>,
+    /// stats of the running managed machines
+    pub domains: Vec<DomainStats>,
+    /// managed machines and when they were made, started and changed state
+    pub timestamps: Vec<(String, Timestamps)>,
+}
+
+type Sample = (String, f64);
+
+impl Metrics {
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        family(
+            &mut out,
+            "bigiron_virt_machines",
+            "gauge",
+            "Machines managed on this host",
+            [(String::new(), self.machines as f64)],
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machines_running",
+            "gauge",
+            "Managed machines currently running",
+            [(String::new(), self.machines_running as f64)],
+        );
+        family(
+            &mut out,
+            "bigiron_virt_images",
+            "gauge",
+            "Images in the image store",
+            [(String::new(), self.images as f64)],
+        );
+
+        if let Some((cpus, memory)) = self.host {
+            family(
+                &mut out,
+                "bigiron_virt_host_cpus",
+                "gauge",
+                "Host cpus",
+                [(String::new(), cpus as f64)],
+            );
+            family(
+                &mut out,
+                "bigiron_virt_host_memory_bytes",
+                "gauge",
+                "Host memory",
+                [(String::new(), memory as f64)],
+            );
+        }
+
+        let per_machine = |f: fn(&DomainStats) -> f64| -> Vec<Sample> {
+            self.domains
+                .iter()
+                .map(|d| (labels(&[("machine", &d.name)]), f(d)))
+                .collect()
+        };
+
+        family(
+            &mut out,
+            "bigiron_virt_machine_vcpus",
+            "gauge",
+            "Virtual cpus of the machine",
+            per_machine(|d| d.vcpus as f64),
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machine_cpu_seconds_total",
+            "counter",
+            "Cpu time used by the machine",
+            per_machine(|d| d.cpu_time_ns as f64 / 1e9),
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machine_memory_bytes",
+            "gauge",
+            "Memory currently given to the machine",
+            per_machine(|d| d.memory_bytes as f64),
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machine_memory_rss_bytes",
+            "gauge",
+            "Host memory used by the machine",
+            per_machine(|d| d.memory_rss_bytes as f64),
+        );
+
+        let per_timestamp = |f: fn(&Timestamps) -> Option<u64>| -> Vec<Sample> {
+            self.timestamps
+                .iter()
+                .filter_map(|(name, t)| Some((labels(&[("machine", name)]), f(t)? as f64)))
+                .collect()
+        };
+
+        family(
+            &mut out,
+            "bigiron_virt_machine_created_timestamp_seconds",
+            "gauge",
+            "When creating the machine began",
+            per_timestamp(|t| t.created_at),
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machine_started_timestamp_seconds",
+            "gauge",
+            "When the machine was created and first started",
+            per_timestamp(|t| t.started_at),
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machine_create_duration_seconds",
+            "gauge",
+            "How long creating the machine took",
+            per_timestamp(|t| t.started_at?.checked_sub(t.created_at?)),
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machine_state_changed_timestamp_seconds",
+            "gauge",
+            "When bigiron-virt last changed the machine's state",
+            per_timestamp(|t| t.state_changed_at),
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machine_ready_timestamp_seconds",
+            "gauge",
+            "When cloud-init in the machine was done",
+            per_timestamp(|t| t.ready_at),
+        );
+
+        let disks: Vec<_> = self
+            .domains
+            .iter()
+            .flat_map(|d| d.disks.iter().map(move |disk| (d, disk)))
+            .collect();
+        let per_disk = |f: fn(u64, u64) -> u64| -> Vec<Sample> {
+            disks
+                .iter()
+                .map(|(d, disk)| {
+                    let l = labels(&[("machine", &d.name), ("disk", &disk.name)]);
+                    (l, f(disk.read_bytes, disk.write_bytes) as f64)
+                })
+                .collect()
+        };
+
+        family(
+            &mut out,
+            "bigiron_virt_machine_disk_read_bytes_total",
+            "counter",
+            "Bytes read from the disk",
+            per_disk(|read, _| read),
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machine_disk_write_bytes_total",
+            "counter",
+            "Bytes written to the disk",
+            per_disk(|_, write| write),
+        );
+
+        let ifaces: Vec<_> = self
+            .domains
+            .iter()
+            .flat_map(|d| d.interfaces.iter().map(move |iface| (d, iface)))
+            .collect();
+        let per_iface = |f: fn(u64, u64) -> u64| -> Vec<Sample> {
+            ifaces
+                .iter()
+                .map(|(d, iface)| {
+                    let l = labels(&[("machine", &d.name), ("interface", &iface.name)]);
+                    (l, f(iface.rx_bytes, iface.tx_bytes) as f64)
+                })
+                .collect()
+        };
+
+        family(
+            &mut out,
+            "bigiron_virt_machine_network_receive_bytes_total",
+            "counter",
+            "Bytes received on the interface",
+            per_iface(|rx, _| rx),
+        );
+        family(
+            &mut out,
+            "bigiron_virt_machine_network_transmit_bytes_total",
+            "counter",
+            "Bytes transmitted on the interface",
+            per_iface(|_, tx| tx),
+        );
+
+        out
+    }
+}
+
+fn family<I>(out: &mut String, name: &str, kind: &str, help: &str, samples: I)
+where
+    I: IntoIterator<Item = Sample>,
+{
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+// `{k="v",...}` with values escaped as the exposition format requires
+fn labels(pairs: &[(&str, &str)]) -> String {
+    let pairs: Vec<_> = pairs
+        .iter()
+        .map(|(k, v)| {
+            let v = v
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", k, v)
+        })
+        .collect();
+
+    format!("{{{}}}", pairs.join(","))
+}
+
+/// What `serve` answers requests with
+pub trait Handler {
+    fn metrics(&mut self) -> Result<Metrics, Error>;
+    fn machines(&mut self) -> Result<Vec<Machine>, Error>;
+    fn list(&mut self) -> Result<Vec<MachineStatus>, Error>;
+    fn show(&mut self, id: &str) -> Result<MachineInfo, Error>;
+    /// Create the machines in model `yaml`
+    fn create(&mut self, yaml: &str) -> Result<(), Error>;
+    fn destroy(&mut self, id: &str) -> Result<(), Error>;
+    /// Record that cloud-init in machine `id`, which says its instance-id
+    /// is `instance_id`, is done
+    fn ready(&mut self, id: &str, instance_id: &str) -> Result<(), Error>;
+}
+
+// the endpoints a listener answers
+#[derive(Debug, Clone, Copy)]
+struct Routes {
+    metrics: bool,
+    ready: bool,
+    api: bool,
+}
+
+/// Answer `GET /metrics` on `listen` from `handler`, forever, and the
+/// machine endpoints too if `api`. With `tls` only clients it accepts are
+/// answered, without it guests' `/ready` posts are too.
+pub fn serve(
+    listen: &str,
+    api: bool,
+    tls: Option<&TlsServer>,
+    handler: &mut dyn Handler,
+) -> Result<(), Error> {
+    let listener =
+        TcpListener::bind(listen).map_err(|e| format!("error listening on {}: {}", listen, e))?;
+    let scheme = if tls.is_some() { "https" } else { "http" };
+    info!(
+        "Serving metrics on {}://{}/metrics",
+        scheme,
+        listener.local_addr()?
+    );
+
+    let routes = Routes {
+        metrics: true,
+        ready: tls.is_none(),
+        api,
+    };
+    serve_on(listener, routes, tls, handler)
+}
+
+/// Answer only guests' `POST /ready/<name>` on `listen` from `handler`,
+/// over plain HTTP, forever
+pub fn serve_ready(listen: &str, handler: &mut dyn Handler) -> Result<(), Error> {
+    let listener =
+        TcpListener::bind(listen).map_err(|e| format!("error listening on {}: {}", listen, e))?;
+    info!(
+        "Serving phone_home on http://{}/ready",
+        listener.local_addr()?
+    );
+
+    let routes = Routes {
+        metrics: false,
+        ready: true,
+        api: false,
+    };
+    serve_on(listener, routes, None, handler)
+}
+
+fn serve_on(
+    listener: TcpListener,
+    routes: Routes,
+    tls: Option<&TlsServer>,
+    handler: &mut dyn Handler,
+) -> Result<(), Error> {
+    for stream in listener.incoming() {
+        let result = stream.map_err(Error::from).and_then(|s| {
+            s.set_read_timeout(Some(Duration::from_secs(5)))?;
+            match tls {
+                Some(tls) => {
+                    let mut stream = tls.accept(s)?;
+                    respond(&mut stream, routes, handler)?;
+                    stream.conn.send_close_notify();
+                    Ok(stream.flush()?)
+                }
+                None => respond(s, routes, handler),
+            }
+        });
+
+        if let Err(e) = result {
+            warn!("metrics request failed: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+fn respond<S: Read + Write>(
+    mut stream: S,
+    routes: Routes,
+    handler: &mut dyn Handler,
+) -> Result<(), Error> {
+    let mut reader = BufReader::new(&mut stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // only the body length is needed, but all must be read before answering
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse()?;
+            }
+        }
+    }
+    if length > BODY_LIMIT {
+        return Err(format!("request body of {} bytes is too large", length).into());
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    drop(reader);
+
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split_once('?').map_or(path, |(p, _)| p);
+    let id = path
+        .strip_prefix("/machines/")
+        .filter(|id| !id.contains('/'));
+
+    let (status, body) = match (method, path, id) {
+        ("GET", "/metrics", _) if routes.metrics => match handler.metrics() {
+            Ok(metrics) => ("200 OK", metrics.render()),
+            Err(e) => failed(e),
+        },
+        (_, "/metrics", _) if routes.metrics => ("405 Method Not Allowed", String::new()),
+        // cloud-init's phone_home, from guests rather than other hosts
+        ("POST", _, _) if routes.ready && path.starts_with("/ready/") => {
+            let instance_id = url::form_urlencoded::parse(&body)
+                .find(|(k, _)| k == "instance_id")
+                .map(|(_, v)| v.into_owned())
+                .unwrap_or_default();
+            match handler.ready(&path["/ready/".len()..], &instance_id) {
+                Ok(_) => ("204 No Content", String::new()),
+                Err(e) => failed(e),
+            }
+        }
+        _ if !routes.api => ("404 Not Found", String::new()),
+        ("GET", "/machines", _) => json(handler.machines())?,
+        ("POST", "/machines", _) => {
+            let yaml = String::from_utf8_lossy(&body);
+            match handler.create(&yaml) {
+                Ok(_) => ("201 Created", String::new()),
+                Err(e) => failed(e),
+            }
+        }
+        ("GET", "/status", _) => json(handler.list())?,
+        ("GET", _, Some(id)) => json(handler.show(id))?,
+        ("DELETE", _, Some(id)) => match handler.destroy(id) {
+            Ok(_) => ("204 No Content", String::new()),
+            Err(e) => failed(e),
+        },
+        (_, "/machines" | "/status", _) | (_, _, Some(_)) => {
+            ("405 Method Not Allowed", String::new())
+        }
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let content_type = match path {
+        "/metrics" => "text/plain; version=0.0.4",
+        _ => "application/json",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+fn failed(e: Error) -> (&'static str, String) {
+    ("500 Internal Server Error", format!("{}\n", e))
+}
+
+fn json<T: serde::Serialize>(result: Result<T, Error>) -> Result<(&'static str, String), Error> {
+    Ok(match result {
+        Ok(value) => ("200 OK", serde_json::to_string(&value)?),
+        Err(e) => failed(e),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::TcpStream;
+
+    use crate::hypervisor::{DiskStats, InterfaceStats};
+    use crate::remote::Client;
+
+    fn sample() -> Metrics {
+        Metrics {
+            machines: 3,
+            machines_running: 1,
+            images: 2,
+            host: Some((8, 16 << 30)),
+            domains: vec![DomainStats {
+                name: "web \"01\"".to_string(),
+                cpu_time_ns: 1_500_000_000,
+                vcpus: 2,
+                memory_bytes: 1 << 30,
+                memory_rss_bytes: 512 << 20,
+                disks: vec![DiskStats {
+                    name: "vda".to_string(),
+                    read_bytes: 4096,
+                    write_bytes: 8192,
+                }],
+                interfaces: vec![InterfaceStats {
+                    name: "vnet0".to_string(),
+                    rx_bytes: 100,
+                    tx_bytes: 200,
+                }],
+            }],
+            timestamps: vec![(
+                "vm1".to_string(),
+                Timestamps {
+                    created_at: Some(1697448645),
+                    started_at: Some(1697448732),
+                    state_changed_at: Some(1697450000),
+                    ready_at: Some(1697448790),
+                },
+            )],
+        }
+    }
+
+    #[test]
+    fn render() {
+        let text = sample().render();
+
+        assert!(text.contains("# TYPE bigiron_virt_machines gauge\nbigiron_virt_machines 3\n"));
+        assert!(text.contains("bigiron_virt_images 2\n"));
+        assert!(text.contains("bigiron_virt_host_cpus 8\n"));
+        assert!(text
+            .contains("bigiron_virt_machine_cpu_seconds_total{machine=\"web \\\"01\\\"\"} 1.5\n"));
+        assert!(text.contains(
+            "bigiron_virt_machine_disk_write_bytes_total{machine=\"web \\\"01\\\"\",disk=\"vda\"} 8192\n"
+        ));
+        assert!(text.contains(
+            "bigiron_virt_machine_network_receive_bytes_total{machine=\"web \\\"01\\\"\",interface=\"vnet0\"} 100\n"
+        ));
+        assert!(text.contains("bigiron_virt_machine_create_duration_seconds{machine=\"vm1\"} 87\n"));
+        assert!(text.contains(
+            "bigiron_virt_machine_created_timestamp_seconds{machine=\"vm1\"} 1697448645\n"
+        ));
+        assert!(text.contains(
+            "bigiron_virt_machine_ready_timestamp_seconds{machine=\"vm1\"} 1697448790\n"
+        ));
+
+        // families are still declared when nothing is running
+        let idle = Metrics::default().render();
+        assert!(idle.contains("# TYPE bigiron_virt_machine_vcpus gauge\n"));
+        assert!(!idle.contains("bigiron_virt_host_cpus"));
+    }
+
+    #[derive(Default)]
+    struct Fake {
+        created: Vec<String>,
+        destroyed: Vec<String>,
+        ready: Vec<String>,
+    }
+
+    impl Handler for Fake {
+        fn metrics(&mut self) -> Result<Metrics, Error> {
+            Ok(sample())
+        }
+
+        fn machines(&mut self) -> Result<Vec<Machine>, Error> {
+            let m = Machine::builder()
+                .name("vm1")
+                .cpu(1)
+                .memory("1Gi")
+                .image(
+                    "file:///jammy.qcow2",
+                    "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+                )
+                .build()?;
+            Ok(vec![m])
+        }
+
+        fn list(&mut self) -> Result<Vec<MachineStatus>, Error> {
+            Ok(vec![MachineStatus {
+                id: "vm1".to_string(),
+                uuid: None,
+                status: "running".to_string(),
+                addresses: vec!["192.0.2.10".parse()?],
+                timestamps: Timestamps::default(),
+                health: None,
+            }])
+        }
+
+        fn show(&mut self, id: &str) -> Result<MachineInfo, Error> {
+            match id {
+                "vm1" => Ok(MachineInfo {
+                    machine: self.machines()?.remove(0),
+                    state: "running".to_string(),
+                    addresses: Vec::new(),
+                    timestamps: Timestamps::default(),
+                }),
+                _ => Err(format!("no machine {}", id).into()),
+            }
+        }
+
+        fn create(&mut self, yaml: &str) -> Result<(), Error> {
+            self.created.push(yaml.to_string());
+            Ok(())
+        }
+
+        fn destroy(&mut self, id: &str) -> Result<(), Error> {
+            self.destroyed.push(id.to_string());
+            Ok(())
+        }
+
+        fn ready(&mut self, id: &str, instance_id: &str) -> Result<(), Error> {
+            if instance_id != "6f1e2c52-0b1d-4a8e-9c4f-2d7a9b3e5f10" {
+                return Err(format!("{} isn't the instance-id of {}", instance_id, id).into());
+            }
+            self.ready.push(id.to_string());
+            Ok(())
+        }
+    }
+
+    const PLAIN: Routes = Routes {
+        metrics: true,
+        ready: true,
+        api: false,
+    };
+
+    // serve one request per call of `client` with the address
+    fn exchange<T, F>(routes: Routes, handler: &mut Fake, requests: usize, client: F) -> T
+    where
+        T: Send + 'static,
+        F: FnOnce(std::net::SocketAddr) -> T + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || client(addr));
+
+        for _ in 0..requests {
+            let (stream, _) = listener.accept().unwrap();
+            respond(stream, routes, handler).unwrap();
+        }
+        client.join().unwrap()
+    }
+
+    fn get(path: &str, routes: Routes) -> String {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+        exchange(routes, &mut Fake::default(), 1, move |addr| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        })
+    }
+
+    #[test]
+    fn http() {
+        let response = get("/metrics?x=1", PLAIN);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with(&sample().render()));
+
+        let api = Routes { api: true, ..PLAIN };
+        assert!(get("/", api).starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(get("/machines", PLAIN).starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let ready_only = Routes {
+            metrics: false,
+            ready: true,
+            api: false,
+        };
+        assert!(get("/metrics", ready_only).starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+
+    #[test]
+    fn phone_home() {
+        // as cloud-init posts it
+        let post = |routes: Routes, fake: &mut Fake, body: &str| {
+            let request = format!(
+                "POST /ready/vm1 HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            exchange(routes, fake, 1, move |addr| {
+                let mut stream = TcpStream::connect(addr).unwrap();
+                stream.write_all(request.as_bytes()).unwrap();
+                let mut response = String::new();
+                stream.read_to_string(&mut response).unwrap();
+                response
+            })
+        };
+        let body = "instance_id=6f1e2c52-0b1d-4a8e-9c4f-2d7a9b3e5f10";
+
+        let mut fake = Fake::default();
+        let response = post(PLAIN, &mut fake, body);
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert_eq!(fake.ready, ["vm1"]);
+
+        let response = post(PLAIN, &mut fake, "instance_id=other");
+        assert!(response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+
+        // not on the TLS listener, guests go to --ready-listen
+        let tls = Routes {
+            ready: false,
+            ..PLAIN
+        };
+        let response = post(tls, &mut fake, body);
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert_eq!(fake.ready, ["vm1"]);
+    }
+
+    #[test]
+    fn machines_api() {
+        let mut fake = Fake::default();
+        let routes = Routes { api: true, ..PLAIN };
+        let machines = exchange(routes, &mut fake, 6, |addr| {
+            let client = Client::new(&addr.to_string(), None).unwrap();
+            let machines = client.machines().unwrap();
+            client.create(&machines[0]).unwrap();
+
+            let list = client.list(None).unwrap();
+            assert_eq!(list[0].status, "running");
+            assert_eq!(list[0].addresses[0].to_string(), "192.0.2.10");
+            assert_eq!(client.show("vm1").unwrap().machine, machines[0]);
+            let err = client.show("vm2").err().unwrap();
+            assert_eq!(
+                err.to_string(),
+                "GET /machines/vm2 failed (500 Internal Server Error): no machine vm2"
+            );
+
+            client.destroy("vm1").unwrap();
+            machines
+        });
+
+        assert_eq!(machines[0].metadata.name, "vm1");
+        assert_eq!(fake.created, [machines[0].to_yaml().unwrap()]);
+        assert_eq!(fake.destroyed, ["vm1"]);
+    }
+}
@@ -0,0 +1,83 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+use std::net::IpAddr;
+use std::process::Command;
+
+use crate::error::Error;
+
+/// Entries of the host IPv4 ARP and IPv6 neighbor tables, as
+/// `(mac, address)` pairs with the MAC in lowercase
+pub fn table() -> Result<Vec<(String, IpAddr)>, Error> {
+    let output = Command::new("ip")
+        .args(["neigh", "show"])
+        .output()
+        .map_err(|e| format!("error executing ip: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!("ip neigh failed: {}", output.status).into());
+    }
+
+    Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// parses `ip neigh show` lines like
+// `192.168.1.5 dev br0 lladdr 52:54:00:12:34:56 REACHABLE`
+fn parse(out: &str) -> Vec<(String, IpAddr)> {
+    let mut entries = Vec::new();
+
+    for line in out.lines() {
+        let fields: Vec<_> = line.split_whitespace().collect();
+
+        // entries that failed resolution have no lladdr
+        if fields.contains(&"FAILED") || fields.contains(&"INCOMPLETE") {
+            continue;
+        }
+
+        let addr = fields.first().and_then(|a| a.parse::<IpAddr>().ok());
+        let mac = fields
+            .iter()
+            .position(|f| *f == "lladdr")
+            .and_then(|i| fields.get(i + 1));
+
+        if let (Some(addr), Some(mac)) = (addr, mac) {
+            entries.push((mac.to_lowercase(), addr));
+        }
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_neigh() {
+        let out = "192.168.122.10 dev br0 lladdr 52:54:00:AB:cd:01 REACHABLE
+10.0.0.1 dev eth0 lladdr 02:fc:00:00:00:05 STALE
+10.0.0.9 dev eth0  FAILED
+fe80::5054:ff:feab:cd01 dev br0 lladdr 52:54:00:ab:cd:01 router DELAY
+";
+
+        let entries = parse(out);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].0, "52:54:00:ab:cd:01");
+        assert_eq!(entries[0].1.to_string(), "192.168.122.10");
+        assert_eq!(entries[2].1.to_string(), "fe80::5054:ff:feab:cd01");
+    }
+}
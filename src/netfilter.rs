@@ -0,0 +1,180 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Port forwards to machines on NAT'd libvirt networks, as iptables rules.
+//!
+//! A forward is a DNAT rule in the nat table's PREROUTING chain, and in
+//! OUTPUT for connections from the host itself, plus a FORWARD rule ahead
+//! of libvirt's own, which only let in replies to connections the guest
+//! made. Every rule carries a comment naming its machine, so they're found
+//! again for removal without keeping a list.
+
+use std::net::Ipv4Addr;
+use std::process::Command;
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::api::models::PortForward;
+use crate::error::Error;
+use crate::process::{self, Policy};
+
+const IPTABLES: Policy = Policy::new(Duration::from_secs(30));
+
+/// Forward `forwards` to `guest`, the address of machine `name`
+pub fn add(name: &str, guest: Ipv4Addr, forwards: &[PortForward]) -> Result<(), Error> {
+    for fwd in forwards {
+        for rule in rules(name, guest, fwd) {
+            iptables(&rule)?;
+        }
+        info!(
+            "Forwarding host port {}/{} to {}:{}",
+            fwd.host_port,
+            fwd.protocol.unwrap_or_default(),
+            guest,
+            fwd.guest_port
+        );
+    }
+
+    Ok(())
+}
+
+/// Remove the forwards of machine `name`, if any
+pub fn remove(name: &str) -> Result<(), Error> {
+    for table in ["nat", "filter"] {
+        let out = iptables(&["-t", table, "-S"].map(String::from))?;
+        for rule in tagged(table, &String::from_utf8_lossy(&out), name) {
+            iptables(&rule)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn iptables(args: &[String]) -> Result<Vec<u8>, Error> {
+    let mut cmd = Command::new("iptables");
+    // waits for the xtables lock rather than failing
+    cmd.arg("-w").args(args);
+    Ok(process::run(&mut cmd, &IPTABLES)?.stdout)
+}
+
+fn comment(name: &str) -> String {
+    format!("bigiron-virt:{}", name)
+}
+
+// iptables arguments adding the rules of `fwd`
+fn rules(name: &str, guest: Ipv4Addr, fwd: &PortForward) -> Vec<Vec<String>> {
+    let proto = fwd.protocol.unwrap_or_default().to_string();
+    let host_port = fwd.host_port.to_string();
+    let guest_port = fwd.guest_port.to_string();
+    let destination = format!("{}:{}", guest, fwd.guest_port);
+    let comment = comment(name);
+
+    // only connections to the host's own addresses, not traffic it routes
+    // to the same port elsewhere
+    let dnat = |chain: &'static str| {
+        let mut rule = vec!["-t", "nat", "-A", chain];
+        rule.extend(["-p", &proto, "--dport", &host_port]);
+        rule.extend(["-m", "addrtype", "--dst-type", "LOCAL"]);
+        rule.extend(["-m", "comment", "--comment", &comment]);
+        rule.extend(["-j", "DNAT", "--to-destination", &destination]);
+        rule
+    };
+
+    let guest = format!("{}/32", guest);
+    let mut accept = vec!["-t", "filter", "-I", "FORWARD", "-d", &guest];
+    accept.extend(["-p", &proto, "--dport", &guest_port]);
+    accept.extend(["-m", "comment", "--comment", &comment, "-j", "ACCEPT"]);
+
+    [dnat("PREROUTING"), dnat("OUTPUT"), accept]
+        .into_iter()
+        .map(|rule| rule.into_iter().map(String::from).collect())
+        .collect()
+}
+
+// iptables arguments deleting the rules of machine `name` in `listing`,
+// the `iptables -S` output of `table`
+fn tagged(table: &str, listing: &str, name: &str) -> Vec<Vec<String>> {
+    let comment = comment(name);
+    listing
+        .lines()
+        .filter_map(|line| {
+            let mut args: Vec<String> = line
+                .split_whitespace()
+                .map(|a| a.trim_matches('"').to_string())
+                .collect();
+            if args.first()? != "-A" || !args.contains(&comment) {
+                return None;
+            }
+            args[0] = String::from("-D");
+            args.splice(0..0, [String::from("-t"), table.to_string()]);
+            Some(args)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::api::models::Protocol;
+
+    #[test]
+    fn forward_rules() {
+        let fwd = PortForward {
+            host_port: 2222,
+            guest_port: 22,
+            protocol: None,
+        };
+        let added: Vec<String> = rules("vm1", Ipv4Addr::new(192, 168, 122, 10), &fwd)
+            .iter()
+            .map(|r| r.join(" "))
+            .collect();
+        assert_eq!(
+            added,
+            [
+                "-t nat -A PREROUTING -p tcp --dport 2222 -m addrtype --dst-type LOCAL -m comment --comment bigiron-virt:vm1 -j DNAT --to-destination 192.168.122.10:22",
+                "-t nat -A OUTPUT -p tcp --dport 2222 -m addrtype --dst-type LOCAL -m comment --comment bigiron-virt:vm1 -j DNAT --to-destination 192.168.122.10:22",
+                "-t filter -I FORWARD -d 192.168.122.10/32 -p tcp --dport 22 -m comment --comment bigiron-virt:vm1 -j ACCEPT",
+            ]
+        );
+
+        let udp = PortForward {
+            protocol: Some(Protocol::Udp),
+            ..fwd
+        };
+        let added = rules("vm1", Ipv4Addr::new(192, 168, 122, 10), &udp);
+        assert!(added.iter().all(|r| r.contains(&String::from("udp"))));
+    }
+
+    #[test]
+    fn tagged_rules() {
+        let listing = "-P PREROUTING ACCEPT
+-A PREROUTING -p tcp -m tcp --dport 2222 -m comment --comment \"bigiron-virt:vm1\" -j DNAT --to-destination 192.168.122.10:22
+-A PREROUTING -p tcp -m tcp --dport 2223 -m comment --comment \"bigiron-virt:vm10\" -j DNAT --to-destination 192.168.122.11:22
+-A LIBVIRT_PRT -s 192.168.122.0/24 -d 224.0.0.0/24 -j RETURN
+";
+        let deleted: Vec<String> = tagged("nat", listing, "vm1")
+            .iter()
+            .map(|r| r.join(" "))
+            .collect();
+        assert_eq!(
+            deleted,
+            ["-t nat -D PREROUTING -p tcp -m tcp --dport 2222 -m comment --comment bigiron-virt:vm1 -j DNAT --to-destination 192.168.122.10:22"]
+        );
+    }
+}
@@ -16,8 +16,10 @@
 //  USA
 
 use std::collections::HashMap as Map;
+use std::net::Ipv4Addr;
 
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use serde_yaml;
 
 use crate::api;
@@ -52,6 +54,75 @@ pub fn build_net_config(nics: &Option<Vec<api::models::Nic>>) -> Result<Vec<u8>,
     Ok(buf)
 }
 
+/// OpenStack `network_data.json` for `nics`, the network config of
+/// config drives in the OpenStack layout. Empty without nics.
+pub fn build_network_data(nics: &Option<Vec<api::models::Nic>>) -> Result<Vec<u8>, Error> {
+    use api::models::AddressKind;
+
+    let nics = match nics {
+        Some(n) if !n.is_empty() => n,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut links = Vec::new();
+    let mut networks = Vec::new();
+    let mut services = Vec::new();
+
+    for (i, nic) in nics.iter().enumerate() {
+        let link = format!("id{}", i);
+        let mut l = json!({ "id": link, "type": "phy", "ethernet_mac_address": nic.macaddress });
+        if let Some(ref name) = nic.name {
+            l["name"] = json!(name);
+        }
+        links.push(l);
+
+        let v4static = match nic.address {
+            AddressKind::IPv6SLAAC => None,
+            AddressKind::IPv4Static(ref v4static) => Some(v4static),
+            AddressKind::Pool(ref p) => Some(
+                p.assigned
+                    .as_ref()
+                    .ok_or_else(|| format!("no address assigned from pool {}", p.pool))?,
+            ),
+        };
+
+        match v4static {
+            None => networks.push(json!({
+                "id": format!("network{}", i),
+                "link": link,
+                "type": "ipv6_slaac",
+            })),
+            Some(v4static) => {
+                let (addr, netmask) = split_cidr(&v4static.addr)?;
+                networks.push(json!({
+                    "id": format!("network{}", i),
+                    "link": link,
+                    "type": "ipv4",
+                    "ip_address": addr,
+                    "netmask": netmask,
+                    "routes": [{ "network": "0.0.0.0", "netmask": "0.0.0.0", "gateway": v4static.gateway }],
+                }));
+                for ns in &v4static.nameservers {
+                    services.push(json!({ "type": "dns", "address": ns }));
+                }
+            }
+        }
+    }
+
+    let data = json!({ "links": links, "networks": networks, "services": services });
+    Ok(serde_json::to_vec_pretty(&data)?)
+}
+
+// address and dotted netmask of IPv4 CIDR `addr`, e.g. "10.0.0.5/24"
+fn split_cidr(addr: &str) -> Result<(Ipv4Addr, Ipv4Addr), Error> {
+    let bad = || format!("'{}' is not an IPv4 address with a prefix length", addr);
+    let (ip, prefix) = addr.split_once('/').ok_or_else(bad)?;
+    let ip: Ipv4Addr = ip.parse().map_err(|_| bad())?;
+    let prefix: u32 = prefix.parse().ok().filter(|p| *p <= 32).ok_or_else(bad)?;
+    let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+    Ok((ip, Ipv4Addr::from(mask)))
+}
+
 impl TryFrom<&api::models::Nic> for Ethernet {
     type Error = Error;
 
@@ -59,12 +130,24 @@ impl TryFrom<&api::models::Nic> for Ethernet {
         use api::models::AddressKind;
 
         let mut s = Ethernet::new_with_mac(&nic.macaddress);
+        s.set_name = nic.name.clone();
+
+        let v4static = match nic.address {
+            AddressKind::IPv6SLAAC => None,
+            AddressKind::IPv4Static(ref v4static) => Some(v4static),
+            // assigned on create
+            AddressKind::Pool(ref p) => Some(
+                p.assigned
+                    .as_ref()
+                    .ok_or_else(|| format!("no address assigned from pool {}", p.pool))?,
+            ),
+        };
 
-        match nic.address {
-            AddressKind::IPv6SLAAC => {
+        match v4static {
+            None => {
                 s.dhcp6 = Some(true);
             }
-            AddressKind::IPv4Static(ref v4static) => {
+            Some(v4static) => {
                 s.addresses = Some(vec![v4static.addr.clone()]);
                 s.gateway4 = Some(v4static.gateway.clone());
 
@@ -178,6 +261,58 @@ struct Route {
 mod test {
     use super::*;
 
+    #[test]
+    fn set_name() {
+        let mut named = api::models::Nic::bridge("br0").with_name("uplink0");
+        named.macaddress = "00:16:3e:00:00:01".to_string();
+        let mut unnamed = api::models::Nic::bridge("br1");
+        unnamed.macaddress = "00:16:3e:00:00:02".to_string();
+
+        let buf = build_net_config(&Some(vec![named, unnamed])).unwrap();
+        let conf: NetworkConfig = serde_yaml::from_slice(&buf).unwrap();
+
+        let id0 = &conf.network.ethernets["id0"];
+        assert_eq!(id0.set_name.as_deref(), Some("uplink0"));
+        assert_eq!(id0.r#match.macaddress.as_deref(), Some("00:16:3e:00:00:01"));
+        assert_eq!(conf.network.ethernets["id1"].set_name, None);
+        assert!(String::from_utf8(buf)
+            .unwrap()
+            .contains("set-name: uplink0"));
+    }
+
+    #[test]
+    fn openstack_network_data() {
+        let mut nic = api::models::Nic::bridge("br0").with_name("eth0");
+        nic.macaddress = "00:16:3e:00:00:01".to_string();
+        nic.address = api::models::AddressKind::IPv4Static(api::models::IPv4Static {
+            addr: "10.0.0.5/24".to_string(),
+            gateway: "10.0.0.1".to_string(),
+            nameservers: vec!["10.0.0.2".to_string()],
+        });
+        let mut slaac = api::models::Nic::bridge("br1");
+        slaac.macaddress = "00:16:3e:00:00:02".to_string();
+
+        let buf = build_network_data(&Some(vec![nic, slaac])).unwrap();
+        let data: serde_json::Value = serde_json::from_slice(&buf).unwrap();
+        assert_eq!(
+            data["links"][0]["ethernet_mac_address"],
+            "00:16:3e:00:00:01"
+        );
+        assert_eq!(data["links"][0]["name"], "eth0");
+        assert_eq!(data["networks"][0]["ip_address"], "10.0.0.5");
+        assert_eq!(data["networks"][0]["netmask"], "255.255.255.0");
+        assert_eq!(data["networks"][0]["routes"][0]["gateway"], "10.0.0.1");
+        assert_eq!(data["networks"][1]["type"], "ipv6_slaac");
+        assert_eq!(data["services"][0]["address"], "10.0.0.2");
+
+        assert!(build_network_data(&None).unwrap().is_empty());
+        assert_eq!(
+            split_cidr("10.1.2.3/0").unwrap().1,
+            Ipv4Addr::new(0, 0, 0, 0)
+        );
+        assert!(split_cidr("10.1.2.3").is_err());
+    }
+
     #[test]
     fn deserialize() {
         let sample = "
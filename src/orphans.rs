@@ -0,0 +1,188 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Leftovers `bigiron-virt gc` finds where its records and the host
+//! disagree.
+//!
+//! Domains are transient and only exist while a machine runs, so a stopped
+//! machine having none is normal. What isn't: a domain on an instance disk
+//! with no instance around it, an instance a create left behind part way,
+//! and images in the repo no machine was made from. Named images and ones
+//! imported within `IMAGE_GRACE` are kept for machines still to come.
+
+use std::time::Duration;
+
+use crate::backup::utc_timestamp;
+use crate::image::repo::ImageInfo;
+use crate::vmstore::{Phase, Timestamps};
+
+/// How long a create has to be stuck in one phase to count as abandoned
+/// rather than still going
+pub const STALE: Duration = Duration::from_secs(60 * 60);
+
+/// How long after being imported an unused image is kept, for the create
+/// or `image pull` ahead of one to use it
+pub const IMAGE_GRACE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Orphan {
+    /// a domain on the disk of an instance that no longer exists, destroyed
+    /// to fix
+    Domain(String),
+    /// an instance whose domain runs though its create never finished,
+    /// marked running to fix
+    Unadopted(String),
+    /// an instance an unfinished create left without a domain, removed to
+    /// fix
+    Instance(String),
+    /// an unnamed image file in the repo no machine uses, imported longer
+    /// than `IMAGE_GRACE` ago, removed to fix
+    Image(String),
+}
+
+impl std::fmt::Display for Orphan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Orphan::Domain(name) => write!(f, "domain {} has no instance", name),
+            Orphan::Unadopted(id) => write!(f, "{} is running but its create never finished", id),
+            Orphan::Instance(id) => write!(f, "{} was left behind by an unfinished create", id),
+            Orphan::Image(file) => write!(f, "image {} isn't used by any machine", file),
+        }
+    }
+}
+
+/// What instance `id` is an orphan as, if anything, given whether its
+/// machine could be read, its phase, whether it has a domain and when it
+/// last changed state, in seconds since the epoch as `now`
+pub(crate) fn instance(
+    id: &str,
+    has_machine: bool,
+    phase: Option<&Phase>,
+    defined: bool,
+    timestamps: &Timestamps,
+    now: u64,
+) -> Option<Orphan> {
+    let stale = timestamps
+        .state_changed_at
+        .is_none_or(|t| now.saturating_sub(t) >= STALE.as_secs());
+
+    match (phase, has_machine) {
+        // failed creates stay until destroyed, and instances from before
+        // phases were kept have none
+        (Some(Phase::Running | Phase::Error(_)), _) | (None, true) => None,
+        _ if !stale => None,
+        // the domain is started in this phase, only marking it was missed
+        (Some(Phase::DefiningDomain), true) if defined => Some(Orphan::Unadopted(id.to_string())),
+        _ if !defined => Some(Orphan::Instance(id.to_string())),
+        _ => None,
+    }
+}
+
+/// Whether `image`, used by no machine, is an orphan, with `now` in
+/// seconds since the epoch
+pub(crate) fn image(image: &ImageInfo, now: u64) -> Option<Orphan> {
+    // a name is kept for `spec.image.name` to refer to
+    if image.name.is_some() || !image.aliases.is_empty() {
+        return None;
+    }
+    // timestamps of the same form sort by time
+    let cutoff = utc_timestamp(now.saturating_sub(IMAGE_GRACE.as_secs()));
+    match image.imported_at {
+        Some(ref at) if *at < cutoff => Some(Orphan::Image(image.file_name())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn instances() {
+        let now = 10_000;
+        let old = Timestamps {
+            created_at: Some(1_000),
+            state_changed_at: Some(1_000),
+            ..Default::default()
+        };
+        let recent = Timestamps {
+            state_changed_at: Some(9_000),
+            ..old
+        };
+        let building = Some(&Phase::CreatingDisk);
+        let defining = Some(&Phase::DefiningDomain);
+
+        // stopped machines have no domain
+        assert_eq!(
+            instance("vm1", true, Some(&Phase::Running), false, &old, now),
+            None
+        );
+        assert_eq!(instance("vm1", true, None, false, &old, now), None);
+        let failed = Phase::Error(String::from("no space left"));
+        assert_eq!(instance("vm1", true, Some(&failed), false, &old, now), None);
+
+        assert_eq!(
+            instance("vm1", true, building, false, &old, now),
+            Some(Orphan::Instance(String::from("vm1")))
+        );
+        assert_eq!(
+            instance("vm1", false, None, false, &old, now),
+            Some(Orphan::Instance(String::from("vm1")))
+        );
+        // maybe still being created
+        assert_eq!(instance("vm1", true, building, false, &recent, now), None);
+
+        assert_eq!(
+            instance("vm1", true, defining, true, &old, now),
+            Some(Orphan::Unadopted(String::from("vm1")))
+        );
+        assert_eq!(instance("vm1", true, defining, true, &recent, now), None);
+        assert_eq!(instance("vm1", true, building, true, &old, now), None);
+    }
+
+    #[test]
+    fn images() {
+        let now = 1_700_000_000;
+        let old = ImageInfo {
+            hash: String::from("754129c5"),
+            format: String::from("qcow2"),
+            imported_at: Some(utc_timestamp(now - IMAGE_GRACE.as_secs() - 1)),
+            ..Default::default()
+        };
+        assert_eq!(
+            image(&old, now),
+            Some(Orphan::Image(String::from("754129c5.qcow2")))
+        );
+
+        let recent = ImageInfo {
+            imported_at: Some(utc_timestamp(now - 60)),
+            ..old.clone()
+        };
+        assert_eq!(image(&recent, now), None);
+
+        let named = ImageInfo {
+            name: Some(String::from("ubuntu-22.04")),
+            ..old.clone()
+        };
+        assert_eq!(image(&named, now), None);
+        let aliased = ImageInfo {
+            aliases: vec![String::from("jammy")],
+            ..old
+        };
+        assert_eq!(image(&aliased, now), None);
+    }
+}
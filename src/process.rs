@@ -0,0 +1,311 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Running external programs such as `mkisofs` and `qemu-img`.
+//!
+//! Each run has a time limit, after which the program is killed, and is
+//! retried a few times if it failed in a way that can pass, like a timeout
+//! or an image lock held for a moment by another process. Failures are a
+//! `CommandError` carrying the tail of the program's stderr.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, instrument, warn};
+
+// at most this much stderr is kept in an error, from the end
+const STDERR_LIMIT: usize = 2048;
+
+// stderr that says trying again may work
+const TRANSIENT: &[&str] = &[
+    "Failed to get \"write\" lock",
+    "Failed to get shared \"write\" lock",
+    "Resource temporarily unavailable",
+    "Device or resource busy",
+];
+
+/// How long a program may run and how often it's retried
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    pub timeout: Duration,
+    /// further attempts after a transient failure
+    pub retries: u32,
+    pub retry_delay: Duration,
+}
+
+impl Policy {
+    pub const fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            retries: 2,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+
+    pub const fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(60))
+    }
+}
+
+#[derive(Debug)]
+pub enum CommandError {
+    /// the program couldn't be started, e.g. it isn't installed
+    Spawn {
+        program: String,
+        source: io::Error,
+    },
+    TimedOut {
+        program: String,
+        after: Duration,
+    },
+    Failed {
+        program: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+}
+
+impl CommandError {
+    /// Whether running the program again may work
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CommandError::Spawn { .. } => false,
+            CommandError::TimedOut { .. } => true,
+            CommandError::Failed { stderr, .. } => TRANSIENT.iter().any(|t| stderr.contains(t)),
+        }
+    }
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Spawn { program, source } => {
+                write!(f, "error executing {}: {}", program, source)
+            }
+            CommandError::TimedOut { program, after } => {
+                write!(f, "{} timed out after {}s", program, after.as_secs())
+            }
+            CommandError::Failed {
+                program,
+                status,
+                stderr,
+            } if stderr.is_empty() => write!(f, "{} failed ({})", program, status),
+            CommandError::Failed {
+                program,
+                status,
+                stderr,
+            } => write!(f, "{} failed ({}): {}", program, status, stderr),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CommandError::Spawn { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Run `cmd` to completion under `policy`, returning its output if it
+/// exited successfully
+#[instrument(name = "command", skip_all, fields(program = %describe(cmd)))]
+pub fn run(cmd: &mut Command, policy: &Policy) -> Result<Output, CommandError> {
+    let mut attempt = 0;
+    loop {
+        match run_once(cmd, policy.timeout) {
+            Err(e) if e.is_transient() && attempt < policy.retries => {
+                attempt += 1;
+                warn!("{}, retrying ({}/{})", e, attempt, policy.retries);
+                thread::sleep(policy.retry_delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+fn run_once(cmd: &mut Command, timeout: Duration) -> Result<Output, CommandError> {
+    let program = describe(cmd);
+    debug!("Running: {:?}", cmd);
+
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| CommandError::Spawn {
+            program: program.clone(),
+            source,
+        })?;
+
+    // drained alongside, a full pipe would stall the program
+    let stdout = drain(child.stdout.take());
+    let stderr = drain(child.stderr.take());
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break status,
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(CommandError::TimedOut {
+                    program,
+                    after: timeout,
+                });
+            }
+            Ok(None) => thread::sleep(Duration::from_millis(20)),
+            Err(source) => return Err(CommandError::Spawn { program, source }),
+        }
+    };
+
+    let stdout = stdout.join().unwrap_or_default();
+    let stderr = stderr.join().unwrap_or_default();
+
+    if !status.success() {
+        return Err(CommandError::Failed {
+            program,
+            status,
+            stderr: truncate(&String::from_utf8_lossy(&stderr)),
+        });
+    }
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+fn drain<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+// program name and subcommand, e.g. "qemu-img convert"
+fn describe(cmd: &Command) -> String {
+    let program = Path::new(cmd.get_program())
+        .file_name()
+        .unwrap_or(cmd.get_program())
+        .to_string_lossy()
+        .into_owned();
+
+    match cmd.get_args().next().map(|a| a.to_string_lossy()) {
+        Some(arg) if !arg.starts_with('-') && !arg.contains('/') => {
+            format!("{} {}", program, arg)
+        }
+        _ => program,
+    }
+}
+
+fn truncate(stderr: &str) -> String {
+    let stderr = stderr.trim();
+    if stderr.len() <= STDERR_LIMIT {
+        return stderr.to_string();
+    }
+
+    let mut start = stderr.len() - STDERR_LIMIT;
+    while !stderr.is_char_boundary(start) {
+        start += 1;
+    }
+    format!("...{}", &stderr[start..])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    #[test]
+    fn runs() {
+        let out = run(&mut sh("echo hi"), &Policy::default()).unwrap();
+        assert_eq!(out.stdout, b"hi\n");
+
+        let err = run(&mut sh("echo bad input >&2; exit 3"), &Policy::default()).unwrap_err();
+        assert!(!err.is_transient());
+        assert_eq!(err.to_string(), "sh failed (exit status: 3): bad input");
+
+        let err = run(
+            &mut Command::new("/nonexistent/qemu-img"),
+            &Policy::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, CommandError::Spawn { .. }));
+        assert!(err.to_string().starts_with("error executing qemu-img: "));
+    }
+
+    #[test]
+    fn timeouts_and_retries() {
+        let policy = Policy {
+            timeout: Duration::from_millis(200),
+            retries: 1,
+            retry_delay: Duration::ZERO,
+        };
+        let started = Instant::now();
+        let err = run(&mut sh("sleep 5"), &policy).unwrap_err();
+        assert!(matches!(err, CommandError::TimedOut { .. }));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        // fails with a lock error the first time only
+        let dir = std::env::temp_dir().join(format!("bigiron-virt-process-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = format!(
+            "if [ -e {0}/ran ]; then echo ok; else touch {0}/ran; echo 'Failed to get \"write\" lock' >&2; exit 1; fi",
+            dir.display()
+        );
+        let out = run(&mut sh(&script), &policy).unwrap();
+        assert_eq!(out.stdout, b"ok\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn truncates_stderr() {
+        let long = "x".repeat(STDERR_LIMIT * 2);
+        let cut = truncate(&long);
+        assert_eq!(cut.len(), STDERR_LIMIT + 3);
+        assert!(cut.starts_with("..."));
+
+        assert_eq!(describe(&Command::new("/usr/bin/qemu-img")), "qemu-img");
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("convert").arg("-q");
+        assert_eq!(describe(&cmd), "qemu-img convert");
+    }
+}
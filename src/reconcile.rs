@@ -0,0 +1,441 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Keeping this host's machines to the Machine resources declared in a
+//! directory, for `bigiron-virt reconcile`.
+//!
+//! Each pass reads every `.yaml` and `.yml` file in the directory, creates
+//! the declared machines that don't exist and, when pruning, destroys the
+//! machines it created that aren't declared any more, as their `OWNER_LABEL`
+//! says. A file that can't be read or is invalid fails the whole pass, so
+//! its machines are never pruned for it, and a directory declaring nothing
+//! isn't pruned from at all.
+//!
+//! Machines that exist are left as they are, a changed spec isn't applied.
+//! One whose create failed stays in its error phase for `show` to explain,
+//! and is created again once it has been destroyed. `plan` reports such
+//! differences, along with what a pass would create and destroy.
+
+use std::collections::HashMap as Map;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{info, warn};
+
+use crate::api::models::{Machine, MachineClass, Resource, Selector};
+use crate::api::{classes_from_yaml, resources_with_classes};
+use crate::config::{HostConfig, SpecDefaults};
+use crate::error::Error;
+use crate::hostmanager::HostManager;
+
+/// Label reconcile puts on the machines it creates, `reconcile`, so only
+/// those are pruned
+pub const OWNER_LABEL: &str = "bigiron-virt/owner";
+
+pub struct ReconcileOptions {
+    pub dir: PathBuf,
+    /// destroy machines reconcile created that aren't declared
+    pub prune: bool,
+    /// time between passes, a single pass if not set
+    pub interval: Option<Duration>,
+}
+
+/// Machines a pass creates and destroys
+#[derive(Debug, Default, PartialEq)]
+pub struct Actions {
+    pub create: Vec<String>,
+    pub prune: Vec<String>,
+}
+
+/// Reconcile every `opts.interval` until the process is stopped, logging
+/// passes that fail, or once if there's no interval, returning what was
+/// done
+pub fn run(opts: &ReconcileOptions) -> Result<Actions, Error> {
+    let Some(interval) = opts.interval else {
+        return pass(opts);
+    };
+
+    info!("Reconciling {:?} every {}s", opts.dir, interval.as_secs());
+    loop {
+        if let Err(e) = pass(opts) {
+            warn!("Reconciling {:?} failed: {}", opts.dir, e);
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+fn pass(opts: &ReconcileOptions) -> Result<Actions, Error> {
+    let declared = declared(&opts.dir, &HostConfig::load()?.defaults)?;
+    check_prune(&declared, opts.prune)?;
+
+    let mut hm = HostManager::new()?;
+    let existing: Vec<String> = hm.list_machines(None)?.into_iter().map(|m| m.id).collect();
+    let owned = hm.select_machines(&owner_selector())?;
+    let actions = diff(&declared, &existing, &owned, opts.prune);
+
+    // one failure doesn't hold up the other machines
+    let mut failed = Vec::new();
+    for mut machine in declared {
+        let name = machine.metadata.name.clone();
+        if !actions.create.contains(&name) {
+            continue;
+        }
+
+        info!("Creating declared machine {}", name);
+        machine
+            .metadata
+            .labels
+            .get_or_insert_with(Map::new)
+            .insert(OWNER_LABEL.to_string(), String::from("reconcile"));
+        if let Err(e) = hm.create_machine(&mut machine) {
+            warn!("Error creating {}: {}", name, e);
+            failed.push(name);
+        }
+    }
+    for id in &actions.prune {
+        info!("Destroying undeclared machine {}", id);
+        if let Err(e) = hm.destroy_machine(id) {
+            warn!("Error destroying {}: {}", id, e);
+            failed.push(id.clone());
+        }
+    }
+
+    if !failed.is_empty() {
+        return Err(format!("failed to reconcile {}", failed.join(", ")).into());
+    }
+    Ok(actions)
+}
+
+/// The machines declared in the model files in `dir` with `defaults`
+/// filled in, checked and with `userdataFile` paths relative to `dir`.
+/// Machine classes declared in any of the files can be used in all of them.
+pub fn declared(dir: &Path, defaults: &SpecDefaults) -> Result<Vec<Machine>, Error> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(dir)
+        .map_err(|e| format!("error reading {:?}: {}", dir, e))?
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| is_model_file(p))
+        .collect();
+    files.sort();
+
+    let mut models = Vec::new();
+    let mut classes: Vec<MachineClass> = Vec::new();
+    let mut class_in: Map<String, PathBuf> = Map::new();
+    for file in files {
+        let yaml = std::fs::read_to_string(&file)
+            .map_err(|e| format!("error reading model file {:?}: {}", file, e))?;
+        for class in classes_from_yaml(&yaml).map_err(|e| format!("{:?}: {}", file, e))? {
+            if let Some(other) = class_in.insert(class.metadata.name.clone(), file.clone()) {
+                return Err(format!(
+                    "machine class {} is declared in both {:?} and {:?}",
+                    class.metadata.name, other, file
+                )
+                .into());
+            }
+            classes.push(class);
+        }
+        models.push((file, yaml));
+    }
+
+    let mut machines = Vec::new();
+    let mut declared_in: Map<String, PathBuf> = Map::new();
+    for (file, yaml) in models {
+        let resources =
+            resources_with_classes(&yaml, &classes).map_err(|e| format!("{:?}: {}", file, e))?;
+
+        for res in resources {
+            let Resource::Machine(mut m) = res;
+            m.apply_defaults(defaults);
+            m.validate().map_err(|e| format!("{:?}: {}", file, e))?;
+            m.resolve_files(dir)?;
+
+            if let Some(other) = declared_in.insert(m.metadata.name.clone(), file.clone()) {
+                return Err(format!(
+                    "{} is declared in both {:?} and {:?}",
+                    m.metadata.name, other, file
+                )
+                .into());
+            }
+            machines.push(m);
+        }
+    }
+
+    Ok(machines)
+}
+
+// editors' backup and lock files are skipped along with anything else
+fn is_model_file(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .is_none_or(|n| n.starts_with('.'));
+    let yaml = matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("yaml" | "yml")
+    );
+    path.is_file() && yaml && !hidden
+}
+
+fn owner_selector() -> Selector {
+    let selector = format!("{}=reconcile", OWNER_LABEL);
+    selector
+        .parse()
+        .expect("the owner label is a valid selector")
+}
+
+// an empty directory, or one mounted late, would otherwise prune every
+// machine reconcile made
+fn check_prune(declared: &[Machine], prune: bool) -> Result<(), Error> {
+    if prune && declared.is_empty() {
+        return Err("no machines are declared, refusing to prune them all".into());
+    }
+    Ok(())
+}
+
+/// What to do to bring the `existing` machines to `declared`, leaving
+/// undeclared ones unless `prune` and they're among the `owned` ones
+/// reconcile created
+pub fn diff(declared: &[Machine], existing: &[String], owned: &[String], prune: bool) -> Actions {
+    let create = declared
+        .iter()
+        .map(|m| m.metadata.name.clone())
+        .filter(|name| !existing.contains(name))
+        .collect();
+
+    let prune = match prune {
+        true => existing
+            .iter()
+            .filter(|id| owned.contains(id))
+            .filter(|id| !declared.iter().any(|m| m.metadata.name == **id))
+            .cloned()
+            .collect(),
+        false => Vec::new(),
+    };
+
+    Actions { create, prune }
+}
+
+/// A field a model sets to something other than what the machine has
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct FieldChange {
+    /// e.g. spec.nics[0].parent
+    pub path: String,
+    pub current: Value,
+    pub declared: Value,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct MachineChange {
+    pub name: String,
+    pub fields: Vec<FieldChange>,
+}
+
+/// How declared machines differ from the ones on the host
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct StateDiff {
+    pub create: Vec<String>,
+    /// existing machines declared differently, which creating leaves as
+    /// they are
+    pub change: Vec<MachineChange>,
+    pub destroy: Vec<String>,
+}
+
+/// Compare `declared` machines to the stored specs of the `existing` ones,
+/// counting undeclared machines reconcile created as destroyed if `prune`
+pub fn state_diff(
+    declared: &[Machine],
+    existing: &[Machine],
+    prune: bool,
+) -> Result<StateDiff, Error> {
+    check_prune(declared, prune)?;
+
+    let ids: Vec<String> = existing.iter().map(|m| m.metadata.name.clone()).collect();
+    let owner = owner_selector();
+    let owned: Vec<String> = existing
+        .iter()
+        .filter(|m| owner.matches(&m.metadata))
+        .map(|m| m.metadata.name.clone())
+        .collect();
+    let actions = diff(declared, &ids, &owned, prune);
+
+    let mut change = Vec::new();
+    for machine in declared {
+        let name = &machine.metadata.name;
+        let Some(current) = existing.iter().find(|m| m.metadata.name == *name) else {
+            continue;
+        };
+
+        let mut fields = Vec::new();
+        compare(
+            "",
+            &serde_json::to_value(current)?,
+            &serde_json::to_value(machine)?,
+            &mut fields,
+        );
+        if !fields.is_empty() {
+            change.push(MachineChange {
+                name: name.clone(),
+                fields,
+            });
+        }
+    }
+
+    Ok(StateDiff {
+        create: actions.create,
+        change,
+        destroy: actions.prune,
+    })
+}
+
+// record where `declared` differs from `current`, leaving fields it doesn't
+// set (or sets empty) to whatever creating the machine filled in
+fn compare(path: &str, current: &Value, declared: &Value, changes: &mut Vec<FieldChange>) {
+    match (current, declared) {
+        (_, Value::Null) => {}
+        (_, Value::String(s)) if s.is_empty() => {}
+        (Value::Object(current), Value::Object(declared)) => {
+            for (key, value) in declared {
+                let path = match path.is_empty() {
+                    true => key.clone(),
+                    false => format!("{}.{}", path, key),
+                };
+                compare(
+                    &path,
+                    current.get(key).unwrap_or(&Value::Null),
+                    value,
+                    changes,
+                );
+            }
+        }
+        (Value::Array(current), Value::Array(declared)) if current.len() == declared.len() => {
+            for (i, (c, d)) in current.iter().zip(declared).enumerate() {
+                compare(&format!("{}[{}]", path, i), c, d, changes);
+            }
+        }
+        _ if current != declared => changes.push(FieldChange {
+            path: path.to_string(),
+            current: current.clone(),
+            declared: declared.clone(),
+        }),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::api::models::Metadata;
+
+    fn model(name: &str) -> String {
+        format!(
+            "kind: Machine\nmetadata:\n  name: {}\nspec:\n  cpu: 1\n  memory: 1Gi\n  image:\n    url: file:///jammy.qcow2\n    hash: {}\n",
+            name,
+            "a".repeat(64)
+        )
+    }
+
+    #[test]
+    fn reconcile() {
+        let dir =
+            std::env::temp_dir().join(format!("bigiron-virt-reconcile-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(
+            dir.join("web.yaml"),
+            model("web1") + "---\n" + &model("web2"),
+        )
+        .unwrap();
+        std::fs::write(dir.join("db.yml"), model("db1")).unwrap();
+        std::fs::write(dir.join(".db.yml.swp"), "not yaml").unwrap();
+        std::fs::write(dir.join("README"), "not yaml").unwrap();
+
+        let none = SpecDefaults::default();
+        let declared = declared(&dir, &none).unwrap();
+        let names: Vec<&str> = declared.iter().map(|m| m.metadata.name.as_str()).collect();
+        assert_eq!(names, ["db1", "web1", "web2"]);
+
+        let existing = vec!["web1".to_string(), "old1".to_string(), "mine".to_string()];
+        let owned = vec!["web1".to_string(), "old1".to_string()];
+        assert_eq!(
+            diff(&declared, &existing, &owned, false),
+            Actions {
+                create: vec!["db1".into(), "web2".into()],
+                prune: Vec::new(),
+            }
+        );
+        // machines reconcile didn't create are left alone
+        assert_eq!(diff(&declared, &existing, &owned, true).prune, ["old1"]);
+        assert!(check_prune(&[], true).is_err());
+        assert!(check_prune(&[], false).is_ok());
+
+        // as stored once created, with a uuid and different sizing
+        let mut web1 = declared[1].clone();
+        web1.metadata.uuid = Some("0e7a3c52-9a8d-4b8e-9f4c-3f3f0b1d2c11".into());
+        web1.spec.cpu = 2;
+        let old1 = Machine {
+            metadata: Metadata {
+                name: "old1".into(),
+                labels: Some(Map::from([(OWNER_LABEL.into(), "reconcile".into())])),
+                ..web1.metadata.clone()
+            },
+            ..web1.clone()
+        };
+        let mine = Machine {
+            metadata: Metadata {
+                name: "mine".into(),
+                ..web1.metadata.clone()
+            },
+            ..web1.clone()
+        };
+
+        let plan = state_diff(&declared, &[web1, old1, mine], true).unwrap();
+        assert_eq!(plan.create, ["db1", "web2"]);
+        assert_eq!(plan.destroy, ["old1"]);
+        assert_eq!(
+            plan.change,
+            [MachineChange {
+                name: "web1".into(),
+                fields: vec![FieldChange {
+                    path: "spec.cpu".into(),
+                    current: 2.into(),
+                    declared: 1.into(),
+                }],
+            }]
+        );
+
+        std::fs::write(dir.join("more.yaml"), model("db1")).unwrap();
+        let err = super::declared(&dir, &none).unwrap_err().to_string();
+        assert!(err.starts_with("db1 is declared in both"));
+
+        std::fs::write(dir.join("more.yaml"), "kind: Machine\nmetadata: {}\n").unwrap();
+        assert!(super::declared(&dir, &none).is_err());
+
+        // a class from another file
+        let class = model("small").replace("kind: Machine", "kind: MachineClass");
+        std::fs::write(dir.join("classes.yaml"), class).unwrap();
+        let yaml = "kind: Machine\nmetadata:\n  name: db2\nspec:\n  class: small\n  cpu: 2\n";
+        std::fs::write(dir.join("more.yaml"), yaml).unwrap();
+        let declared = super::declared(&dir, &none).unwrap();
+        let db2 = declared.iter().find(|m| m.metadata.name == "db2").unwrap();
+        assert_eq!((db2.spec.cpu, db2.spec.memory.bytes()), (2, 1 << 30));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Managing machines on other hosts, which run `bigiron-virt serve --api`.
+//!
+//! Over `https://` both ends authenticate with certificates from the `tls`
+//! section of the host config: the server only takes clients whose
+//! certificate the CA signed, and only those named in `allowedClients` if
+//! any are, and the client only talks to servers the CA signed for the
+//! host name it connected to.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::crypto::{ring, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection};
+use rustls::{StreamOwned, DEFAULT_VERSIONS};
+use tracing::info;
+
+use crate::api::models::{Machine, Selector};
+use crate::config::TlsConfig;
+use crate::error::Error;
+use crate::hostmanager::{MachineInfo, MachineStatus};
+
+/// A connection accepted by `TlsServer`
+pub type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+/// Accepts connections from clients with an allowed certificate
+pub struct TlsServer {
+    config: Arc<ServerConfig>,
+    allowed: Vec<ServerName<'static>>,
+}
+
+impl TlsServer {
+    pub fn new(tls: &TlsConfig) -> Result<Self, Error> {
+        let provider = Arc::new(ring::default_provider());
+        let verifier =
+            WebPkiClientVerifier::builder_with_provider(Arc::new(roots(tls)?), provider.clone())
+                .build()?;
+
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(DEFAULT_VERSIONS)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs(tls)?, key(tls)?)?;
+
+        let allowed = tls
+            .allowed_clients
+            .iter()
+            .map(|name| {
+                ServerName::try_from(name.clone())
+                    .map_err(|_| format!("invalid allowed client name '{}'", name))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            config: Arc::new(config),
+            allowed,
+        })
+    }
+
+    /// Complete the handshake on `sock`, failing for clients that aren't
+    /// allowed
+    pub fn accept(&self, mut sock: TcpStream) -> Result<TlsStream, Error> {
+        let mut conn = ServerConnection::new(self.config.clone())?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+
+        if !self.allowed.is_empty() {
+            let der = conn
+                .peer_certificates()
+                .and_then(|c| c.first())
+                .ok_or("client sent no certificate")?;
+            let cert = webpki::EndEntityCert::try_from(der)?;
+            if !self
+                .allowed
+                .iter()
+                .any(|name| cert.verify_is_valid_for_subject_name(name).is_ok())
+            {
+                let peer = sock.peer_addr()?;
+                return Err(format!("client {} is not in allowedClients", peer).into());
+            }
+        }
+
+        Ok(StreamOwned::new(conn, sock))
+    }
+}
+
+/// A host serving the machine API at an `http://` or `https://` endpoint
+pub struct Client {
+    endpoint: String,
+    host: String,
+    port: u16,
+    tls: Option<Arc<ClientConfig>>,
+}
+
+impl Client {
+    /// `endpoint` is e.g. https://kvm01:8700, plain host:port being taken
+    /// as http. https needs `tls`.
+    pub fn new(endpoint: &str, tls: Option<&TlsConfig>) -> Result<Self, Error> {
+        let endpoint = match endpoint.contains("://") {
+            true => endpoint.to_string(),
+            false => format!("http://{}", endpoint),
+        };
+        let url = url::Url::parse(&endpoint)?;
+        let host = url.host_str().ok_or("endpoint has no host")?.to_string();
+
+        let tls = match (url.scheme(), tls) {
+            ("http", _) => None,
+            ("https", Some(tls)) => Some(Arc::new(client_config(tls)?)),
+            ("https", None) => {
+                return Err(format!("{} needs a tls section in the host config", endpoint).into())
+            }
+            (scheme, _) => return Err(format!("unsupported endpoint scheme {}", scheme).into()),
+        };
+
+        Ok(Self {
+            port: url.port_or_known_default().unwrap_or(80),
+            endpoint,
+            host,
+            tls,
+        })
+    }
+
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Stored specs of the host's machines
+    pub fn machines(&self) -> Result<Vec<Machine>, Error> {
+        let body = self.request("GET", "/machines", "")?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// What `list` shows for the host's machines, only those matching
+    /// `selector` if given
+    pub fn list(&self, selector: Option<&Selector>) -> Result<Vec<MachineStatus>, Error> {
+        let body = self.request("GET", "/status", "")?;
+        let mut list: Vec<MachineStatus> = serde_json::from_str(&body)?;
+
+        if let Some(selector) = selector {
+            let ids = self.select(selector)?;
+            list.retain(|m| ids.contains(&m.id));
+        }
+        Ok(list)
+    }
+
+    /// Ids of the host's machines whose labels match `selector`
+    pub fn select(&self, selector: &Selector) -> Result<Vec<String>, Error> {
+        Ok(self
+            .machines()?
+            .into_iter()
+            .filter(|m| selector.matches(&m.metadata))
+            .map(|m| m.metadata.name)
+            .collect())
+    }
+
+    pub fn show(&self, id: &str) -> Result<MachineInfo, Error> {
+        let body = self.request("GET", &format!("/machines/{}", id), "")?;
+        Ok(serde_json::from_str(&body)?)
+    }
+
+    /// Create `machine`, its `userdataFile` already resolved
+    pub fn create(&self, machine: &Machine) -> Result<(), Error> {
+        self.request("POST", "/machines", &machine.to_yaml()?)?;
+        info!("Created {} on {}", machine.metadata.name, self.endpoint);
+        Ok(())
+    }
+
+    pub fn destroy(&self, id: &str) -> Result<(), Error> {
+        self.request("DELETE", &format!("/machines/{}", id), "")?;
+        Ok(())
+    }
+
+    // a single HTTP/1.1 exchange, returning the body of a 2xx response
+    fn request(&self, method: &str, path: &str, body: &str) -> Result<String, Error> {
+        let sock = TcpStream::connect((self.host.as_str(), self.port))
+            .map_err(|e| format!("error connecting to {}: {}", self.endpoint, e))?;
+        // creating a machine includes importing its image
+        sock.set_read_timeout(Some(Duration::from_secs(3600)))?;
+
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            self.host,
+            body.len(),
+            body
+        );
+
+        let (status, response) = match self.tls {
+            Some(ref config) => {
+                let name = ServerName::try_from(self.host.clone())?;
+                let conn = ClientConnection::new(config.clone(), name)?;
+                exchange(StreamOwned::new(conn, sock), &request)?
+            }
+            None => exchange(sock, &request)?,
+        };
+
+        let code = status.split_whitespace().nth(1).unwrap_or_default();
+        if !code.starts_with('2') {
+            let status = status.trim().trim_start_matches("HTTP/1.1 ");
+            let msg = format!(
+                "{} {} failed ({}): {}",
+                method,
+                path,
+                status,
+                response.trim()
+            );
+            return Err(msg.into());
+        }
+
+        Ok(response)
+    }
+}
+
+// the response status line and body
+fn exchange<S: Read + Write>(mut stream: S, request: &str) -> Result<(String, String), Error> {
+    stream.write_all(request.as_bytes())?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status = String::new();
+    reader.read_line(&mut status)?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+    let mut body = String::new();
+    reader.read_to_string(&mut body)?;
+
+    Ok((status, body))
+}
+
+fn client_config(tls: &TlsConfig) -> Result<ClientConfig, Error> {
+    let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+    Ok(ClientConfig::builder_with_provider(provider)
+        .with_protocol_versions(DEFAULT_VERSIONS)?
+        .with_root_certificates(roots(tls)?)
+        .with_client_auth_cert(certs(tls)?, key(tls)?)?)
+}
+
+fn roots(tls: &TlsConfig) -> Result<RootCertStore, Error> {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(&tls.ca)
+        .map_err(|e| format!("error reading CA certificates {:?}: {}", tls.ca, e))?
+    {
+        roots.add(cert?)?;
+    }
+    Ok(roots)
+}
+
+fn certs(tls: &TlsConfig) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(&tls.cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("error reading certificate {:?}: {}", tls.cert, e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificate in {:?}", tls.cert).into());
+    }
+    Ok(certs)
+}
+
+fn key(tls: &TlsConfig) -> Result<PrivateKeyDer<'static>, Error> {
+    PrivateKeyDer::from_pem_file(&tls.key)
+        .map_err(|e| format!("error reading private key {:?}: {}", tls.key, e).into())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::net::TcpListener;
+    use std::path::Path;
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+    struct Pki {
+        ca: rcgen::Certificate,
+        ca_key: KeyPair,
+    }
+
+    impl Pki {
+        fn new() -> Self {
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca_key = KeyPair::generate().unwrap();
+            let ca = params.self_signed(&ca_key).unwrap();
+            Self { ca, ca_key }
+        }
+
+        // cert, key and CA files for `name` in `dir`
+        fn issue(&self, dir: &Path, name: &str, allowed: &[&str]) -> TlsConfig {
+            let key = KeyPair::generate().unwrap();
+            let params = CertificateParams::new(vec![name.to_string()]).unwrap();
+            let cert = params.signed_by(&key, &self.ca, &self.ca_key).unwrap();
+
+            let tls = TlsConfig {
+                cert: dir.join(format!("{}.crt", name)),
+                key: dir.join(format!("{}.key", name)),
+                ca: dir.join("ca.crt"),
+                allowed_clients: allowed.iter().map(|a| a.to_string()).collect(),
+            };
+            std::fs::write(&tls.cert, cert.pem()).unwrap();
+            std::fs::write(&tls.key, key.serialize_pem()).unwrap();
+            std::fs::write(&tls.ca, self.ca.pem()).unwrap();
+            tls
+        }
+    }
+
+    // answer one request over mutual TLS, returning whether it was accepted
+    fn serve_once(server: TlsServer) -> (u16, std::thread::JoinHandle<bool>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = std::thread::spawn(move || {
+            let (sock, _) = listener.accept().unwrap();
+            let Ok(mut stream) = server.accept(sock) else {
+                return false;
+            };
+
+            let mut line = String::new();
+            BufReader::new(&mut stream).read_line(&mut line).unwrap();
+            assert_eq!(line, "GET /status HTTP/1.1\r\n");
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n[]")
+                .unwrap();
+            stream.conn.send_close_notify();
+            stream.flush().unwrap();
+            true
+        });
+
+        (port, handle)
+    }
+
+    #[test]
+    fn mutual_tls() {
+        let dir = std::env::temp_dir().join(format!("bigiron-virt-remote-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let pki = Pki::new();
+        let server = pki.issue(&dir, "localhost", &["operator.example.com"]);
+        let operator = pki.issue(&dir, "operator.example.com", &[]);
+        let intruder = pki.issue(&dir, "intruder.example.com", &[]);
+
+        let (port, handle) = serve_once(TlsServer::new(&server).unwrap());
+        let client = Client::new(&format!("https://localhost:{}", port), Some(&operator)).unwrap();
+        assert!(client.list(None).unwrap().is_empty());
+        assert!(handle.join().unwrap());
+
+        // signed by the CA, but not allowed
+        let (port, handle) = serve_once(TlsServer::new(&server).unwrap());
+        let client = Client::new(&format!("https://localhost:{}", port), Some(&intruder)).unwrap();
+        assert!(client.list(None).is_err());
+        assert!(!handle.join().unwrap());
+
+        let err = Client::new("https://kvm01:8700", None).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "https://kvm01:8700 needs a tls section in the host config"
+        );
+
+        let missing = TlsConfig {
+            key: dir.join("missing.key"),
+            ..operator
+        };
+        let err = TlsServer::new(&missing).err().unwrap().to_string();
+        assert!(err.starts_with("error reading private key"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Placing machines across several hosts for `bigiron-virt schedule`.
+//!
+//! The hosts are listed in an inventory file, each running
+//! `bigiron-virt serve --api`. Their machines are fetched from
+//! `GET /machines`, each new machine is put on a host its
+//! `spec.scheduling` rules allow, preferring the host with the fewest
+//! machines, and created there with `POST /machines`.
+
+use std::collections::HashMap as Map;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::models::{Machine, Metadata, Selector};
+use crate::config::TlsConfig;
+use crate::error::Error;
+use crate::remote::Client;
+
+/// Hosts to schedule machines on
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Inventory {
+    pub hosts: Vec<HostEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct HostEntry {
+    pub name: String,
+    /// where `bigiron-virt serve --api` listens, e.g. https://kvm01:8700
+    pub endpoint: String,
+    #[serde(default)]
+    pub labels: Map<String, String>,
+}
+
+impl Inventory {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let yaml = std::fs::read_to_string(path)
+            .map_err(|e| format!("error reading inventory {:?}: {}", path, e))?;
+        let inventory: Inventory = serde_yaml::from_str(&yaml)
+            .map_err(|e| format!("invalid inventory {:?}: {}", path, e))?;
+
+        if inventory.hosts.is_empty() {
+            return Err(format!("inventory {:?} lists no hosts", path).into());
+        }
+        Ok(inventory)
+    }
+}
+
+/// A host and the machines on it, including ones placed so far
+#[derive(Debug, Clone)]
+pub struct HostView {
+    pub host: HostEntry,
+    pub machines: Vec<Metadata>,
+}
+
+/// Puts machines on hosts, keeping track of each placement so later
+/// machines see the earlier ones
+pub struct Scheduler {
+    hosts: Vec<HostView>,
+}
+
+impl Scheduler {
+    pub fn new(hosts: Vec<HostView>) -> Self {
+        Self { hosts }
+    }
+
+    /// Fetch each host's machines, over mutual TLS with `tls` for https
+    /// endpoints
+    pub fn connect(inventory: &Inventory, tls: Option<&TlsConfig>) -> Result<Self, Error> {
+        let mut hosts = Vec::new();
+        for host in &inventory.hosts {
+            let machines = Client::new(&host.endpoint, tls)
+                .and_then(|c| c.machines())
+                .map_err(|e| format!("error listing machines on {}: {}", host.name, e))?;
+            hosts.push(HostView {
+                host: host.clone(),
+                machines: machines.into_iter().map(|m| m.metadata).collect(),
+            });
+        }
+
+        Ok(Self::new(hosts))
+    }
+
+    /// Pick a host for `machine` and count it there, failing with why
+    /// each host was ruled out
+    pub fn place(&mut self, machine: &Machine) -> Result<&HostEntry, Error> {
+        let name = &machine.metadata.name;
+        if let Some(view) = self
+            .hosts
+            .iter()
+            .find(|v| v.machines.iter().any(|m| m.name == *name))
+        {
+            return Err(format!("{} already exists on {}", name, view.host.name).into());
+        }
+
+        let rules = machine.spec.scheduling.clone().unwrap_or_default();
+        let affinity = selectors(&rules.affinity)?;
+        let anti_affinity = selectors(&rules.anti_affinity)?;
+
+        // affinity only binds once some machine it names exists, so the
+        // first of a group can go anywhere
+        let anchored: Vec<&Selector> = affinity
+            .iter()
+            .filter(|s| {
+                self.hosts
+                    .iter()
+                    .flat_map(|v| &v.machines)
+                    .any(|m| s.matches(m))
+            })
+            .collect();
+
+        let mut reasons = Vec::new();
+        let mut best: Option<usize> = None;
+        for (i, view) in self.hosts.iter().enumerate() {
+            let unmet = rules
+                .host_selector
+                .iter()
+                .flatten()
+                .find(|(k, v)| view.host.labels.get(*k) != Some(v));
+            if let Some((k, v)) = unmet {
+                reasons.push(format!("{}: not labelled {}={}", view.host.name, k, v));
+                continue;
+            }
+
+            let has = |s: &Selector| view.machines.iter().any(|m| s.matches(m));
+            if let Some(i) = anti_affinity.iter().position(has) {
+                reasons.push(format!(
+                    "{}: runs a machine matching anti-affinity {}",
+                    view.host.name, rules.anti_affinity[i]
+                ));
+                continue;
+            }
+            if anchored.iter().any(|s| !has(s)) {
+                reasons.push(format!(
+                    "{}: runs no machine matching the affinity rules",
+                    view.host.name
+                ));
+                continue;
+            }
+
+            // spread, the first listed of the emptiest hosts
+            if best.is_none_or(|b| view.machines.len() < self.hosts[b].machines.len()) {
+                best = Some(i);
+            }
+        }
+
+        let Some(i) = best else {
+            return Err(format!("no host for {}: {}", name, reasons.join("; ")).into());
+        };
+        self.hosts[i].machines.push(machine.metadata.clone());
+
+        Ok(&self.hosts[i].host)
+    }
+}
+
+fn selectors(rules: &[String]) -> Result<Vec<Selector>, Error> {
+    rules.iter().map(|r| r.parse()).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::api::models::Scheduling;
+
+    fn host(name: &str, labels: &[(&str, &str)], machines: &[(&str, &str)]) -> HostView {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let machines = machines
+            .iter()
+            .map(|(name, app)| Metadata {
+                name: name.to_string(),
+                labels: Some(Map::from([("app".to_string(), app.to_string())])),
+                uuid: None,
+                ttl: None,
+            })
+            .collect();
+
+        HostView {
+            host: HostEntry {
+                name: name.to_string(),
+                endpoint: format!("http://{}:9180", name),
+                labels,
+            },
+            machines,
+        }
+    }
+
+    fn machine(name: &str, app: &str, scheduling: Scheduling) -> Machine {
+        let mut m = Machine::builder()
+            .name(name)
+            .label("app", app)
+            .cpu(1)
+            .memory("1Gi")
+            .image(
+                "file:///jammy.qcow2",
+                "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            )
+            .build()
+            .unwrap();
+        m.spec.scheduling = Some(scheduling);
+        m
+    }
+
+    #[test]
+    fn placement() {
+        let mut s = Scheduler::new(vec![
+            host("kvm01", &[("zone", "a")], &[("db1", "db"), ("web1", "web")]),
+            host("kvm02", &[("zone", "a")], &[("cache1", "cache")]),
+            host("kvm03", &[("zone", "b")], &[]),
+        ]);
+
+        // the emptiest host
+        let m = machine("app1", "app", Scheduling::default());
+        assert_eq!(s.place(&m).unwrap().name, "kvm03");
+
+        let zone_a = Scheduling {
+            host_selector: Some(Map::from([("zone".to_string(), "a".to_string())])),
+            ..Default::default()
+        };
+        let m = machine("web2", "web", zone_a.clone());
+        assert_eq!(s.place(&m).unwrap().name, "kvm02");
+
+        let near_db = Scheduling {
+            affinity: vec!["app=db".to_string()],
+            anti_affinity: vec!["app=web".to_string()],
+            ..zone_a
+        };
+        let err = s.place(&machine("web3", "web", near_db)).unwrap_err();
+        let err = err.to_string();
+        assert!(err.contains("kvm01: runs a machine matching anti-affinity app=web"));
+        assert!(err.contains("kvm02: runs a machine matching anti-affinity app=web"));
+        assert!(err.contains("kvm03: not labelled zone=a"));
+
+        // nothing matches yet, so the first of a group goes anywhere
+        let together = Scheduling {
+            affinity: vec!["app=batch".to_string()],
+            ..Default::default()
+        };
+        let first = s.place(&machine("batch1", "batch", together.clone()));
+        let first = first.unwrap().name.clone();
+        let second = s.place(&machine("batch2", "batch", together)).unwrap();
+        assert_eq!(second.name, first);
+
+        let err = s.place(&machine("db1", "db", Scheduling::default()));
+        assert_eq!(err.unwrap_err().to_string(), "db1 already exists on kvm01");
+    }
+
+    #[test]
+    fn inventory() {
+        let yaml = "hosts:\n  - name: kvm01\n    endpoint: http://kvm01:9180\n    labels:\n      zone: a\n  - name: kvm02\n    endpoint: kvm02:9180\n";
+        let inventory: Inventory = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(inventory.hosts[0].labels["zone"], "a");
+        assert!(inventory.hosts[1].labels.is_empty());
+    }
+}
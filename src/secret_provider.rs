@@ -0,0 +1,271 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Secret lookup for values that shouldn't live in model files.
+//!
+//! Secrets are referenced as `<scheme>:<key>` strings:
+//!
+//! - `file:db-pass` reads a file in `/etc/bigiron-virt/secrets`
+//! - `env:DB_PASS` reads an environment variable
+//! - `exec:/usr/local/bin/get-secret db-pass` runs a command and uses its stdout
+//! - `vault:secret/data/db#password` reads a field from a Vault KV v2 secret,
+//!   using `VAULT_ADDR` and `VAULT_TOKEN` from the environment
+//!
+//! Models sent from another host can't reference secrets at all, as every
+//! provider reads with this host's files, environment and credentials.
+
+use std::collections::HashMap as Map;
+use std::io::Write;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Component, Path};
+use std::process::{Command, Stdio};
+
+use crate::error::Error;
+
+pub trait SecretProvider {
+    /// Look up the secret named by `key`
+    fn get(&self, key: &str) -> Result<Vec<u8>, Error>;
+}
+
+/// Reads a file in `/etc/bigiron-virt/secrets`, named by a relative path
+/// that stays inside it
+pub struct FileProvider;
+
+impl SecretProvider for FileProvider {
+    fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        read_secret_file(Path::new("/etc/bigiron-virt/secrets"), key)
+    }
+}
+
+fn read_secret_file(dir: &Path, key: &str) -> Result<Vec<u8>, Error> {
+    let relative = Path::new(key);
+    let inside = relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)));
+    if key.is_empty() || !inside {
+        return Err(format!("secret file '{}' must be a path inside {:?}", key, dir).into());
+    }
+
+    let path = dir.join(relative);
+    std::fs::read(&path).map_err(|e| format!("error reading secret file {:?}: {}", path, e).into())
+}
+
+pub struct EnvProvider;
+
+impl SecretProvider for EnvProvider {
+    fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        match std::env::var_os(key) {
+            Some(v) => Ok(v.into_vec()),
+            None => Err(format!("secret environment variable {} is not set", key).into()),
+        }
+    }
+}
+
+/// Runs `key` as a whitespace separated command line, the secret is its
+/// stdout with any trailing newline removed
+pub struct CommandProvider;
+
+impl SecretProvider for CommandProvider {
+    fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        let mut args = key.split_whitespace();
+        let program = args.next().ok_or("empty secret command")?;
+
+        let output = Command::new(program)
+            .args(args)
+            .stderr(Stdio::inherit())
+            .output()?;
+
+        if !output.status.success() {
+            return Err(format!("secret command {:?} failed: {}", program, output.status).into());
+        }
+
+        let mut out = output.stdout;
+        if out.ends_with(b"\n") {
+            out.pop();
+        }
+
+        Ok(out)
+    }
+}
+
+/// Reads `path#field` from a Vault KV v2 engine
+pub struct VaultProvider {
+    pub addr: String,
+    pub token: String,
+}
+
+impl VaultProvider {
+    pub fn from_env() -> Result<Self, Error> {
+        let addr = std::env::var("VAULT_ADDR").map_err(|_| "VAULT_ADDR is not set")?;
+        let token = std::env::var("VAULT_TOKEN").map_err(|_| "VAULT_TOKEN is not set")?;
+
+        Ok(Self { addr, token })
+    }
+}
+
+impl SecretProvider for VaultProvider {
+    fn get(&self, key: &str) -> Result<Vec<u8>, Error> {
+        let (path, field) = key
+            .split_once('#')
+            .ok_or("vault secrets must be given as path#field")?;
+
+        let url = format!("{}/v1/{}", self.addr.trim_end_matches('/'), path);
+
+        // pass the token header on stdin so it doesn't show up in the process list
+        let mut child = Command::new("curl")
+            .arg("--silent")
+            .arg("--fail")
+            .arg("--header")
+            .arg("@-")
+            .arg(&url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("error executing curl: {}", e))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            writeln!(stdin, "X-Vault-Token: {}", self.token)?;
+        }
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(format!("error reading vault secret {}: {}", path, output.status).into());
+        }
+
+        vault_field(&output.stdout, field)
+    }
+}
+
+// extracts data.data.<field> from a KV v2 read response
+fn vault_field(body: &[u8], field: &str) -> Result<Vec<u8>, Error> {
+    let resp: serde_json::Value = serde_json::from_slice(body)?;
+
+    match resp["data"]["data"][field] {
+        serde_json::Value::String(ref s) => Ok(s.as_bytes().to_vec()),
+        serde_json::Value::Null => Err(format!("vault secret has no field '{}'", field).into()),
+        ref v => Ok(v.to_string().into_bytes()),
+    }
+}
+
+/// Resolve a `<scheme>:<key>` secret reference
+pub fn resolve(reference: &str) -> Result<Vec<u8>, Error> {
+    let (scheme, key) = reference
+        .split_once(':')
+        .ok_or_else(|| format!("invalid secret reference '{}'", reference))?;
+
+    let provider: Box<dyn SecretProvider> = match scheme {
+        "file" => Box::new(FileProvider),
+        "env" => Box::new(EnvProvider),
+        "exec" => Box::new(CommandProvider),
+        "vault" => Box::new(VaultProvider::from_env()?),
+        _ => return Err(format!("unknown secret provider '{}'", scheme).into()),
+    };
+
+    provider.get(key)
+}
+
+/// Resolve a secret reference that must be valid UTF-8 text
+pub fn resolve_string(reference: &str) -> Result<String, Error> {
+    Ok(String::from_utf8(resolve(reference)?)?)
+}
+
+/// Replace `${secret:NAME}` placeholders in `text` with the secret that
+/// `secrets[NAME]` references
+pub fn inject(text: &str, secrets: &Map<String, String>) -> Result<String, Error> {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find("${secret:") {
+        out.push_str(&rest[..start]);
+
+        let after = &rest[start + "${secret:".len()..];
+        let end = after
+            .find('}')
+            .ok_or("unterminated ${secret:...} placeholder")?;
+        let name = &after[..end];
+
+        let reference = secrets
+            .get(name)
+            .ok_or_else(|| format!("no secret named '{}' declared", name))?;
+        out.push_str(&resolve_string(reference)?);
+
+        rest = &after[end + 1..];
+    }
+
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn env_and_exec() {
+        std::env::set_var("BIGIRON_TEST_SECRET", "hunter2");
+        assert_eq!(
+            resolve_string("env:BIGIRON_TEST_SECRET").unwrap(),
+            "hunter2"
+        );
+        assert!(resolve("env:BIGIRON_TEST_SECRET_MISSING").is_err());
+
+        assert_eq!(resolve_string("exec:echo s3cret").unwrap(), "s3cret");
+        assert!(resolve("exec:false").is_err());
+
+        assert!(resolve("nope:key").is_err());
+        assert!(resolve("no-scheme").is_err());
+    }
+
+    #[test]
+    fn file() {
+        let dir = std::env::temp_dir().join(format!("bigiron-virt-secrets-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("db")).unwrap();
+        std::fs::write(dir.join("db/pass"), b"from-a-file").unwrap();
+
+        assert_eq!(read_secret_file(&dir, "db/pass").unwrap(), b"from-a-file");
+        assert!(read_secret_file(&dir, "db/missing").is_err());
+        for outside in ["", "/etc/shadow", "../shadow", "db/../../shadow"] {
+            assert!(read_secret_file(&dir, outside).is_err(), "{}", outside);
+        }
+        assert!(resolve("file:/etc/hostname").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn vault_response() {
+        let body = br#"{"data": {"data": {"password": "pw", "port": 5432}, "metadata": {}}}"#;
+
+        assert_eq!(vault_field(body, "password").unwrap(), b"pw");
+        assert_eq!(vault_field(body, "port").unwrap(), b"5432");
+        assert!(vault_field(body, "missing").is_err());
+    }
+
+    #[test]
+    fn inject_placeholders() {
+        std::env::set_var("BIGIRON_TEST_DB_PASS", "pa55");
+
+        let mut secrets = Map::new();
+        secrets.insert("db".to_string(), "env:BIGIRON_TEST_DB_PASS".to_string());
+
+        let out = inject("password: ${secret:db}\nhome: ${HOME}\n", &secrets).unwrap();
+        assert_eq!(out, "password: pa55\nhome: ${HOME}\n");
+
+        assert!(inject("${secret:other}", &secrets).is_err());
+        assert!(inject("${secret:db", &secrets).is_err());
+    }
+}
@@ -0,0 +1,334 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! libvirt secrets, the Ceph keys, iSCSI passwords and LUKS passphrases
+//! disks refer to by UUID, for `bigiron-virt secret`.
+//!
+//! A secret is found by its usage, e.g. `ceph:client.libvirt`, so setting
+//! one again replaces its value and keeps the UUID specs already name.
+
+use std::fmt;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use quick_xml::events::{BytesText, Event};
+use quick_xml::reader::Reader;
+use quick_xml::writer::Writer;
+use virt::secret::Secret;
+use virt::sys;
+
+use crate::error::Error;
+use crate::libvirt;
+
+/// What a secret is for, libvirt keeps one secret per usage
+#[derive(Debug, Clone, PartialEq)]
+pub enum Usage {
+    /// a Ceph user's key, e.g. `ceph:client.libvirt`
+    Ceph(String),
+    /// the CHAP password of an iSCSI target, e.g. `iscsi:iqn.2024-01.com.example:data`
+    Iscsi(String),
+    /// the passphrase of an encrypted disk image, e.g. `volume:/srv/data.qcow2`
+    Volume(PathBuf),
+}
+
+impl Usage {
+    fn kind(&self) -> &'static str {
+        match self {
+            Usage::Ceph(_) => "ceph",
+            Usage::Iscsi(_) => "iscsi",
+            Usage::Volume(_) => "volume",
+        }
+    }
+
+    // the child of <usage> holding the id
+    fn element(&self) -> &'static str {
+        match self {
+            Usage::Ceph(_) => "name",
+            Usage::Iscsi(_) => "target",
+            Usage::Volume(_) => "volume",
+        }
+    }
+
+    fn id(&self) -> String {
+        match self {
+            Usage::Ceph(name) | Usage::Iscsi(name) => name.clone(),
+            Usage::Volume(path) => path.to_string_lossy().into_owned(),
+        }
+    }
+
+    fn code(&self) -> i32 {
+        let code = match self {
+            Usage::Ceph(_) => sys::VIR_SECRET_USAGE_TYPE_CEPH,
+            Usage::Iscsi(_) => sys::VIR_SECRET_USAGE_TYPE_ISCSI,
+            Usage::Volume(_) => sys::VIR_SECRET_USAGE_TYPE_VOLUME,
+        };
+        code as i32
+    }
+
+    fn new(kind: &str, id: &str) -> Result<Self, Error> {
+        if id.is_empty() {
+            return Err(format!("secret usage '{}' has no name", kind).into());
+        }
+
+        match kind {
+            "ceph" => Ok(Usage::Ceph(id.to_string())),
+            "iscsi" => Ok(Usage::Iscsi(id.to_string())),
+            "volume" => Ok(Usage::Volume(PathBuf::from(id))),
+            _ => Err(format!(
+                "unknown secret usage '{}', expected ceph, iscsi or volume",
+                kind
+            )
+            .into()),
+        }
+    }
+}
+
+impl FromStr for Usage {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, id) = s
+            .split_once(':')
+            .ok_or_else(|| format!("invalid secret usage '{}', expected KIND:NAME", s))?;
+        Usage::new(kind, id)
+    }
+}
+
+impl fmt::Display for Usage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.kind(), self.id())
+    }
+}
+
+/// A defined secret, its value is never included
+#[derive(Debug, Clone, PartialEq)]
+pub struct SecretInfo {
+    pub uuid: String,
+    pub usage: Option<Usage>,
+    pub description: Option<String>,
+    /// the value can't be read back, only used by libvirt itself
+    pub private: bool,
+}
+
+/// Set the value of the secret for `usage`, defining it if there is none
+/// yet, and return its UUID. An existing secret keeps its description and
+/// whether it's private.
+pub fn set(
+    usage: &Usage,
+    value: &[u8],
+    private: bool,
+    description: Option<&str>,
+) -> Result<String, Error> {
+    let c = libvirt::connect()?;
+
+    let secret = match Secret::lookup_by_usage(&c, usage.code(), &usage.id()) {
+        Ok(secret) => secret,
+        Err(_) => Secret::define_xml(&c, &definition(usage, private, description)?, 0)?,
+    };
+    secret.set_value(value, 0)?;
+
+    Ok(secret.get_uuid_string()?)
+}
+
+/// UUID of the secret for `usage`, if there is one
+pub fn lookup(usage: &Usage) -> Result<Option<String>, Error> {
+    let c = libvirt::connect()?;
+    match Secret::lookup_by_usage(&c, usage.code(), &usage.id()) {
+        Ok(secret) => Ok(Some(secret.get_uuid_string()?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// The value of secret `uuid`, which must not be private
+pub fn value(uuid: &str) -> Result<Vec<u8>, Error> {
+    let c = libvirt::connect()?;
+    let secret = Secret::lookup_by_uuid_string(&c, uuid)
+        .map_err(|e| format!("no libvirt secret {}: {}", uuid, e))?;
+
+    Ok(secret
+        .get_value(0)
+        .map_err(|e| format!("can't read libvirt secret {}, is it private? {}", uuid, e))?)
+}
+
+/// All secrets libvirt knows about
+pub fn list() -> Result<Vec<SecretInfo>, Error> {
+    let c = libvirt::connect()?;
+
+    let mut secrets = Vec::new();
+    for secret in c.list_all_secrets(0)? {
+        secrets.push(parse(&secret.get_xml_desc(0)?)?);
+    }
+    secrets.sort_by(|a, b| a.uuid.cmp(&b.uuid));
+
+    Ok(secrets)
+}
+
+/// Undefine the secret `id` names, by UUID or as a usage such as
+/// `ceph:client.libvirt`
+pub fn remove(id: &str) -> Result<(), Error> {
+    let c = libvirt::connect()?;
+
+    let secret = match id.contains(':') {
+        true => {
+            let usage: Usage = id.parse()?;
+            Secret::lookup_by_usage(&c, usage.code(), &usage.id())
+        }
+        false => Secret::lookup_by_uuid_string(&c, id),
+    };
+    let secret = secret.map_err(|_| format!("no libvirt secret {}", id))?;
+
+    Ok(secret.undefine()?)
+}
+
+fn definition(usage: &Usage, private: bool, description: Option<&str>) -> Result<String, Error> {
+    let mut w = Writer::new(Cursor::new(Vec::new()));
+    w.create_element("secret")
+        .with_attribute(("ephemeral", "no"))
+        .with_attribute(("private", if private { "yes" } else { "no" }))
+        .write_inner_content(|w| {
+            if let Some(description) = description {
+                w.create_element("description")
+                    .write_text_content(BytesText::new(description))?;
+            }
+            w.create_element("usage")
+                .with_attribute(("type", usage.kind()))
+                .write_inner_content(|w| {
+                    w.create_element(usage.element())
+                        .write_text_content(BytesText::new(&usage.id()))?;
+                    Ok(())
+                })?;
+            Ok(())
+        })?;
+
+    Ok(String::from_utf8(w.into_inner().into_inner())?)
+}
+
+// secret XML as libvirt describes it
+fn parse(xml: &str) -> Result<SecretInfo, Error> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut info = SecretInfo {
+        uuid: String::new(),
+        usage: None,
+        description: None,
+        private: false,
+    };
+    let mut path: Vec<String> = Vec::new();
+    let mut usage_kind = String::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"secret" => {
+                for a in e.attributes() {
+                    let a = a?;
+                    if a.key.as_ref() == b"private" {
+                        info.private = a.value.as_ref() == b"yes";
+                    }
+                }
+                path.push(String::from("secret"));
+            }
+            Event::Start(e) => {
+                let name = String::from_utf8_lossy(e.name().as_ref()).into_owned();
+                if name == "usage" {
+                    for a in e.attributes() {
+                        let a = a?;
+                        if a.key.as_ref() == b"type" {
+                            usage_kind = a.unescape_value()?.into_owned();
+                        }
+                    }
+                }
+                path.push(name);
+            }
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(t) => {
+                let text = t.unescape()?.into_owned();
+                let at: Vec<&str> = path.iter().map(String::as_str).collect();
+                match at.as_slice() {
+                    ["secret", "uuid"] => info.uuid = text,
+                    ["secret", "description"] => info.description = Some(text),
+                    // kinds other than these, e.g. tls, are listed without a usage
+                    ["secret", "usage", _] => info.usage = Usage::new(&usage_kind, &text).ok(),
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    if info.uuid.is_empty() {
+        return Err("secret XML has no uuid".into());
+    }
+    Ok(info)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn usages() {
+        let usage: Usage = "ceph:client.libvirt".parse().unwrap();
+        assert_eq!(usage, Usage::Ceph("client.libvirt".into()));
+        assert_eq!(usage.to_string(), "ceph:client.libvirt");
+
+        let usage: Usage = "iscsi:iqn.2024-01.com.example:data".parse().unwrap();
+        assert_eq!(usage, Usage::Iscsi("iqn.2024-01.com.example:data".into()));
+
+        assert!("tls:x".parse::<Usage>().is_err());
+        assert!("ceph:".parse::<Usage>().is_err());
+        assert!("client.libvirt".parse::<Usage>().is_err());
+    }
+
+    #[test]
+    fn definitions() {
+        let usage = Usage::Volume("/srv/a&b.qcow2".into());
+        let xml = definition(&usage, true, Some("data disk")).unwrap();
+        assert_eq!(
+            xml,
+            "<secret ephemeral=\"no\" private=\"yes\"><description>data disk</description><usage type=\"volume\"><volume>/srv/a&amp;b.qcow2</volume></usage></secret>"
+        );
+
+        // as libvirt describes it
+        let xml = "<secret ephemeral='no' private='no'>
+  <uuid>2ec115d7-3a88-3ceb-bc12-0ac909a6fd87</uuid>
+  <description>ceph client key</description>
+  <usage type='ceph'>
+    <name>client.libvirt secret</name>
+  </usage>
+</secret>
+";
+        assert_eq!(
+            parse(xml).unwrap(),
+            SecretInfo {
+                uuid: "2ec115d7-3a88-3ceb-bc12-0ac909a6fd87".into(),
+                usage: Some(Usage::Ceph("client.libvirt secret".into())),
+                description: Some("ceph client key".into()),
+                private: false,
+            }
+        );
+
+        let xml = "<secret ephemeral='no' private='yes'><uuid>6f1e2c52-0b1d-4a8e-9c4f-2d7a9b3e5f10</uuid><usage type='tls'><name>web</name></usage></secret>";
+        let info = parse(xml).unwrap();
+        assert!(info.private);
+        assert_eq!(info.usage, None);
+    }
+}
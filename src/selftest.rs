@@ -0,0 +1,240 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use rand::{thread_rng, Rng};
+use tracing::info;
+
+use crate::api::models::{Machine, Resource};
+use crate::error::Error;
+use crate::hostmanager::HostManager;
+use crate::image::repo::hash_file;
+use crate::mac::Mac;
+
+/// Image used when none is given, installed by distribution packages
+pub const DEFAULT_IMAGE: &str = "/usr/share/bigiron-virt/selftest.qcow2";
+
+pub struct SelftestOptions {
+    pub image: Option<PathBuf>,
+    // bridge to attach the test machine to, networking is only checked if set
+    pub bridge: Option<String>,
+    pub timeout: Duration,
+}
+
+pub struct Step {
+    pub name: &'static str,
+    pub result: Result<String, String>,
+}
+
+pub struct SelftestReport {
+    pub steps: Vec<Step>,
+}
+
+impl SelftestReport {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|s| s.result.is_ok())
+    }
+
+    fn record(&mut self, name: &'static str, result: Result<String, Error>) -> bool {
+        let ok = result.is_ok();
+        self.steps.push(Step {
+            name,
+            result: result.map_err(|e| e.to_string()),
+        });
+        ok
+    }
+}
+
+/// Create a throwaway machine, check it boots (and is reachable, if on a
+/// bridge), then destroy it along with its image if the repo didn't have it
+pub fn run(opts: &SelftestOptions) -> Result<SelftestReport, Error> {
+    let mut report = SelftestReport { steps: Vec::new() };
+
+    let image = opts
+        .image
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_IMAGE));
+    let name = format!(
+        "bigiron-selftest-{:06x}",
+        thread_rng().gen_range(0..0xffffff)
+    );
+
+    let mut machine = match test_machine(&name, &image, &opts.bridge) {
+        Ok(m) => m,
+        Err(e) => {
+            report.record("prepare", Err(e));
+            return Ok(report);
+        }
+    };
+
+    let mut hm = HostManager::new()?;
+    let hash = machine.spec.image.hash.clone();
+    let in_repo = |hm: &HostManager| -> Result<bool, Error> {
+        Ok(hm
+            .list_images()?
+            .iter()
+            .any(|i| i.hash == hash && i.format == "qcow2"))
+    };
+    let had_image = in_repo(&hm)?;
+
+    info!("Creating selftest machine '{}'", name);
+    let created = hm.create_machine(&mut machine);
+    let booted = report.record("create", created.map(|_| name.clone()))
+        && report.record("boot", wait_running(&hm, &name, opts.timeout));
+
+    if let (true, Some(bridge)) = (booted, &opts.bridge) {
+        let mac = machine
+            .spec
+            .nics
+            .as_ref()
+            .and_then(|n| n.first())
+            .map(|n| n.macaddress.clone())
+            .unwrap_or_default();
+        report.record("network", wait_reachable(&mac, bridge, opts.timeout));
+    }
+
+    // always clean up, even after a failed create, without waiting on the
+    // guest since it's thrown away
+    hm.set_destroy_timeout(Duration::ZERO);
+    let destroyed = hm.destroy_machine(&name);
+    let destroyed = report.record("destroy", destroyed.map(|_| name.clone()));
+
+    // and take out the image again if creating the machine imported it
+    if destroyed && !had_image && in_repo(&hm).unwrap_or(true) {
+        let removed = hm.remove_image(&hash);
+        report.record("remove image", removed.map(|_| hash.clone()));
+    }
+
+    Ok(report)
+}
+
+fn test_machine(name: &str, image: &Path, bridge: &Option<String>) -> Result<Machine, Error> {
+    let image = image.canonicalize()?;
+    let url =
+        url::Url::from_file_path(&image).map_err(|_| format!("invalid image path {:?}", image))?;
+
+    let mut yaml = format!(
+        "kind: Machine
+metadata:
+  name: {name}
+spec:
+  cpu: 1
+  memory: 256Mi
+  image:
+    url: \"{url}\"
+    hash: {hash}
+",
+        name = name,
+        url = url,
+        hash = hash_file(&image)?,
+    );
+
+    if let Some(bridge) = bridge {
+        yaml.push_str(&format!(
+            "  nics:
+    - kind: Bridge
+      parent: {}
+      address:
+        kind: IPv6SLAAC
+",
+            bridge
+        ));
+    }
+
+    match serde_yaml::from_str(&yaml)? {
+        Resource::Machine(m) => Ok(m),
+    }
+}
+
+// the domain must reach and stay in the running state for a few seconds,
+// so an immediate crash doesn't count as booted
+fn wait_running(hm: &HostManager, name: &str, timeout: Duration) -> Result<String, Error> {
+    let deadline = Instant::now() + timeout;
+    let settle = Duration::from_secs(5);
+    let mut running_since = None;
+
+    loop {
+        let state = hm.machine_state(name)?;
+
+        if state == "running" {
+            let since = *running_since.get_or_insert_with(Instant::now);
+            if since.elapsed() >= settle {
+                return Ok(state);
+            }
+        } else {
+            running_since = None;
+        }
+
+        if Instant::now() >= deadline {
+            return Err(
+                format!("machine not running after {:?}, state: {}", timeout, state).into(),
+            );
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+// ping the machine's SLAAC link-local address over the bridge
+fn wait_reachable(mac: &str, bridge: &str, timeout: Duration) -> Result<String, Error> {
+    let mac: Mac = mac
+        .parse()
+        .map_err(|_| format!("invalid MAC address '{}'", mac))?;
+    let addr = format!("{}%{}", mac.to_ipv6_link_local(), bridge);
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let status = Command::new("ping")
+            .args(["-6", "-c", "1", "-W", "2"])
+            .arg(&addr)
+            .output()?
+            .status;
+
+        if status.success() {
+            return Ok(addr);
+        }
+
+        if Instant::now() >= deadline {
+            return Err(format!("{} not reachable after {:?}", addr, timeout).into());
+        }
+
+        std::thread::sleep(Duration::from_secs(2));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn machine_spec() {
+        let image = std::env::temp_dir().join("bigiron-virt-selftest-image");
+        std::fs::write(&image, b"not really qcow2").unwrap();
+
+        let m = test_machine("selftest1", &image, &Some("br0".to_string())).unwrap();
+
+        assert_eq!(m.metadata.name, "selftest1");
+        assert_eq!(m.spec.cpu, 1);
+        assert_eq!(m.spec.image.hash, hash_file(&image).unwrap());
+        assert_eq!(m.spec.nics.unwrap()[0].parent, "br0");
+
+        std::fs::remove_file(&image).unwrap();
+    }
+}
@@ -15,7 +15,9 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
+use std::fs::Metadata;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use crate::error::Error;
 
@@ -23,6 +25,14 @@ pub struct DirectoryStore {
     path: PathBuf,
 }
 
+/// A file in a store, as `list_files_with_ext` finds it
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
 impl DirectoryStore {
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
         if !path.as_ref().is_dir() {
@@ -34,20 +44,51 @@ impl DirectoryStore {
         })
     }
 
-    pub fn list_files(&self) -> Result<Vec<String>, Error> {
-        let entries = std::fs::read_dir(&self.path)?
-            .map(|res| res.map(|e| e.file_name()))
-            .collect::<Result<Vec<_>, std::io::Error>>()?;
+    /// Names of the directories in the store, e.g. one per instance
+    pub fn list_dirs(&self) -> Result<Vec<String>, Error> {
+        Ok(self
+            .entries(|m| m.is_dir())?
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect())
+    }
 
-        let mut str_entries = Vec::new();
+    /// The regular files named `<something>.<ext>`, sorted by name
+    pub fn list_files_with_ext(&self, ext: &str) -> Result<Vec<FileEntry>, Error> {
+        let suffix = format!(".{}", ext);
+        let mut files = Vec::new();
+        for (name, meta) in self.entries(|m| m.is_file())? {
+            if name.len() > suffix.len() && name.ends_with(&suffix) {
+                files.push(FileEntry {
+                    name,
+                    size: meta.len(),
+                    modified: meta.modified()?,
+                });
+            }
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(files)
+    }
 
-        for e in entries {
-            if let Ok(s) = e.into_string() {
-                str_entries.push(s);
+    // the entries with UTF-8 names whose metadata passes `keep`, symlinks
+    // followed so a dangling one is left out
+    fn entries<F: Fn(&Metadata) -> bool>(&self, keep: F) -> Result<Vec<(String, Metadata)>, Error> {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(&self.path)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            match std::fs::metadata(entry.path()) {
+                Ok(meta) if keep(&meta) => entries.push((name, meta)),
+                Ok(_) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
             }
         }
 
-        Ok(str_entries)
+        Ok(entries)
     }
 
     pub fn path(&self) -> &Path {
@@ -63,9 +104,13 @@ mod test {
     pub fn test_list() {
         let d = DirectoryStore::new(".").unwrap();
 
-        let files = d.list_files().unwrap();
+        let dirs = d.list_dirs().unwrap();
+        eprintln!("{:?}", dirs);
+        assert!(dirs.contains(&"src".to_string()));
+        assert!(!dirs.contains(&"Cargo.toml".to_string()));
 
-        eprintln!("{:?}", files);
-        assert!(files.contains(&"src".to_string()));
+        let files = d.list_files_with_ext("toml").unwrap();
+        assert!(files.iter().any(|f| f.name == "Cargo.toml" && f.size > 0));
+        assert!(files.iter().all(|f| f.name.ends_with(".toml")));
     }
 }
@@ -0,0 +1,159 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Resource usage of one machine for `bigiron-virt stats`.
+//!
+//! The hypervisor only hands out counters, so usage is worked out from two
+//! samples of them taken a little apart.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hypervisor::DomainStats;
+
+/// How busy a machine was between two samples of its counters
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MachineStats {
+    pub name: String,
+    /// of one host cpu, so up to 100 times the vcpus
+    pub cpu_percent: f64,
+    pub vcpus: u32,
+    /// memory the balloon leaves the guest
+    pub memory_bytes: u64,
+    /// host memory the machine actually uses
+    pub memory_rss_bytes: u64,
+    pub disks: Vec<DiskRates>,
+    pub interfaces: Vec<InterfaceRates>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskRates {
+    /// target name, e.g. "vda"
+    pub name: String,
+    pub read_bytes_per_sec: u64,
+    pub write_bytes_per_sec: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InterfaceRates {
+    /// host side device name, e.g. "vnet0"
+    pub name: String,
+    pub rx_bytes_per_sec: u64,
+    pub tx_bytes_per_sec: u64,
+}
+
+impl MachineStats {
+    /// Usage from counters `before` and `after`, sampled `elapsed` apart.
+    /// Disks and interfaces missing from `before`, e.g. hot plugged in
+    /// between, count from zero.
+    pub fn between(before: &DomainStats, after: &DomainStats, elapsed: Duration) -> Self {
+        let secs = elapsed.as_secs_f64().max(f64::EPSILON);
+        let rate = |from: u64, to: u64| (to.saturating_sub(from) as f64 / secs) as u64;
+        let cpu_ns = after.cpu_time_ns.saturating_sub(before.cpu_time_ns) as f64;
+
+        Self {
+            name: after.name.clone(),
+            cpu_percent: cpu_ns / (secs * 1e9) * 100.0,
+            vcpus: after.vcpus,
+            memory_bytes: after.memory_bytes,
+            memory_rss_bytes: after.memory_rss_bytes,
+            disks: after
+                .disks
+                .iter()
+                .map(|d| {
+                    let old = before.disks.iter().find(|o| o.name == d.name);
+                    DiskRates {
+                        name: d.name.clone(),
+                        read_bytes_per_sec: rate(old.map_or(0, |o| o.read_bytes), d.read_bytes),
+                        write_bytes_per_sec: rate(old.map_or(0, |o| o.write_bytes), d.write_bytes),
+                    }
+                })
+                .collect(),
+            interfaces: after
+                .interfaces
+                .iter()
+                .map(|i| {
+                    let old = before.interfaces.iter().find(|o| o.name == i.name);
+                    InterfaceRates {
+                        name: i.name.clone(),
+                        rx_bytes_per_sec: rate(old.map_or(0, |o| o.rx_bytes), i.rx_bytes),
+                        tx_bytes_per_sec: rate(old.map_or(0, |o| o.tx_bytes), i.tx_bytes),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hypervisor::{DiskStats, InterfaceStats};
+
+    #[test]
+    fn rates() {
+        let disk = |read_bytes, write_bytes| DiskStats {
+            name: String::from("vda"),
+            read_bytes,
+            write_bytes,
+        };
+        let before = DomainStats {
+            name: String::from("vm1"),
+            cpu_time_ns: 1_000_000_000,
+            vcpus: 2,
+            disks: vec![disk(4096, 0)],
+            ..Default::default()
+        };
+        let after = DomainStats {
+            cpu_time_ns: 4_000_000_000,
+            memory_bytes: 2 << 30,
+            memory_rss_bytes: 1 << 30,
+            disks: vec![disk(4096 + 2 * 8192, 2 * 1024)],
+            interfaces: vec![InterfaceStats {
+                name: String::from("vnet0"),
+                rx_bytes: 2000,
+                tx_bytes: 500,
+            }],
+            ..before.clone()
+        };
+
+        let stats = MachineStats::between(&before, &after, Duration::from_secs(2));
+        assert_eq!(stats.name, "vm1");
+        // 1.5 cpus busy
+        assert_eq!(stats.cpu_percent, 150.0);
+        assert_eq!(stats.memory_rss_bytes, 1 << 30);
+        assert_eq!(
+            stats.disks,
+            [DiskRates {
+                name: String::from("vda"),
+                read_bytes_per_sec: 8192,
+                write_bytes_per_sec: 1024,
+            }]
+        );
+        assert_eq!(stats.interfaces[0].rx_bytes_per_sec, 1000);
+        assert_eq!(stats.interfaces[0].tx_bytes_per_sec, 250);
+
+        // counters reset by a restart in between
+        let stats = MachineStats::between(&after, &before, Duration::from_secs(2));
+        assert_eq!(stats.cpu_percent, 0.0);
+        assert_eq!(stats.disks[0].read_bytes_per_sec, 0);
+    }
+}
@@ -0,0 +1,161 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Starting machines again after they stop by themselves, as their
+//! `spec.restartPolicy` asks, from `bigiron-virt serve`.
+//!
+//! Whether a stop counts is decided from its lifecycle event, the restart
+//! itself happens on the next tick once the backoff is over. Backoff
+//! doubles with every restart from 5 seconds up to 5 minutes, and starts
+//! over once a machine stays up for 10 minutes.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::models::RestartPolicy;
+use crate::events::LifecycleEvent;
+
+const FIRST_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+// up this long and the machine is restarted without delay again
+const STABLE_UPTIME: Duration = Duration::from_secs(600);
+
+/// Restarts of a machine by its restartPolicy, kept with the instance
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RestartState {
+    /// bigiron-virt is stopping the machine, so it isn't restarted
+    pub stop_requested: bool,
+    /// when the machine stopped and is waiting to be restarted, in
+    /// seconds since the epoch
+    pub pending_since: Option<u64>,
+    /// restarts since the machine last stayed up
+    pub attempts: u32,
+    pub last_restart_at: Option<u64>,
+}
+
+impl RestartState {
+    /// Whether `event` is a stop `policy` restarts the machine after
+    pub fn wants_restart(&self, policy: RestartPolicy, event: &LifecycleEvent) -> bool {
+        if event.event != "stopped" {
+            return false;
+        }
+        match (policy, event.detail.as_str()) {
+            (RestartPolicy::Never, _) => false,
+            (_, "crashed" | "failed") => true,
+            (RestartPolicy::Always, "shutdown") => !self.stop_requested,
+            _ => false,
+        }
+    }
+
+    /// Wait for a restart from `now`
+    pub fn stopped(&mut self, now: u64) {
+        let stable = self
+            .last_restart_at
+            .is_some_and(|at| now >= at + STABLE_UPTIME.as_secs());
+        if stable {
+            self.attempts = 0;
+        }
+        self.pending_since = Some(now);
+    }
+
+    /// Whether a pending restart is due at `now`
+    pub fn due(&self, now: u64) -> bool {
+        self.pending_since
+            .is_some_and(|since| now >= since + self.backoff().as_secs())
+    }
+
+    /// How long to wait before the next restart
+    pub fn backoff(&self) -> Duration {
+        match self.attempts {
+            0 => Duration::ZERO,
+            n => FIRST_BACKOFF
+                .saturating_mul(1 << (n - 1).min(16))
+                .min(MAX_BACKOFF),
+        }
+    }
+
+    /// Count a restart attempt at `now`, keeping the restart pending if it
+    /// failed
+    pub fn attempted(&mut self, ok: bool, now: u64) {
+        self.attempts += 1;
+        self.last_restart_at = Some(now);
+        self.pending_since = if ok { None } else { Some(now) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(event: &str, detail: &str) -> LifecycleEvent {
+        LifecycleEvent {
+            timestamp: String::new(),
+            machine: "test123".to_string(),
+            event: event.to_string(),
+            detail: detail.to_string(),
+        }
+    }
+
+    #[test]
+    fn wants_restart() {
+        let mut state = RestartState::default();
+        let crashed = event("stopped", "crashed");
+        let shutdown = event("stopped", "shutdown");
+
+        assert!(!state.wants_restart(RestartPolicy::Never, &crashed));
+        assert!(state.wants_restart(RestartPolicy::OnFailure, &crashed));
+        assert!(state.wants_restart(RestartPolicy::OnFailure, &event("stopped", "failed")));
+        assert!(!state.wants_restart(RestartPolicy::OnFailure, &shutdown));
+        assert!(state.wants_restart(RestartPolicy::Always, &shutdown));
+        assert!(!state.wants_restart(RestartPolicy::Always, &event("stopped", "destroyed")));
+        assert!(!state.wants_restart(RestartPolicy::Always, &event("crashed", "panicked")));
+
+        state.stop_requested = true;
+        assert!(!state.wants_restart(RestartPolicy::Always, &shutdown));
+        assert!(state.wants_restart(RestartPolicy::Always, &crashed));
+    }
+
+    #[test]
+    fn backoff() {
+        let mut state = RestartState::default();
+        assert!(!state.due(100));
+
+        state.stopped(100);
+        assert!(state.due(100));
+        state.attempted(true, 100);
+        assert_eq!(state.pending_since, None);
+
+        state.stopped(110);
+        assert!(!state.due(114));
+        assert!(state.due(115));
+        state.attempted(false, 115);
+        assert!(!state.due(124));
+        assert!(state.due(125));
+
+        state.attempts = 10;
+        assert_eq!(state.backoff(), MAX_BACKOFF);
+
+        // up long enough, the next restart is immediate again
+        state.attempted(true, 1000);
+        state.stopped(1000 + STABLE_UPTIME.as_secs());
+        assert_eq!(state.attempts, 0);
+        assert!(state.due(1000 + STABLE_UPTIME.as_secs()));
+    }
+}
@@ -0,0 +1,273 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! Working out what `bigiron-virt update` changes in a machine.
+//!
+//! Domains are rebuilt from the stored spec each time they start, so an
+//! update always replaces the stored spec. A running machine takes some
+//! changes right away: less memory through the balloon, nics and file or
+//! block disks added after the existing ones, and nics removed from the
+//! end. Everything else waits for the machine to restart.
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::models::{Image, Machine, StorageKind};
+use crate::error::Error;
+
+/// One difference between a machine's stored spec and its update
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    /// balloon down to this many bytes
+    Memory(u64),
+    /// hot plug nic `i` of the update
+    AttachNic(usize),
+    /// hot unplug nic `i` of the stored spec
+    DetachNic(usize),
+    /// hot plug storage `i` of the update
+    AttachDisk(usize),
+    /// only the stored spec changes, e.g. labels
+    Stored(String),
+    /// applies when the machine next starts
+    Restart(String),
+}
+
+impl Change {
+    /// Whether a running machine can take the change
+    pub fn live(&self) -> bool {
+        !matches!(self, Change::Restart(_))
+    }
+}
+
+/// What `update` did, in words
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct UpdateReport {
+    /// changes the machine has taken
+    pub applied: Vec<String>,
+    /// changes waiting for the machine to restart
+    pub pending: Vec<String>,
+    pub restarted: bool,
+}
+
+/// The changes from machine `current` to `update`, failing on ones an
+/// update can't make
+pub fn plan(current: &Machine, update: &Machine) -> Result<Vec<(Change, String)>, Error> {
+    let name = &current.metadata.name;
+    if update.metadata.name != *name {
+        return Err(format!("the update is for {}, not {}", update.metadata.name, name).into());
+    }
+    // resize-disk grows the disk past the size it was made with
+    let image = Image {
+        resize: current.spec.image.resize,
+        ..update.spec.image.clone()
+    };
+    if image != current.spec.image {
+        return Err(format!("the image of {} can't change, create a new machine", name).into());
+    }
+
+    let mut changes = Vec::new();
+    let (cur, new) = (&current.spec, &update.spec);
+
+    if new.cpu != cur.cpu {
+        let what = format!("cpu {} -> {}", cur.cpu, new.cpu);
+        changes.push((Change::Restart(what.clone()), what));
+    }
+
+    if new.memory != cur.memory {
+        let what = format!("memory {} -> {}", cur.memory, new.memory);
+        let change = match new.memory < cur.memory {
+            true => Change::Memory(new.memory.bytes()),
+            false => Change::Restart(what.clone()),
+        };
+        changes.push((change, what));
+    }
+
+    let cur_nics = cur.nics.as_deref().unwrap_or_default();
+    let new_nics = new.nics.as_deref().unwrap_or_default();
+    let common = cur_nics.len().min(new_nics.len());
+    for i in 0..common {
+        if cur_nics[i] != new_nics[i] {
+            let what = format!("nic {} changed", i);
+            changes.push((Change::Restart(what.clone()), what));
+        }
+    }
+    for (i, nic) in new_nics.iter().enumerate().skip(common) {
+        let what = format!("nic {} added on {} {}", i, nic.kind, nic.parent);
+        changes.push((Change::AttachNic(i), what));
+    }
+    for (i, nic) in cur_nics.iter().enumerate().skip(common) {
+        let what = format!("nic {} on {} {} removed", i, nic.kind, nic.parent);
+        changes.push((Change::DetachNic(i), what));
+    }
+
+    let bus = new.storage_bus.unwrap_or_default();
+    let cur_disks = cur.storage.as_deref().unwrap_or_default();
+    let new_disks = new.storage.as_deref().unwrap_or_default();
+    let common = cur_disks.len().min(new_disks.len());
+    for i in 0..common {
+        if cur_disks[i] != new_disks[i] {
+            let what = format!("disk {} changed", bus.target(i)?);
+            changes.push((Change::Restart(what.clone()), what));
+        }
+    }
+    for (i, disk) in new_disks.iter().enumerate().skip(common) {
+        let what = format!("disk {} added", bus.target(i)?);
+        let change = match disk {
+            StorageKind::File(_) | StorageKind::Block(_) => Change::AttachDisk(i),
+            _ => Change::Restart(what.clone()),
+        };
+        changes.push((change, what));
+    }
+    if cur_disks.len() > new_disks.len() {
+        let what = format!("{} disks removed", cur_disks.len() - new_disks.len());
+        changes.push((Change::Restart(what.clone()), what));
+    }
+
+    // the rest of the spec by field, all rebuilt into the domain
+    let handled = ["cpu", "memory", "image", "nics", "storage"];
+    for field in changed_fields(&serde_json::to_value(cur)?, &serde_json::to_value(new)?) {
+        if !handled.contains(&field.as_str()) {
+            let what = format!("{} changed", field);
+            changes.push((Change::Restart(what.clone()), what));
+        }
+    }
+
+    let cur_meta = (&current.metadata.labels, &current.metadata.ttl);
+    if (&update.metadata.labels, &update.metadata.ttl) != cur_meta {
+        let what = String::from("metadata changed");
+        changes.push((Change::Stored(what.clone()), what));
+    }
+
+    Ok(changes)
+}
+
+// the top level fields that differ between two objects, in name order
+fn changed_fields(current: &serde_json::Value, update: &serde_json::Value) -> Vec<String> {
+    let empty = serde_json::Map::new();
+    let current = current.as_object().unwrap_or(&empty);
+    let update = update.as_object().unwrap_or(&empty);
+
+    let mut fields: Vec<String> = current
+        .keys()
+        .chain(update.keys())
+        .filter(|k| current.get(*k) != update.get(*k))
+        .cloned()
+        .collect();
+    fields.sort();
+    fields.dedup();
+    fields
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use crate::api::models::{DiskDriver, File, Nic};
+
+    fn machine() -> Machine {
+        Machine::builder()
+            .name("vm1")
+            .cpu(2)
+            .memory("4Gi")
+            .image(
+                "file:///images/ubuntu.img",
+                "754129c5052756ee47a0c395e518bd3413f444dff69b98f8a8fa42f2fa3acc2d",
+            )
+            .nic(Nic::bridge("br0"))
+            .nic(Nic::bridge("br1"))
+            .build()
+            .unwrap()
+    }
+
+    fn described(current: &Machine, update: &Machine) -> Vec<(bool, String)> {
+        plan(current, update)
+            .unwrap()
+            .into_iter()
+            .map(|(c, what)| (c.live(), what))
+            .collect()
+    }
+
+    #[test]
+    fn live_and_restart() {
+        let current = machine();
+        assert!(plan(&current, &current).unwrap().is_empty());
+
+        let mut update = current.clone();
+        update.spec.cpu = 4;
+        update.spec.memory = "2Gi".parse().unwrap();
+        update.spec.iothreads = Some(2);
+        update.spec.nics.as_mut().unwrap().truncate(1);
+        update.spec.storage = Some(vec![StorageKind::File(File {
+            path: PathBuf::from("/data/disk.qcow2"),
+            driver: DiskDriver::default(),
+        })]);
+        assert_eq!(
+            described(&current, &update),
+            [
+                (false, String::from("cpu 2 -> 4")),
+                (true, String::from("memory 4 GiB -> 2 GiB")),
+                (true, String::from("nic 1 on Bridge br1 removed")),
+                (true, String::from("disk vdb added")),
+                (false, String::from("iothreads changed")),
+            ]
+        );
+        assert_eq!(plan(&current, &update).unwrap()[3].0, Change::AttachDisk(0));
+
+        let mut update = current.clone();
+        update.spec.memory = "8Gi".parse().unwrap();
+        update.spec.nics.as_mut().unwrap()[0] = Nic::macvtap("eth0");
+        update
+            .spec
+            .nics
+            .as_mut()
+            .unwrap()
+            .push(Nic::network("default"));
+        update.metadata.labels = Some([("app".to_string(), "web".to_string())].into());
+        assert_eq!(
+            described(&current, &update),
+            [
+                (false, String::from("memory 4 GiB -> 8 GiB")),
+                (false, String::from("nic 0 changed")),
+                (true, String::from("nic 2 added on Network default")),
+                (true, String::from("metadata changed")),
+            ]
+        );
+    }
+
+    #[test]
+    fn refused() {
+        let current = machine();
+
+        let mut other = current.clone();
+        other.metadata.name = String::from("vm2");
+        let err = plan(&current, &other).unwrap_err();
+        assert_eq!(err.to_string(), "the update is for vm2, not vm1");
+
+        let mut resized = current.clone();
+        resized.spec.image.resize = Some("200G".parse().unwrap());
+        assert!(plan(&resized, &current).unwrap().is_empty());
+
+        let mut rebased = current.clone();
+        rebased.spec.image.url = String::from("file:///images/fedora.img");
+        let err = plan(&current, &rebased).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the image of vm1 can't change, create a new machine"
+        );
+    }
+}
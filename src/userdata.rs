@@ -0,0 +1,146 @@
+//  Copyright (C) 2023 IBM Corp.
+//
+//  This library is free software; you can redistribute it and/or
+//  modify it under the terms of the GNU Lesser General Public
+//  License as published by the Free Software Foundation; either
+//  version 2.1 of the License, or (at your option) any later version.
+//
+//  This library is distributed in the hope that it will be useful,
+//  but WITHOUT ANY WARRANTY; without even the implied warranty of
+//  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+//  Lesser General Public License for more details.
+//
+//  You should have received a copy of the GNU Lesser General Public
+//  License along with this library; if not, write to the Free Software
+//  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
+//  USA
+
+//! User-data kept in files beside the model file (`spec.userdataFile`).
+//!
+//! A single file is used as it is, whatever its format. Several files must
+//! all be `#cloud-config` and are merged in order: mappings merge key by
+//! key, lists are appended to and any other value is replaced by the later
+//! file's.
+
+use std::path::{Path, PathBuf};
+
+use serde_yaml::Value;
+
+use crate::error::Error;
+
+pub(crate) const CLOUD_CONFIG_HEADER: &str = "#cloud-config";
+
+/// Read `files`, relative paths being taken from `base_dir`, into one
+/// user-data document
+pub fn load(files: &[PathBuf], base_dir: &Path) -> Result<String, Error> {
+    let mut parts = Vec::new();
+    for file in files {
+        let path = base_dir.join(file);
+        let data = std::fs::read_to_string(&path)
+            .map_err(|e| format!("error reading userdata file {:?}: {}", path, e))?;
+        parts.push((format!("userdata file {:?}", path), data));
+    }
+
+    match parts.len() {
+        0 => Err("no userdata files given".into()),
+        1 => Ok(parts.remove(0).1),
+        _ => merge(&parts),
+    }
+}
+
+/// Merge `(source, data)` cloud-configs in order, `source` naming the
+/// document in errors
+pub fn merge(parts: &[(String, String)]) -> Result<String, Error> {
+    let mut merged = Value::Mapping(Default::default());
+
+    for (source, data) in parts {
+        if !data.starts_with(CLOUD_CONFIG_HEADER) {
+            return Err(format!(
+                "{} is not {}, only cloud-configs can be merged",
+                source, CLOUD_CONFIG_HEADER
+            )
+            .into());
+        }
+
+        let value: Value =
+            serde_yaml::from_str(data).map_err(|e| format!("error parsing {}: {}", source, e))?;
+
+        match value {
+            Value::Mapping(_) => merge_value(&mut merged, value),
+            // comments only
+            Value::Null => {}
+            _ => return Err(format!("{} is not a mapping", source).into()),
+        }
+    }
+
+    Ok(format!(
+        "{}\n{}",
+        CLOUD_CONFIG_HEADER,
+        serde_yaml::to_string(&merged)?
+    ))
+}
+
+fn merge_value(into: &mut Value, from: Value) {
+    match (into, from) {
+        (Value::Mapping(into), Value::Mapping(from)) => {
+            for (key, value) in from {
+                match into.get_mut(&key) {
+                    Some(existing) => merge_value(existing, value),
+                    None => {
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(into), Value::Sequence(from)) => into.extend(from),
+        (into, from) => *into = from,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn merge_cloud_configs() {
+        let dir = std::env::temp_dir().join("bigiron-virt-userdata-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(
+            dir.join("base.yaml"),
+            "#cloud-config\npackages: [git]\nssh_pwauth: false\nusers:\n  - default\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("web.yaml"),
+            "#cloud-config\npackages: [nginx]\nssh_pwauth: true\nwrite_files:\n  - path: /etc/motd\n    content: hi\n",
+        )
+        .unwrap();
+        std::fs::write(dir.join("setup.sh"), "#!/bin/sh\necho hi\n").unwrap();
+
+        let files = [PathBuf::from("base.yaml"), PathBuf::from("web.yaml")];
+        let merged = load(&files, &dir).unwrap();
+        assert!(merged.starts_with("#cloud-config\n"));
+
+        let value: Value = serde_yaml::from_str(&merged).unwrap();
+        assert_eq!(
+            value["packages"],
+            serde_yaml::from_str::<Value>("[git, nginx]").unwrap()
+        );
+        assert_eq!(value["ssh_pwauth"], Value::Bool(true));
+        assert_eq!(value["users"][0], Value::from("default"));
+        assert_eq!(value["write_files"][0]["path"], Value::from("/etc/motd"));
+
+        // one file is taken verbatim, scripts included
+        let script = load(&[dir.join("setup.sh")], Path::new("/")).unwrap();
+        assert_eq!(script, "#!/bin/sh\necho hi\n");
+
+        let err = load(&[files[0].clone(), PathBuf::from("setup.sh")], &dir).unwrap_err();
+        assert!(err.to_string().contains("only cloud-configs can be merged"));
+
+        assert!(load(&[PathBuf::from("missing.yaml")], &dir).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
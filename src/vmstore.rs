@@ -15,77 +15,652 @@
 //  Foundation, Inc., 51 Franklin Street, Fifth Floor, Boston, MA  02110-1301
 //  USA
 
-use std::path::{Path, PathBuf};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::api::models::{ImageMode, Machine};
+use crate::config::InstanceStorage;
+use crate::error::{self, Error};
+use crate::health::HealthStatus;
 use crate::statestore::DirectoryStore;
+use crate::supervise::RestartState;
+
+// where tmpfs scratch disks go, a directory per instance
+const SCRATCH_TMPFS: &str = "/dev/shm";
 
 pub struct VMStore {
     store: DirectoryStore,
+    storage: InstanceStorage,
+}
+
+/// An instance root disk, either an image file or a raw block device
+pub enum InstanceImage {
+    File(PathBuf),
+    Block(PathBuf),
+}
+
+/// How far creating an instance got, kept with it so a create that failed
+/// or was interrupted shows as such rather than as a stopped machine
+#[derive(Debug, Clone, PartialEq)]
+pub enum Phase {
+    DownloadingImage,
+    CreatingDisk,
+    BuildingConfigDrive,
+    DefiningDomain,
+    /// created, the hypervisor has the machine's state from here on
+    Running,
+    Error(String),
+}
+
+/// When an instance was made, first ran and last changed state through
+/// bigiron-virt, in seconds since the epoch. Instances made before these
+/// were recorded have none.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Timestamps {
+    pub created_at: Option<u64>,
+    /// when the create finished, so less `created_at` how long it took
+    pub started_at: Option<u64>,
+    pub state_changed_at: Option<u64>,
+    /// when cloud-init was done, as it phoned home or the guest agent said
+    pub ready_at: Option<u64>,
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Phase::DownloadingImage => write!(f, "building: downloading image"),
+            Phase::CreatingDisk => write!(f, "building: creating disk"),
+            Phase::BuildingConfigDrive => write!(f, "building: building configdrive"),
+            Phase::DefiningDomain => write!(f, "building: defining domain"),
+            Phase::Running => write!(f, "running"),
+            Phase::Error(reason) => write!(f, "error: {}", reason),
+        }
+    }
+}
+
+impl std::str::FromStr for Phase {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "building: downloading image" => Ok(Phase::DownloadingImage),
+            "building: creating disk" => Ok(Phase::CreatingDisk),
+            "building: building configdrive" => Ok(Phase::BuildingConfigDrive),
+            "building: defining domain" => Ok(Phase::DefiningDomain),
+            "running" => Ok(Phase::Running),
+            _ => match s.strip_prefix("error: ") {
+                Some(reason) => Ok(Phase::Error(reason.to_string())),
+                None => Err(format!("unknown instance phase '{}'", s).into()),
+            },
+        }
+    }
+}
+
+impl InstanceImage {
+    pub fn path(&self) -> &Path {
+        match self {
+            InstanceImage::File(p) | InstanceImage::Block(p) => p,
+        }
+    }
 }
 
 impl VMStore {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+    pub fn new<P: AsRef<Path>>(path: P, storage: InstanceStorage) -> Result<Self, Error> {
         Ok(Self {
             store: DirectoryStore::new(path)?,
+            storage,
         })
     }
 
+    pub fn path(&self) -> &Path {
+        self.store.path()
+    }
+
     pub fn path_for_instance(&self, id: &str) -> PathBuf {
         self.store.path().join(id)
     }
 
     pub fn list_instances(&self) -> Result<Vec<String>, Error> {
-        Ok(self.store.list_files()?)
+        self.store.list_dirs()
     }
 
     pub fn new_instance(&mut self, id: &str) -> Result<PathBuf, Error> {
         let path = self.path_for_instance(id);
         std::fs::create_dir(&path)?;
+
+        let now = now()?;
+        self.save_timestamps(
+            id,
+            &Timestamps {
+                created_at: Some(now),
+                started_at: None,
+                state_changed_at: Some(now),
+                ready_at: None,
+            },
+        )?;
+
         Ok(path)
     }
 
+    /// When instance `id` was made, what a `ttl` counts from
+    pub fn created(&self, id: &str) -> Option<SystemTime> {
+        let secs = self.timestamps(id).created_at?;
+        Some(UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    pub fn timestamps(&self, id: &str) -> Timestamps {
+        std::fs::read(self.path_for_instance(id).join("timestamps.json"))
+            .ok()
+            .and_then(|s| serde_json::from_slice(&s).ok())
+            .unwrap_or_default()
+    }
+
+    /// Record that instance `id` changed state just now, and that it
+    /// started for the first time if `started`
+    pub fn state_changed(&mut self, id: &str, started: bool) -> Result<(), Error> {
+        let mut timestamps = self.timestamps(id);
+        let now = now()?;
+        timestamps.state_changed_at = Some(now);
+        if started && timestamps.started_at.is_none() {
+            timestamps.started_at = Some(now);
+        }
+        self.save_timestamps(id, &timestamps)
+    }
+
+    /// Record that cloud-init in instance `id` is done, the first time
+    /// only
+    pub fn set_ready(&mut self, id: &str) -> Result<(), Error> {
+        let mut timestamps = self.timestamps(id);
+        if timestamps.ready_at.is_none() {
+            timestamps.ready_at = Some(now()?);
+            self.save_timestamps(id, &timestamps)?;
+        }
+        Ok(())
+    }
+
+    fn save_timestamps(&mut self, id: &str, timestamps: &Timestamps) -> Result<(), Error> {
+        let path = self.path_for_instance(id).join("timestamps.json");
+        std::fs::write(path, serde_json::to_vec(timestamps)?)?;
+        Ok(())
+    }
+
+    /// What the health check of instance `id` found so far
+    pub fn health(&self, id: &str) -> HealthStatus {
+        std::fs::read(self.path_for_instance(id).join("health.json"))
+            .ok()
+            .and_then(|s| serde_json::from_slice(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn set_health(&mut self, id: &str, health: &HealthStatus) -> Result<(), Error> {
+        let path = self.path_for_instance(id).join("health.json");
+        std::fs::write(path, serde_json::to_vec(health)?)?;
+        Ok(())
+    }
+
+    /// Restarts of instance `id` by its restartPolicy so far
+    pub fn restart_state(&self, id: &str) -> RestartState {
+        std::fs::read(self.path_for_instance(id).join("restart.json"))
+            .ok()
+            .and_then(|s| serde_json::from_slice(&s).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn set_restart_state(&mut self, id: &str, state: &RestartState) -> Result<(), Error> {
+        let path = self.path_for_instance(id).join("restart.json");
+        std::fs::write(path, serde_json::to_vec(state)?)?;
+        Ok(())
+    }
+
+    /// Make the root disk of instance `id` from `image_path`, LUKS
+    /// encrypted with `passphrase` if given
+    #[instrument(name = "instance_image", skip_all, fields(machine = %id, storage = ?self.storage))]
     pub fn create_instance_image<P: AsRef<Path>>(
         &mut self,
         id: &str,
         image_path: P,
         resize: Option<u64>,
+        mode: ImageMode,
+        passphrase: Option<&[u8]>,
+    ) -> Result<InstanceImage, Error> {
+        if passphrase.is_some() && self.storage != InstanceStorage::Qcow2 {
+            return Err("encrypted instance disks need qcow2 instance storage".into());
+        }
+
+        match self.storage {
+            InstanceStorage::Qcow2 => {
+                let imgpath = self.path_for_instance(id).join("instance.qcow2");
+
+                self.with_key(id, passphrase, |key| {
+                    match mode {
+                        ImageMode::Cow => {
+                            imgutil::create(&imgpath, resize, Some(&image_path), key)?
+                        }
+                        ImageMode::Flatten => {
+                            imgutil::flatten(&image_path, &imgpath, key)?;
+                            if let Some(size) = resize {
+                                imgutil::resize(&imgpath, size, key)?;
+                            }
+                        }
+                    }
+                    Ok(())
+                })?;
+
+                Ok(InstanceImage::File(imgpath))
+            }
+            // independent of the base image whatever the mode
+            InstanceStorage::Reflink => {
+                let imgpath = self.path_for_instance(id).join("instance.qcow2");
+
+                imgutil::reflink(&image_path, &imgpath)?;
+                if let Some(size) = resize {
+                    imgutil::resize(&imgpath, size, None)?;
+                }
+
+                Ok(InstanceImage::File(imgpath))
+            }
+            // always a full copy
+            InstanceStorage::Lvm { ref volume_group } => {
+                let size = match resize {
+                    Some(size) => size,
+                    None => imgutil::virtual_size(&image_path)?,
+                };
+
+                let dev = lvm::create(volume_group, &lv_name(id), size)?;
+
+                if let Err(e) = imgutil::convert_raw(&image_path, &dev) {
+                    let _ = lvm::remove(volume_group, &lv_name(id));
+                    return Err(e);
+                }
+
+                Ok(InstanceImage::Block(dev))
+            }
+        }
+    }
+
+    /// Write a standalone qcow2 copy of an instance root disk to `dest`
+    pub fn export_instance_image<P: AsRef<Path>>(&self, id: &str, dest: P) -> Result<(), Error> {
+        imgutil::flatten(self.instance_image(id).path(), dest, None)
+    }
+
+    /// Make the standalone qcow2 image at `source`, inside the instance
+    /// directory, the instance root disk
+    pub fn import_instance_image<P: AsRef<Path>>(
+        &mut self,
+        id: &str,
+        source: P,
+    ) -> Result<InstanceImage, Error> {
+        let image = self.instance_image(id);
+
+        match self.storage {
+            InstanceStorage::Qcow2 | InstanceStorage::Reflink => {
+                std::fs::rename(source.as_ref(), image.path())?;
+            }
+            InstanceStorage::Lvm { ref volume_group } => {
+                let size = imgutil::virtual_size(&source)?;
+                lvm::create(volume_group, &lv_name(id), size)?;
+
+                if let Err(e) = imgutil::convert_raw(&source, image.path()) {
+                    let _ = lvm::remove(volume_group, &lv_name(id));
+                    return Err(e);
+                }
+
+                std::fs::remove_file(source.as_ref())?;
+            }
+        }
+
+        Ok(image)
+    }
+
+    /// The root disk of an existing instance
+    pub fn instance_image(&self, id: &str) -> InstanceImage {
+        match self.storage {
+            InstanceStorage::Qcow2 | InstanceStorage::Reflink => {
+                InstanceImage::File(self.path_for_instance(id).join("instance.qcow2"))
+            }
+            InstanceStorage::Lvm { ref volume_group } => {
+                InstanceImage::Block(Path::new("/dev").join(volume_group).join(lv_name(id)))
+            }
+        }
+    }
+
+    /// Grow an instance root disk to `size` bytes, unlocking it with
+    /// `passphrase` if encrypted. Running domains must also be told of the
+    /// new size, and for qcow2 images that is the only step, so `live`
+    /// skips resizing the image file itself.
+    pub fn resize_instance_image(
+        &mut self,
+        id: &str,
+        size: u64,
+        live: bool,
+        passphrase: Option<&[u8]>,
+    ) -> Result<(), Error> {
+        match self.storage {
+            InstanceStorage::Qcow2 | InstanceStorage::Reflink => {
+                if !live {
+                    let image = self.instance_image(id);
+                    self.with_key(id, passphrase, |key| {
+                        imgutil::resize(image.path(), size, key)
+                    })?;
+                }
+            }
+            InstanceStorage::Lvm { ref volume_group } => {
+                lvm::extend(volume_group, &lv_name(id), size)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Where `create_scratch_disk` makes the scratch disk of instance `id`
+    /// attached as `target`
+    pub fn scratch_disk_path(&self, id: &str, target: &str, tmpfs: bool) -> PathBuf {
+        if tmpfs {
+            scratch_tmpfs_dir(id).join(format!("{}.raw", target))
+        } else {
+            self.path_for_instance(id)
+                .join(format!("scratch-{}.qcow2", target))
+        }
+    }
+
+    /// Make an empty `size` byte scratch disk for instance `id` attached as
+    /// `target`, replacing what was there. On `tmpfs` it is a sparse raw
+    /// file, otherwise a qcow2 in the instance directory.
+    pub fn create_scratch_disk(
+        &mut self,
+        id: &str,
+        target: &str,
+        size: u64,
+        tmpfs: bool,
     ) -> Result<PathBuf, Error> {
-        let path = self.path_for_instance(id);
+        let path = self.scratch_disk_path(id, target, tmpfs);
+        if tmpfs {
+            std::fs::create_dir_all(scratch_tmpfs_dir(id))?;
+
+            let file = std::fs::File::create(&path)
+                .map_err(|e| format!("error creating scratch disk {:?}: {}", path, e))?;
+            file.set_len(size)?;
+
+            return Ok(path);
+        }
 
-        let imgpath = path.join("instance.qcow2");
+        if path.exists() {
+            std::fs::remove_file(&path)?;
+        }
+        imgutil::create(&path, Some(size), None::<&Path>, None)?;
 
-        imgutil::create(&imgpath, resize, Some(image_path))?;
+        Ok(path)
+    }
+
+    // run `f` with `passphrase` in a file only the owner can read, for
+    // qemu-img, removed again once it's done
+    fn with_key<T>(
+        &self,
+        id: &str,
+        passphrase: Option<&[u8]>,
+        f: impl FnOnce(Option<&Path>) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let Some(passphrase) = passphrase else {
+            return f(None);
+        };
+
+        let path = self.path_for_instance(id).join("disk.key");
+        let result = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&path)
+            .and_then(|mut file| file.write_all(passphrase))
+            .map_err(Error::from)
+            .and_then(|_| f(Some(&path)));
+        let _ = std::fs::remove_file(&path);
+
+        result
+    }
+
+    /// Record how far creating instance `id` got
+    pub fn set_phase(&mut self, id: &str, phase: &Phase) -> Result<(), Error> {
+        // one line, whatever the error said
+        let line = phase.to_string().replace('\n', " ");
+        std::fs::write(self.path_for_instance(id).join("status"), line + "\n")?;
+        self.state_changed(id, *phase == Phase::Running)
+    }
 
-        Ok(imgpath)
+    /// How far creating instance `id` got, instances created before
+    /// phases were recorded have none
+    pub fn phase(&self, id: &str) -> Option<Phase> {
+        let s = std::fs::read_to_string(self.path_for_instance(id).join("status")).ok()?;
+        s.trim_end().parse().ok()
     }
 
+    /// Persist the machine as created, so later commands can see its spec
+    pub fn save_machine(&mut self, id: &str, machine: &Machine) -> Result<(), Error> {
+        let path = self.path_for_instance(id).join("machine.yaml");
+        std::fs::write(path, machine.to_yaml()?)?;
+        Ok(())
+    }
+
+    /// Where the XML machine `id` was last started from is kept
+    pub fn domain_xml_path(&self, id: &str) -> PathBuf {
+        self.path_for_instance(id).join("domain.xml")
+    }
+
+    /// The serial console log of machine `id`, kept across restarts
+    pub fn console_log_path(&self, id: &str) -> PathBuf {
+        self.path_for_instance(id).join("console.log")
+    }
+
+    /// Where `save` puts the memory state of machine `id`, a symlink if it
+    /// was saved to a file of the user's choosing
+    pub fn saved_state_path(&self, id: &str) -> PathBuf {
+        self.path_for_instance(id).join("saved.state")
+    }
+
+    pub fn has_saved_state(&self, id: &str) -> bool {
+        self.saved_state_path(id).symlink_metadata().is_ok()
+    }
+
+    pub fn load_machine(&self, id: &str) -> Result<Machine, Error> {
+        let path = self.path_for_instance(id).join("machine.yaml");
+
+        let s = std::fs::read_to_string(&path).map_err(|e| {
+            let message = format!("no stored state for machine '{}': {}", id, e);
+            match e.kind() {
+                std::io::ErrorKind::NotFound => error::not_found(message),
+                _ => message.into(),
+            }
+        })?;
+
+        Ok(serde_yaml::from_str(&s)?)
+    }
+
+    /// Remove instance `id` with everything in its directory, succeeding if
+    /// it is already gone. Links in it, e.g. to an adopted disk, are
+    /// removed without touching what they point at.
     pub fn remove_instance(&mut self, id: &str) -> Result<(), Error> {
+        let path = self.instance_dir_in_store(id)?;
+
+        if let InstanceStorage::Lvm { ref volume_group } = self.storage {
+            if lvm::exists(volume_group, &lv_name(id)) {
+                lvm::remove(volume_group, &lv_name(id))?;
+            }
+        }
+
+        if let Some(path) = path {
+            std::fs::remove_dir_all(&path)?;
+        }
+
+        let scratch = scratch_tmpfs_dir(id);
+        if scratch.exists() {
+            std::fs::remove_dir_all(&scratch)?;
+        }
+
+        Ok(())
+    }
+
+    // the directory of instance `id`, made sure to be a real directory right
+    // under the store root before anything in it is removed, `None` if
+    // there is nothing there
+    fn instance_dir_in_store(&self, id: &str) -> Result<Option<PathBuf>, Error> {
+        let mut components = Path::new(id).components();
+        let single = matches!(
+            (components.next(), components.next()),
+            (Some(Component::Normal(_)), None)
+        );
+        if !single {
+            return Err(format!("'{}' is not an instance name", id).into());
+        }
+
         let path = self.path_for_instance(id);
+        let meta = match path.symlink_metadata() {
+            Ok(meta) => meta,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        if !meta.is_dir() {
+            return Err(format!("{:?} is not an instance directory", path).into());
+        }
+
+        let root = self.path().canonicalize()?;
+        if path.canonicalize()?.parent() != Some(root.as_path()) {
+            return Err(format!("{:?} is outside the instance store {:?}", path, root).into());
+        }
+
+        Ok(Some(path))
+    }
+}
+
+fn scratch_tmpfs_dir(id: &str) -> PathBuf {
+    Path::new(SCRATCH_TMPFS).join(lv_name(id))
+}
+
+fn now() -> Result<u64, Error> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+// instance ids may contain characters LVM doesn't allow in LV names
+fn lv_name(id: &str) -> String {
+    let id: String = id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '+' | '_' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+
+    format!("bigiron-{}", id)
+}
+
+mod lvm {
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
 
-        for entry in std::fs::read_dir(&path)? {
-            let entry = entry?;
-            std::fs::remove_file(entry.path())?;
+    use tracing::debug;
+
+    use crate::api::models::Size;
+    use crate::error::Error;
+
+    pub fn create(vg: &str, lv: &str, size: u64) -> Result<PathBuf, Error> {
+        debug!("Creating logical volume {}/{} of {}", vg, lv, Size(size));
+
+        let mut cmd = Command::new("lvcreate");
+        cmd.arg("--yes")
+            .arg("--size")
+            .arg(format!("{}b", size))
+            .arg("--name")
+            .arg(lv)
+            .arg(vg);
+
+        debug!("Running: {:?}", cmd);
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "failed to create logical volume {}/{}: {}",
+                vg,
+                lv,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
         }
 
-        std::fs::remove_dir(&path)?;
+        Ok(Path::new("/dev").join(vg).join(lv))
+    }
+
+    pub fn extend(vg: &str, lv: &str, size: u64) -> Result<(), Error> {
+        let mut cmd = Command::new("lvextend");
+        cmd.arg("--size")
+            .arg(format!("{}b", size))
+            .arg(format!("{}/{}", vg, lv));
+
+        debug!("Running: {:?}", cmd);
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "failed to extend logical volume {}/{}: {}",
+                vg,
+                lv,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        Ok(())
+    }
+
+    pub fn exists(vg: &str, lv: &str) -> bool {
+        Path::new("/dev").join(vg).join(lv).exists()
+    }
+
+    pub fn remove(vg: &str, lv: &str) -> Result<(), Error> {
+        let mut cmd = Command::new("lvremove");
+        cmd.arg("--yes").arg(format!("{}/{}", vg, lv));
+
+        debug!("Running: {:?}", cmd);
+        let output = cmd.output()?;
+        if !output.status.success() {
+            return Err(format!(
+                "failed to remove logical volume {}/{}: {}",
+                vg,
+                lv,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
 
         Ok(())
     }
 }
 
-mod imgutil {
+pub mod imgutil {
     use std::path::Path;
     use std::process::Command;
+    use std::time::Duration;
 
     use tracing::debug;
 
+    use crate::api::models::Size;
     use crate::error::Error;
+    use crate::process::{self, Policy};
+
+    // metadata changes are quick, copies are bounded by disk speed and not
+    // worth starting over
+    const QUICK: Policy = Policy::new(Duration::from_secs(60));
+    const COPY: Policy = Policy::new(Duration::from_secs(3600)).retries(0);
 
+    /// Create a qcow2 at `filepath`, LUKS encrypted with the passphrase in
+    /// file `key` if given
     pub fn create<P: AsRef<Path>, B: AsRef<Path>>(
         filepath: P,
         resize: Option<u64>,
         backing_file: Option<B>,
+        key: Option<&Path>,
     ) -> Result<(), Error> {
         let mut cmd = Command::new("/usr/bin/qemu-img");
         cmd.arg("create");
@@ -100,18 +675,258 @@ mod imgutil {
 
         cmd.arg("-f");
         cmd.arg("qcow2");
+        if let Some(key) = key {
+            encrypt(&mut cmd, key);
+        }
         cmd.arg(filepath.as_ref());
 
         if let Some(size) = resize {
+            debug!("Resizing instance image to {}", Size(size));
             cmd.arg(size.to_string());
         }
 
-        debug!("Running: {:?}", cmd);
-        let r = cmd.status()?;
-        if r.success() {
-            return Ok(());
-        } else {
-            return Err("failed to create new image".into());
+        process::run(&mut cmd, &QUICK)?;
+        Ok(())
+    }
+
+    /// Copy `image` to a new qcow2 at `dest` without any backing file,
+    /// LUKS encrypted with the passphrase in file `key` if given
+    pub fn flatten<P: AsRef<Path>, D: AsRef<Path>>(
+        image: P,
+        dest: D,
+        key: Option<&Path>,
+    ) -> Result<(), Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("convert")
+            .arg("-q")
+            .arg("--force-share")
+            .arg("-O")
+            .arg("qcow2");
+        if let Some(key) = key {
+            encrypt(&mut cmd, key);
         }
+        cmd.arg(image.as_ref()).arg(dest.as_ref());
+
+        process::run(&mut cmd, &COPY)?;
+        Ok(())
+    }
+
+    /// Clone `image` to `dest` sharing its blocks, failing rather than
+    /// copying if the filesystem can't
+    pub fn reflink<P: AsRef<Path>, D: AsRef<Path>>(image: P, dest: D) -> Result<(), Error> {
+        let mut cmd = Command::new("cp");
+        cmd.arg("--reflink=always")
+            .arg(image.as_ref())
+            .arg(dest.as_ref());
+
+        process::run(&mut cmd, &QUICK).map_err(|e| {
+            format!(
+                "failed to reflink image {:?}, are the image repo and instance directory on the same reflink capable filesystem? {}",
+                image.as_ref(),
+                e
+            )
+        })?;
+
+        Ok(())
+    }
+
+    /// Write `image` onto an existing raw target such as a block device
+    pub fn convert_raw<P: AsRef<Path>, T: AsRef<Path>>(image: P, target: T) -> Result<(), Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("convert")
+            .arg("-q")
+            .arg("-n")
+            .arg("-O")
+            .arg("raw")
+            .arg(image.as_ref())
+            .arg(target.as_ref());
+
+        process::run(&mut cmd, &COPY)?;
+        Ok(())
+    }
+
+    /// Grow `image` to `size` bytes, unlocking it with the passphrase in
+    /// file `key` if it's encrypted
+    pub fn resize<P: AsRef<Path>>(image: P, size: u64, key: Option<&Path>) -> Result<(), Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("resize").arg("-q");
+        match key {
+            Some(key) => {
+                cmd.arg("--object")
+                    .arg(format!("secret,id=sec0,file={}", escape(key)))
+                    .arg("--image-opts")
+                    .arg(format!(
+                        "driver=qcow2,file.filename={},encrypt.key-secret=sec0",
+                        escape(image.as_ref())
+                    ));
+            }
+            None => {
+                cmd.arg(image.as_ref());
+            }
+        }
+        cmd.arg(size.to_string());
+
+        process::run(&mut cmd, &QUICK)?;
+        Ok(())
+    }
+
+    // output options making a LUKS encrypted qcow2
+    fn encrypt(cmd: &mut Command, key: &Path) {
+        cmd.arg("--object")
+            .arg(format!("secret,id=sec0,file={}", escape(key)))
+            .arg("-o")
+            .arg("encrypt.format=luks,encrypt.key-secret=sec0");
+    }
+
+    // a path as a QEMU option value, where commas are doubled
+    fn escape(path: &Path) -> String {
+        path.to_string_lossy().replace(',', ",,")
+    }
+
+    /// Size of the disk as seen by the guest, also works on images in use
+    pub fn virtual_size<P: AsRef<Path>>(image: P) -> Result<u64, Error> {
+        let mut cmd = Command::new("/usr/bin/qemu-img");
+        cmd.arg("info")
+            .arg("--force-share")
+            .arg("--output=json")
+            .arg(image.as_ref());
+
+        let output = process::run(&mut cmd, &QUICK)?;
+
+        let info: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+        info["virtual-size"]
+            .as_u64()
+            .ok_or_else(|| format!("no virtual-size for {:?}", image.as_ref()).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lv_names() {
+        assert_eq!(lv_name("web-01.example"), "bigiron-web-01.example");
+        assert_eq!(lv_name("a b/c"), "bigiron-a_b_c");
+    }
+
+    #[test]
+    fn tmpfs_scratch_disks() {
+        let dir = std::env::temp_dir().join("bigiron-virt-vmstore-scratch-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let id = format!("scratch-test-{}", std::process::id());
+
+        let mut store = VMStore::new(&dir, InstanceStorage::Qcow2).unwrap();
+        store.new_instance(&id).unwrap();
+
+        let path = store
+            .create_scratch_disk(&id, "vdb", 1 << 20, true)
+            .unwrap();
+        assert_eq!(path, scratch_tmpfs_dir(&id).join("vdb.raw"));
+        std::fs::write(&path, b"leftovers").unwrap();
+
+        // made empty again
+        let path = store
+            .create_scratch_disk(&id, "vdb", 1 << 20, true)
+            .unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 1 << 20);
+        assert!(std::fs::read(&path).unwrap().iter().all(|b| *b == 0));
+
+        store.remove_instance(&id).unwrap();
+        assert!(!scratch_tmpfs_dir(&id).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remove_instances() {
+        let dir = std::env::temp_dir().join("bigiron-virt-vmstore-remove-test");
+        let _ = std::fs::remove_dir_all(&dir);
+        let outside = std::env::temp_dir().join("bigiron-virt-vmstore-remove-outside");
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(outside.join("disk.qcow2"), b"keep").unwrap();
+
+        let mut store = VMStore::new(&dir, InstanceStorage::Qcow2).unwrap();
+        let instance = store.new_instance("vm1").unwrap();
+        std::fs::create_dir_all(instance.join("cidata-dir/openstack/latest")).unwrap();
+        std::fs::write(
+            instance.join("cidata-dir/openstack/latest/meta_data.json"),
+            b"{}",
+        )
+        .unwrap();
+        std::os::unix::fs::symlink(outside.join("disk.qcow2"), instance.join("instance.qcow2"))
+            .unwrap();
+
+        store.remove_instance("vm1").unwrap();
+        assert!(!instance.exists());
+        assert!(outside.join("disk.qcow2").exists());
+        // already gone
+        store.remove_instance("vm1").unwrap();
+
+        // nothing outside the store, even through a link in it
+        std::os::unix::fs::symlink(&outside, dir.join("vm2")).unwrap();
+        assert!(store.remove_instance("vm2").is_err());
+        assert!(store
+            .remove_instance("../bigiron-virt-vmstore-remove-outside")
+            .is_err());
+        assert!(store.remove_instance("").is_err());
+        assert!(outside.join("disk.qcow2").exists());
+
+        std::fs::remove_dir_all(&outside).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn saved_state() {
+        let dir = std::env::temp_dir().join("bigiron-virt-vmstore-test");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut store = VMStore::new(&dir, InstanceStorage::Qcow2).unwrap();
+        store.new_instance("vm1").unwrap();
+        assert!(!store.has_saved_state("vm1"));
+
+        // a link to a state file elsewhere counts, even if it is gone
+        std::os::unix::fs::symlink(dir.join("missing"), store.saved_state_path("vm1")).unwrap();
+        assert!(store.has_saved_state("vm1"));
+
+        store.remove_instance("vm1").unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn phases() {
+        let dir = std::env::temp_dir().join("bigiron-virt-vmstore-phase-test");
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let mut store = VMStore::new(&dir, InstanceStorage::Qcow2).unwrap();
+        store.new_instance("vm1").unwrap();
+        assert_eq!(store.phase("vm1"), None);
+
+        let created = store.created("vm1").unwrap();
+        assert!(created.elapsed().unwrap() < Duration::from_secs(60));
+        let timestamps = store.timestamps("vm1");
+        assert_eq!(timestamps.state_changed_at, timestamps.created_at);
+        assert_eq!(timestamps.started_at, None);
+
+        store.set_phase("vm1", &Phase::CreatingDisk).unwrap();
+        assert_eq!(store.phase("vm1"), Some(Phase::CreatingDisk));
+        assert_eq!(store.timestamps("vm1").started_at, None);
+
+        store.set_phase("vm1", &Phase::Running).unwrap();
+        let started = store.timestamps("vm1").started_at;
+        assert!(started.is_some());
+        store.state_changed("vm1", true).unwrap();
+        assert_eq!(store.timestamps("vm1").started_at, started);
+
+        let failed = Phase::Error(String::from("failed to create new image\nexit 1"));
+        store.set_phase("vm1", &failed).unwrap();
+        assert_eq!(
+            store.phase("vm1"),
+            Some(Phase::Error(String::from(
+                "failed to create new image exit 1"
+            )))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }